@@ -0,0 +1,162 @@
+use std::{fmt::Display, str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serenity::{http::Http, model::prelude::GuildId};
+use tracing::info;
+
+use crate::{
+    db::{get_setting, ACTIVE_HOURS_SETTING, TIMEZONE_SETTING},
+    namerestorer::restore_guild,
+    store::{NameStore, Names, Store},
+    tasks,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const TIME_FORMAT: &str = "%H:%M";
+
+/// Days `first` through `last` (wrapping past Sunday), from `start` until `end`. Windows that
+/// end before they start run past midnight.
+struct Window {
+    first: Weekday,
+    last: Weekday,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    fn includes_day(&self, day: Weekday) -> bool {
+        let offset =
+            |day: Weekday| (day.num_days_from_monday() + 7 - self.first.num_days_from_monday()) % 7;
+        offset(day) <= offset(self.last)
+    }
+
+    fn contains<T: TimeZone>(&self, now: &DateTime<T>) -> bool {
+        let (day, time) = (now.weekday(), now.time());
+        if self.start < self.end {
+            self.includes_day(day) && self.start <= time && time < self.end
+        } else {
+            (self.includes_day(day) && self.start <= time)
+                || (self.includes_day(day.pred()) && time < self.end)
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Days never contain digits, so the times start at the first one.
+        let (days, times) = s
+            .find(|c: char| c.is_ascii_digit())
+            .map(|times_start| s.split_at(times_start))
+            .ok_or_else(|| format!("{s} should look like mon-fri 18:00-23:00."))?;
+        let day = |day: &str| {
+            let day = day.trim();
+            day.parse::<Weekday>()
+                .map_err(|_| format!("{day} isn't a day of the week."))
+        };
+        let (first, last) = match days.trim() {
+            "daily" => (Weekday::Mon, Weekday::Sun),
+            days => match days.split_once('-') {
+                Some((first, last)) => (day(first)?, day(last)?),
+                None => (day(days)?, day(days)?),
+            },
+        };
+        let time = |time: &str| {
+            let time = time.trim();
+            NaiveTime::parse_from_str(time, TIME_FORMAT)
+                .map_err(|_| format!("{time} isn't a time like 18:00."))
+        };
+        let (start, end) = times
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| format!("{times} should look like 18:00-23:00."))?;
+        Ok(Window {
+            first,
+            last,
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let day = |day: Weekday| day.to_string().to_lowercase();
+        if self.first == self.last {
+            write!(f, "{}", day(self.first))?;
+        } else {
+            write!(f, "{}-{}", day(self.first), day(self.last))?;
+        }
+        write!(
+            f,
+            " {}-{}",
+            self.start.format(TIME_FORMAT),
+            self.end.format(TIME_FORMAT)
+        )
+    }
+}
+
+/// The times of the week a guild wants names changed, like `mon-fri 18:00-23:00, sat-sun
+/// 12:00-02:00`, in the guild's timezone.
+pub struct ActiveHours(Vec<Window>);
+
+impl ActiveHours {
+    pub fn contains<T: TimeZone>(&self, now: &DateTime<T>) -> bool {
+        self.0.iter().any(|window| window.contains(now))
+    }
+}
+
+impl FromStr for ActiveHours {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let windows = s
+            .split(',')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .map(|window| window.to_lowercase().parse())
+            .collect::<Result<Vec<_>, _>>()?;
+        if windows.is_empty() {
+            return Err("No active hours given.".to_string());
+        }
+        Ok(ActiveHours(windows))
+    }
+}
+
+impl Display for ActiveHours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, window) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{window}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether it's within the guild's active hours. Guilds without active hours are always active.
+pub fn is_active(db: &Store, guild_id: GuildId) -> bool {
+    let Some(hours) = get_setting::<ActiveHours>(db, guild_id, ACTIVE_HOURS_SETTING) else {
+        return true;
+    };
+    let timezone = get_setting(db, guild_id, TIMEZONE_SETTING).unwrap_or(Tz::UTC);
+    hours.contains(&Utc::now().with_timezone(&timezone))
+}
+
+/// Restores everyone's names in guilds whose active hours are over.
+pub fn spawn_sweeper(db: Store, http: Arc<Http>) {
+    tasks::spawn("active hours", async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for guild_id in db.guilds() {
+                if db.count_names(Names::Override, guild_id) == 0 || is_active(&db, guild_id) {
+                    continue;
+                }
+                info!("Active hours are over in guild {guild_id}, restoring names");
+                restore_guild(&db, &http, guild_id).await;
+            }
+        }
+    });
+}
@@ -0,0 +1,135 @@
+use serenity::{
+    all::{CreateEmbed, CreateEmbedFooter, CreateMessage, MessageId, ReactionType},
+    http::Http,
+    model::prelude::{ChannelId, GuildId, UserId},
+    prelude::*,
+    utils::MessageBuilder,
+};
+use tracing::{info, warn};
+
+use crate::{
+    db::{
+        get_setting, reroll_messages_db_tree_name, ANNOUNCE_CHANNEL_SETTING,
+        MAPPING_ANNOUNCEMENT_SETTING, TTS_ANNOUNCEMENT_SETTING, TTS_MESSAGE_SETTING,
+    },
+    store::Store,
+};
+
+/// Discord's limit on the length of an embed's description.
+const MAX_DESCRIPTION_LEN: usize = 4096;
+
+/// Reacting with this to a mapping announcement reshuffles its voice channel.
+pub const REROLL_EMOJI: &str = "🎲";
+
+pub const DEFAULT_TTS_MESSAGE: &str = "Names have been shuffled. Good luck on the Rift.";
+
+/// Tells the guild's announce channel that the names in a voice channel were shuffled, as a TTS
+/// message so people already in voice hear it. `{channel}` in the guild's template is replaced
+/// with the voice channel.
+pub async fn announce_shuffle(
+    db: &Store,
+    http: &Http,
+    guild_id: GuildId,
+    voice_channel: ChannelId,
+) {
+    if !get_setting(db, guild_id, TTS_ANNOUNCEMENT_SETTING).unwrap_or(false) {
+        return;
+    }
+    let Some(channel) = get_setting::<ChannelId>(db, guild_id, ANNOUNCE_CHANNEL_SETTING) else {
+        return;
+    };
+    let template: String = get_setting(db, guild_id, TTS_MESSAGE_SETTING)
+        .unwrap_or_else(|| DEFAULT_TTS_MESSAGE.to_string());
+    let content = template.replace("{channel}", &voice_channel.mention().to_string());
+    info!("Announcing shuffle in {voice_channel} to {channel} in guild {guild_id}");
+    if let Err(e) = channel
+        .send_message(http, CreateMessage::new().content(content).tts(true))
+        .await
+    {
+        warn!("Failed to announce shuffle in guild {guild_id}: {e:?}");
+    }
+}
+
+/// Posts who got which name, and whose game it came from, to the guild's announce channel.
+/// Members can react with [`REROLL_EMOJI`] to the latest one for a channel to reshuffle it.
+pub async fn announce_mapping(
+    db: &Store,
+    http: &Http,
+    guild_id: GuildId,
+    voice_channel: ChannelId,
+    mapping: &[(UserId, String, Option<UserId>)],
+) {
+    if !get_setting(db, guild_id, MAPPING_ANNOUNCEMENT_SETTING).unwrap_or(false) {
+        return;
+    }
+    let Some(channel) = get_setting::<ChannelId>(db, guild_id, ANNOUNCE_CHANNEL_SETTING) else {
+        return;
+    };
+    let mut description = format!("In {}\n", voice_channel.mention());
+    for (user_id, nick, from) in mapping {
+        let mut line = MessageBuilder::new();
+        line.push("\n")
+            .mention(user_id)
+            .push(" is ")
+            .push_bold_safe(nick);
+        if let Some(from) = from {
+            line.push(" after ").mention(from);
+        }
+        let line = line.build();
+        if description.len() + line.len() > MAX_DESCRIPTION_LEN {
+            break;
+        }
+        description.push_str(&line);
+    }
+    info!("Posting the names in {voice_channel} to {channel} in guild {guild_id}");
+    match channel
+        .send_message(
+            http,
+            CreateMessage::new()
+                .embed(
+                    CreateEmbed::new()
+                        .title("New names")
+                        .description(description)
+                        .footer(CreateEmbedFooter::new(format!(
+                            "React with {REROLL_EMOJI} to reroll"
+                        ))),
+                )
+                .reactions([ReactionType::Unicode(REROLL_EMOJI.to_string())]),
+        )
+        .await
+    {
+        Ok(message) => record_reroll_message(db, guild_id, message.id, voice_channel),
+        Err(e) => warn!("Failed to post the names in guild {guild_id}: {e:?}"),
+    }
+}
+
+/// Remembers which channel the announcement is about, forgetting older announcements for it so
+/// only the latest one rerolls.
+fn record_reroll_message(db: &Store, guild_id: GuildId, message_id: MessageId, channel: ChannelId) {
+    let reroll_messages = db
+        .open_tree(reroll_messages_db_tree_name(guild_id))
+        .unwrap();
+    let channel = channel.get().to_be_bytes();
+    for entry in reroll_messages.iter() {
+        let (key, value) = entry.unwrap();
+        if *value == channel {
+            reroll_messages.remove(key).unwrap();
+        }
+    }
+    reroll_messages
+        .insert(message_id.get().to_be_bytes(), &channel)
+        .unwrap();
+}
+
+/// The voice channel a mapping announcement is about, if it's still the latest one.
+pub fn reroll_channel(db: &Store, guild_id: GuildId, message_id: MessageId) -> Option<ChannelId> {
+    let reroll_messages = db
+        .open_tree(reroll_messages_db_tree_name(guild_id))
+        .unwrap();
+    let channel = reroll_messages
+        .get(message_id.get().to_be_bytes())
+        .unwrap()?;
+    Some(ChannelId::new(u64::from_be_bytes(
+        (*channel).try_into().ok()?,
+    )))
+}
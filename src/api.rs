@@ -0,0 +1,220 @@
+use std::{collections::BTreeMap, num::NonZeroU64, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    cache::Cache,
+    http::Http,
+    model::prelude::{GuildId, UserId},
+};
+use tracing::info;
+
+use crate::{
+    active_hours,
+    commands::{clear_permanent_nick, set_permanent_nick},
+    namerestorer::restore_guild,
+    session,
+    settings::GuildSettings,
+    store::{NameStore, Names, Store},
+    tasks,
+};
+
+struct Api {
+    db: Store,
+    http: Arc<Http>,
+    cache: Arc<Cache>,
+    token: String,
+}
+
+/// JSON endpoints for managing the running bot, behind a bearer token.
+pub fn router(db: Store, http: Arc<Http>, cache: Arc<Cache>, token: String) -> Router {
+    Router::new()
+        .route("/api/guilds", get(guilds))
+        .route("/api/guilds/:guild_id/names", get(names))
+        .route("/api/guilds/:guild_id/overrides", get(overrides))
+        .route(
+            "/api/guilds/:guild_id/overrides/:user_id",
+            put(set_override).get(get_override).delete(clear_override),
+        )
+        .route("/api/guilds/:guild_id/restore", post(restore))
+        .route("/api/guilds/:guild_id/shuffle", post(shuffle))
+        .with_state(Arc::new(Api {
+            db,
+            http,
+            cache,
+            token,
+        }))
+}
+
+fn authorized(api: &Api, headers: &HeaderMap) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        == Some(api.token.as_str())
+}
+
+/// Every member → name pair, keyed by user id.
+fn all_names(db: &Store, names: Names, guild_id: GuildId) -> BTreeMap<UserId, String> {
+    db.names(names, guild_id)
+        .into_iter()
+        .map(|(user_id, name)| (user_id, name.name))
+        .collect()
+}
+
+/// Whether the bot has anything stored for the guild. Checked before reading its names, since
+/// reading them can store an empty guild the bot doesn't know.
+fn is_known(db: &Store, guild_id: GuildId) -> bool {
+    db.guilds().contains(&guild_id)
+}
+
+#[derive(Serialize)]
+struct GuildSummary {
+    id: GuildId,
+    name: Option<String>,
+    enabled: bool,
+}
+
+async fn guilds(State(api): State<Arc<Api>>, headers: HeaderMap) -> Response {
+    if !authorized(&api, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let guilds: Vec<_> = api
+        .cache
+        .guilds()
+        .into_iter()
+        .map(|id| GuildSummary {
+            id,
+            name: id.name(&api.cache),
+            enabled: GuildSettings::load(&api.db, id).enabled
+                && active_hours::is_active(&api.db, id),
+        })
+        .collect();
+    Json(guilds).into_response()
+}
+
+async fn names(
+    State(api): State<Arc<Api>>,
+    Path(guild_id): Path<NonZeroU64>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&api, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let guild_id = GuildId::from(guild_id);
+    if !is_known(&api.db, guild_id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Json(all_names(&api.db, Names::Original, guild_id)).into_response()
+}
+
+async fn overrides(
+    State(api): State<Arc<Api>>,
+    Path(guild_id): Path<NonZeroU64>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&api, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let guild_id = GuildId::from(guild_id);
+    if !is_known(&api.db, guild_id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Json(all_names(&api.db, Names::Permanent, guild_id)).into_response()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Override {
+    name: String,
+}
+
+async fn get_override(
+    State(api): State<Arc<Api>>,
+    Path((guild_id, user_id)): Path<(NonZeroU64, NonZeroU64)>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&api, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let guild_id = GuildId::from(guild_id);
+    if !is_known(&api.db, guild_id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match api
+        .db
+        .get_name(Names::Permanent, guild_id, UserId::from(user_id))
+    {
+        Some(stored) => Json(Override { name: stored.name }).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Always gives the member this nickname, like `/override set`.
+async fn set_override(
+    State(api): State<Arc<Api>>,
+    Path((guild_id, user_id)): Path<(NonZeroU64, NonZeroU64)>,
+    headers: HeaderMap,
+    Json(Override { name }): Json<Override>,
+) -> Response {
+    if !authorized(&api, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let (guild_id, user_id) = (GuildId::from(guild_id), UserId::from(user_id));
+    info!("Setting permanent nick {name} for {user_id} in guild {guild_id} over the API");
+    match set_permanent_nick(&api.db, guild_id, user_id, &name) {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+    }
+}
+
+async fn clear_override(
+    State(api): State<Arc<Api>>,
+    Path((guild_id, user_id)): Path<(NonZeroU64, NonZeroU64)>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&api, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let (guild_id, user_id) = (GuildId::from(guild_id), UserId::from(user_id));
+    info!("Clearing permanent nick for {user_id} in guild {guild_id} over the API");
+    match clear_permanent_nick(&api.db, guild_id, user_id) {
+        Some(_) => StatusCode::NO_CONTENT.into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Starts putting everyone's names back and returns straight away.
+async fn restore(
+    State(api): State<Arc<Api>>,
+    Path(guild_id): Path<NonZeroU64>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&api, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let guild_id = GuildId::from(guild_id);
+    info!("Restoring names in guild {guild_id} over the API");
+    tasks::spawn(&format!("api restore {guild_id}"), async move {
+        restore_guild(&api.db, &api.http, guild_id).await;
+    });
+    StatusCode::ACCEPTED.into_response()
+}
+
+/// Deals out new names the next time each of the guild's channels syncs.
+async fn shuffle(
+    State(api): State<Arc<Api>>,
+    Path(guild_id): Path<NonZeroU64>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&api, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    session::clear_guild(&api.db, GuildId::from(guild_id));
+    StatusCode::ACCEPTED.into_response()
+}
@@ -0,0 +1,102 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serenity::model::prelude::{GuildId, Timestamp, UserId};
+use sled::Tree;
+use tracing::warn;
+
+use crate::{
+    db::{audit_db_tree_name, DbKey},
+    encryption,
+    store::{NameStore, Names, Store},
+};
+
+/// Starts an entry for a name the bot gave the member, rather than one they chose.
+const OVERRIDE_MARKER: char = '\u{2}';
+
+/// Key for a name change: the user followed by when we saw it, so a member's history sorts
+/// together and in order.
+fn audit_key(user_id: UserId, at_ms: u64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&DbKey::from(user_id).0);
+    key[8..].copy_from_slice(&at_ms.to_be_bytes());
+    key
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Records the name a member is showing now, unless it's the same as the last one recorded.
+/// Names that are the member's override are marked as the bot's.
+pub fn record(db: &Store, guild_id: GuildId, user_id: UserId, name: &str) {
+    let history = db.open_tree(audit_db_tree_name(guild_id)).unwrap();
+    let name_override = db.get_name(Names::Override, guild_id, user_id);
+    let entry = if name_override.is_some_and(|stored| stored.name == name) {
+        format!("{OVERRIDE_MARKER}{name}")
+    } else {
+        name.to_string()
+    };
+    let last = entries_until(&history, user_id, u64::MAX).next_back();
+    if last.as_deref() == Some(entry.as_str()) {
+        return;
+    }
+    history
+        .insert(audit_key(user_id, now_ms()), encryption::seal(&entry))
+        .unwrap();
+}
+
+/// The member's entries up to the given time, oldest first, as `record` wrote them.
+fn entries_until(
+    history: &Tree,
+    user_id: UserId,
+    at_ms: u64,
+) -> impl DoubleEndedIterator<Item = String> {
+    history
+        .range(audit_key(user_id, 0)..=audit_key(user_id, at_ms))
+        .values()
+        .filter_map(move |entry| {
+            let entry = entry
+                .inspect_err(|e| warn!("Failed to read name history for {user_id}: {e}"))
+                .ok()?;
+            encryption::open(&entry)
+                .inspect_err(|e| warn!("Corrupt name history for {user_id}: {e}"))
+                .ok()
+        })
+}
+
+/// The last name the member chose themselves at or before the given time, as far as we saw.
+/// Names the bot gave them are skipped.
+pub fn own_name_as_of(history: &Tree, user_id: UserId, at_ms: u64) -> Option<String> {
+    entries_until(history, user_id, at_ms)
+        .rev()
+        .find(|entry| !entry.starts_with(OVERRIDE_MARKER))
+}
+
+/// Everyone with recorded history in the guild.
+pub fn members(history: &Tree) -> Vec<UserId> {
+    let mut members: Vec<UserId> = history
+        .iter()
+        .keys()
+        .filter_map(|key| Some(DbKey(key.ok()?[..8].try_into().ok()?).into()))
+        .collect();
+    members.dedup();
+    members
+}
+
+/// Parses an RFC 3339 time, allowing the seconds to be left off (e.g. `2024-05-01T20:00Z`).
+pub fn parse_time(s: &str) -> Result<Timestamp, String> {
+    Timestamp::parse(s)
+        .or_else(|e| {
+            let zone = s.rfind(['Z', 'z', '+', '-']).filter(|i| *i > 10);
+            match zone {
+                Some(i) if s[..i].matches(':').count() == 1 => {
+                    Timestamp::parse(&format!("{}:00{}", &s[..i], &s[i..]))
+                }
+                _ => Err(e),
+            }
+        })
+        .map_err(|e| format!("Invalid time {s:?}: {e}"))
+}
@@ -0,0 +1,130 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use sled::Db;
+use tracing::info;
+
+/// Starts every backup, so restoring something else fails straight away.
+const MAGIC: &[u8] = b"namechanger backup 1\n";
+
+/// What `Db::export` produces and `Db::import` takes: each collection's type, name and entries.
+type Collection = (Vec<u8>, Vec<u8>, Vec<Vec<Vec<u8>>>);
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u64).to_be_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_bytes(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_u64(reader)?;
+    let mut bytes = Vec::new();
+    // Don't trust a corrupt length enough to allocate it up front.
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+/// Whether another record follows, as opposed to the end of the list.
+fn read_more(reader: &mut impl Read) -> io::Result<bool> {
+    let mut marker = [0];
+    reader.read_exact(&mut marker)?;
+    match marker {
+        [0] => Ok(false),
+        [1] => Ok(true),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt backup")),
+    }
+}
+
+/// Writes everything in the database to a single file, which is safe to take while the bot is
+/// running, unlike copying the sled directory. Returns how many entries were written.
+pub fn write(db: &Db, path: &Path) -> io::Result<usize> {
+    // Only replace an older backup once the new one is complete.
+    let partial = path.with_extension("partial");
+    let mut writer = BufWriter::new(File::create(&partial)?);
+    writer.write_all(MAGIC)?;
+    let mut written = 0;
+    for (kind, name, entries) in db.export() {
+        writer.write_all(&[1])?;
+        write_bytes(&mut writer, &kind)?;
+        write_bytes(&mut writer, &name)?;
+        for entry in entries {
+            writer.write_all(&[1])?;
+            writer.write_all(&(entry.len() as u64).to_be_bytes())?;
+            for part in entry {
+                write_bytes(&mut writer, &part)?;
+            }
+            written += 1;
+        }
+        writer.write_all(&[0])?;
+    }
+    writer.write_all(&[0])?;
+    writer.into_inner()?.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    info!("Backed up {written} entries to {path:?}");
+    Ok(written)
+}
+
+fn read(path: &Path) -> io::Result<Vec<Collection>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = vec![0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a namechanger backup",
+        ));
+    }
+    let mut collections = Vec::new();
+    while read_more(&mut reader)? {
+        let kind = read_bytes(&mut reader)?;
+        let name = read_bytes(&mut reader)?;
+        let mut entries = Vec::new();
+        while read_more(&mut reader)? {
+            let parts = read_u64(&mut reader)?;
+            entries.push(
+                (0..parts)
+                    .map(|_| read_bytes(&mut reader))
+                    .collect::<io::Result<_>>()?,
+            );
+        }
+        collections.push((kind, name, entries));
+    }
+    Ok(collections)
+}
+
+/// Loads a backup into an empty database. The whole file is checked before anything is
+/// written, so a truncated or corrupt backup leaves the database empty.
+pub fn restore(db: &Db, path: &Path) -> Result<usize, String> {
+    let is_empty = db
+        .tree_names()
+        .into_iter()
+        .all(|name| db.open_tree(name).unwrap().is_empty());
+    if !is_empty {
+        return Err("Backups can only be restored into an empty database".to_string());
+    }
+    let collections = read(path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+    let entries = collections
+        .iter()
+        .map(|(_, _, entries)| entries.len())
+        .sum();
+    db.import(
+        collections
+            .into_iter()
+            .map(|(kind, name, entries)| (kind, name, entries.into_iter()))
+            .collect(),
+    );
+    db.flush().map_err(|e| e.to_string())?;
+    info!("Restored {entries} entries from {path:?}");
+    Ok(entries)
+}
@@ -0,0 +1,82 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use serenity::model::prelude::GuildId;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::{metrics, owner::OWNER};
+
+/// Consecutive permission or rate-limit errors before we stop renaming in a guild.
+const FAILURE_THRESHOLD: u32 = 5;
+/// How long renames in a guild stay paused once the breaker trips.
+const COOLDOWN: Duration = Duration::from_secs(300);
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    /// How many of the failures were for missing permissions rather than rate limits.
+    forbidden: u32,
+    open_until: Option<Instant>,
+}
+
+/// Stops nickname edits in guilds that keep refusing them, so we don't hammer a guild that
+/// has taken away our permissions or is rate limiting us.
+pub struct CircuitBreakers {
+    breakers: LazyLock<Mutex<HashMap<GuildId, Breaker>>>,
+}
+
+pub static BREAKERS: CircuitBreakers = CircuitBreakers {
+    breakers: LazyLock::new(Mutex::default),
+};
+
+impl CircuitBreakers {
+    /// Whether renames in the guild are paused. Once the cooldown is over edits are let through
+    /// again, and the next one decides whether the breaker closes or trips again.
+    pub fn is_open(&self, guild_id: GuildId) -> bool {
+        self.breakers
+            .lock()
+            .unwrap()
+            .get(&guild_id)
+            .and_then(|breaker| breaker.open_until)
+            .is_some_and(|open_until| Instant::now() < open_until)
+    }
+
+    pub fn record_success(&self, guild_id: GuildId) {
+        if let Some(breaker) = self.breakers.lock().unwrap().remove(&guild_id) {
+            if breaker.open_until.is_some() {
+                info!("Circuit breaker for guild {guild_id} closed, resuming renames");
+            }
+        }
+    }
+
+    /// Records a permission (`forbidden`) or rate-limit error from the guild.
+    pub fn record_failure(&self, guild_id: GuildId, forbidden: bool) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(guild_id).or_default();
+        breaker.failures += 1;
+        breaker.forbidden += u32::from(forbidden);
+        // After the cooldown a single failure is enough to trip it again.
+        if breaker.failures >= FAILURE_THRESHOLD || breaker.open_until.is_some() {
+            breaker.open_until = Some(Instant::now() + COOLDOWN);
+            let trips = metrics::CIRCUIT_BREAKER_TRIPS.increment();
+            warn!(
+                "Circuit breaker for guild {guild_id} opened after {} failures, pausing renames for {COOLDOWN:?} ({trips} trips so far)",
+                breaker.failures
+            );
+            let cause = if breaker.forbidden * 2 > breaker.failures {
+                "Discord keeps refusing to let the bot change nicknames there. Make sure its role has Manage Nicknames and sits above the roles of the people it renames."
+            } else {
+                "Discord keeps rate limiting the bot's nickname edits there."
+            };
+            OWNER.alert(
+                "breaker",
+                Some(guild_id),
+                format!("Renames in guild {guild_id} are paused for {COOLDOWN:?} after {} failed edits. {cause}", breaker.failures),
+            );
+        }
+    }
+}
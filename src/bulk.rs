@@ -0,0 +1,70 @@
+use std::path::Path;
+
+use serenity::model::prelude::{GuildId, UserId};
+use tracing::{info, warn};
+
+use crate::{
+    db::{stored_names, NameSource},
+    nickname::MAX_NAME_CHARS,
+    sanitize::sanitize_name,
+    store::{NameStore, Names},
+};
+
+/// Parses a `user_id,name` row. Names may be wrapped in double quotes if they contain commas.
+fn parse_row(line: &str) -> Result<(UserId, String), String> {
+    let (user_id, name) = line
+        .split_once(',')
+        .ok_or_else(|| "expected user_id,name".to_string())?;
+    let user_id = match user_id.trim().parse::<u64>() {
+        Ok(id) if id != 0 => UserId::new(id),
+        _ => return Err(format!("{:?} is not a user id", user_id.trim())),
+    };
+    let name = name.trim();
+    let name = name
+        .strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+        .map(|name| name.replace("\"\"", "\""))
+        .unwrap_or_else(|| name.to_string());
+    let name = sanitize_name(&name);
+    if name.trim().is_empty() {
+        return Err("name is empty".to_string());
+    }
+    if name.chars().count() > MAX_NAME_CHARS {
+        return Err(format!("name is longer than {MAX_NAME_CHARS} characters"));
+    }
+    Ok((user_id, name))
+}
+
+/// Stores the names in a CSV of `user_id,name` rows as the guild members' names in a single
+/// batch. Rows that don't validate are reported and skipped; a leading header row is ignored.
+pub fn set_bulk(db: &(impl NameStore + ?Sized), guild_id: GuildId, file: &Path) {
+    let csv =
+        std::fs::read_to_string(file).unwrap_or_else(|e| panic!("Failed to read {file:?}: {e}"));
+    let mut entries = Vec::new();
+    let mut rejected = 0;
+    for (number, line) in csv.lines().enumerate().map(|(i, line)| (i + 1, line)) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_row(line) {
+            Ok(entry) => {
+                entries.push(entry);
+            }
+            Err(_) if number == 1 => info!("Treating {line:?} as a header"),
+            Err(e) => {
+                warn!("Rejected row {number} ({line:?}): {e}");
+                println!("Rejected row {number}: {e}");
+                rejected += 1;
+            }
+        }
+    }
+    db.set_names(
+        Names::Original,
+        guild_id,
+        &stored_names(entries.iter(), NameSource::Cli),
+    );
+    println!(
+        "Wrote {} names for guild {guild_id}, rejected {rejected} rows",
+        entries.len()
+    );
+}
@@ -0,0 +1,72 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use serenity::{
+    http::Http,
+    model::prelude::{ChannelId, GuildId},
+};
+use tracing::{error, warn};
+
+use crate::{
+    metrics,
+    owner::OWNER,
+    store::{NameStore, Names},
+};
+
+/// Guilds with fewer names than this are too small for a fraction of them to mean anything.
+const MIN_RECORDS: usize = 10;
+
+/// Watches the names we need to restore people's names, and raises an alarm if a guild loses a
+/// large part of them at once.
+pub struct ShrinkCanary {
+    max_shrink: f64,
+    alert_channel: Option<ChannelId>,
+    counts: Mutex<HashMap<(Names, GuildId), usize>>,
+}
+
+impl ShrinkCanary {
+    pub fn new(max_shrink: f64, alert_channel: Option<ChannelId>) -> Self {
+        Self {
+            max_shrink,
+            alert_channel,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Compares how many of the guild's names there are against the last time they were
+    /// checked. Call after changing them.
+    pub async fn check(
+        &self,
+        http: &Http,
+        store: &(impl NameStore + ?Sized),
+        names: Names,
+        guild_id: GuildId,
+    ) {
+        let count = store.count_names(names, guild_id);
+        let previous = self.counts.lock().unwrap().insert((names, guild_id), count);
+        let label = match names {
+            Names::Original => "stored names",
+            Names::Override => "name overrides",
+            Names::Permanent => "permanent nicknames",
+        };
+        let Some(previous) = previous else {
+            return;
+        };
+        if previous < MIN_RECORDS
+            || count >= previous
+            || (previous - count) as f64 / previous as f64 <= self.max_shrink
+        {
+            return;
+        }
+        let alerts = metrics::SHRINK_ALERTS.increment();
+        let message = format!(
+            "The {label} for guild {guild_id} shrank from {previous} to {count} records at once"
+        );
+        error!("{message} ({alerts} alerts so far)");
+        OWNER.alert("shrink", Some(guild_id), message.clone());
+        if let Some(channel) = self.alert_channel {
+            if let Err(e) = channel.say(http, &message).await {
+                warn!("Failed to post shrink alert to {channel}: {e:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,1633 @@
+use std::collections::HashMap;
+
+use chrono_tz::Tz;
+use clap::ValueEnum;
+use itertools::Itertools;
+use serenity::{
+    all::{
+        Command, CommandInteraction, CommandOptionType, CreateCommand, CreateCommandOption,
+        CreateEmbed, CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage,
+        Reaction, ReactionType, ResolvedOption, ResolvedValue,
+    },
+    model::prelude::{ChannelId, ChannelType, GuildId, MessageId, Permissions, UserId},
+    prelude::*,
+};
+use tracing::{info, warn};
+
+use crate::{
+    active_hours::ActiveHours,
+    announce::DEFAULT_TTS_MESSAGE,
+    db::{
+        consents_db_tree_name, forget_user, get_setting, opt_outs_db_tree_name, plays_db_tree_name,
+        remove_setting, riot_accounts_db_tree_name, rollouts_db_tree_name, set_setting,
+        ChampionMode, ConsentMode, DbKey, MissingAssetsPolicy, NameCache, NameList, Participation,
+        SpotifyMode, StoredName, StreamingMode, ACTIVE_HOURS_SETTING, ANNOUNCE_CHANNEL_SETTING,
+        BANNED_NAMES_SETTING, CHAMPION_MODE_SETTING, CONSENT_MESSAGE_SETTING, CONSENT_MODE_SETTING,
+        DM_NOTIFICATIONS_SETTING, LOCALE_SETTING, MAPPING_ANNOUNCEMENT_SETTING,
+        MISSING_ASSETS_SETTING, PLAYERS_ONLY_SETTING, PROFANITY_BUILTIN_SETTING,
+        PROFANITY_WORDS_SETTING, SKIP_MODERATORS_SETTING, SOURCE_PRIORITIES_SETTING,
+        SPOTIFY_MODE_SETTING, STREAMING_MODE_SETTING, TIMEZONE_SETTING, TTS_ANNOUNCEMENT_SETTING,
+        TTS_MESSAGE_SETTING,
+    },
+    ddragon::ChampionIndex,
+    event,
+    name_source::{NameSourceKind, NameSources, SourcePriorities},
+    namerestorer::{restore_guild, restore_guild_members, restore_member, restore_user},
+    nickname::MAX_NAME_CHARS,
+    pagination::Paginator,
+    riot::parse_riot_id,
+    rules::{self, ActivityField, Rule},
+    session,
+    settings::GuildSettings,
+    stats,
+    store::{NameStore, Names, Store},
+    template::{NameTemplate, TemplateValues},
+};
+
+const CONSENT_EMOJI: &str = "✅";
+/// Subcommands only server managers may use.
+const ADMIN_SUBCOMMANDS: &[&str] = &[
+    "mode",
+    "setup",
+    "enable",
+    "disable",
+    "channels",
+    "names",
+    "announce",
+    "spotify",
+    "streaming",
+    "rules",
+    "priority",
+    "champion",
+    "template",
+    "locale",
+    "ban",
+    "profanity",
+    "expiry",
+    "hours",
+    "timezone",
+    "players",
+    "minimum",
+    "moderators",
+    "dm",
+    "missing",
+];
+
+pub async fn register(ctx: &Context) {
+    let field_option = ActivityField::ALL.iter().fold(
+        CreateCommandOption::new(
+            CommandOptionType::String,
+            "field",
+            "Part of the activity to take the name from",
+        )
+        .required(true),
+        |option, (name, _)| option.add_string_choice(*name, *name),
+    );
+    let source_option = NameSourceKind::value_variants().iter().fold(
+        CreateCommandOption::new(CommandOptionType::String, "source", "Name source").required(true),
+        |option, kind| option.add_string_choice(kind.name(), kind.name()),
+    );
+    let namechanger = CreateCommand::new("namechanger")
+        .description("Configure the name changer")
+        .dm_permission(false)
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "optout",
+            "Stop the bot from changing your nickname in this server",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "optin",
+            "Allow the bot to change your nickname in this server again",
+        ))
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommandGroup,
+                "riot",
+                "Link your Riot ID so your champion is found even with rich presence off",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "link",
+                    "Link your Riot ID",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "riot_id",
+                        "Your Riot ID, like Faker#KR1",
+                    )
+                    .required(true),
+                ),
+            )
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "unlink",
+                "Unlink your Riot ID",
+            )),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "reshuffle",
+            "Deal out new names in your voice channel",
+        ))
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "mode",
+                "Choose whether members have to opt in before being renamed",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::String, "mode", "Consent mode")
+                    .required(true)
+                    .add_string_choice("opt-out", "opt-out")
+                    .add_string_choice("opt-in", "opt-in"),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "spotify",
+                "Name members listening to Spotify after what they're listening to",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::String, "mode", "Spotify mode")
+                    .required(true)
+                    .add_string_choice("off", "off")
+                    .add_string_choice("song", "song")
+                    .add_string_choice("artist", "artist"),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "champion",
+                "Name members playing League after their champion, its title or their skin",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::String, "mode", "Champion mode")
+                    .required(true)
+                    .add_string_choice("name", "name")
+                    .add_string_choice("title", "title")
+                    .add_string_choice("skin", "skin"),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "locale",
+                "Choose the language champion names are given in",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "locale",
+                    "A Data Dragon locale like en_US or ko_KR",
+                )
+                .required(true),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "hours",
+                "Only rename people at these times, like mon-fri 18:00-23:00. Leave empty to clear",
+            )
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::String,
+                "hours",
+                "Comma separated days and times, like mon-fri 18:00-23:00, sat-sun 12:00-02:00",
+            )),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "timezone",
+                "Choose the timezone active hours are in",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "timezone",
+                    "An IANA timezone like America/New_York",
+                )
+                .required(true),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "players",
+                "Choose whether only members who are playing get renamed",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::Boolean,
+                    "only",
+                    "Leave members who aren't playing with their own names",
+                )
+                .required(true),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "dm",
+                "Choose whether renamed members get a DM explaining their new name",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::Boolean, "enabled", "Send DMs")
+                    .required(true),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "moderators",
+                "Choose whether members who can manage nicknames get renamed",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::Boolean,
+                    "skip",
+                    "Leave members with Manage Nicknames or Administrator alone",
+                )
+                .required(true),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "minimum",
+                "Only rename people in channels with at least this many members",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::Integer, "members", "Members")
+                    .required(true)
+                    .min_int_value(1),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "expiry",
+                "Restore names that have been changed for this long, even without a voice event",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::Integer, "hours", "0 never restores")
+                    .required(true)
+                    .min_int_value(0),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "template",
+                "Format picked names, like {champion} ({original}). Leave empty to clear",
+            )
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::String,
+                "template",
+                "Uses {champion}, {original}, {username} and {channel}",
+            )),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "streaming",
+                "Name members after what someone in their channel is streaming",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::String, "mode", "Streaming mode")
+                    .required(true)
+                    .add_string_choice("off", "off")
+                    .add_string_choice("title", "title")
+                    .add_string_choice("game", "game"),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "missing",
+                "What to do when someone's League activity doesn't say which champion",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::String, "policy", "Fallback")
+                    .required(true)
+                    .add_string_choice("skip", "skip")
+                    .add_string_choice("fallback-to-stored", "fallback-to-stored")
+                    .add_string_choice("fallback-to-theme", "fallback-to-theme")
+                    .add_string_choice("use-activity-name", "use-activity-name"),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommandGroup,
+                "event",
+                "Give everyone with an event role a themed name, regardless of voice",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "start",
+                    "Start an event for everyone with a role",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::Role, "role", "Event role")
+                        .required(true),
+                ),
+            )
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "stop",
+                "End the event and restore everyone's names",
+            ))
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "join",
+                "Get the event role and a themed name",
+            ))
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "leave",
+                "Give up the event role and get your name back",
+            )),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommandGroup,
+                "rules",
+                "Take names from any game's activity with regex rules",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::SubCommand, "add", "Add a rule")
+                    .add_sub_option(field_option)
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::String,
+                            "template",
+                            "The name to give, with captures like $1 filled in",
+                        )
+                        .required(true),
+                    )
+                    .add_sub_option(CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "application_id",
+                        "Only match activities from this application",
+                    ))
+                    .add_sub_option(CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "activity_name",
+                        "Only match activities whose name matches this regex",
+                    ))
+                    .add_sub_option(CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "pattern",
+                        "Regex matched against the field (default: the whole field)",
+                    ))
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::Integer,
+                            "priority",
+                            "Lower priorities are tried first (default 100)",
+                        )
+                        .min_int_value(0),
+                    ),
+            )
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::SubCommand, "remove", "Remove a rule")
+                    .add_sub_option(
+                        CreateCommandOption::new(CommandOptionType::Integer, "id", "Rule id")
+                            .required(true)
+                            .min_int_value(0),
+                    ),
+            )
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "list",
+                "List the rules in priority order",
+            )),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommandGroup,
+                "priority",
+                "Choose which name sources win when several have a name",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "set",
+                    "Set a source's priority. Lower priorities win",
+                )
+                .add_sub_option(source_option)
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::Integer, "priority", "Priority")
+                        .required(true)
+                        .min_int_value(0),
+                ),
+            )
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "reset",
+                "Go back to the default order",
+            ))
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "list",
+                "Show the order sources are tried in",
+            )),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "setup",
+            "Post a message members can react to in order to opt in",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "enable",
+            "Resume changing nicknames in this server",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "disable",
+            "Pause changing nicknames in this server and restore everyone's names",
+        ))
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommandGroup,
+                "channels",
+                "Choose which voice channels the bot renames people in",
+            )
+            .add_sub_option(channel_subcommand(
+                "allow",
+                "Only rename people in allowed channels",
+            ))
+            .add_sub_option(channel_subcommand(
+                "deny",
+                "Never rename people in this channel",
+            ))
+            .add_sub_option(channel_subcommand(
+                "remove",
+                "Remove a channel from the allow and deny lists",
+            ))
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "list",
+                "Show the allowed and denied channels",
+            )),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommandGroup,
+                "ban",
+                "Stop the bot from handing out particular names",
+            )
+            .add_sub_option(name_subcommand("add", "Never hand out this name"))
+            .add_sub_option(name_subcommand("remove", "Allow this name again"))
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "list",
+                "Show the banned names",
+            )),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommandGroup,
+                "profanity",
+                "Keep names containing offensive words from being handed out",
+            )
+            .add_sub_option(name_subcommand("add", "Filter names containing this word"))
+            .add_sub_option(name_subcommand("remove", "Stop filtering this word"))
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "builtin",
+                    "Also filter a built-in list of common profanity and slurs",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::Boolean, "enabled", "Use it")
+                        .required(true),
+                ),
+            )
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "list",
+                "Show the server's filtered words",
+            )),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "announce",
+                "Configure the announcement when names are shuffled",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::Channel,
+                    "channel",
+                    "Text channel to announce in",
+                )
+                .channel_types(vec![ChannelType::Text]),
+            )
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::Boolean,
+                "tts",
+                "Read the announcement aloud with text to speech",
+            ))
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::Boolean,
+                "mapping",
+                "Also post who got which name and whose game it came from",
+            ))
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "message",
+                    "What to say. {channel} is replaced with the voice channel",
+                )
+                .max_length(2000),
+            ),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "names",
+            "List the names the bot has stored for members",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "leaderboard",
+            "Show the most played champions in this server",
+        ));
+    let user_option =
+        || CreateCommandOption::new(CommandOptionType::User, "user", "Member").required(true);
+    let override_command = CreateCommand::new("override")
+        .description("Manage nicknames members always get instead of a shuffled one")
+        .dm_permission(false)
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "set",
+                "Always give a member this nickname",
+            )
+            .add_sub_option(user_option())
+            .add_sub_option(
+                CreateCommandOption::new(CommandOptionType::String, "name", "Nickname")
+                    .required(true)
+                    .max_length(32),
+            ),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "clear",
+                "Go back to shuffling a member's nickname",
+            )
+            .add_sub_option(user_option()),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "list",
+            "List the permanent nicknames",
+        ));
+    let stats_command = CreateCommand::new("stats")
+        .description("Show how names have been shuffled in this server")
+        .dm_permission(false)
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "server",
+            "Show this server's totals",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "me",
+            "Show how often you've been renamed",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "champions",
+            "Show the champions handed out most often",
+        ));
+    let forget_me_command = CreateCommand::new("forgetme")
+        .description("Delete everything the bot has stored about you, in every server");
+    if let Err(e) = Command::set_global_commands(
+        &ctx.http,
+        vec![
+            namechanger,
+            override_command,
+            stats_command,
+            forget_me_command,
+        ],
+    )
+    .await
+    {
+        warn!("Failed to register slash commands: {e:?}");
+    }
+}
+
+fn name_subcommand(name: &str, description: &str) -> CreateCommandOption {
+    CreateCommandOption::new(CommandOptionType::SubCommand, name, description).add_sub_option(
+        CreateCommandOption::new(CommandOptionType::String, "name", "Name")
+            .required(true)
+            .max_length(32),
+    )
+}
+
+fn channel_subcommand(name: &str, description: &str) -> CreateCommandOption {
+    CreateCommandOption::new(CommandOptionType::SubCommand, name, description).add_sub_option(
+        CreateCommandOption::new(CommandOptionType::Channel, "channel", "Voice channel")
+            .required(true)
+            .channel_types(vec![ChannelType::Voice]),
+    )
+}
+
+pub async fn handle(
+    db: &Store,
+    name_cache: &NameCache,
+    pages: &Paginator,
+    champions: &ChampionIndex,
+    sources: &NameSources,
+    ctx: &Context,
+    command: &CommandInteraction,
+) {
+    // Works from DMs too, since it covers every server.
+    if command.data.name == "forgetme" {
+        respond(
+            ctx,
+            command,
+            text(forget_me(db, name_cache, ctx, command.user.id).await),
+        )
+        .await;
+        return;
+    }
+    let Some(guild_id) = command.guild_id else {
+        respond(
+            ctx,
+            command,
+            text("This command can only be used in a server."),
+        )
+        .await;
+        return;
+    };
+    let options = command.data.options();
+    let message = match (command.data.name.as_str(), subcommand(&options)) {
+        ("namechanger", Some(("optout", _))) => {
+            text(opt_out(db, ctx, guild_id, command.user.id).await)
+        }
+        ("namechanger", Some(("optin", _))) => text(opt_in(db, guild_id, command.user.id)),
+        ("namechanger", Some(("reshuffle", _))) => {
+            let channel_id = guild_id
+                .to_guild_cached(&ctx.cache)
+                .and_then(|guild| guild.voice_states.get(&command.user.id)?.channel_id);
+            text(match channel_id {
+                Some(channel_id) => {
+                    session::clear(db, guild_id, channel_id);
+                    format!(
+                        "Names in {} will be reshuffled the next time it syncs.",
+                        channel_id.mention()
+                    )
+                }
+                None => "Join a voice channel first.".to_string(),
+            })
+        }
+        ("namechanger", Some(("riot", args))) => {
+            let riot_accounts = db.open_tree(riot_accounts_db_tree_name(guild_id)).unwrap();
+            let key = DbKey::from(command.user.id);
+            text(match subcommand(args) {
+                Some(("link", args)) => match option(args, "riot_id") {
+                    Some(ResolvedValue::String(riot_id)) if parse_riot_id(riot_id).is_some() => {
+                        info!(
+                            "Linking {} to Riot ID {riot_id} in guild {guild_id}",
+                            command.user.id
+                        );
+                        riot_accounts.insert(key, riot_id.trim()).unwrap();
+                        format!(
+                            "Linked {}. Your champion will be looked up while you're in a game.",
+                            riot_id.trim()
+                        )
+                    }
+                    _ => "That isn't a Riot ID. It should look like Faker#KR1.".to_string(),
+                },
+                Some(("unlink", _)) => {
+                    riot_accounts.remove(key).unwrap();
+                    "Unlinked your Riot ID.".to_string()
+                }
+                _ => "Unknown riot command.".to_string(),
+            })
+        }
+        (name, Some((subcommand, _))) if requires_admin(name, subcommand) && !is_admin(command) => {
+            text("Only server managers can do that.")
+        }
+        (
+            "namechanger",
+            Some((
+                "mode",
+                [ResolvedOption {
+                    value: ResolvedValue::String(mode),
+                    ..
+                }],
+            )),
+        ) => text(match mode.parse() {
+            Ok(mode) => set_mode(db, ctx, guild_id, mode).await,
+            Err(()) => format!("Unknown mode {mode}."),
+        }),
+        ("namechanger", Some(("spotify", args))) => match option(args, "mode") {
+            Some(ResolvedValue::String(mode)) => text(match mode.parse() {
+                Ok(mode) => set_spotify_mode(db, guild_id, mode),
+                Err(()) => format!("Unknown mode {mode}."),
+            }),
+            _ => text("Missing mode."),
+        },
+        ("namechanger", Some(("champion", args))) => match option(args, "mode") {
+            Some(ResolvedValue::String(mode)) => text(match mode.parse() {
+                Ok(mode) => set_champion_mode(db, guild_id, mode),
+                Err(()) => format!("Unknown mode {mode}."),
+            }),
+            _ => text("Missing mode."),
+        },
+        ("namechanger", Some(("locale", args))) => match option(args, "locale") {
+            Some(ResolvedValue::String(locale)) => {
+                text(set_locale(db, champions, guild_id, locale))
+            }
+            _ => text("Missing locale."),
+        },
+        ("namechanger", Some(("hours", args))) => text(match option(args, "hours") {
+            Some(ResolvedValue::String(hours)) => set_active_hours(db, guild_id, hours),
+            _ => {
+                info!("Clearing active hours for guild {guild_id}");
+                remove_setting(db, guild_id, ACTIVE_HOURS_SETTING);
+                "Names will be changed at any time.".to_string()
+            }
+        }),
+        ("namechanger", Some(("timezone", args))) => match option(args, "timezone") {
+            Some(ResolvedValue::String(timezone)) => text(match timezone.parse::<Tz>() {
+                Ok(timezone) => {
+                    info!("Setting the timezone for guild {guild_id} to {timezone}");
+                    set_setting(db, guild_id, TIMEZONE_SETTING, timezone);
+                    format!("Active hours are now in {timezone}.")
+                }
+                Err(_) => format!("Unknown timezone {timezone}."),
+            }),
+            _ => text("Missing timezone."),
+        },
+        ("namechanger", Some(("players", args))) => match option(args, "only") {
+            Some(ResolvedValue::Boolean(only)) => {
+                info!("Setting players only for guild {guild_id} to {only}");
+                set_setting(db, guild_id, PLAYERS_ONLY_SETTING, only);
+                text(if *only {
+                    "Only members who are playing will be renamed."
+                } else {
+                    "Everyone in voice will be renamed."
+                })
+            }
+            _ => text("Missing only."),
+        },
+        ("namechanger", Some(("dm", args))) => match option(args, "enabled") {
+            Some(ResolvedValue::Boolean(enabled)) => {
+                info!("Setting DM notifications for guild {guild_id} to {enabled}");
+                set_setting(db, guild_id, DM_NOTIFICATIONS_SETTING, enabled);
+                text(if *enabled {
+                    "Renamed members will get a DM explaining their new name."
+                } else {
+                    "Renamed members won't get a DM."
+                })
+            }
+            _ => text("Missing enabled."),
+        },
+        ("namechanger", Some(("moderators", args))) => match option(args, "skip") {
+            Some(ResolvedValue::Boolean(skip)) => {
+                info!("Setting skip moderators for guild {guild_id} to {skip}");
+                set_setting(db, guild_id, SKIP_MODERATORS_SETTING, skip);
+                text(if *skip {
+                    "Members who can manage nicknames keep their own names."
+                } else {
+                    "Members who can manage nicknames will be renamed too."
+                })
+            }
+            _ => text("Missing skip."),
+        },
+        ("namechanger", Some(("minimum", args))) => match option(args, "members") {
+            Some(ResolvedValue::Integer(members)) => {
+                let members = (*members).max(1) as usize;
+                info!("Setting the minimum channel size for guild {guild_id} to {members}");
+                text(
+                    match GuildSettings::update(db, guild_id, |settings| {
+                        settings.min_members = members
+                    }) {
+                        Ok(_) => format!(
+                            "Channels need at least {members} members before names are shuffled."
+                        ),
+                        Err(e) => e,
+                    },
+                )
+            }
+            _ => text("Missing members."),
+        },
+        ("namechanger", Some(("expiry", args))) => match option(args, "hours") {
+            Some(ResolvedValue::Integer(hours)) => {
+                let hours = (*hours).max(0) as u64;
+                info!("Setting the override TTL for guild {guild_id} to {hours} hours");
+                text(
+                    match GuildSettings::update(db, guild_id, |settings| {
+                        settings.override_ttl_hours = Some(hours)
+                    }) {
+                        Err(e) => e,
+                        Ok(_) if hours == 0 => {
+                            "Changed names will only be restored by voice events.".to_string()
+                        }
+                        Ok(_) => format!("Changed names will be restored after {hours} hours."),
+                    },
+                )
+            }
+            _ => text("Missing hours."),
+        },
+        ("namechanger", Some(("template", args))) => text(match option(args, "template") {
+            Some(ResolvedValue::String(template)) => set_name_template(db, guild_id, template),
+            _ => {
+                info!("Clearing the name template for guild {guild_id}");
+                match GuildSettings::update(db, guild_id, |settings| settings.name_template = None)
+                {
+                    Ok(_) => "Names will be used as they are.".to_string(),
+                    Err(e) => e,
+                }
+            }
+        }),
+        ("namechanger", Some(("streaming", args))) => match option(args, "mode") {
+            Some(ResolvedValue::String(mode)) => text(match mode.parse() {
+                Ok(mode) => set_streaming_mode(db, guild_id, mode),
+                Err(()) => format!("Unknown mode {mode}."),
+            }),
+            _ => text("Missing mode."),
+        },
+        ("namechanger", Some(("missing", args))) => match option(args, "policy") {
+            Some(ResolvedValue::String(policy)) => text(match policy.parse() {
+                Ok(policy) => set_missing_assets_policy(db, guild_id, policy),
+                Err(()) => format!("Unknown policy {policy}."),
+            }),
+            _ => text("Missing policy."),
+        },
+        ("namechanger", Some(("event", args))) => match subcommand(args) {
+            Some(("join", _)) => {
+                text(event::set_membership(db, &ctx.http, guild_id, command.user.id, true).await)
+            }
+            Some(("leave", _)) => {
+                text(event::set_membership(db, &ctx.http, guild_id, command.user.id, false).await)
+            }
+            Some(_) if !is_admin(command) => text("Only server managers can do that."),
+            Some(("start", args)) => match option(args, "role") {
+                Some(ResolvedValue::Role(role)) => text(
+                    event::start(
+                        db,
+                        ctx,
+                        &event::Namer {
+                            champions,
+                            sources,
+                            name_cache,
+                        },
+                        guild_id,
+                        command.channel_id,
+                        role.id,
+                    )
+                    .await,
+                ),
+                _ => text("Missing role."),
+            },
+            Some(("stop", _)) => text(event::stop(db, ctx, guild_id).await),
+            _ => text("Unknown event command."),
+        },
+        ("namechanger", Some(("rules", args))) => match subcommand(args) {
+            Some(("list", _)) => pages.start("Activity rules", list_rules(db, guild_id)),
+            Some(("remove", args)) => text(match option(args, "id") {
+                Some(ResolvedValue::Integer(id)) if rules::remove(db, guild_id, *id as u64) => {
+                    format!("Removed rule {id}.")
+                }
+                _ => "No such rule.".to_string(),
+            }),
+            Some(("add", args)) => text(add_rule(db, guild_id, args)),
+            _ => text("Unknown rules command."),
+        },
+        ("namechanger", Some(("priority", args))) => text(match subcommand(args) {
+            Some(("set", args)) => match (option(args, "source"), option(args, "priority")) {
+                (Some(ResolvedValue::String(source)), Some(ResolvedValue::Integer(priority))) => {
+                    match NameSourceKind::from_str(source, false) {
+                        Ok(kind) => set_source_priority(
+                            db,
+                            guild_id,
+                            kind,
+                            (*priority).try_into().unwrap_or(u32::MAX),
+                        ),
+                        Err(_) => "Unknown source.".to_string(),
+                    }
+                }
+                _ => "Missing source or priority.".to_string(),
+            },
+            Some(("reset", _)) => {
+                info!("Resetting source priorities for guild {guild_id}");
+                remove_setting(db, guild_id, SOURCE_PRIORITIES_SETTING);
+                "Name sources are back to the default order.".to_string()
+            }
+            Some(("list", _)) => sources
+                .order(guild_id)
+                .into_iter()
+                .enumerate()
+                .map(|(i, (kind, priority))| {
+                    format!("{}. {} (priority {priority})", i + 1, kind.name())
+                })
+                .join("\n"),
+            _ => "Unknown priority command.".to_string(),
+        }),
+        ("namechanger", Some(("setup", _))) => text(setup(db, ctx, command, guild_id).await),
+        ("namechanger", Some(("enable", _))) => text(set_enabled(db, ctx, guild_id, true).await),
+        ("namechanger", Some(("disable", _))) => text(set_enabled(db, ctx, guild_id, false).await),
+        ("namechanger", Some(("channels", args))) => text(channels(db, guild_id, subcommand(args))),
+        ("namechanger", Some(("ban", args))) => match subcommand(args) {
+            Some(("list", _)) => pages.start("Banned names", banned_names(db, guild_id)),
+            subcommand => text(ban(db, guild_id, subcommand)),
+        },
+        ("namechanger", Some(("profanity", args))) => {
+            match subcommand(args) {
+                Some(("list", _)) => {
+                    let NameList(words) =
+                        get_setting(db, guild_id, PROFANITY_WORDS_SETTING).unwrap_or_default();
+                    pages.start("Filtered words", words)
+                }
+                Some(("builtin", args)) => match option(args, "enabled") {
+                    Some(ResolvedValue::Boolean(enabled)) => {
+                        info!("Setting the built-in profanity filter for guild {guild_id} to {enabled}");
+                        set_setting(db, guild_id, PROFANITY_BUILTIN_SETTING, enabled);
+                        text(if *enabled {
+                            "Names with common profanity won't be handed out."
+                        } else {
+                            "Only the server's own words are filtered."
+                        })
+                    }
+                    _ => text("Missing enabled."),
+                },
+                subcommand => text(filter_word(db, guild_id, subcommand)),
+            }
+        }
+        ("namechanger", Some(("announce", args))) => text(announce(db, guild_id, args)),
+        ("namechanger", Some(("names", _))) => pages.start("Stored names", names(db, guild_id)),
+        ("namechanger", Some(("leaderboard", _))) => {
+            pages.start("Most played champions", leaderboard(db, guild_id))
+        }
+        ("override", Some(("set", args))) => match (option(args, "user"), option(args, "name")) {
+            (Some(ResolvedValue::User(user, _)), Some(ResolvedValue::String(name))) => {
+                text(set_permanent_nick(db, guild_id, user.id, name).unwrap_or_else(|e| e))
+            }
+            _ => text("Missing user or name."),
+        },
+        ("override", Some(("clear", args))) => match option(args, "user") {
+            Some(ResolvedValue::User(user, _)) => text(
+                clear_permanent_nick(db, guild_id, user.id).unwrap_or_else(|| {
+                    format!("{} doesn't have a permanent nickname.", user.id.mention())
+                }),
+            ),
+            _ => text("Missing user."),
+        },
+        ("override", Some(("list", _))) => {
+            pages.start("Permanent nicknames", permanent_nicks(db, guild_id))
+        }
+        ("stats", Some(("server", _))) => embed(server_stats(db, guild_id)),
+        ("stats", Some(("me", _))) => embed(member_stats(db, champions, guild_id, command.user.id)),
+        ("stats", Some(("champions", _))) => embed(champion_stats(db, champions, guild_id)),
+        (name, _) => {
+            warn!("Received unknown command {name}");
+            text("Unknown command.")
+        }
+    };
+    respond(ctx, command, message).await;
+}
+
+fn subcommand<'a>(
+    options: &'a [ResolvedOption<'a>],
+) -> Option<(&'a str, &'a [ResolvedOption<'a>])> {
+    match options.first()? {
+        ResolvedOption {
+            name,
+            value: ResolvedValue::SubCommand(args) | ResolvedValue::SubCommandGroup(args),
+            ..
+        } => Some((name, args.as_slice())),
+        _ => None,
+    }
+}
+
+fn option<'a>(args: &'a [ResolvedOption<'a>], name: &str) -> Option<&'a ResolvedValue<'a>> {
+    args.iter()
+        .find(|option| option.name == name)
+        .map(|option| &option.value)
+}
+
+fn requires_admin(command: &str, subcommand: &str) -> bool {
+    match command {
+        "namechanger" => ADMIN_SUBCOMMANDS.contains(&subcommand),
+        "stats" => false,
+        _ => true,
+    }
+}
+
+fn is_admin(command: &CommandInteraction) -> bool {
+    command
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.manage_guild())
+}
+
+fn text(content: impl Into<String>) -> CreateInteractionResponseMessage {
+    CreateInteractionResponseMessage::new()
+        .content(content)
+        .ephemeral(true)
+}
+
+fn embed(embed: CreateEmbed) -> CreateInteractionResponseMessage {
+    CreateInteractionResponseMessage::new()
+        .embed(embed)
+        .ephemeral(true)
+}
+
+async fn respond(
+    ctx: &Context,
+    command: &CommandInteraction,
+    message: CreateInteractionResponseMessage,
+) {
+    if let Err(e) = command
+        .create_response(&ctx.http, CreateInteractionResponse::Message(message))
+        .await
+    {
+        warn!("Failed to respond to command {}: {e:?}", command.data.name);
+    }
+}
+
+async fn opt_out(db: &Store, ctx: &Context, guild_id: GuildId, user_id: UserId) -> String {
+    info!("Opting out {user_id} in guild {guild_id}");
+    let key = DbKey::from(user_id);
+    db.open_tree(opt_outs_db_tree_name(guild_id))
+        .unwrap()
+        .insert(key, &[])
+        .unwrap();
+    db.open_tree(consents_db_tree_name(guild_id))
+        .unwrap()
+        .remove(key)
+        .unwrap();
+    cancel_rollout(db, guild_id, user_id);
+    restore_member(db, &ctx.http, guild_id, user_id).await;
+    "You have opted out. The bot will no longer change your nickname in this server.".to_string()
+}
+
+async fn forget_me(db: &Store, name_cache: &NameCache, ctx: &Context, user_id: UserId) -> String {
+    info!("Forgetting {user_id} at their request");
+    restore_user(db, &ctx.http, user_id).await;
+    let deleted = forget_user(db, user_id);
+    name_cache.invalidate_user(user_id);
+    match deleted {
+        0 => "Nothing was stored about you.".to_string(),
+        deleted => format!(
+            "Deleted everything stored about you ({deleted} entries) and put your own name back wherever the bot had changed it. Servers you opted out of still leave you alone."
+        ),
+    }
+}
+
+pub fn opt_in(db: &Store, guild_id: GuildId, user_id: UserId) -> String {
+    info!("Opting in {user_id} in guild {guild_id}");
+    let key = DbKey::from(user_id);
+    db.open_tree(opt_outs_db_tree_name(guild_id))
+        .unwrap()
+        .remove(key)
+        .unwrap();
+    db.open_tree(consents_db_tree_name(guild_id))
+        .unwrap()
+        .insert(key, &[])
+        .unwrap();
+    "You have opted in. Your nickname will be shuffled the next time your channel syncs."
+        .to_string()
+}
+
+async fn set_mode(db: &Store, ctx: &Context, guild_id: GuildId, mode: ConsentMode) -> String {
+    info!("Setting consent mode for guild {guild_id} to {mode}");
+    set_setting(db, guild_id, CONSENT_MODE_SETTING, mode);
+    if mode == ConsentMode::OptIn {
+        // Members who never opted in shouldn't be left with a shuffled name.
+        let participation = Participation::new(db, guild_id);
+        restore_guild_members(db, &ctx.http, guild_id, |user_id| {
+            !participation.allows(user_id)
+        })
+        .await;
+    }
+    format!("Consent mode is now {mode}.")
+}
+
+fn set_spotify_mode(db: &Store, guild_id: GuildId, mode: SpotifyMode) -> String {
+    info!("Setting Spotify mode for guild {guild_id} to {mode}");
+    set_setting(db, guild_id, SPOTIFY_MODE_SETTING, mode);
+    match mode {
+        SpotifyMode::Off => "Members won't be named after what they're listening to.".to_string(),
+        _ => format!("Members listening to Spotify while not playing anything will be named after the {mode}."),
+    }
+}
+
+fn set_champion_mode(db: &Store, guild_id: GuildId, mode: ChampionMode) -> String {
+    info!("Setting champion mode for guild {guild_id} to {mode}");
+    set_setting(db, guild_id, CHAMPION_MODE_SETTING, mode);
+    match mode {
+        ChampionMode::Name => "Members will be named after their champion.".to_string(),
+        ChampionMode::Title => "Members will be named after their champion's title.".to_string(),
+        ChampionMode::Skin => {
+            "Members will be named after the skin they're using, or their champion if their client doesn't say.".to_string()
+        }
+    }
+}
+
+fn set_locale(db: &Store, champions: &ChampionIndex, guild_id: GuildId, locale: &str) -> String {
+    let locales = champions.locales();
+    if !locales.contains(&locale) {
+        return format!(
+            "Unknown locale {locale}. Use one of {}.",
+            locales.join(", ")
+        );
+    }
+    info!("Setting the locale for guild {guild_id} to {locale}");
+    set_setting(db, guild_id, LOCALE_SETTING, locale);
+    format!("Champion names will be given in {locale}.")
+}
+
+fn set_active_hours(db: &Store, guild_id: GuildId, hours: &str) -> String {
+    let hours: ActiveHours = match hours.parse() {
+        Ok(hours) => hours,
+        Err(e) => return e,
+    };
+    info!("Setting active hours for guild {guild_id} to {hours}");
+    set_setting(db, guild_id, ACTIVE_HOURS_SETTING, &hours);
+    let timezone: Tz = get_setting(db, guild_id, TIMEZONE_SETTING).unwrap_or(Tz::UTC);
+    format!(
+        "Names will only be changed {hours} ({timezone}). Everyone's names are restored outside those hours."
+    )
+}
+
+fn set_name_template(db: &Store, guild_id: GuildId, template: &str) -> String {
+    let template: NameTemplate = match template.parse() {
+        Ok(template) => template,
+        Err(e) => return e,
+    };
+    info!("Setting the name template for guild {guild_id} to {template}");
+    if let Err(e) = GuildSettings::update(db, guild_id, |settings| {
+        settings.name_template = Some(template.clone())
+    }) {
+        return e;
+    }
+    let example = template.render(&TemplateValues {
+        champion: "Ahri",
+        original: "Faker",
+        username: "faker",
+        channel: "General",
+    });
+    format!("Names will look like {example}.")
+}
+
+fn set_missing_assets_policy(db: &Store, guild_id: GuildId, policy: MissingAssetsPolicy) -> String {
+    info!("Setting missing assets policy for guild {guild_id} to {policy}");
+    set_setting(db, guild_id, MISSING_ASSETS_SETTING, policy);
+    match policy {
+        MissingAssetsPolicy::Skip => {
+            "Members whose source doesn't say which champion will be left alone."
+        }
+        MissingAssetsPolicy::FallbackToStored => {
+            "Members whose source doesn't say which champion will get their own name."
+        }
+        MissingAssetsPolicy::FallbackToTheme => {
+            "Members whose source doesn't say which champion will get a random champion."
+        }
+        MissingAssetsPolicy::UseActivityName => {
+            "Members whose source doesn't say which champion will be named after the game."
+        }
+    }
+    .to_string()
+}
+
+fn set_streaming_mode(db: &Store, guild_id: GuildId, mode: StreamingMode) -> String {
+    info!("Setting streaming mode for guild {guild_id} to {mode}");
+    set_setting(db, guild_id, STREAMING_MODE_SETTING, mode);
+    match mode {
+        StreamingMode::Off => "Members won't be named after streams.".to_string(),
+        _ => format!("Members will be named after the {mode} of streams in their channel."),
+    }
+}
+
+fn set_source_priority(
+    db: &Store,
+    guild_id: GuildId,
+    kind: NameSourceKind,
+    priority: u32,
+) -> String {
+    info!("Setting the priority of {kind:?} to {priority} in guild {guild_id}");
+    let mut priorities: SourcePriorities =
+        get_setting(db, guild_id, SOURCE_PRIORITIES_SETTING).unwrap_or_default();
+    priorities.set(kind, priority);
+    set_setting(db, guild_id, SOURCE_PRIORITIES_SETTING, priorities);
+    format!("{} now has priority {priority}.", kind.name())
+}
+
+fn add_rule(db: &Store, guild_id: GuildId, args: &[ResolvedOption]) -> String {
+    let string = |name| match option(args, name) {
+        Some(ResolvedValue::String(value)) => Some(value.to_string()),
+        _ => None,
+    };
+    let Some(field) = string("field").and_then(|field| {
+        ActivityField::ALL
+            .iter()
+            .find(|(name, _)| *name == field)
+            .map(|(_, field)| *field)
+    }) else {
+        return "Unknown field.".to_string();
+    };
+    let application_id = match string("application_id").map(|id| id.parse::<u64>()) {
+        Some(Err(_)) => return "The application id must be a number.".to_string(),
+        id => id.and_then(Result::ok),
+    };
+    let priority = match option(args, "priority") {
+        Some(ResolvedValue::Integer(priority)) => (*priority).try_into().unwrap_or(u32::MAX),
+        _ => 100,
+    };
+    let rule = Rule {
+        application_id,
+        activity_name: string("activity_name"),
+        field,
+        pattern: string("pattern").unwrap_or_else(|| "^(.*)$".to_string()),
+        template: string("template").unwrap_or_default(),
+    };
+    info!("Adding rule {rule:?} with priority {priority} to guild {guild_id}");
+    match rules::add(db, guild_id, priority, &rule) {
+        Ok(id) => format!("Added rule {id}."),
+        Err(e) => e,
+    }
+}
+
+fn list_rules(db: &Store, guild_id: GuildId) -> Vec<String> {
+    rules::list(db, guild_id)
+        .into_iter()
+        .map(|(priority, id, rule)| {
+            let matcher = match (rule.application_id, &rule.activity_name) {
+                (Some(application_id), Some(name)) => {
+                    format!("app {application_id}, name `{name}`")
+                }
+                (Some(application_id), None) => format!("app {application_id}"),
+                (None, Some(name)) => format!("name `{name}`"),
+                (None, None) => "anything".to_string(),
+            };
+            format!(
+                "**{id}** (priority {priority}): {matcher}, {:?} `{}` → `{}`",
+                rule.field, rule.pattern, rule.template
+            )
+        })
+        .collect()
+}
+
+async fn set_enabled(db: &Store, ctx: &Context, guild_id: GuildId, enabled: bool) -> String {
+    info!("Setting enabled for guild {guild_id} to {enabled}");
+    if let Err(e) = GuildSettings::update(db, guild_id, |settings| settings.enabled = enabled) {
+        return e;
+    }
+    if enabled {
+        "The name changer is enabled. Names will be shuffled the next time a channel syncs."
+            .to_string()
+    } else {
+        restore_guild(db, &ctx.http, guild_id).await;
+        "The name changer is disabled and everyone's names have been restored.".to_string()
+    }
+}
+
+fn channels(
+    db: &Store,
+    guild_id: GuildId,
+    subcommand: Option<(&str, &[ResolvedOption])>,
+) -> String {
+    match subcommand {
+        Some(("list", _)) => {
+            let settings = GuildSettings::load(db, guild_id);
+            let mentions = |channels: &[ChannelId]| {
+                if channels.is_empty() {
+                    "none".to_string()
+                } else {
+                    channels.iter().map(|id| id.mention()).join(", ")
+                }
+            };
+            format!(
+                "Allowed: {}\nDenied: {}",
+                mentions(&settings.allowed_channels),
+                mentions(&settings.denied_channels)
+            )
+        }
+        Some((
+            action,
+            [ResolvedOption {
+                value: ResolvedValue::Channel(channel),
+                ..
+            }],
+        )) => {
+            info!("Updating channel lists for guild {guild_id}");
+            let updated = GuildSettings::update(db, guild_id, |settings| {
+                let GuildSettings {
+                    allowed_channels: allowed,
+                    denied_channels: denied,
+                    ..
+                } = settings;
+                allowed.retain(|id| *id != channel.id);
+                denied.retain(|id| *id != channel.id);
+                match action {
+                    "allow" => allowed.push(channel.id),
+                    "deny" => denied.push(channel.id),
+                    _ => {}
+                }
+            });
+            match updated {
+                Ok(_) => format!("Updated {}.", channel.id.mention()),
+                Err(e) => e,
+            }
+        }
+        _ => "Unknown channels command.".to_string(),
+    }
+}
+
+fn ban(db: &Store, guild_id: GuildId, subcommand: Option<(&str, &[ResolvedOption])>) -> String {
+    let NameList(mut banned) = get_setting(db, guild_id, BANNED_NAMES_SETTING).unwrap_or_default();
+    let Some((
+        action,
+        [ResolvedOption {
+            value: ResolvedValue::String(name),
+            ..
+        }],
+    )) = subcommand
+    else {
+        return "Unknown ban command.".to_string();
+    };
+    let name = name.trim();
+    banned.retain(|banned| banned.to_lowercase() != name.to_lowercase());
+    let response = match action {
+        "add" => {
+            banned.push(name.to_string());
+            format!("{name} won't be handed out any more.")
+        }
+        _ => format!("{name} can be handed out again."),
+    };
+    info!("Updating banned names for guild {guild_id}");
+    set_setting(db, guild_id, BANNED_NAMES_SETTING, NameList(banned));
+    response
+}
+
+fn filter_word(
+    db: &Store,
+    guild_id: GuildId,
+    subcommand: Option<(&str, &[ResolvedOption])>,
+) -> String {
+    let NameList(mut words) =
+        get_setting(db, guild_id, PROFANITY_WORDS_SETTING).unwrap_or_default();
+    let Some((
+        action,
+        [ResolvedOption {
+            value: ResolvedValue::String(word),
+            ..
+        }],
+    )) = subcommand
+    else {
+        return "Unknown profanity command.".to_string();
+    };
+    let word = word.trim();
+    words.retain(|filtered| filtered.to_lowercase() != word.to_lowercase());
+    let response = match action {
+        "add" => {
+            words.push(word.to_string());
+            format!("Names containing {word} won't be handed out.")
+        }
+        _ => format!("{word} isn't filtered any more."),
+    };
+    info!("Updating filtered words for guild {guild_id}");
+    set_setting(db, guild_id, PROFANITY_WORDS_SETTING, NameList(words));
+    response
+}
+
+fn banned_names(db: &Store, guild_id: GuildId) -> Vec<String> {
+    let NameList(banned) = get_setting(db, guild_id, BANNED_NAMES_SETTING).unwrap_or_default();
+    banned
+}
+
+fn announce(db: &Store, guild_id: GuildId, args: &[ResolvedOption]) -> String {
+    info!("Updating announcement settings for guild {guild_id}");
+    if let Some(ResolvedValue::Channel(channel)) = option(args, "channel") {
+        set_setting(db, guild_id, ANNOUNCE_CHANNEL_SETTING, channel.id);
+    }
+    if let Some(ResolvedValue::Boolean(tts)) = option(args, "tts") {
+        set_setting(db, guild_id, TTS_ANNOUNCEMENT_SETTING, tts);
+    }
+    if let Some(ResolvedValue::String(message)) = option(args, "message") {
+        set_setting(db, guild_id, TTS_MESSAGE_SETTING, message);
+    }
+    if let Some(ResolvedValue::Boolean(mapping)) = option(args, "mapping") {
+        set_setting(db, guild_id, MAPPING_ANNOUNCEMENT_SETTING, mapping);
+    }
+    let mapping = get_setting(db, guild_id, MAPPING_ANNOUNCEMENT_SETTING).unwrap_or(false);
+    let channel = get_setting::<ChannelId>(db, guild_id, ANNOUNCE_CHANNEL_SETTING);
+    let tts = get_setting(db, guild_id, TTS_ANNOUNCEMENT_SETTING).unwrap_or(false);
+    let message: String = get_setting(db, guild_id, TTS_MESSAGE_SETTING)
+        .unwrap_or_else(|| DEFAULT_TTS_MESSAGE.to_string());
+    format!(
+        "Announce channel: {}\nText to speech: {}\nMessage: {message}\nName list: {}",
+        channel.map_or("none".to_string(), |id| id.mention().to_string()),
+        if tts { "on" } else { "off" },
+        if mapping { "on" } else { "off" },
+    )
+}
+
+/// Every stored name in the guild, along with any name the bot has currently given them.
+fn names(db: &Store, guild_id: GuildId) -> Vec<String> {
+    let name_overrides: HashMap<UserId, StoredName> =
+        db.names(Names::Override, guild_id).into_iter().collect();
+    db.names(Names::Original, guild_id)
+        .into_iter()
+        .map(|(user_id, name)| {
+            let (user, name) = (user_id.mention(), name.name);
+            match name_overrides.get(&user_id) {
+                Some(nick) => format!("{user} {name} → {}", nick.name),
+                None => format!("{user} {name}"),
+            }
+        })
+        .collect()
+}
+
+/// Champions ordered by how many games of them have been played in the guild.
+fn leaderboard(db: &Store, guild_id: GuildId) -> Vec<String> {
+    db.open_tree(plays_db_tree_name(guild_id))
+        .unwrap()
+        .iter()
+        .values()
+        .filter_map(|champion| String::from_utf8(champion.ok()?.to_vec()).ok())
+        .counts()
+        .into_iter()
+        .sorted_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)))
+        .enumerate()
+        .map(|(rank, (champion, count))| format!("{}. {champion}: {count} games", rank + 1))
+        .collect()
+}
+
+/// How many champions `/stats champions` lists.
+const TOP_CHAMPIONS: usize = 10;
+
+fn server_stats(db: &Store, guild_id: GuildId) -> CreateEmbed {
+    let counts = stats::rename_counts(db, guild_id);
+    CreateEmbed::new()
+        .title("Rename stats")
+        .field("Renames", counts.renames.to_string(), true)
+        .field("Failed edits", counts.failures.to_string(), true)
+        .field(
+            "Most assigned",
+            counts
+                .most_assigned
+                .map_or("Nobody yet".to_string(), |(source, count)| {
+                    format!("{source} ({count} times)")
+                }),
+            true,
+        )
+}
+
+fn member_stats(
+    db: &Store,
+    champions: &ChampionIndex,
+    guild_id: GuildId,
+    user_id: UserId,
+) -> CreateEmbed {
+    let (renames, sources) = stats::member_summary(db, guild_id, user_id);
+    // Songs, streams and the like count as renames but aren't champions.
+    let most_common = sources
+        .into_iter()
+        .find(|(source, _)| champions.canonical_name(source).is_some());
+    CreateEmbed::new()
+        .title("Your rename stats")
+        .field("Times renamed", renames.to_string(), true)
+        .field(
+            "Most common champion",
+            most_common.map_or("None yet".to_string(), |(source, count)| {
+                format!("{source} ({count} times)")
+            }),
+            true,
+        )
+}
+
+fn champion_stats(db: &Store, champions: &ChampionIndex, guild_id: GuildId) -> CreateEmbed {
+    let top: Vec<_> = stats::top_sources(db, guild_id)
+        .into_iter()
+        .filter(|(source, _)| champions.canonical_name(source).is_some())
+        .collect();
+    let description = if top.is_empty() {
+        "Nobody has been renamed yet.".to_string()
+    } else {
+        top.into_iter()
+            .take(TOP_CHAMPIONS)
+            .enumerate()
+            .map(|(rank, (source, count))| format!("{}. {source}: {count} times", rank + 1))
+            .join("\n")
+    };
+    CreateEmbed::new()
+        .title("Most assigned champions")
+        .description(description)
+}
+
+/// Gives the member a nickname they always get instead of a shuffled one, unless Discord
+/// wouldn't accept it as a nickname.
+pub fn set_permanent_nick(
+    db: &Store,
+    guild_id: GuildId,
+    user_id: UserId,
+    name: &str,
+) -> Result<String, String> {
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        return Err(format!("Names must be 1 to {MAX_NAME_CHARS} characters."));
+    }
+    info!("Setting permanent nick {name} for {user_id} in guild {guild_id}");
+    db.set_name(Names::Permanent, guild_id, user_id, &StoredName::bare(name));
+    Ok(format!(
+        "{} will be called {name} the next time their channel syncs.",
+        user_id.mention()
+    ))
+}
+
+/// Returns `None` if the member didn't have a permanent nickname.
+pub fn clear_permanent_nick(db: &Store, guild_id: GuildId, user_id: UserId) -> Option<String> {
+    info!("Clearing permanent nick for {user_id} in guild {guild_id}");
+    db.remove_name(Names::Permanent, guild_id, user_id)?;
+    Some(format!(
+        "{} no longer has a permanent nickname.",
+        user_id.mention()
+    ))
+}
+
+fn permanent_nicks(db: &Store, guild_id: GuildId) -> Vec<String> {
+    db.names(Names::Permanent, guild_id)
+        .into_iter()
+        .map(|(user_id, nick)| format!("{} {}", user_id.mention(), nick.name))
+        .collect()
+}
+
+async fn setup(
+    db: &Store,
+    ctx: &Context,
+    command: &CommandInteraction,
+    guild_id: GuildId,
+) -> String {
+    let message = CreateMessage::new()
+        .content(format!(
+            "React with {CONSENT_EMOJI} to let the bot shuffle your nickname while you're in voice. Remove your reaction to opt back out."
+        ))
+        .reactions([ReactionType::Unicode(CONSENT_EMOJI.to_string())]);
+    match command.channel_id.send_message(&ctx.http, message).await {
+        Ok(message) => {
+            set_setting(db, guild_id, CONSENT_MESSAGE_SETTING, message.id);
+            "Posted the consent message.".to_string()
+        }
+        Err(e) => {
+            warn!("Failed to post consent message in guild {guild_id}: {e:?}");
+            "Failed to post the consent message.".to_string()
+        }
+    }
+}
+
+/// Records consent given or revoked by reacting to the setup message.
+pub async fn handle_consent_reaction(db: &Store, ctx: &Context, reaction: &Reaction, added: bool) {
+    let (Some(guild_id), Some(user_id)) = (reaction.guild_id, reaction.user_id) else {
+        return;
+    };
+    if user_id == ctx.cache.current_user().id
+        || get_setting::<MessageId>(db, guild_id, CONSENT_MESSAGE_SETTING)
+            != Some(reaction.message_id)
+        || !reaction.emoji.unicode_eq(CONSENT_EMOJI)
+    {
+        return;
+    }
+    if added {
+        opt_in(db, guild_id, user_id);
+    } else {
+        withdraw_consent(db, ctx, guild_id, user_id).await;
+    }
+}
+
+/// Takes back the member's opt-in without opting them out, so servers that don't require
+/// consent keep renaming them as before.
+async fn withdraw_consent(db: &Store, ctx: &Context, guild_id: GuildId, user_id: UserId) {
+    info!("Withdrawing consent of {user_id} in guild {guild_id}");
+    db.open_tree(consents_db_tree_name(guild_id))
+        .unwrap()
+        .remove(DbKey::from(user_id))
+        .unwrap();
+    if !Participation::new(db, guild_id).allows(user_id) {
+        cancel_rollout(db, guild_id, user_id);
+        restore_member(db, &ctx.http, guild_id, user_id).await;
+    }
+}
+
+/// Drops the member's pending rollout rename, so a rollout in progress leaves them alone.
+fn cancel_rollout(db: &Store, guild_id: GuildId, user_id: UserId) {
+    db.open_tree(rollouts_db_tree_name(guild_id))
+        .unwrap()
+        .remove(DbKey::from(user_id))
+        .unwrap();
+}
@@ -0,0 +1,295 @@
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::{num::NonZeroU64, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::prelude::Timestamp;
+#[cfg(unix)]
+use serenity::{
+    http::Http,
+    model::prelude::{GuildId, UserId},
+};
+#[cfg(unix)]
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+#[cfg(unix)]
+use tracing::{error, info, warn};
+
+#[cfg(unix)]
+use crate::{
+    backup,
+    db::{drop_guild, forget_user, store_name, unset_name, NameCache, NameSource},
+    namerestorer, nickname, rules,
+    sanitize::sanitize_name,
+    store::Store,
+    tasks,
+    web::DebugState,
+};
+
+/// What the CLI can ask a running bot to do. Each is sent as one line of JSON.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Restore {
+        overridden_only: bool,
+        as_of: Option<Timestamp>,
+        #[serde(default)]
+        dry_run: bool,
+    },
+    Set {
+        guild_id: u64,
+        user_id: u64,
+        name: String,
+    },
+    Unset {
+        guild_id: u64,
+        user_id: u64,
+        override_only: bool,
+    },
+    PurgeGuild {
+        guild_id: u64,
+        restore: bool,
+    },
+    Backup {
+        path: PathBuf,
+    },
+    ForgetUser {
+        user_id: u64,
+        restore: bool,
+    },
+}
+
+/// The bot's answer, also one line of JSON.
+pub type Reply = Result<serde_json::Value, String>;
+
+/// Where the bot using the database at `db` listens unless told otherwise, so bots running on
+/// different databases don't share a socket.
+pub fn default_path(db: &Path) -> PathBuf {
+    let mut path = db.as_os_str().to_owned();
+    path.push(".sock");
+    PathBuf::from(path)
+}
+
+/// Starts listening on `path`. Returns `None` if that failed, or if another bot is already
+/// listening there, whose socket is left alone.
+#[cfg(unix)]
+pub async fn bind(path: &Path) -> Option<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            error!("Another bot is already listening on control socket {path:?}");
+            return None;
+        }
+        // A socket left behind by a bot that didn't shut down cleanly would stop us binding.
+        if let Err(e) = std::fs::remove_file(path) {
+            error!("Failed to remove stale control socket {path:?}: {e}");
+            return None;
+        }
+    }
+    match UnixListener::bind(path) {
+        Ok(listener) => {
+            info!("Listening for control requests on {path:?}");
+            Some(listener)
+        }
+        Err(e) => {
+            error!("Failed to listen on control socket {path:?}: {e}");
+            None
+        }
+    }
+}
+
+/// Answers requests on the listener until the process exits. Only the bot has the database open
+/// while it runs, so the CLI goes through here instead.
+#[cfg(unix)]
+pub async fn serve(
+    listener: UnixListener,
+    db: Store,
+    http: Arc<Http>,
+    name_cache: Arc<NameCache>,
+    debug: Arc<dyn DebugState>,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept a control connection: {e}");
+                continue;
+            }
+        };
+        let (db, http, name_cache, debug) =
+            (db.clone(), http.clone(), name_cache.clone(), debug.clone());
+        tasks::spawn("control connection", async move {
+            if let Err(e) = handle_connection(stream, &db, &http, &name_cache, &*debug).await {
+                warn!("Control connection failed: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn handle_connection(
+    stream: UnixStream,
+    db: &Store,
+    http: &Http,
+    name_cache: &NameCache,
+    debug: &dyn DebugState,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match serde_json::from_str(&line) {
+            Ok(request) => handle(request, db, http, name_cache, debug).await,
+            Err(e) => Err(format!("Invalid request: {e}")),
+        };
+        let mut reply = serde_json::to_vec(&reply).unwrap();
+        reply.push(b'\n');
+        writer.write_all(&reply).await?;
+    }
+    Ok(())
+}
+
+// Anything that writes names has to invalidate the cache, or the bot keeps using the old ones.
+#[cfg(unix)]
+async fn handle(
+    request: Request,
+    db: &Store,
+    http: &Http,
+    name_cache: &NameCache,
+    debug: &dyn DebugState,
+) -> Reply {
+    match request {
+        Request::Status => Ok(debug.debug_state()),
+        Request::Restore {
+            overridden_only,
+            as_of,
+            dry_run,
+        } => {
+            info!("Restoring names for a control request");
+            // A bot running with --dry-run mustn't rename anyone, whoever asks.
+            let dry_run = dry_run || nickname::is_dry_run();
+            let restored = if let Some(as_of) = as_of {
+                namerestorer::restore_as_of_with(http, db, as_of, dry_run).await
+            } else if overridden_only {
+                namerestorer::restore_overridden_with(http, db, dry_run).await
+            } else {
+                namerestorer::run_with(http, db, dry_run).await
+            };
+            if dry_run {
+                Ok(serde_json::to_value(restored).unwrap())
+            } else {
+                Ok(serde_json::Value::Null)
+            }
+        }
+        Request::Set {
+            guild_id,
+            user_id,
+            name,
+        } => {
+            let (Some(guild_id), Some(user_id)) = (
+                NonZeroU64::new(guild_id).map(GuildId::from),
+                NonZeroU64::new(user_id).map(UserId::from),
+            ) else {
+                return Err("Guild and user ids can't be 0".to_string());
+            };
+            info!("Storing {name} for {user_id} in guild {guild_id} for a control request");
+            store_name(
+                db,
+                guild_id,
+                user_id,
+                &sanitize_name(&name),
+                NameSource::Cli,
+            );
+            name_cache.invalidate(guild_id, user_id);
+            Ok(serde_json::Value::Null)
+        }
+        Request::Unset {
+            guild_id,
+            user_id,
+            override_only,
+        } => {
+            let (Some(guild_id), Some(user_id)) = (
+                NonZeroU64::new(guild_id).map(GuildId::from),
+                NonZeroU64::new(user_id).map(UserId::from),
+            ) else {
+                return Err("Guild and user ids can't be 0".to_string());
+            };
+            info!("Unsetting names for {user_id} in guild {guild_id} for a control request");
+            let unset = unset_name(db, guild_id, user_id, override_only);
+            name_cache.invalidate(guild_id, user_id);
+            if unset {
+                Ok(serde_json::Value::Null)
+            } else {
+                Err(format!(
+                    "Nothing is stored for {user_id} in guild {guild_id}"
+                ))
+            }
+        }
+        Request::PurgeGuild { guild_id, restore } => {
+            let Some(guild_id) = NonZeroU64::new(guild_id).map(GuildId::from) else {
+                return Err("Guild ids can't be 0".to_string());
+            };
+            info!("Purging guild {guild_id} for a control request");
+            if restore {
+                namerestorer::restore_guild(db, http, guild_id).await;
+            }
+            let dropped_trees = drop_guild(db, guild_id);
+            name_cache.invalidate_guild(guild_id);
+            rules::invalidate(guild_id);
+            Ok(serde_json::json!({ "dropped_trees": dropped_trees }))
+        }
+        Request::Backup { path } => {
+            info!("Backing up to {path:?} for a control request");
+            let db = db.clone();
+            let entries = tokio::task::spawn_blocking(move || backup::write(&db, &path))
+                .await
+                .unwrap()
+                .map_err(|e| format!("Failed to write the backup: {e}"))?;
+            Ok(serde_json::json!({ "entries": entries }))
+        }
+        Request::ForgetUser { user_id, restore } => {
+            let Some(user_id) = NonZeroU64::new(user_id).map(UserId::from) else {
+                return Err("User ids can't be 0".to_string());
+            };
+            info!("Forgetting {user_id} for a control request");
+            if restore {
+                namerestorer::restore_user(db, http, user_id).await;
+            }
+            let deleted_entries = forget_user(db, user_id);
+            name_cache.invalidate_user(user_id);
+            Ok(serde_json::json!({ "deleted_entries": deleted_entries }))
+        }
+    }
+}
+
+/// Sends the request to the bot listening on `path`. Returns `None` if no bot is listening, in
+/// which case the caller can open the database itself.
+#[cfg(unix)]
+pub async fn send(path: &Path, request: &Request) -> Option<Reply> {
+    let stream = UnixStream::connect(path).await.ok()?;
+    let (reader, mut writer) = stream.into_split();
+    let mut request = serde_json::to_vec(request).unwrap();
+    request.push(b'\n');
+    writer
+        .write_all(&request)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to send to the bot on {path:?}: {e}"));
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to read the bot's reply on {path:?}: {e}"))
+        .unwrap_or_else(|| panic!("The bot on {path:?} hung up without replying"));
+    Some(
+        serde_json::from_str(&line)
+            .unwrap_or_else(|e| panic!("Invalid reply from the bot on {path:?}: {e}")),
+    )
+}
+
+/// The control socket is a Unix socket, so elsewhere no bot is ever listening.
+#[cfg(not(unix))]
+pub async fn send(_path: &Path, _request: &Request) -> Option<Reply> {
+    None
+}
@@ -0,0 +1,186 @@
+use std::{collections::HashMap, num::NonZeroU64, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    http::{
+        header::{AUTHORIZATION, HOST, ORIGIN, WWW_AUTHENTICATE},
+        HeaderMap, StatusCode,
+    },
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serenity::{
+    cache::Cache,
+    http::Http,
+    model::prelude::{GuildId, UserId},
+};
+use tracing::info;
+
+use crate::{
+    active_hours,
+    db::StoredName,
+    guild_names::GuildNames,
+    namerestorer::restore_guild,
+    session,
+    settings::GuildSettings,
+    store::{NameStore, Names, Store},
+    tasks,
+};
+
+struct Dashboard {
+    db: Store,
+    http: Arc<Http>,
+    cache: Arc<Cache>,
+    admin_token: String,
+}
+
+/// The admin dashboard's routes, behind HTTP basic auth with `admin_token` as the password.
+pub fn router(db: Store, http: Arc<Http>, cache: Arc<Cache>, admin_token: String) -> Router {
+    Router::new()
+        .route("/admin", get(index))
+        .route("/admin/guilds/:guild_id/restore", post(restore))
+        .route("/admin/guilds/:guild_id/reshuffle", post(reshuffle))
+        .with_state(Arc::new(Dashboard {
+            db,
+            http,
+            cache,
+            admin_token,
+        }))
+}
+
+/// Accepts any user name as long as the password is the admin token.
+fn authorized(dashboard: &Dashboard, headers: &HeaderMap) -> bool {
+    let password = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|credentials| STANDARD.decode(credentials).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| Some(credentials.split_once(':')?.1.to_string()));
+    password.as_deref() == Some(dashboard.admin_token.as_str())
+}
+
+/// Makes the browser ask for the admin token.
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(WWW_AUTHENTICATE, "Basic realm=\"namechanger\"")],
+    )
+        .into_response()
+}
+
+/// Browsers send basic auth along with forms posted from other sites, so only accept actions
+/// from our own pages.
+fn same_origin(headers: &HeaderMap) -> bool {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    match (header(ORIGIN), header(HOST)) {
+        (Some(origin), Some(host)) => origin.split_once("://").map(|(_, rest)| rest) == Some(host),
+        _ => false,
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+async fn index(State(dashboard): State<Arc<Dashboard>>, headers: HeaderMap) -> Response {
+    if !authorized(&dashboard, &headers) {
+        return unauthorized();
+    }
+    let mut body = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Name changer</title></head><body><h1>Name changer</h1>",
+    );
+    for guild_id in dashboard.cache.guilds() {
+        body.push_str(&guild_section(&dashboard, guild_id));
+    }
+    body.push_str("</body></html>");
+    Html(body).into_response()
+}
+
+fn guild_section(dashboard: &Dashboard, guild_id: GuildId) -> String {
+    let db = &dashboard.db;
+    let names = GuildNames::new(&dashboard.cache, guild_id);
+    let name_overrides: HashMap<UserId, StoredName> =
+        db.names(Names::Override, guild_id).into_iter().collect();
+    let mut section = format!(
+        "<h2>{} ({guild_id}){}</h2>\
+         <form method=\"post\" action=\"/admin/guilds/{guild_id}/restore\"><button>Restore names</button></form>\
+         <form method=\"post\" action=\"/admin/guilds/{guild_id}/reshuffle\"><button>Reshuffle</button></form>",
+        escape(&names.guild()),
+        if !GuildSettings::load(db, guild_id).enabled {
+            " (disabled)"
+        } else if !active_hours::is_active(db, guild_id) {
+            " (outside active hours)"
+        } else {
+            ""
+        },
+    );
+    for (channel_id, assignments) in session::guild_assignments(db, guild_id) {
+        section.push_str(&format!(
+            "<h3>{}</h3><table><tr><th>Member</th><th>Named after</th><th>Nickname</th></tr>",
+            escape(&names.channel(channel_id))
+        ));
+        for (user_id, from) in assignments {
+            section.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&names.member(user_id)),
+                escape(&names.member(from)),
+                escape(name_overrides.get(&user_id).map_or("", |nick| &nick.name)),
+            ));
+        }
+        section.push_str("</table>");
+    }
+    section.push_str("<h3>Stored names</h3><table><tr><th>Member</th><th>Name</th></tr>");
+    for (user_id, name) in db.names(Names::Original, guild_id) {
+        section.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td></tr>",
+            escape(&names.member(user_id)),
+            escape(&name.name),
+        ));
+    }
+    section.push_str("</table>");
+    section
+}
+
+/// Puts everyone's names back, like disabling the guild but without turning it off.
+async fn restore(
+    State(dashboard): State<Arc<Dashboard>>,
+    Path(guild_id): Path<NonZeroU64>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&dashboard, &headers) {
+        return unauthorized();
+    }
+    if !same_origin(&headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let guild_id = GuildId::from(guild_id);
+    info!("Restoring names in guild {guild_id} from the dashboard");
+    tasks::spawn(&format!("dashboard restore {guild_id}"), async move {
+        restore_guild(&dashboard.db, &dashboard.http, guild_id).await;
+    });
+    Redirect::to("/admin").into_response()
+}
+
+/// Forgets every channel's assignments so they're dealt out again the next time each syncs.
+async fn reshuffle(
+    State(dashboard): State<Arc<Dashboard>>,
+    Path(guild_id): Path<NonZeroU64>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorized(&dashboard, &headers) {
+        return unauthorized();
+    }
+    if !same_origin(&headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let guild_id = GuildId::from(guild_id);
+    info!("Reshuffling guild {guild_id} from the dashboard");
+    session::clear_guild(&dashboard.db, guild_id);
+    Redirect::to("/admin").into_response()
+}
@@ -1,28 +1,49 @@
-use std::fmt::Display;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use log::{info, warn};
-use serenity::model::prelude::{GuildId, Member, UserId};
-use sled::{Batch, IVec, Tree};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serenity::model::prelude::{ChannelId, GuildId, Member, UserId};
+use sled::{IVec, Tree};
+use tracing::{info, warn};
+
+use crate::{
+    encryption,
+    store::{NameStore, Names, Store},
+};
 
 pub trait BatchAddable {
-    fn add_to_batch(&self, batch: &mut Batch);
+    /// The member's key and the name to store for them.
+    fn name_entry(&self) -> (DbKey, &str);
+    /// Whether the name is a nickname, rather than what Discord shows when there isn't one.
+    fn has_nickname(&self) -> bool {
+        true
+    }
 }
 impl<S: AsRef<str>> BatchAddable for &(UserId, S) {
-    fn add_to_batch(&self, batch: &mut Batch) {
+    fn name_entry(&self) -> (DbKey, &str) {
         info!("Adding hardcoded {}", self.1.as_ref());
-        (DbKey::from(self.0), self.1.as_ref()).add_to_batch(batch);
+        (DbKey::from(self.0), self.1.as_ref())
     }
 }
 impl<S: AsRef<str>> BatchAddable for (DbKey, S) {
-    fn add_to_batch(&self, batch: &mut Batch) {
+    fn name_entry(&self) -> (DbKey, &str) {
         info!("Adding from key {}", self.1.as_ref());
-        batch.insert(IVec::from(self.0.as_ref()), self.1.as_ref());
+        (self.0, self.1.as_ref())
     }
 }
-impl<'a> BatchAddable for &'a Member {
-    fn add_to_batch(&self, batch: &mut Batch) {
+impl BatchAddable for &Member {
+    fn name_entry(&self) -> (DbKey, &str) {
         info!("Adding member {}", self.display_name());
-        (&(self.user.id, self.display_name())).add_to_batch(batch);
+        (DbKey::from(self.user.id), self.display_name())
+    }
+    fn has_nickname(&self) -> bool {
+        self.nick.is_some()
     }
 }
 
@@ -59,39 +80,733 @@ impl Display for DbKey {
     }
 }
 
-pub fn make_name_batch<T: BatchAddable, I: Iterator<Item = T>>(members: I) -> Batch {
-    let mut batch = Batch::default();
-    for member in members {
-        member.add_to_batch(&mut batch);
-    }
-    batch
+/// The members' names as they're stored for overrides and permanent nicknames, for
+/// `NameStore::set_names`.
+pub fn bare_names<T: BatchAddable, I: Iterator<Item = T>>(members: I) -> Vec<(UserId, StoredName)> {
+    members
+        .map(|member| {
+            let (key, name) = member.name_entry();
+            (UserId::from(key), StoredName::bare(name))
+        })
+        .collect()
+}
+/// Like `bare_names`, for stored names, which keep a record of each name.
+pub fn stored_names<T: BatchAddable, I: Iterator<Item = T>>(
+    members: I,
+    source: NameSource,
+) -> Vec<(UserId, StoredName)> {
+    members
+        .map(|member| {
+            let (key, name) = member.name_entry();
+            let mut stored = StoredName::new(name, source);
+            stored.no_nickname = !member.has_nickname();
+            (UserId::from(key), stored)
+        })
+        .collect()
 }
-pub fn has_overridden_name(member: &Member, name_overrides: &Tree) -> bool {
+pub fn has_overridden_name(store: &(impl NameStore + ?Sized), member: &Member) -> bool {
+    let name_override = store
+        .get_name(Names::Override, member.guild_id, member.user.id)
+        .map(|stored| stored.name);
     info!(
         "Checking {} against {}",
-        get_name(name_overrides, DbKey::from(member.user.id)).unwrap_or("".to_string()),
+        name_override.as_deref().unwrap_or_default(),
         member.display_name()
     );
-    get_name(name_overrides, DbKey::from(member.user.id)).as_deref() == Some(member.display_name())
+    name_override.as_deref() == Some(member.display_name())
 }
-pub type NameOverridesDbTreeNameType = [u8; 9];
-pub fn name_overrides_db_tree_name(guild_id: GuildId) -> NameOverridesDbTreeNameType {
-    let mut name = [b'o'; 9];
+pub type GuildDbTreeNameType = [u8; 9];
+pub const NAME_OVERRIDES_DB_TREE_PREFIX: u8 = b'o';
+pub const OPT_OUTS_DB_TREE_PREFIX: u8 = b'x';
+pub const ROLLOUTS_DB_TREE_PREFIX: u8 = b'r';
+pub const CONSENTS_DB_TREE_PREFIX: u8 = b'c';
+pub const SETTINGS_DB_TREE_PREFIX: u8 = b's';
+pub const REJECTED_NAMES_DB_TREE_PREFIX: u8 = b'j';
+pub const PLAYS_DB_TREE_PREFIX: u8 = b'p';
+pub const PERMANENT_NICKS_DB_TREE_PREFIX: u8 = b'n';
+pub const AUDIT_DB_TREE_PREFIX: u8 = b'a';
+pub const RULES_DB_TREE_PREFIX: u8 = b'l';
+pub const RIOT_ACCOUNTS_DB_TREE_PREFIX: u8 = b'i';
+pub const SESSIONS_DB_TREE_PREFIX: u8 = b'v';
+pub const PENDING_RENAMES_DB_TREE_PREFIX: u8 = b'q';
+pub const OVERRIDE_TIMES_DB_TREE_PREFIX: u8 = b'e';
+pub const UNRENAMABLE_DB_TREE_PREFIX: u8 = b'u';
+pub const REROLL_MESSAGES_DB_TREE_PREFIX: u8 = b'm';
+pub const HISTORY_DB_TREE_PREFIX: u8 = b'h';
+pub const RENAMES_DB_TREE_PREFIX: u8 = b'w';
+pub const RENAME_COUNTS_DB_TREE_PREFIX: u8 = b'k';
+pub const MEMBER_RENAME_COUNTS_DB_TREE_PREFIX: u8 = b't';
+fn guild_db_tree_name(prefix: u8, guild_id: GuildId) -> GuildDbTreeNameType {
+    let mut name = [prefix; 9];
     name[1..].copy_from_slice(&DbKey::from(guild_id).0);
     name
 }
-pub fn get_name(tree: &Tree, user_id: DbKey) -> Option<String> {
+/// The guild whose stored names the tree holds, if it's a names tree. Stored names live in a
+/// tree named after just the guild.
+pub fn names_tree_guild(name: &[u8]) -> Option<GuildId> {
+    Some(DbKey(name.try_into().ok()?).into())
+}
+/// Splits a per-guild tree name into its prefix and guild id.
+pub fn parse_guild_db_tree_name(name: &[u8]) -> Option<(u8, GuildId)> {
+    let [prefix, key @ ..]: GuildDbTreeNameType = name.try_into().ok()?;
+    Some((prefix, DbKey(key).into()))
+}
+pub fn name_overrides_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(NAME_OVERRIDES_DB_TREE_PREFIX, guild_id)
+}
+pub fn opt_outs_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(OPT_OUTS_DB_TREE_PREFIX, guild_id)
+}
+pub fn rollouts_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(ROLLOUTS_DB_TREE_PREFIX, guild_id)
+}
+pub fn consents_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(CONSENTS_DB_TREE_PREFIX, guild_id)
+}
+pub fn settings_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(SETTINGS_DB_TREE_PREFIX, guild_id)
+}
+pub fn rejected_names_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(REJECTED_NAMES_DB_TREE_PREFIX, guild_id)
+}
+pub fn plays_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(PLAYS_DB_TREE_PREFIX, guild_id)
+}
+/// Nicknames particular members always get instead of a shuffled one.
+pub fn permanent_nicks_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(PERMANENT_NICKS_DB_TREE_PREFIX, guild_id)
+}
+/// Every name we've seen each member show, by when we saw it.
+pub fn audit_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(AUDIT_DB_TREE_PREFIX, guild_id)
+}
+/// Admin-defined rules for taking names from activities, by priority.
+pub fn rules_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(RULES_DB_TREE_PREFIX, guild_id)
+}
+/// Voice channel and member → the member whose name they get this session.
+pub fn sessions_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(SESSIONS_DB_TREE_PREFIX, guild_id)
+}
+/// Member → a nickname edit that failed and is waiting to be retried.
+pub fn pending_renames_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(PENDING_RENAMES_DB_TREE_PREFIX, guild_id)
+}
+/// Member → when their override was last set, in seconds since the epoch.
+pub fn override_times_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(OVERRIDE_TIMES_DB_TREE_PREFIX, guild_id)
+}
+/// Member → when we were last refused permission to rename them, in seconds since the epoch.
+pub fn unrenamable_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(UNRENAMABLE_DB_TREE_PREFIX, guild_id)
+}
+/// When and who → a nickname the bot applied, as JSON.
+pub fn renames_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(RENAMES_DB_TREE_PREFIX, guild_id)
+}
+/// Counter name → how many times it happened, as a big-endian u64.
+pub fn rename_counts_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(RENAME_COUNTS_DB_TREE_PREFIX, guild_id)
+}
+/// Member → how many times they were renamed, and member followed by a source name → how many
+/// times they got it, as big-endian u64s.
+pub fn member_rename_counts_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(MEMBER_RENAME_COUNTS_DB_TREE_PREFIX, guild_id)
+}
+/// Member → the names they were most recently given, newline separated.
+pub fn history_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(HISTORY_DB_TREE_PREFIX, guild_id)
+}
+/// Mapping announcement message → the voice channel it's about, for rerolls.
+pub fn reroll_messages_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(REROLL_MESSAGES_DB_TREE_PREFIX, guild_id)
+}
+/// Member → Riot ID (`name#tag`) they linked for live game lookups.
+pub fn riot_accounts_db_tree_name(guild_id: GuildId) -> GuildDbTreeNameType {
+    guild_db_tree_name(RIOT_ACCOUNTS_DB_TREE_PREFIX, guild_id)
+}
+pub fn get_setting<T: FromStr>(
+    store: &(impl NameStore + ?Sized),
+    guild_id: GuildId,
+    key: &str,
+) -> Option<T> {
+    let parsed = store.get_setting(guild_id, key)?.parse().ok();
+    if parsed.is_none() {
+        warn!("Corrupt setting {key} for {guild_id}");
+    }
+    parsed
+}
+pub fn set_setting<T: Display>(
+    store: &(impl NameStore + ?Sized),
+    guild_id: GuildId,
+    key: &str,
+    value: T,
+) {
+    store.set_setting(guild_id, key, &value.to_string());
+}
+pub fn remove_setting(store: &(impl NameStore + ?Sized), guild_id: GuildId, key: &str) {
+    store.remove_setting(guild_id, key);
+}
+
+pub const CONSENT_MODE_SETTING: &str = "consent_mode";
+pub const CONSENT_MESSAGE_SETTING: &str = "consent_message";
+pub const ENABLED_SETTING: &str = "enabled";
+
+pub const ANNOUNCE_CHANNEL_SETTING: &str = "announce_channel";
+pub const TTS_ANNOUNCEMENT_SETTING: &str = "tts_announcement";
+pub const TTS_MESSAGE_SETTING: &str = "tts_message";
+pub const MAPPING_ANNOUNCEMENT_SETTING: &str = "mapping_announcement";
+pub const DM_NOTIFICATIONS_SETTING: &str = "dm_notifications";
+pub const SPOTIFY_MODE_SETTING: &str = "spotify_mode";
+pub const STREAMING_MODE_SETTING: &str = "streaming_mode";
+pub const MISSING_ASSETS_SETTING: &str = "missing_assets";
+pub const SOURCE_PRIORITIES_SETTING: &str = "source_priorities";
+pub const CHAMPION_MODE_SETTING: &str = "champion_mode";
+pub const NAME_TEMPLATE_SETTING: &str = "name_template";
+pub const LOCALE_SETTING: &str = "locale";
+pub const BANNED_NAMES_SETTING: &str = "banned_names";
+pub const PROFANITY_WORDS_SETTING: &str = "profanity_words";
+pub const PROFANITY_BUILTIN_SETTING: &str = "profanity_builtin";
+pub const OVERRIDE_TTL_HOURS_SETTING: &str = "override_ttl_hours";
+pub const ACTIVE_HOURS_SETTING: &str = "active_hours";
+pub const TIMEZONE_SETTING: &str = "timezone";
+pub const PLAYERS_ONLY_SETTING: &str = "players_only";
+pub const MIN_MEMBERS_SETTING: &str = "min_members";
+pub const SKIP_MODERATORS_SETTING: &str = "skip_moderators";
+pub const EVENT_ROLE_SETTING: &str = "event_role";
+pub const EVENT_MESSAGE_SETTING: &str = "event_message";
+/// When the bot was removed from the guild, in seconds since the epoch.
+pub const LEFT_AT_SETTING: &str = "left_at";
+
+pub const ALLOWED_CHANNELS_SETTING: &str = "allowed_channels";
+pub const DENIED_CHANNELS_SETTING: &str = "denied_channels";
+
+/// A set of channels stored as a comma separated list of ids.
+#[derive(Default)]
+pub struct ChannelList(pub Vec<ChannelId>);
+impl FromStr for ChannelList {
+    type Err = std::num::ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|id| !id.is_empty())
+            .map(|id| Ok(ChannelId::new(id.parse()?)))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+impl Display for ChannelList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.iter().join(","))
+    }
+}
+
+/// A list of names stored one per line, since names can contain commas.
+#[derive(Default)]
+pub struct NameList(pub Vec<String>);
+impl NameList {
+    /// Ignores case.
+    pub fn contains(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.0.iter().any(|listed| listed.to_lowercase() == name)
+    }
+}
+impl FromStr for NameList {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            s.lines()
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ))
+    }
+}
+impl Display for NameList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.iter().join("\n"))
+    }
+}
+
+/// Whether members have to opt in before the bot will rename them.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ConsentMode {
+    #[default]
+    OptOut,
+    OptIn,
+}
+impl FromStr for ConsentMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opt-out" => Ok(Self::OptOut),
+            "opt-in" => Ok(Self::OptIn),
+            _ => Err(()),
+        }
+    }
+}
+impl Display for ConsentMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::OptOut => "opt-out",
+            Self::OptIn => "opt-in",
+        })
+    }
+}
+
+/// What to name members listening to Spotify after when they aren't playing anything.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum SpotifyMode {
+    #[default]
+    Off,
+    Song,
+    Artist,
+}
+impl FromStr for SpotifyMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "song" => Ok(Self::Song),
+            "artist" => Ok(Self::Artist),
+            _ => Err(()),
+        }
+    }
+}
+impl Display for SpotifyMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Song => "song",
+            Self::Artist => "artist",
+        })
+    }
+}
+
+/// What to name members after when someone in their channel is streaming.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum StreamingMode {
+    #[default]
+    Off,
+    Title,
+    Game,
+}
+impl FromStr for StreamingMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "title" => Ok(Self::Title),
+            "game" => Ok(Self::Game),
+            _ => Err(()),
+        }
+    }
+}
+impl Display for StreamingMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Title => "title",
+            Self::Game => "game",
+        })
+    }
+}
+
+/// What to do when someone is playing League but their activity doesn't say which champion.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum MissingAssetsPolicy {
+    /// Leave the member who would have received the name alone.
+    Skip,
+    /// Use the member's stored name, as if the source wasn't playing at all.
+    #[default]
+    FallbackToStored,
+    /// Use a random champion name.
+    FallbackToTheme,
+    /// Use the name of the activity itself.
+    UseActivityName,
+}
+impl FromStr for MissingAssetsPolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "fallback-to-stored" => Ok(Self::FallbackToStored),
+            "fallback-to-theme" => Ok(Self::FallbackToTheme),
+            "use-activity-name" => Ok(Self::UseActivityName),
+            _ => Err(()),
+        }
+    }
+}
+impl Display for MissingAssetsPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Skip => "skip",
+            Self::FallbackToStored => "fallback-to-stored",
+            Self::FallbackToTheme => "fallback-to-theme",
+            Self::UseActivityName => "use-activity-name",
+        })
+    }
+}
+
+/// Whether members playing League are named after their champion, the champion's title or the
+/// skin they're using.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ChampionMode {
+    #[default]
+    Name,
+    Title,
+    Skin,
+}
+impl FromStr for ChampionMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(Self::Name),
+            "title" => Ok(Self::Title),
+            "skin" => Ok(Self::Skin),
+            _ => Err(()),
+        }
+    }
+}
+impl Display for ChampionMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Name => "name",
+            Self::Title => "title",
+            Self::Skin => "skin",
+        })
+    }
+}
+
+/// Decides which members of a guild the bot is allowed to rename.
+pub struct Participation {
+    mode: ConsentMode,
+    opt_outs: Tree,
+    consents: Tree,
+}
+impl Participation {
+    pub fn new(db: &Store, guild_id: GuildId) -> Self {
+        Self {
+            mode: get_setting(db, guild_id, CONSENT_MODE_SETTING).unwrap_or_default(),
+            opt_outs: db.open_tree(opt_outs_db_tree_name(guild_id)).unwrap(),
+            consents: db.open_tree(consents_db_tree_name(guild_id)).unwrap(),
+        }
+    }
+    pub fn allows(&self, user_id: UserId) -> bool {
+        if is_opted_out(&self.opt_outs, user_id) {
+            return false;
+        }
+        match self.mode {
+            ConsentMode::OptOut => true,
+            ConsentMode::OptIn => self
+                .consents
+                .contains_key(DbKey::from(user_id))
+                .inspect_err(|e| warn!("Failed to check consent for {user_id}: {e}"))
+                .unwrap_or(false),
+        }
+    }
+}
+pub fn is_opted_out(opt_outs: &Tree, user_id: UserId) -> bool {
+    opt_outs
+        .contains_key(DbKey::from(user_id))
+        .inspect_err(|e| warn!("Failed to check opt out for {user_id}: {e}"))
+        // Err on the side of leaving the member alone.
+        .unwrap_or(true)
+}
+
+/// How a member's stored name was captured.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NameSource {
+    GuildCreate,
+    MemberChunk,
+    MemberUpdate,
+    MemberJoin,
+    /// Set with `set` or `set-bulk`.
+    Cli,
+    /// Loaded from an export or another database.
+    Import,
+    /// Put back by `restore --as-of`.
+    Restore,
+    /// Captured just before an event renamed them.
+    Event,
+}
+
+/// Starts every stored name record. Names stored before records existed are bare and can't
+/// contain it, since control characters are never part of a name.
+const RECORD_MARKER: char = '\u{1}';
+
+/// A member's stored name and when and how it was captured. Both are unknown for names stored
+/// before records existed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredName {
+    pub name: String,
+    /// Seconds since the epoch.
+    pub captured_at: Option<u64>,
+    pub source: Option<NameSource>,
+    /// The member had no nickname, so `name` is just what Discord showed instead. Assumed false
+    /// for names stored before this was recorded.
+    #[serde(default)]
+    pub no_nickname: bool,
+}
+impl StoredName {
+    pub fn new(name: &str, source: NameSource) -> StoredName {
+        StoredName {
+            name: name.to_string(),
+            captured_at: Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            ),
+            source: Some(source),
+            no_nickname: false,
+        }
+    }
+    /// Just the name, for names stored without the rest of a record.
+    pub fn bare(name: &str) -> StoredName {
+        StoredName {
+            name: name.to_string(),
+            captured_at: None,
+            source: None,
+            no_nickname: false,
+        }
+    }
+    /// The nickname that puts the member back the way they were. Empty clears it.
+    pub fn nickname(&self) -> &str {
+        if self.no_nickname {
+            ""
+        } else {
+            &self.name
+        }
+    }
+    /// What to store in the names tree, encrypted if there's a key.
+    pub fn encode(&self) -> Vec<u8> {
+        encryption::seal(&format!(
+            "{RECORD_MARKER}{}",
+            serde_json::to_string(self).unwrap()
+        ))
+    }
+    /// Reads a value from the names tree, or any other tree of bare names.
+    pub fn decode(value: &[u8]) -> Result<StoredName, String> {
+        let text = encryption::open(value)?;
+        match text.strip_prefix(RECORD_MARKER) {
+            Some(json) => serde_json::from_str(json).map_err(|e| e.to_string()),
+            None => Ok(StoredName::bare(&text)),
+        }
+    }
+}
+/// Stores the member's name.
+pub fn store_name(
+    store: &(impl NameStore + ?Sized),
+    guild_id: GuildId,
+    user_id: UserId,
+    name: &str,
+    source: NameSource,
+) {
+    store.set_name(
+        Names::Original,
+        guild_id,
+        user_id,
+        &StoredName::new(name, source),
+    );
+}
+/// Stores the member's current name, noting whether it's a nickname.
+pub fn store_member_name(store: &(impl NameStore + ?Sized), member: &Member, source: NameSource) {
+    let mut stored = StoredName::new(member.display_name(), source);
+    stored.no_nickname = member.nick.is_none();
+    store.set_name(Names::Original, member.guild_id, member.user.id, &stored);
+}
+/// The nickname that restores the member's stored name in the guild. Empty clears it.
+pub fn nick_to_restore(
+    store: &(impl NameStore + ?Sized),
+    guild_id: GuildId,
+    user_id: UserId,
+) -> Option<String> {
+    store
+        .get_name(Names::Original, guild_id, user_id)
+        .map(|stored| stored.nickname().to_string())
+}
+pub fn get_stored_name(tree: &Tree, user_id: DbKey) -> Option<StoredName> {
     match tree.get(user_id) {
         Err(e) => {
             warn!("Failed to get name for {user_id}: {e}");
             None
         }
-        Ok(value) => match String::from_utf8(value?.as_ref().to_vec()) {
+        Ok(value) => match StoredName::decode(&value?) {
             Err(e) => {
                 warn!("Corrupt name for {user_id}: {e}");
                 None
             }
-            Ok(name) => Some(name.to_string()),
+            Ok(stored) => Some(stored),
         },
     }
 }
+pub fn get_name(tree: &Tree, user_id: DbKey) -> Option<String> {
+    get_stored_name(tree, user_id).map(|stored| stored.name)
+}
+/// Deletes the member's stored name and override, or just the override. Returns whether there
+/// was anything to delete.
+pub fn unset_name(db: &Store, guild_id: GuildId, user_id: UserId, override_only: bool) -> bool {
+    let key = DbKey::from(user_id);
+    db.open_tree(override_times_db_tree_name(guild_id))
+        .unwrap()
+        .remove(key)
+        .unwrap();
+    let mut removed = db.remove_name(Names::Override, guild_id, user_id).is_some();
+    if !override_only {
+        removed |= db.remove_name(Names::Original, guild_id, user_id).is_some();
+    }
+    removed
+}
+
+/// Drops every tree holding the guild's data: stored names, overrides, settings and the rest.
+/// Returns how many trees there were in the sled database.
+pub fn drop_guild(db: &Store, guild_id: GuildId) -> usize {
+    for names in Names::ALL {
+        db.clear_names(names, guild_id);
+    }
+    db.clear_settings(guild_id);
+    let trees: Vec<IVec> = db
+        .tree_names()
+        .into_iter()
+        .filter(|name| {
+            name.as_ref() == DbKey::from(guild_id).as_ref()
+                || parse_guild_db_tree_name(name).is_some_and(|(_, id)| id == guild_id)
+        })
+        .collect();
+    for name in &trees {
+        info!("Dropping tree {name:?} of guild {guild_id}");
+        db.drop_tree(name).unwrap();
+    }
+    trees.len()
+}
+
+/// Deletes everything stored about the user in every guild: their stored name, override,
+/// history, plays and the rest. Opt outs are kept, since they hold nothing but the user's id and
+/// deleting them would start renaming the user again. Returns how many entries were deleted.
+pub fn forget_user(db: &Store, user_id: UserId) -> usize {
+    let user = DbKey::from(user_id);
+    let mut forgotten = 0;
+    for guild_id in db.guilds() {
+        for names in Names::ALL {
+            if db.remove_name(names, guild_id, user_id).is_some() {
+                forgotten += 1;
+            }
+        }
+    }
+    for name in db.tree_names() {
+        let tree = db.open_tree(&name).unwrap();
+        let keys: Vec<IVec> = match parse_guild_db_tree_name(&name).map(|(prefix, _)| prefix) {
+            Some(
+                ROLLOUTS_DB_TREE_PREFIX
+                | CONSENTS_DB_TREE_PREFIX
+                | RIOT_ACCOUNTS_DB_TREE_PREFIX
+                | PENDING_RENAMES_DB_TREE_PREFIX
+                | OVERRIDE_TIMES_DB_TREE_PREFIX
+                | UNRENAMABLE_DB_TREE_PREFIX
+                | HISTORY_DB_TREE_PREFIX,
+            ) => vec![user.as_ref().into()],
+            // Keyed by the member, then when or a source name.
+            Some(
+                AUDIT_DB_TREE_PREFIX | PLAYS_DB_TREE_PREFIX | MEMBER_RENAME_COUNTS_DB_TREE_PREFIX,
+            ) => tree.scan_prefix(user).keys().map(Result::unwrap).collect(),
+            // Keyed by the channel or when, then the member. A session also names the member
+            // whose name someone got.
+            Some(SESSIONS_DB_TREE_PREFIX | RENAMES_DB_TREE_PREFIX) => tree
+                .iter()
+                .map(Result::unwrap)
+                .filter(|(key, value)| {
+                    key.ends_with(user.as_ref())
+                        || (name[0] == SESSIONS_DB_TREE_PREFIX && value.as_ref() == user.as_ref())
+                })
+                .map(|(key, _)| key)
+                .collect(),
+            _ => continue,
+        };
+        for key in keys {
+            if tree.remove(key).unwrap().is_some() {
+                forgotten += 1;
+            }
+        }
+    }
+    info!("Forgot {forgotten} entries about {user_id}");
+    forgotten
+}
+
+/// Stored names kept per guild.
+const NAME_CACHE_CAPACITY: usize = 1024;
+
+/// Remembers recently read stored names so a sync doesn't look the same member up over and over.
+/// Anything that writes stored names while the bot is running must invalidate them.
+#[derive(Default)]
+pub struct NameCache {
+    guilds: Mutex<HashMap<GuildId, GuildNames>>,
+}
+#[derive(Default)]
+struct GuildNames {
+    clock: u64,
+    /// The stored name (or lack of one) and when it was last used.
+    entries: HashMap<UserId, (Option<StoredName>, u64)>,
+}
+impl NameCache {
+    /// Reads the member's stored name from the store, unless it's cached.
+    pub fn get(
+        &self,
+        store: &(impl NameStore + ?Sized),
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Option<String> {
+        self.get_stored(store, guild_id, user_id)
+            .map(|stored| stored.name)
+    }
+    /// Like `get`, with the rest of the stored record.
+    pub fn get_stored(
+        &self,
+        store: &(impl NameStore + ?Sized),
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> Option<StoredName> {
+        let mut guilds = self.guilds.lock().unwrap();
+        let guild = guilds.entry(guild_id).or_default();
+        guild.clock += 1;
+        let clock = guild.clock;
+        if let Some((name, last_used)) = guild.entries.get_mut(&user_id) {
+            *last_used = clock;
+            return name.clone();
+        }
+        let name = store.get_name(Names::Original, guild_id, user_id);
+        if guild.entries.len() >= NAME_CACHE_CAPACITY {
+            if let Some(oldest) = guild
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(user_id, _)| *user_id)
+            {
+                guild.entries.remove(&oldest);
+            }
+        }
+        guild.entries.insert(user_id, (name.clone(), clock));
+        name
+    }
+    /// How many of the guild's names are cached.
+    pub fn cached(&self, guild_id: GuildId) -> usize {
+        self.guilds
+            .lock()
+            .unwrap()
+            .get(&guild_id)
+            .map_or(0, |guild| guild.entries.len())
+    }
+    pub fn invalidate(&self, guild_id: GuildId, user_id: UserId) {
+        if let Some(guild) = self.guilds.lock().unwrap().get_mut(&guild_id) {
+            guild.entries.remove(&user_id);
+        }
+    }
+    pub fn invalidate_guild(&self, guild_id: GuildId) {
+        self.guilds.lock().unwrap().remove(&guild_id);
+    }
+    pub fn invalidate_user(&self, user_id: UserId) {
+        for guild in self.guilds.lock().unwrap().values_mut() {
+            guild.entries.remove(&user_id);
+        }
+    }
+}
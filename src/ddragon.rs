@@ -0,0 +1,208 @@
+use std::{collections::HashMap, path::Path};
+
+use futures::{stream::iter, StreamExt, TryStreamExt};
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+const DDRAGON_URL: &str = "https://ddragon.leagueoflegends.com";
+pub const CANONICAL_LOCALE: &str = "en_US";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LocalizedChampion {
+    pub name: String,
+    pub title: String,
+    /// The numeric id the Riot API uses.
+    #[serde(default)]
+    pub key: String,
+}
+
+#[derive(Deserialize)]
+struct ChampionFile {
+    data: HashMap<String, LocalizedChampion>,
+}
+
+/// Champion names from Riot's Data Dragon in every locale League ships in, so that rich presence
+/// from non-English clients can be matched back to a champion.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ChampionIndex {
+    version: String,
+    /// Champion id → locale → localized champion.
+    champions: HashMap<String, HashMap<String, LocalizedChampion>>,
+    /// Normalized localized name → champion id.
+    #[serde(skip)]
+    aliases: HashMap<String, String>,
+}
+
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+async fn fetch<T: for<'de> Deserialize<'de>>(
+    client: &reqwest::Client,
+    path: &str,
+) -> reqwest::Result<T> {
+    client
+        .get(format!("{DDRAGON_URL}/{path}"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+impl ChampionIndex {
+    /// Loads the index from the cache file, refreshing it from Data Dragon when a new patch is
+    /// out. Falls back to whatever is available (possibly nothing) if Data Dragon is unreachable.
+    pub async fn load(cache_path: &Path) -> Self {
+        let cached: Option<Self> = std::fs::read(cache_path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+        let client = reqwest::Client::new();
+        let latest_version = fetch::<Vec<String>>(&client, "api/versions.json")
+            .await
+            .inspect_err(|e| warn!("Failed to get the latest Data Dragon version: {e}"))
+            .ok()
+            .and_then(|versions| versions.into_iter().next());
+        let mut index = match (cached, latest_version) {
+            (Some(cached), Some(latest)) if cached.version == latest => cached,
+            (cached, Some(latest)) => match Self::download(&client, latest).await {
+                Ok(index) => {
+                    match serde_json::to_vec(&index) {
+                        Ok(bytes) => {
+                            if let Err(e) = std::fs::write(cache_path, bytes) {
+                                warn!("Failed to write champion cache {cache_path:?}: {e}");
+                            }
+                        }
+                        Err(e) => warn!("Failed to serialize champion cache: {e}"),
+                    }
+                    index
+                }
+                Err(e) => {
+                    warn!("Failed to download champions from Data Dragon: {e}");
+                    cached.unwrap_or_default()
+                }
+            },
+            (cached, None) => cached.unwrap_or_default(),
+        };
+        index.build_aliases();
+        info!(
+            "Loaded {} champions ({} aliases) from Data Dragon {}",
+            index.champions.len(),
+            index.aliases.len(),
+            index.version
+        );
+        index
+    }
+
+    async fn download(client: &reqwest::Client, version: String) -> reqwest::Result<Self> {
+        let locales: Vec<String> = fetch(client, "cdn/languages.json").await?;
+        let files: Vec<(String, ChampionFile)> = iter(locales)
+            .map(|locale| {
+                let path = format!("cdn/{version}/data/{locale}/champion.json");
+                async move { Ok((locale, fetch(client, &path).await?)) }
+            })
+            .buffer_unordered(8)
+            .try_collect()
+            .await?;
+        let mut champions: HashMap<String, HashMap<String, LocalizedChampion>> = HashMap::new();
+        for (locale, file) in files {
+            for (id, champion) in file.data {
+                champions
+                    .entry(id)
+                    .or_default()
+                    .insert(locale.clone(), champion);
+            }
+        }
+        Ok(Self {
+            version,
+            champions,
+            aliases: HashMap::new(),
+        })
+    }
+
+    fn build_aliases(&mut self) {
+        self.aliases = self
+            .champions
+            .iter()
+            .flat_map(|(id, locales)| {
+                locales
+                    .values()
+                    .map(move |champion| (normalize(&champion.name), id.clone()))
+                    .chain(std::iter::once((normalize(id), id.clone())))
+            })
+            .collect();
+    }
+
+    /// Picks a random champion's English name.
+    pub fn random_name(&self) -> Option<&str> {
+        let champion = self.champions.values().choose(&mut rand::thread_rng())?;
+        Some(&champion.get(CANONICAL_LOCALE)?.name)
+    }
+
+    /// Picks a random champion's English name that isn't in `taken`.
+    pub fn random_name_except(&self, taken: &[String]) -> Option<&str> {
+        self.champions
+            .values()
+            .filter_map(|champion| Some(champion.get(CANONICAL_LOCALE)?.name.as_str()))
+            .filter(|name| !taken.iter().any(|taken| taken == name))
+            .choose(&mut rand::thread_rng())
+    }
+
+    /// Whether Data Dragon has never been reachable, in which case nothing can be validated.
+    pub fn is_empty(&self) -> bool {
+        self.champions.is_empty()
+    }
+
+    /// The locales champions are known in, like `en_US` and `ko_KR`.
+    pub fn locales(&self) -> Vec<&str> {
+        let mut locales: Vec<&str> = self
+            .champions
+            .values()
+            .flat_map(|locales| locales.keys().map(String::as_str))
+            .collect();
+        locales.sort_unstable();
+        locales.dedup();
+        locales
+    }
+
+    /// A champion's name and title in the locale, given their name in any locale.
+    pub fn localized(&self, name: &str, locale: &str) -> Option<&LocalizedChampion> {
+        let id = self.aliases.get(&normalize(name))?;
+        self.champions.get(id)?.get(locale)
+    }
+
+    /// Maps the Riot API's numeric champion id to the champion's English name.
+    pub fn name_by_key(&self, key: i64) -> Option<&str> {
+        let key = key.to_string();
+        self.champions
+            .values()
+            .filter_map(|locales| locales.get(CANONICAL_LOCALE))
+            .find(|champion| champion.key == key)
+            .map(|champion| champion.name.as_str())
+    }
+
+    /// Splits rich presence like "Star Guardian Ahri" into the champion's English name and the
+    /// skin, which is None for the default skin. Returns None if no champion is named.
+    pub fn split_skin<'a>(&'a self, large_text: &'a str) -> Option<(&'a str, Option<&'a str>)> {
+        if let Some(champion) = self.canonical_name(large_text) {
+            return Some((champion, None));
+        }
+        let words: Vec<&str> = large_text.split_whitespace().collect();
+        // The longest run of words naming a champion, so "Master Yi" wins over "Yi".
+        let champion = (1..words.len())
+            .rev()
+            .flat_map(|len| words.windows(len))
+            .find_map(|window| self.canonical_name(&window.join(" ")))?;
+        Some((champion, Some(large_text.trim())))
+    }
+
+    /// Maps a champion name in any locale to the champion's English name.
+    pub fn canonical_name(&self, localized_name: &str) -> Option<&str> {
+        let id = self.aliases.get(&normalize(localized_name))?;
+        Some(&self.champions.get(id)?.get(CANONICAL_LOCALE)?.name)
+    }
+}
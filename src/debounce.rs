@@ -0,0 +1,60 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use serenity::model::prelude::{ChannelId, GuildId};
+use tokio::time::Instant;
+use tracing::debug;
+
+/// A channel that never settles still syncs after this many windows.
+const MAX_WINDOWS: u32 = 10;
+
+/// Coalesces the bursts of presence and voice updates a channel gets into a single sync.
+pub struct SyncDebouncer {
+    window: Duration,
+    /// When each waiting channel's sync is due, pushed back by every update.
+    pending: Mutex<HashMap<(GuildId, ChannelId), Instant>>,
+}
+
+impl SyncDebouncer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Waits until the channel has had no updates for the window and returns true if the
+    /// caller should run the sync, or returns false straight away if a sync is already waiting
+    /// and will cover this request too.
+    pub async fn wait(&self, guild_id: GuildId, channel_id: ChannelId) -> bool {
+        let key = (guild_id, channel_id);
+        let now = Instant::now();
+        if let Some(due) = self.pending.lock().unwrap().get_mut(&key) {
+            debug!("Coalescing sync of channel {channel_id} in guild {guild_id}");
+            *due = now + self.window;
+            return false;
+        }
+        self.pending.lock().unwrap().insert(key, now + self.window);
+        let deadline = now + self.window * MAX_WINDOWS;
+        loop {
+            let due = self.pending.lock().unwrap()[&key].min(deadline);
+            tokio::time::sleep_until(due).await;
+            let mut pending = self.pending.lock().unwrap();
+            if pending[&key] <= Instant::now() || deadline <= Instant::now() {
+                // Requests from here on need a sync of their own, since this one may have read
+                // the channel before they happened.
+                pending.remove(&key);
+                return true;
+            }
+        }
+    }
+
+    /// How many channels in the guild are waiting to sync.
+    pub fn pending(&self, guild_id: GuildId) -> usize {
+        self.pending
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(pending_guild_id, _)| *pending_guild_id == guild_id)
+            .count()
+    }
+}
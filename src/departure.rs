@@ -0,0 +1,74 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serenity::{cache::Cache, model::prelude::GuildId};
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::{
+    db::{drop_guild, get_setting, remove_setting, set_setting, LEFT_AT_SETTING},
+    rules,
+    store::{NameStore, Store},
+    tasks,
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Records that the bot was removed from the guild, so its data is dropped once `grace` has
+/// passed without the bot being added back. Without a grace period it's dropped straight away.
+pub fn left(db: &Store, guild_id: GuildId, grace: Duration) {
+    if grace.is_zero() {
+        let dropped = drop_guild(db, guild_id);
+        rules::invalidate(guild_id);
+        info!("Removed from guild {guild_id}, dropped its {dropped} trees");
+    } else {
+        info!("Removed from guild {guild_id}, dropping its data in {grace:?} unless added back");
+        set_setting(db, guild_id, LEFT_AT_SETTING, now_secs());
+    }
+}
+
+/// Keeps the guild's data, since the bot is in it again.
+pub fn rejoined(db: &Store, guild_id: GuildId) {
+    if get_setting::<u64>(db, guild_id, LEFT_AT_SETTING).is_some() {
+        info!("Added back to guild {guild_id}, keeping its data");
+        remove_setting(db, guild_id, LEFT_AT_SETTING);
+    }
+}
+
+/// Drops the data of guilds the bot left more than `grace` ago. Departures are kept in the
+/// database, so this carries on across restarts.
+pub fn spawn_sweeper(db: Store, cache: Arc<Cache>, grace: Duration) {
+    tasks::spawn("left guild cleanup", async move {
+        // Give the guilds the bot was added back to while it was offline time to arrive first.
+        let mut interval =
+            tokio::time::interval_at(Instant::now() + SWEEP_INTERVAL, SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweep(&db, &cache, grace);
+        }
+    });
+}
+
+fn sweep(db: &Store, cache: &Cache, grace: Duration) {
+    for guild_id in db.guilds() {
+        let Some(left_at) = get_setting::<u64>(db, guild_id, LEFT_AT_SETTING) else {
+            continue;
+        };
+        if cache.guild(guild_id).is_some() {
+            rejoined(db, guild_id);
+        } else if now_secs().saturating_sub(left_at) >= grace.as_secs() {
+            let dropped = drop_guild(db, guild_id);
+            rules::invalidate(guild_id);
+            info!("Dropped the {dropped} trees of guild {guild_id}, which the bot left");
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::path::Path;
+
+use serenity::{
+    all::{ApplicationFlags, GuildPagination, Permissions},
+    http::{Http, HttpError},
+    model::prelude::{GuildId, UserId},
+    Error,
+};
+use sled::Db;
+
+use crate::{
+    db::{
+        names_tree_guild, parse_guild_db_tree_name, DbKey, StoredName,
+        NAME_OVERRIDES_DB_TREE_PREFIX, PERMANENT_NICKS_DB_TREE_PREFIX, SETTINGS_DB_TREE_PREFIX,
+    },
+    encryption, schema,
+};
+
+/// The most guilds Discord returns per page.
+const GUILDS_PER_PAGE: u64 = 200;
+
+/// Collects what `doctor` found, printing each finding as it comes.
+#[derive(Default)]
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn ok(&mut self, finding: impl AsRef<str>) {
+        println!("ok       {}", finding.as_ref());
+    }
+
+    fn problem(&mut self, finding: impl AsRef<str>) {
+        self.problems += 1;
+        println!("PROBLEM  {}", finding.as_ref());
+    }
+}
+
+/// Checks the token, gateway, intents and the bot's permissions in every guild.
+async fn check_discord(report: &mut Report, token: &str) {
+    let http = Http::new(token);
+    let user = match http.get_current_user().await {
+        Ok(user) => {
+            report.ok(format!("The token belongs to {} ({})", user.name, user.id));
+            user
+        }
+        Err(Error::Http(HttpError::UnsuccessfulRequest(response)))
+            if response.status_code.as_u16() == 401 =>
+        {
+            report.problem(
+                "Discord rejected the token. Copy a fresh one from the Bot page of the developer portal.",
+            );
+            return;
+        }
+        Err(e) => {
+            report.problem(format!(
+                "Couldn't reach Discord: {e}. Check this machine can reach discord.com."
+            ));
+            return;
+        }
+    };
+    match http.get_bot_gateway().await {
+        Ok(gateway) => report.ok(format!(
+            "The gateway is reachable and recommends {} shards, with {} of {} sessions left to start today",
+            gateway.shards, gateway.session_start_limit.remaining, gateway.session_start_limit.total
+        )),
+        Err(e) => report.problem(format!(
+            "Couldn't reach the gateway: {e}. Check this machine can reach discord.com."
+        )),
+    }
+    match http.get_current_application_info().await {
+        Ok(application) => {
+            let flags = application.flags.unwrap_or_else(ApplicationFlags::empty);
+            for (intent, full, limited) in [
+                (
+                    "Presence",
+                    ApplicationFlags::GATEWAY_PRESENCE,
+                    ApplicationFlags::GATEWAY_PRESENCE_LIMITED,
+                ),
+                (
+                    "Server Members",
+                    ApplicationFlags::GATEWAY_GUILD_MEMBERS,
+                    ApplicationFlags::GATEWAY_GUILD_MEMBERS_LIMITED,
+                ),
+            ] {
+                if flags.intersects(full | limited) {
+                    report.ok(format!("The {intent} intent is on"));
+                } else {
+                    report.problem(format!(
+                        "The {intent} intent is off, so the bot can't connect. Turn it on under Privileged Gateway Intents on the Bot page of the developer portal."
+                    ));
+                }
+            }
+        }
+        Err(e) => report.problem(format!("Couldn't check the bot's intents: {e}")),
+    }
+    let mut after = None;
+    loop {
+        let guilds = match http
+            .get_guilds(after.map(GuildPagination::After), Some(GUILDS_PER_PAGE))
+            .await
+        {
+            Ok(guilds) => guilds,
+            Err(e) => {
+                report.problem(format!("Couldn't list the bot's servers: {e}"));
+                return;
+            }
+        };
+        for guild in &guilds {
+            check_guild(
+                report,
+                &http,
+                guild.id,
+                &guild.name,
+                guild.permissions,
+                user.id,
+            )
+            .await;
+        }
+        match guilds.last() {
+            Some(last) if guilds.len() as u64 == GUILDS_PER_PAGE => after = Some(last.id),
+            _ => break,
+        }
+    }
+}
+
+async fn check_guild(
+    report: &mut Report,
+    http: &Http,
+    guild_id: GuildId,
+    name: &str,
+    permissions: Permissions,
+    bot_id: UserId,
+) {
+    let label = format!("{name} ({guild_id})");
+    if !permissions.manage_nicknames() {
+        report.problem(format!(
+            "{label}: the bot doesn't have Manage Nicknames. Give it to one of its roles."
+        ));
+        return;
+    }
+    let (roles, member) = match (
+        guild_id.roles(http).await,
+        guild_id.member(http, bot_id).await,
+    ) {
+        (Ok(roles), Ok(member)) => (roles, member),
+        (Err(e), _) | (_, Err(e)) => {
+            report.problem(format!("{label}: couldn't look up the bot's roles: {e}"));
+            return;
+        }
+    };
+    let top = member
+        .roles
+        .iter()
+        .filter_map(|role_id| roles.get(role_id))
+        .map(|role| role.position)
+        .max()
+        .unwrap_or(0);
+    let above: Vec<&str> = roles
+        .values()
+        .filter(|role| role.position > top && !role.managed)
+        .map(|role| role.name.as_str())
+        .collect();
+    if above.is_empty() {
+        report.ok(format!(
+            "{label}: the bot can rename everyone but the owner"
+        ));
+    } else {
+        report.problem(format!(
+            "{label}: members with {} can't be renamed. Move the bot's role above them in Server Settings > Roles.",
+            above.join(", ")
+        ));
+    }
+}
+
+/// Looks for tree names, keys and names the bot wouldn't be able to read.
+fn check_db(report: &mut Report, db: &Db) {
+    let mut unreadable = 0;
+    for name in db.tree_names() {
+        let prefix = parse_guild_db_tree_name(&name).map(|(prefix, _)| prefix);
+        let holds_names = names_tree_guild(&name).is_some()
+            || matches!(
+                prefix,
+                Some(NAME_OVERRIDES_DB_TREE_PREFIX | PERMANENT_NICKS_DB_TREE_PREFIX)
+            );
+        let holds_settings = prefix == Some(SETTINGS_DB_TREE_PREFIX);
+        let sealed = encryption::is_sealed_tree(&name);
+        if !holds_names && !holds_settings && !sealed {
+            continue;
+        }
+        let tree = db.open_tree(&name).unwrap();
+        for entry in tree.iter() {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    report.problem(format!("Failed to read tree {name:?}: {e}"));
+                    unreadable += 1;
+                    break;
+                }
+            };
+            if holds_names && key.len() != 8 {
+                report.problem(format!(
+                    "Tree {name:?} has a key {key:?} that isn't a user id"
+                ));
+                unreadable += 1;
+            } else if let (true, Err(e)) = (holds_names, StoredName::decode(&value)) {
+                report.problem(format!(
+                    "Tree {name:?} has a name for {} that can't be read ({e}); pass the key it was encrypted with in --old-name-keys, or `unset` it and set it again",
+                    DbKey(key.as_ref().try_into().unwrap())
+                ));
+                unreadable += 1;
+            } else if let (true, Err(e)) = (sealed, encryption::open(&value)) {
+                report.problem(format!(
+                    "Tree {name:?} has a value for {key:?} that can't be read ({e}); pass the key it was encrypted with in --old-name-keys"
+                ));
+                unreadable += 1;
+            } else if holds_settings
+                && (std::str::from_utf8(&key).is_err() || std::str::from_utf8(&value).is_err())
+            {
+                report.problem(format!(
+                    "Tree {name:?} has a setting {key:?} that isn't UTF-8"
+                ));
+                unreadable += 1;
+            }
+        }
+    }
+    match schema::version(db) {
+        version if version > schema::CURRENT_VERSION => report.problem(format!(
+            "The database is at schema version {version}, newer than this build understands ({}). Run a newer build.",
+            schema::CURRENT_VERSION
+        )),
+        version if version < schema::CURRENT_VERSION => report.ok(format!(
+            "The database is at schema version {version} and will be upgraded to {} the next time the bot or another command opens it",
+            schema::CURRENT_VERSION
+        )),
+        version => report.ok(format!("The database is at schema version {version}")),
+    }
+    match db.checksum() {
+        Ok(_) if unreadable == 0 => report.ok("Everything in the database can be read"),
+        Ok(_) => {}
+        Err(e) => report.problem(format!("The database failed its checksum: {e}")),
+    }
+}
+
+/// Checks everything the bot needs to work and prints what's wrong and how to fix it. Returns
+/// whether there were any problems.
+pub async fn run(token: Result<String, String>, db_path: &Path) -> bool {
+    let mut report = Report::default();
+    match token {
+        Ok(token) => check_discord(&mut report, &token).await,
+        Err(e) => report.problem(e),
+    }
+    if !db_path.exists() {
+        report.problem(format!(
+            "There's no database at {db_path:?}. Pass the path the bot uses with --db."
+        ));
+        return true;
+    }
+    match sled::open(db_path) {
+        Ok(db) => check_db(&mut report, &db),
+        Err(e) => report.problem(format!(
+            "Couldn't open the database {db_path:?}: {e}. If the bot is running, stop it first, or check a copy made with `backup` and `restore-backup`."
+        )),
+    }
+    report.problems > 0
+}
@@ -0,0 +1,125 @@
+use std::sync::OnceLock;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+use sled::{Batch, Db};
+
+use crate::db::{
+    names_tree_guild, parse_guild_db_tree_name, AUDIT_DB_TREE_PREFIX, HISTORY_DB_TREE_PREFIX,
+    NAME_OVERRIDES_DB_TREE_PREFIX, PENDING_RENAMES_DB_TREE_PREFIX, PERMANENT_NICKS_DB_TREE_PREFIX,
+    RENAMES_DB_TREE_PREFIX, ROLLOUTS_DB_TREE_PREFIX,
+};
+
+/// Starts every encrypted name. It never appears in UTF-8, so it can't start a plain name.
+const MARKER: u8 = 0xff;
+const NONCE_LEN: usize = 12;
+
+struct Keys {
+    /// Whether the first key is the current one, to encrypt names with.
+    encrypting: bool,
+    /// Every key that can decrypt names, the current one first.
+    keys: Vec<ChaCha20Poly1305>,
+}
+
+static KEYS: OnceLock<Keys> = OnceLock::new();
+
+fn parse_key(key: &str) -> Result<ChaCha20Poly1305, String> {
+    let bytes = STANDARD
+        .decode(key.trim())
+        .map_err(|e| format!("A name key isn't valid base64: {e}"))?;
+    ChaCha20Poly1305::new_from_slice(&bytes)
+        .map_err(|_| format!("A name key is {} bytes instead of 32", bytes.len()))
+}
+
+/// Sets the keys stored names are encrypted and decrypted with. Without a current key, names
+/// are stored in plain text, but the old keys can still decrypt them.
+pub fn configure(current: Option<&str>, old: &[String]) -> Result<(), String> {
+    let keys = current
+        .into_iter()
+        .chain(old.iter().map(String::as_str))
+        .map(parse_key)
+        .collect::<Result<Vec<_>, _>>()?;
+    let keys = Keys {
+        encrypting: current.is_some(),
+        keys,
+    };
+    KEYS.set(keys)
+        .map_err(|_| "Name keys are already set".to_string())
+}
+
+fn keys() -> &'static [ChaCha20Poly1305] {
+    KEYS.get()
+        .map(|keys| keys.keys.as_slice())
+        .unwrap_or_default()
+}
+
+/// What to store for a name: encrypted with the current key if there is one.
+pub fn seal(name: &str) -> Vec<u8> {
+    if !KEYS.get().is_some_and(|keys| keys.encrypting) {
+        return name.as_bytes().to_vec();
+    }
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = keys()[0].encrypt(&nonce, name.as_bytes()).unwrap();
+    [&[MARKER], nonce.as_slice(), &ciphertext].concat()
+}
+
+/// The name stored as `value`, decrypting it if it's encrypted.
+pub fn open(value: &[u8]) -> Result<String, String> {
+    let plaintext = match value.split_first() {
+        Some((&MARKER, sealed)) if sealed.len() >= NONCE_LEN => {
+            let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+            keys()
+                .iter()
+                .find_map(|key| key.decrypt(Nonce::from_slice(nonce), ciphertext).ok())
+                .ok_or("it's encrypted with a key that isn't configured")?
+        }
+        _ => value.to_vec(),
+    };
+    String::from_utf8(plaintext).map_err(|e| e.to_string())
+}
+
+/// Whether the tree's values are sealed: the stored names, overrides and permanent nicknames,
+/// and the name history, renames, pending edits and planned renames that are kept alongside them.
+pub fn is_sealed_tree(name: &[u8]) -> bool {
+    names_tree_guild(name).is_some()
+        || matches!(
+            parse_guild_db_tree_name(name),
+            Some((
+                NAME_OVERRIDES_DB_TREE_PREFIX
+                    | PERMANENT_NICKS_DB_TREE_PREFIX
+                    | AUDIT_DB_TREE_PREFIX
+                    | HISTORY_DB_TREE_PREFIX
+                    | RENAMES_DB_TREE_PREFIX
+                    | PENDING_RENAMES_DB_TREE_PREFIX
+                    | ROLLOUTS_DB_TREE_PREFIX,
+                _
+            ))
+        )
+}
+
+/// Stores every sealed value again with the current key, or in plain text without one, so older
+/// keys can be dropped. Returns how many values were rewritten.
+pub fn rewrite_names(db: &Db) -> Result<usize, String> {
+    let mut rewritten = 0;
+    for tree_name in db
+        .tree_names()
+        .into_iter()
+        .filter(|name| is_sealed_tree(name))
+    {
+        let tree = db.open_tree(&tree_name).unwrap();
+        let mut batch = Batch::default();
+        for entry in tree.iter() {
+            let (key, value) = entry.unwrap();
+            let name = open(&value)
+                .map_err(|e| format!("Can't read {key:?} in tree {tree_name:?}: {e}"))?;
+            batch.insert(key, seal(&name));
+            rewritten += 1;
+        }
+        tree.apply_batch(batch).unwrap();
+    }
+    db.flush().unwrap();
+    Ok(rewritten)
+}
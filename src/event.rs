@@ -0,0 +1,243 @@
+use serenity::{
+    all::{CreateMessage, Reaction, ReactionType},
+    http::Http,
+    model::prelude::{ChannelId, GuildId, Member, MessageId, RoleId, UserId},
+    prelude::*,
+};
+use tracing::{info, warn};
+
+use crate::{
+    db::{
+        get_setting, remove_setting, set_setting, store_member_name, NameCache, NameSource,
+        Participation, StoredName, EVENT_MESSAGE_SETTING, EVENT_ROLE_SETTING,
+    },
+    ddragon::ChampionIndex,
+    expiry,
+    name_source::NameSources,
+    namerestorer::{restore_guild_members, restore_member},
+    nickname::{self, apply_nick, record_rejection, NickOutcome},
+    session, stats,
+    store::{NameStore, Names, Store},
+};
+
+const EVENT_EMOJI: &str = "🎉";
+
+/// The role that decides who gets a theme name while an event is running. Voice channels are
+/// ignored while it's set.
+pub fn event_role(db: &Store, guild_id: GuildId) -> Option<RoleId> {
+    get_setting(db, guild_id, EVENT_ROLE_SETTING)
+}
+
+/// What renaming members for the event needs from the bot.
+pub struct Namer<'a> {
+    pub champions: &'a ChampionIndex,
+    pub sources: &'a NameSources,
+    pub name_cache: &'a NameCache,
+}
+
+/// Who is named after whom during the event is kept like a voice channel's session, under the
+/// role's id.
+fn session_channel(role_id: RoleId) -> ChannelId {
+    ChannelId::new(role_id.get())
+}
+
+/// Starts an event for everyone with the role and posts a message members can react to in
+/// order to join.
+pub async fn start(
+    db: &Store,
+    ctx: &Context,
+    namer: &Namer<'_>,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    role_id: RoleId,
+) -> String {
+    info!("Starting an event for role {role_id} in guild {guild_id}");
+    set_setting(db, guild_id, EVENT_ROLE_SETTING, role_id);
+    let message = CreateMessage::new()
+        .content(format!(
+            "A name event is on! React with {EVENT_EMOJI} to get {} and a themed nickname. Remove your reaction to leave.",
+            role_id.mention()
+        ))
+        .reactions([ReactionType::Unicode(EVENT_EMOJI.to_string())]);
+    match channel_id.send_message(&ctx.http, message).await {
+        Ok(message) => set_setting(db, guild_id, EVENT_MESSAGE_SETTING, message.id),
+        Err(e) => warn!("Failed to post event message in guild {guild_id}: {e:?}"),
+    }
+    // Names from voice channels don't apply during the event.
+    restore_guild_members(db, &ctx.http, guild_id, |_| true).await;
+    session::clear(db, guild_id, session_channel(role_id));
+    let members: Vec<UserId> = role_holders(ctx, guild_id, role_id)
+        .iter()
+        .map(|member| member.user.id)
+        .collect();
+    let count = members.len();
+    rename(db, ctx, namer, guild_id, role_id, &members).await;
+    format!(
+        "Started an event for {}. {count} members are getting themed names.",
+        role_id.mention()
+    )
+}
+
+/// Ends the event and restores everyone's names.
+pub async fn stop(db: &Store, ctx: &Context, guild_id: GuildId) -> String {
+    info!("Stopping the event in guild {guild_id}");
+    if let Some(role_id) = event_role(db, guild_id) {
+        session::clear(db, guild_id, session_channel(role_id));
+    }
+    remove_setting(db, guild_id, EVENT_ROLE_SETTING);
+    remove_setting(db, guild_id, EVENT_MESSAGE_SETTING);
+    restore_guild_members(db, &ctx.http, guild_id, |_| true).await;
+    "The event is over and everyone's names have been restored.".to_string()
+}
+
+/// Gives or takes away the event role. The rename follows from the member update.
+pub async fn set_membership(
+    db: &Store,
+    http: &Http,
+    guild_id: GuildId,
+    user_id: UserId,
+    join: bool,
+) -> String {
+    let Some(role_id) = event_role(db, guild_id) else {
+        return "There's no event running.".to_string();
+    };
+    let result = if join {
+        http.add_member_role(guild_id, user_id, role_id, Some("Joined the name event"))
+            .await
+    } else {
+        http.remove_member_role(guild_id, user_id, role_id, Some("Left the name event"))
+            .await
+    };
+    match result {
+        Ok(()) if join => format!("You've joined the event as {}.", role_id.mention()),
+        Ok(()) => "You've left the event.".to_string(),
+        Err(e) => {
+            warn!("Failed to update event role {role_id} for {user_id} in guild {guild_id}: {e:?}");
+            "Failed to update your event role.".to_string()
+        }
+    }
+}
+
+/// Joins or leaves the event by reacting to its message.
+pub async fn handle_reaction(db: &Store, ctx: &Context, reaction: &Reaction, added: bool) {
+    let (Some(guild_id), Some(user_id)) = (reaction.guild_id, reaction.user_id) else {
+        return;
+    };
+    if user_id == ctx.cache.current_user().id
+        || get_setting::<MessageId>(db, guild_id, EVENT_MESSAGE_SETTING)
+            != Some(reaction.message_id)
+        || !reaction.emoji.unicode_eq(EVENT_EMOJI)
+    {
+        return;
+    }
+    set_membership(db, &ctx.http, guild_id, user_id, added).await;
+}
+
+/// Renames members who just got the event role and restores those who lost it.
+pub async fn member_updated(db: &Store, ctx: &Context, namer: &Namer<'_>, member: &Member) {
+    let Some(role_id) = event_role(db, member.guild_id) else {
+        return;
+    };
+    let has_name = db
+        .get_name(Names::Override, member.guild_id, member.user.id)
+        .is_some();
+    match (member.roles.contains(&role_id), has_name) {
+        (true, false) => rename(db, ctx, namer, member.guild_id, role_id, &[member.user.id]).await,
+        (false, true) => restore_member(db, &ctx.http, member.guild_id, member.user.id).await,
+        _ => {}
+    }
+}
+
+/// Everyone in the guild with the role who takes part in renaming, as far as the cache knows.
+fn role_holders(ctx: &Context, guild_id: GuildId, role_id: RoleId) -> Vec<Member> {
+    let Some(guild) = guild_id.to_guild_cached(&ctx.cache) else {
+        warn!("Guild {guild_id} isn't cached, so nobody with the event role can be found");
+        return Vec::new();
+    };
+    guild
+        .members
+        .values()
+        .filter(|member| !member.user.bot && member.roles.contains(&role_id))
+        .cloned()
+        .collect()
+}
+
+/// Renames the given members with the role the way a voice channel is shuffled: everyone with
+/// the role is named after another of them, and members with nothing to be named after pass on
+/// a theme name nobody has.
+async fn rename(
+    db: &Store,
+    ctx: &Context,
+    namer: &Namer<'_>,
+    guild_id: GuildId,
+    role_id: RoleId,
+    renaming: &[UserId],
+) {
+    let participation = Participation::new(db, guild_id);
+    let holders: Vec<Member> = role_holders(ctx, guild_id, role_id)
+        .into_iter()
+        .filter(|member| participation.allows(member.user.id))
+        .collect();
+    let user_ids: Vec<UserId> = holders.iter().map(|member| member.user.id).collect();
+    let assignments =
+        session::assignments(db, guild_id, session_channel(role_id), &user_ids, |_, _| {
+            false
+        });
+    // Names the server refused recently count as taken too.
+    let mut taken: Vec<String> = db
+        .names(Names::Override, guild_id)
+        .into_iter()
+        .map(|(_, name)| name.name)
+        .chain(nickname::rejected_names(db, guild_id))
+        .collect();
+    let presences = guild_id
+        .to_guild_cached(&ctx.cache)
+        .map(|guild| guild.presences.clone())
+        .unwrap_or_default();
+    // One at a time so nobody ends up with the same name.
+    for (member, from) in holders.iter().zip(assignments) {
+        if !renaming.contains(&member.user.id) {
+            continue;
+        }
+        let source = &holders[from].user.id;
+        let nick = namer
+            .sources
+            .name(guild_id, *source, presences.get(source))
+            .filter(|name| !taken.contains(name))
+            .or_else(|| {
+                namer
+                    .champions
+                    .random_name_except(&taken)
+                    .map(str::to_string)
+            });
+        let Some(nick) = nick else {
+            warn!("Ran out of theme names for the event in guild {guild_id}");
+            return;
+        };
+        // The original goes first so there's something to restore.
+        store_member_name(db, member, NameSource::Event);
+        namer.name_cache.invalidate(guild_id, member.user.id);
+        // Record the override first so the member update this causes isn't mistaken for their
+        // own.
+        db.set_name(
+            Names::Override,
+            guild_id,
+            member.user.id,
+            &StoredName::bare(&nick),
+        );
+        expiry::touch(db, guild_id, [member.user.id]);
+        match apply_nick(&ctx.http, guild_id, member.user.id, &nick).await {
+            NickOutcome::Applied => {
+                stats::record_rename(db, guild_id, member.user.id, None, &nick, None);
+                taken.push(nick);
+            }
+            outcome => {
+                stats::record_rename_failure(db, guild_id, member.user.id, &nick);
+                if let NickOutcome::Rejected = outcome {
+                    record_rejection(db, guild_id, &nick);
+                }
+                db.remove_name(Names::Override, guild_id, member.user.id);
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serenity::{
+    http::Http,
+    model::prelude::{GuildId, UserId},
+};
+use sled::Db;
+use tracing::info;
+
+use crate::{
+    db::{override_times_db_tree_name, DbKey},
+    namerestorer,
+    settings::GuildSettings,
+    store::{NameStore, Names, Store},
+    tasks,
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Records that the members' overrides were just set.
+pub fn touch<I: IntoIterator<Item = UserId>>(db: &Db, guild_id: GuildId, user_ids: I) {
+    let override_times = db.open_tree(override_times_db_tree_name(guild_id)).unwrap();
+    let now = now_secs().to_be_bytes();
+    for user_id in user_ids {
+        override_times.insert(DbKey::from(user_id), &now).unwrap();
+    }
+}
+
+/// When the member's override was last set, in seconds since the epoch.
+pub fn overridden_since(db: &Db, guild_id: GuildId, user_id: UserId) -> Option<u64> {
+    let since = db
+        .open_tree(override_times_db_tree_name(guild_id))
+        .unwrap()
+        .get(DbKey::from(user_id))
+        .unwrap()?;
+    Some(u64::from_be_bytes(since.as_ref().try_into().ok()?))
+}
+
+/// The guild's override TTL, or `None` if its overrides never expire.
+pub fn ttl(db: &Store, guild_id: GuildId, default_hours: u64) -> Option<Duration> {
+    match GuildSettings::load(db, guild_id)
+        .override_ttl_hours
+        .unwrap_or(default_hours)
+    {
+        0 => None,
+        // Too long to count in seconds is as good as never.
+        hours => hours.checked_mul(60 * 60).map(Duration::from_secs),
+    }
+}
+
+/// Restores members whose overrides outlived their guild's TTL, which catches sessions that
+/// ended without a voice event telling us.
+pub fn spawn_sweeper(db: Store, http: Arc<Http>, default_ttl_hours: u64) {
+    tasks::spawn("override expiry", async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweep(&db, &http, default_ttl_hours).await;
+        }
+    });
+}
+
+async fn sweep(db: &Store, http: &Http, default_ttl_hours: u64) {
+    for guild_id in db.guilds() {
+        let overridden: HashSet<UserId> = db
+            .names(Names::Override, guild_id)
+            .into_iter()
+            .map(|(user_id, _)| user_id)
+            .collect();
+        let override_times = db.open_tree(override_times_db_tree_name(guild_id)).unwrap();
+        // Forget the times of overrides that have since been cleared.
+        for key in override_times.iter().keys() {
+            let key = key.unwrap();
+            let Ok(user_key) = key.as_ref().try_into() else {
+                continue;
+            };
+            if !overridden.contains(&UserId::from(DbKey(user_key))) {
+                override_times.remove(&key).unwrap();
+            }
+        }
+        let Some(ttl) = ttl(db, guild_id, default_ttl_hours) else {
+            continue;
+        };
+        let now = now_secs();
+        let expired: Vec<UserId> = overridden
+            .into_iter()
+            .filter(|&user_id| {
+                let Some(set_at) = override_times.get(DbKey::from(user_id)).unwrap() else {
+                    // Overrides from before we kept times start counting now.
+                    touch(db, guild_id, [user_id]);
+                    return false;
+                };
+                set_at.as_ref().try_into().is_ok_and(|set_at| {
+                    now.saturating_sub(u64::from_be_bytes(set_at)) >= ttl.as_secs()
+                })
+            })
+            .collect();
+        for user_id in expired {
+            info!("Override for {user_id} in guild {guild_id} expired after {ttl:?}");
+            namerestorer::restore_member(db, http, guild_id, user_id).await;
+            override_times.remove(DbKey::from(user_id)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::{collections::BTreeMap, fs::File, io::BufWriter, path::Path};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::store::{NameStore, Names};
+
+/// Everything needed to put people's names back and run the bot the same way elsewhere.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Export {
+    pub guilds: BTreeMap<u64, GuildExport>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct GuildExport {
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+    #[serde(default)]
+    pub members: BTreeMap<u64, MemberExport>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct MemberExport {
+    /// The member's own name, to restore when they're renamed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<String>,
+    /// The member had no nickname, so `original` is what Discord showed instead.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_nickname: bool,
+    /// The name the bot gave them, if they're renamed right now.
+    #[serde(rename = "override", default, skip_serializing_if = "Option::is_none")]
+    pub name_override: Option<String>,
+    /// The nickname they always get instead of a shuffled one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permanent: Option<String>,
+}
+
+pub type MemberField = fn(&mut MemberExport) -> &mut Option<String>;
+
+/// Each kind of name a guild stores, with the field of a member's export it goes in.
+pub const NAME_FIELDS: [(Names, MemberField); 3] = [
+    (Names::Original, |member| &mut member.original),
+    (Names::Override, |member| &mut member.name_override),
+    (Names::Permanent, |member| &mut member.permanent),
+];
+
+/// Collects every guild's stored names, overrides, permanent nicknames and settings.
+pub fn collect(store: &(impl NameStore + ?Sized)) -> Export {
+    let mut export = Export::default();
+    for guild_id in store.guilds() {
+        let guild = export.guilds.entry(guild_id.get()).or_default();
+        for (names, field) in NAME_FIELDS {
+            for (user_id, name) in store.names(names, guild_id) {
+                let member = guild.members.entry(user_id.get()).or_default();
+                if names == Names::Original {
+                    member.no_nickname = name.no_nickname;
+                }
+                *field(member) = Some(name.name);
+            }
+        }
+        guild.settings.extend(store.settings(guild_id));
+    }
+    export
+}
+
+/// Writes every guild's names, overrides and settings to a JSON file.
+pub fn run(store: &dyn NameStore, path: &Path) {
+    let export = collect(store);
+    let file = File::create(path).unwrap_or_else(|e| panic!("Failed to create {path:?}: {e}"));
+    serde_json::to_writer_pretty(BufWriter::new(file), &export).unwrap();
+    info!("Exported {} guilds to {path:?}", export.guilds.len());
+}
@@ -0,0 +1,65 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serenity::model::prelude::{GuildId, UserId};
+use tokio::task::AbortHandle;
+use tracing::info;
+
+use crate::tasks;
+
+/// Holds off on restoring the names of members who leave voice, in case they were only dropped
+/// for a moment and come straight back.
+pub struct PendingRestores {
+    grace: Duration,
+    pending: Arc<Mutex<HashMap<(GuildId, UserId), AbortHandle>>>,
+}
+
+impl PendingRestores {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            pending: Arc::default(),
+        }
+    }
+
+    /// Runs `restore` once the grace period is over unless the member rejoins first. Replaces
+    /// any restore already pending for them.
+    pub fn schedule<F>(&self, guild_id: GuildId, user_id: UserId, restore: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let grace = self.grace;
+        let pending = self.pending.clone();
+        let mut pending_guard = self.pending.lock().unwrap();
+        let handle = tasks::spawn(&format!("restore {user_id} in {guild_id}"), async move {
+            tokio::time::sleep(grace).await;
+            pending.lock().unwrap().remove(&(guild_id, user_id));
+            restore.await;
+        });
+        if let Some(previous) = pending_guard.insert((guild_id, user_id), handle.abort_handle()) {
+            previous.abort();
+        }
+    }
+
+    /// How many restores are waiting in the guild.
+    pub fn pending(&self, guild_id: GuildId) -> usize {
+        self.pending
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(pending_guild_id, _)| *pending_guild_id == guild_id)
+            .count()
+    }
+
+    /// Called when the member is back in voice.
+    pub fn cancel(&self, guild_id: GuildId, user_id: UserId) {
+        if let Some(pending) = self.pending.lock().unwrap().remove(&(guild_id, user_id)) {
+            info!("{user_id} is back in voice in guild {guild_id}, not restoring their name");
+            pending.abort();
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use serenity::{
+    cache::{Cache, GuildRef},
+    model::prelude::{ChannelId, GuildId, UserId},
+};
+
+/// Looks up what to call a guild, its members and its channels for the dashboard and the
+/// terminal UI. Anything the cache doesn't have is shown by its id. Holds the guild's cache
+/// entry, so keep it only while rendering.
+pub struct GuildNames<'a> {
+    guild_id: GuildId,
+    guild: Option<GuildRef<'a>>,
+}
+
+impl<'a> GuildNames<'a> {
+    pub fn new(cache: &'a Cache, guild_id: GuildId) -> Self {
+        Self {
+            guild_id,
+            guild: cache.guild(guild_id),
+        }
+    }
+
+    pub fn guild(&self) -> String {
+        self.guild
+            .as_ref()
+            .map_or_else(|| self.guild_id.to_string(), |guild| guild.name.clone())
+    }
+
+    pub fn member(&self, user_id: UserId) -> String {
+        self.guild
+            .as_ref()
+            .and_then(|guild| guild.members.get(&user_id))
+            .map_or_else(
+                || user_id.to_string(),
+                |member| member.display_name().to_string(),
+            )
+    }
+
+    pub fn channel(&self, channel_id: ChannelId) -> String {
+        self.guild
+            .as_ref()
+            .and_then(|guild| guild.channels.get(&channel_id))
+            .map_or_else(|| channel_id.to_string(), |channel| channel.name.clone())
+    }
+}
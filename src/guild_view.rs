@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use serenity::{
+    client::Context,
+    model::{
+        prelude::{ChannelId, GuildId, Member, Presence, Role, RoleId, UserId},
+        Permissions,
+    },
+};
+use tracing::warn;
+
+/// What syncing a channel needs to know about its guild. Comes from the cache when it has the
+/// guild and over HTTP otherwise, in which case there are no presences to go on. `None` if the
+/// guild or our own member in it can't be found.
+pub struct GuildView {
+    guild_id: GuildId,
+    pub owner_id: UserId,
+    roles: HashMap<RoleId, Role>,
+    /// The position of our own highest role.
+    pub bot_role_position: u16,
+    pub channel_name: String,
+    presences: HashMap<UserId, Presence>,
+    /// Whether the guild came from the cache, so its members' presences are known.
+    pub has_presences: bool,
+}
+
+impl GuildView {
+    pub async fn load(
+        ctx: &Context,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        members: &[Member],
+    ) -> Option<Self> {
+        let bot_user_id = ctx.cache.current_user().id;
+        let cached = ctx.cache.guild(guild_id).map(|guild| {
+            let view = GuildView {
+                guild_id,
+                owner_id: guild.owner_id,
+                roles: guild.roles.clone(),
+                bot_role_position: 0,
+                channel_name: guild
+                    .channels
+                    .get(&channel_id)
+                    .map_or(String::new(), |channel| channel.name.clone()),
+                presences: members
+                    .iter()
+                    .filter_map(|member| guild.presences.get(&member.user.id))
+                    .map(|presence| (presence.user.id, presence.clone()))
+                    .collect(),
+                has_presences: true,
+            };
+            (view, guild.members.get(&bot_user_id).cloned())
+        });
+        let (mut view, bot) = match cached {
+            Some(cached) => cached,
+            None => {
+                warn!("Guild {guild_id} isn't cached, fetching it instead");
+                let guild = ctx
+                    .http
+                    .get_guild(guild_id)
+                    .await
+                    .inspect_err(|e| warn!("Failed to fetch guild {guild_id}: {e}"))
+                    .ok()?;
+                let channel_name = ctx
+                    .http
+                    .get_channel(channel_id)
+                    .await
+                    .ok()
+                    .and_then(|channel| channel.guild())
+                    .map_or(String::new(), |channel| channel.name);
+                let view = GuildView {
+                    guild_id,
+                    owner_id: guild.owner_id,
+                    roles: guild.roles,
+                    bot_role_position: 0,
+                    channel_name,
+                    presences: HashMap::new(),
+                    has_presences: false,
+                };
+                (view, None)
+            }
+        };
+        // Without our own roles, everyone with a role would look like they outrank us.
+        let bot = match bot {
+            Some(bot) => bot,
+            None => ctx
+                .http
+                .get_member(guild_id, bot_user_id)
+                .await
+                .inspect_err(|e| warn!("Failed to fetch our own member in guild {guild_id}: {e}"))
+                .ok()?,
+        };
+        view.bot_role_position = view.role_position(&bot);
+        Some(view)
+    }
+
+    /// The position of the member's highest role, or 0 (@everyone) if they have none.
+    pub fn role_position(&self, member: &Member) -> u16 {
+        member
+            .roles
+            .iter()
+            .filter_map(|role_id| self.roles.get(role_id))
+            .map(|role| role.position)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The member's guild-wide permissions.
+    pub fn permissions(&self, member: &Member) -> Permissions {
+        if member.user.id == self.owner_id {
+            return Permissions::all();
+        }
+        // @everyone shares the guild's id.
+        let everyone = RoleId::new(self.guild_id.get());
+        member
+            .roles
+            .iter()
+            .chain([&everyone])
+            .filter_map(|role_id| self.roles.get(role_id))
+            .fold(Permissions::empty(), |permissions, role| {
+                permissions | role.permissions
+            })
+    }
+
+    pub fn presence(&self, user_id: UserId) -> Option<&Presence> {
+        self.presences.get(&user_id)
+    }
+}
@@ -0,0 +1,39 @@
+use serenity::model::prelude::{GuildId, UserId};
+use sled::Db;
+use tracing::debug;
+
+use crate::{
+    db::{get_name, history_db_tree_name, DbKey, NameList},
+    encryption,
+};
+
+/// How many of each member's most recent names are remembered.
+const HISTORY_LEN: usize = 5;
+
+/// The names the member was most recently given, oldest first.
+pub fn recent(db: &Db, guild_id: GuildId, user_id: UserId) -> NameList {
+    let history = db.open_tree(history_db_tree_name(guild_id)).unwrap();
+    get_name(&history, DbKey::from(user_id))
+        .and_then(|names| names.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Remembers that the member was given the name, unless it's the one they had last.
+pub fn record(db: &Db, guild_id: GuildId, user_id: UserId, name: &str) {
+    let NameList(mut names) = recent(db, guild_id, user_id);
+    if names.last().map(String::as_str) == Some(name) {
+        return;
+    }
+    debug!("Adding {name} to the history of {user_id} in guild {guild_id}");
+    names.push(name.to_string());
+    if names.len() > HISTORY_LEN {
+        names.drain(..names.len() - HISTORY_LEN);
+    }
+    db.open_tree(history_db_tree_name(guild_id))
+        .unwrap()
+        .insert(
+            DbKey::from(user_id).0,
+            encryption::seal(&NameList(names).to_string()),
+        )
+        .unwrap();
+}
@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use serenity::model::prelude::{GuildId, UserId};
+use tracing::info;
+
+use crate::{
+    db::{NameSource, StoredName},
+    export::{Export, MemberExport, NAME_FIELDS},
+    nickname::MAX_NAME_CHARS,
+    sanitize::sanitize_name,
+    store::{NameStore, Names},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub enum ImportMode {
+    /// Add to what's stored, overwriting members and settings the file also has.
+    Merge,
+    /// Throw away the stored names and settings of every guild in the file first.
+    Replace,
+}
+
+/// Sanitizes a name and checks Discord would accept it.
+fn validate_name(name: &str) -> Result<String, String> {
+    let name = sanitize_name(name);
+    if name.is_empty() {
+        return Err("name is empty".to_string());
+    }
+    if name.chars().count() > MAX_NAME_CHARS {
+        return Err(format!(
+            "{name:?} is longer than {MAX_NAME_CHARS} characters"
+        ));
+    }
+    Ok(name)
+}
+
+/// Checks every id and name in the export, sanitizing the names. Returns every problem found.
+fn validate(export: &mut Export) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (guild_id, guild) in &mut export.guilds {
+        if *guild_id == 0 {
+            problems.push("0 is not a guild id".to_string());
+        }
+        for (user_id, member) in &mut guild.members {
+            if *user_id == 0 {
+                problems.push(format!("0 in guild {guild_id} is not a user id"));
+            }
+            let MemberExport {
+                original,
+                name_override,
+                permanent,
+                ..
+            } = member;
+            for name in [original, name_override, permanent].into_iter().flatten() {
+                match validate_name(name) {
+                    Ok(valid) => *name = valid,
+                    Err(e) => problems.push(format!("User {user_id} in guild {guild_id}: {e}")),
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Loads a file written by `export` into the database. Nothing is written unless every id and
+/// name in it is valid.
+pub fn run(store: &dyn NameStore, path: &Path, mode: ImportMode) {
+    let json =
+        std::fs::read_to_string(path).unwrap_or_else(|e| panic!("Failed to read {path:?}: {e}"));
+    let mut export: Export = serde_json::from_str(&json)
+        .unwrap_or_else(|e| panic!("{path:?} is not a valid export: {e}"));
+    let problems = validate(&mut export);
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("{problem}");
+        }
+        eprintln!("Not importing {path:?}: {} problems", problems.len());
+        std::process::exit(1);
+    }
+    load(store, export, mode);
+    store.persist();
+}
+
+/// Writes a validated export into the store, all at once where the backend can.
+pub fn load(store: &(impl NameStore + ?Sized), export: Export, mode: ImportMode) {
+    store.begin();
+    for (guild_id, mut guild) in export.guilds {
+        let guild_id = GuildId::new(guild_id);
+        if let ImportMode::Replace = mode {
+            store.clear_settings(guild_id);
+            for (names, _) in NAME_FIELDS {
+                store.clear_names(names, guild_id);
+            }
+        }
+        for (key, value) in &guild.settings {
+            store.set_setting(guild_id, key, value);
+        }
+        for (names, field) in NAME_FIELDS {
+            for (user_id, member) in &mut guild.members {
+                let no_nickname = names == Names::Original && member.no_nickname;
+                if let Some(name) = field(member) {
+                    let mut name = StoredName::new(name, NameSource::Import);
+                    name.no_nickname = no_nickname;
+                    store.set_name(names, guild_id, UserId::new(*user_id), &name);
+                }
+            }
+        }
+        info!(
+            "Imported {} members and {} settings for guild {guild_id}",
+            guild.members.len(),
+            guild.settings.len()
+        );
+    }
+    store.commit();
+}
@@ -0,0 +1,87 @@
+use chrono::{DateTime, Local};
+use serenity::model::prelude::{GuildId, UserId};
+use sled::Tree;
+
+use crate::{
+    db::{
+        consents_db_tree_name, opt_outs_db_tree_name, unrenamable_db_tree_name, DbKey, StoredName,
+    },
+    expiry, history,
+    nickname::is_unrenamable,
+    retry,
+    store::{NameStore, Names, Store},
+};
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Prints everything stored about a member, for debugging why they did or didn't get renamed.
+pub fn member(db: &Store, guild_id: GuildId, user_id: UserId) {
+    let key = DbKey::from(user_id);
+    let name = |names| {
+        db.get_name(names, guild_id, user_id)
+            .map_or_else(|| "-".to_string(), |stored| stored.name)
+    };
+    let contains = |tree: Tree| yes_no(tree.contains_key(key).unwrap()).to_string();
+    let mut fields = vec![
+        ("Original", name(Names::Original)),
+        ("Override", name(Names::Override)),
+        ("Permanent nickname", name(Names::Permanent)),
+        (
+            "Opted out",
+            contains(db.open_tree(opt_outs_db_tree_name(guild_id)).unwrap()),
+        ),
+        (
+            "Opted in",
+            contains(db.open_tree(consents_db_tree_name(guild_id)).unwrap()),
+        ),
+        (
+            "Unrenamable",
+            yes_no(is_unrenamable(
+                &db.open_tree(unrenamable_db_tree_name(guild_id)).unwrap(),
+                user_id,
+            ))
+            .to_string(),
+        ),
+    ];
+    let stored = db.get_name(Names::Original, guild_id, user_id);
+    if stored.as_ref().is_some_and(|stored| stored.no_nickname) {
+        fields.push(("Original nickname", "none".to_string()));
+    }
+    if let Some(StoredName {
+        captured_at: Some(captured_at),
+        source,
+        ..
+    }) = stored
+    {
+        let captured_at = DateTime::from_timestamp(captured_at as i64, 0).unwrap_or_default();
+        let source = source.map_or("unknown".to_string(), |source| format!("{source:?}"));
+        fields.push((
+            "Original captured",
+            format!(
+                "{} ({source})",
+                captured_at.with_timezone(&Local).to_rfc3339()
+            ),
+        ));
+    }
+    if let Some(since) = expiry::overridden_since(db, guild_id, user_id) {
+        let since = DateTime::from_timestamp(since as i64, 0).unwrap_or_default();
+        fields.push(("Overridden since", since.with_timezone(&Local).to_rfc3339()));
+    }
+    if let Some((nick, attempts)) = retry::pending(db, guild_id, user_id) {
+        fields.push(("Retrying", format!("{nick} ({attempts} failed attempts)")));
+    }
+    let recent = history::recent(db, guild_id, user_id).0;
+    if !recent.is_empty() {
+        fields.push(("Recent names", recent.join(", ")));
+    }
+    let width = fields.iter().map(|(label, _)| label.len()).max().unwrap();
+    for (label, value) in fields {
+        println!("{label:<width$}  {value}");
+    }
+}
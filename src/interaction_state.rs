@@ -0,0 +1,118 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{Db, Tree};
+use tracing::{info, warn};
+
+use crate::tasks;
+
+const TREE_NAME: &[u8] = b"interaction_state";
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn key(kind: &str, id: u64) -> Vec<u8> {
+    [kind.as_bytes(), b":", &id.to_be_bytes()].concat()
+}
+
+/// State for interactions that outlive a single event (pagination cursors, pending
+/// confirmations and so on). Entries are persisted so they survive restarts and expire after
+/// their TTL.
+pub struct InteractionStore {
+    db: Db,
+    tree: Tree,
+}
+
+impl InteractionStore {
+    pub fn new(db: &Db) -> Self {
+        Self {
+            db: db.clone(),
+            tree: db.open_tree(TREE_NAME).unwrap(),
+        }
+    }
+
+    /// Stores the value and returns the id to look it up with.
+    pub fn insert<T: Serialize>(&self, kind: &str, value: &T, ttl: Duration) -> u64 {
+        let id = self.db.generate_id().unwrap();
+        self.put(kind, id, value, ttl);
+        id
+    }
+
+    /// Stores the value under an id the caller already has (e.g. a message id).
+    pub fn put<T: Serialize>(&self, kind: &str, id: u64, value: &T, ttl: Duration) {
+        let expires_at = now() + ttl.as_secs();
+        let entry = [
+            &expires_at.to_be_bytes()[..],
+            &serde_json::to_vec(value).unwrap(),
+        ]
+        .concat();
+        self.tree.insert(key(kind, id), entry).unwrap();
+    }
+
+    /// How many entries are stored, including expired ones that haven't been swept yet.
+    pub fn entries(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, kind: &str, id: u64) -> Option<T> {
+        let entry = self.tree.get(key(kind, id)).unwrap()?;
+        let Some((expires_at, value)) = entry.split_first_chunk::<8>() else {
+            warn!("Dropping truncated {kind} state {id}");
+            self.remove(kind, id);
+            return None;
+        };
+        if u64::from_be_bytes(*expires_at) <= now() {
+            self.remove(kind, id);
+            return None;
+        }
+        serde_json::from_slice(value)
+            .inspect_err(|e| warn!("Dropping corrupt {kind} state {id}: {e}"))
+            .ok()
+    }
+
+    pub fn remove(&self, kind: &str, id: u64) {
+        self.tree.remove(key(kind, id)).unwrap();
+    }
+
+    /// Removes expired entries. Returns how many were removed.
+    pub fn sweep(&self) -> usize {
+        let now = now();
+        let expired: Vec<_> = self
+            .tree
+            .iter()
+            .filter_map(Result::ok)
+            .filter(|(_, entry)| {
+                entry
+                    .split_first_chunk::<8>()
+                    .is_none_or(|(expires_at, _)| u64::from_be_bytes(*expires_at) <= now)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        for key in &expired {
+            self.tree.remove(key).unwrap();
+        }
+        expired.len()
+    }
+
+    pub fn spawn_sweeper(self: &Arc<Self>) {
+        let store = self.clone();
+        tasks::spawn("interaction state sweeper", async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let removed = store.sweep();
+                if removed > 0 {
+                    info!("Removed {removed} expired interaction states");
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use serenity::{
+    http::Http,
+    model::prelude::{GuildId, UserId},
+};
+use tracing::warn;
+
+use crate::{export, store::NameStore};
+
+/// Looks up guild and user names, remembering them so each is only fetched once.
+struct Names {
+    http: Http,
+    guilds: HashMap<u64, Option<String>>,
+    users: HashMap<u64, Option<String>>,
+}
+
+impl Names {
+    async fn guild(&mut self, guild_id: u64) -> Option<String> {
+        if !self.guilds.contains_key(&guild_id) {
+            let name = match self.http.get_guild(GuildId::new(guild_id)).await {
+                Ok(guild) => Some(guild.name),
+                Err(e) => {
+                    warn!("Failed to look up guild {guild_id}: {e}");
+                    None
+                }
+            };
+            self.guilds.insert(guild_id, name);
+        }
+        self.guilds[&guild_id].clone()
+    }
+
+    async fn user(&mut self, user_id: u64) -> Option<String> {
+        if !self.users.contains_key(&user_id) {
+            let name = match self.http.get_user(UserId::new(user_id)).await {
+                Ok(user) => Some(user.global_name.unwrap_or(user.name)),
+                Err(e) => {
+                    warn!("Failed to look up user {user_id}: {e}");
+                    None
+                }
+            };
+            self.users.insert(user_id, name);
+        }
+        self.users[&user_id].clone()
+    }
+}
+
+/// `id (name)` if the name is known.
+fn label(id: u64, name: Option<String>) -> String {
+    match name {
+        Some(name) => format!("{id} ({name})"),
+        None => id.to_string(),
+    }
+}
+
+/// Prints the stored original names and current overrides as a table, with guild and user
+/// names next to their ids when there's a token to look them up with.
+pub async fn run(
+    store: &dyn NameStore,
+    token: Option<String>,
+    only_guild: Option<u64>,
+    overridden_only: bool,
+) {
+    let mut names = token.map(|token| Names {
+        http: Http::new(&token),
+        guilds: HashMap::new(),
+        users: HashMap::new(),
+    });
+    let mut rows = vec![[
+        "Guild".to_string(),
+        "User".to_string(),
+        "Original".to_string(),
+        "Override".to_string(),
+    ]];
+    for (guild_id, guild) in export::collect(store).guilds {
+        if only_guild.is_some_and(|only_guild| only_guild != guild_id) {
+            continue;
+        }
+        let guild_name = match &mut names {
+            Some(names) => names.guild(guild_id).await,
+            None => None,
+        };
+        for (user_id, member) in guild.members {
+            if member.original.is_none() && member.name_override.is_none()
+                || overridden_only && member.name_override.is_none()
+            {
+                continue;
+            }
+            let user_name = match &mut names {
+                Some(names) => names.user(user_id).await,
+                None => None,
+            };
+            rows.push([
+                label(guild_id, guild_name.clone()),
+                label(user_id, user_name),
+                member.original.unwrap_or_default(),
+                member.name_override.unwrap_or_default(),
+            ]);
+        }
+    }
+    let widths: Vec<usize> = (0..4)
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap()
+        })
+        .collect();
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
@@ -0,0 +1,43 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serenity::model::prelude::{ChannelId, GuildId};
+use tokio::sync::OwnedMutexGuard;
+
+type ChannelLock = Arc<tokio::sync::Mutex<()>>;
+
+/// Makes syncs of the same channel take turns, so one can't write overrides between another's
+/// old nickname and new nickname phases.
+#[derive(Default)]
+pub struct ChannelLocks {
+    locks: Mutex<HashMap<(GuildId, ChannelId), ChannelLock>>,
+}
+
+impl ChannelLocks {
+    /// Waits for any other sync of the channel to finish. The channel is held until the guard
+    /// is dropped.
+    pub async fn lock(&self, guild_id: GuildId, channel_id: ChannelId) -> OwnedMutexGuard<()> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry((guild_id, channel_id))
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// How many of the guild's channels are being synced right now.
+    pub fn held(&self, guild_id: GuildId) -> usize {
+        self.locks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((lock_guild_id, _), lock)| {
+                *lock_guild_id == guild_id && lock.try_lock().is_err()
+            })
+            .count()
+    }
+}
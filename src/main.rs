@@ -1,18 +1,101 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
 use clap::{Parser, Subcommand};
-use db::DbKey;
-use serenity::model::id::{GuildId, UserId};
-use simple_logger::SimpleLogger;
+use db::{drop_guild, forget_user, store_name, unset_name, NameSource};
+use sanitize::sanitize_name;
+use serenity::{
+    http::Http,
+    model::{
+        id::{GuildId, UserId},
+        Timestamp,
+    },
+};
+use store::{NameStore, Names, SledStore, Store};
+use tracing::info;
+use tracing_subscriber::{filter::LevelFilter, filter::Targets, fmt, prelude::*};
 
+mod active_hours;
+mod announce;
+mod api;
+mod audit;
+mod backup;
+mod breaker;
+mod bulk;
+mod canary;
+mod commands;
+mod control;
+mod dashboard;
 mod db;
+mod ddragon;
+mod debounce;
+mod departure;
+mod doctor;
+mod encryption;
+mod event;
+mod expiry;
+mod export;
+mod grace;
+mod guild_names;
+mod guild_view;
+mod history;
+mod import;
+mod inspect;
+mod interaction_state;
+mod list;
+mod locks;
+mod metrics;
+mod migrate;
+mod name_source;
 mod namechanger;
 mod namerestorer;
+mod nickname;
+mod notify;
+mod owner;
+mod pagination;
+mod presence;
+mod profanity;
+#[cfg(feature = "redis")]
+mod redis_store;
+mod reporting;
+mod retry;
+mod riot;
+mod rollout;
+mod rules;
+mod sanitize;
+mod scheduler;
+mod schema;
+mod session;
+mod settings;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod store;
+mod tasks;
+mod template;
+mod tui;
+mod voice;
+mod web;
 
 #[derive(Subcommand)]
 enum Commands {
     Restore {
         #[arg(short, long)]
         overridden_only: bool,
+        /// Put back the names members had chosen themselves at this time (RFC 3339, e.g.
+        /// 2024-05-01T20:00Z) according to the audit history.
+        #[arg(long, value_parser = audit::parse_time, conflicts_with = "overridden_only")]
+        as_of: Option<Timestamp>,
+        /// Print who would be renamed to what, without renaming anyone or changing the
+        /// database.
+        #[arg(long)]
+        dry_run: bool,
     },
+    /// Prints the running bot's internal state.
+    Status,
     Set {
         #[arg(short)]
         guild_id: u64,
@@ -21,32 +104,401 @@ enum Commands {
         #[arg(short)]
         name: String,
     },
+    /// Deletes a member's stored name and override.
+    Unset {
+        #[arg(short)]
+        guild_id: u64,
+        #[arg(short)]
+        user_id: u64,
+        /// Only delete the override, keeping the stored name.
+        #[arg(long)]
+        override_only: bool,
+        /// Don't ask for confirmation.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Deletes everything stored about a server, for after the bot has left it.
+    PurgeGuild {
+        #[arg(short)]
+        guild_id: u64,
+        /// Put back members' original names first. Needs the bot to still be in the server.
+        #[arg(long)]
+        restore: bool,
+        /// Don't ask for confirmation.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Deletes everything stored about a user in every server, like their /forgetme.
+    ForgetUser {
+        #[arg(short)]
+        user_id: u64,
+        /// Put back their original names first. Needs the bot to still be in the servers.
+        #[arg(long)]
+        restore: bool,
+        /// Don't ask for confirmation.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Writes the whole database to a single file, through the running bot if there is one.
+    Backup { file: PathBuf },
+    /// Loads a file written by `backup` into an empty database.
+    RestoreBackup { file: PathBuf },
+    /// Checks the token, intents, permissions in every server and the database, and says how to
+    /// fix anything that's wrong.
+    Doctor,
+    /// Encrypts every stored name and name record again with the current `--name-key`, or
+    /// decrypts them all without one, so older keys can be dropped.
+    RotateNameKey,
+    /// Sets many members' names at once from a CSV of `user_id,name` rows.
+    SetBulk {
+        #[arg(long)]
+        guild_id: u64,
+        #[arg(long)]
+        file: PathBuf,
+    },
+    Stats {
+        #[command(subcommand)]
+        command: StatsCommands,
+    },
+    /// Manages nicknames particular members always get instead of a shuffled one.
+    Override {
+        #[command(subcommand)]
+        command: OverrideCommands,
+    },
+    /// Prints the stored original names and current overrides, with server and member names
+    /// when a token is available.
+    List {
+        #[arg(long)]
+        guild: Option<u64>,
+        /// Only list members who are renamed right now.
+        #[arg(long)]
+        overridden_only: bool,
+    },
+    /// Prints everything stored about a member: their names, whether they opted out or can't
+    /// be renamed, and any edit waiting to be retried.
+    Get {
+        #[arg(short)]
+        guild_id: u64,
+        #[arg(short)]
+        user_id: u64,
+    },
+    /// Writes every server's stored names, overrides and settings to a JSON file.
+    Export { file: PathBuf },
+    /// Loads a file written by `export`, checking every id and name first.
+    Import {
+        file: PathBuf,
+        /// Keep what's stored, overwriting members and settings the file also has.
+        #[arg(long, conflicts_with = "replace", required_unless_present = "replace")]
+        merge: bool,
+        /// Throw away the stored names and settings of every server in the file first.
+        #[arg(long)]
+        replace: bool,
+    },
+    /// Copies all of the bot's data from one sled database to another. Other backends only
+    /// hold names and settings, and anything else that's left behind is listed.
+    Migrate {
+        #[arg(long, value_enum)]
+        from: migrate::Backend,
+        #[arg(long)]
+        from_path: PathBuf,
+        #[arg(long, value_enum)]
+        to: migrate::Backend,
+        #[arg(long)]
+        to_path: PathBuf,
+    },
 }
 
+#[derive(Subcommand)]
+enum StatsCommands {
+    /// Seeds a member's play history from a Riot match history (match-v5) JSON export.
+    Import {
+        #[arg(short)]
+        guild_id: u64,
+        #[arg(short)]
+        user_id: u64,
+        /// The PUUID of the member's Riot account.
+        #[arg(long)]
+        puuid: String,
+        file: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum OverrideCommands {
+    Add {
+        #[arg(short)]
+        guild_id: u64,
+        #[arg(short)]
+        user_id: u64,
+        #[arg(short)]
+        name: String,
+    },
+    Remove {
+        #[arg(short)]
+        guild_id: u64,
+        #[arg(short)]
+        user_id: u64,
+    },
+    List {
+        #[arg(short)]
+        guild_id: u64,
+    },
+}
+
+/// Randomly assigns League of Legends champion names to people in voice channels.
 #[derive(Parser)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    #[command(flatten)]
+    bot: namechanger::BotOptions,
+    /// Path to the sled database.
+    #[arg(long, global = true, default_value = "names.sled.db")]
+    db: PathBuf,
+    /// Where names, overrides, permanent nicknames and settings are kept. Everything else stays
+    /// in the sled database at `--db`.
+    #[arg(long, global = true, value_enum, default_value_t = migrate::Backend::Sled)]
+    backend: migrate::Backend,
+    /// The file or URL of the backend, for every backend but sled, which keeps them in `--db`.
+    #[arg(long, global = true)]
+    store: Option<PathBuf>,
+    /// Log level for everything except the bot itself.
+    #[arg(long, global = true, default_value_t = LevelFilter::WARN)]
+    log_level: LevelFilter,
+    /// Log level for the bot's own modules.
+    #[arg(long, global = true, default_value_t = LevelFilter::DEBUG)]
+    log_module_level: LevelFilter,
+    /// Read the bot token from this file instead of the environment or token.txt.
+    #[arg(long, global = true)]
+    token_file: Option<PathBuf>,
+    /// The socket the running bot listens on for `status`, `restore`, `set` and `unset`. Without
+    /// a bot listening, the others open the database themselves. Defaults to `--db` with `.sock`
+    /// appended.
+    #[arg(long, global = true)]
+    control_socket: Option<PathBuf>,
+    /// Report panics, errors and warnings that keep coming up to this Sentry DSN. Needs the
+    /// sentry feature.
+    #[arg(long, global = true, env = "SENTRY_DSN")]
+    sentry_dsn: Option<String>,
+    /// Encrypt stored names with this key: 32 random bytes in base64.
+    #[arg(
+        long,
+        global = true,
+        env = "NAMECHANGER_NAME_KEY",
+        hide_env_values = true
+    )]
+    name_key: Option<String>,
+    /// Earlier `--name-key`s, comma separated, that stored names may still be encrypted with.
+    #[arg(
+        long,
+        global = true,
+        env = "NAMECHANGER_OLD_NAME_KEYS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    old_name_keys: Vec<String>,
+    /// Serve runtime diagnostics to tokio-console.
+    #[arg(long, global = true)]
+    tokio_console: bool,
+}
+
+const TOKEN_ENV_VAR: &str = "DISCORD_TOKEN";
+const TOKEN_SECRET_PATH: &str = "/run/secrets/discord_token";
+const DEFAULT_TOKEN_PATH: &str = "token.txt";
+
+/// Finds the bot token, preferring `--token-file`, then `DISCORD_TOKEN`, then a Docker secret,
+/// then `token.txt`.
+fn read_token(token_file: Option<&Path>) -> String {
+    find_token(token_file).unwrap_or_else(|e| panic!("{e}"))
+}
+
+fn find_token(token_file: Option<&Path>) -> Result<String, String> {
+    let (source, token) = if let Some(path) = token_file {
+        (
+            format!("{path:?}"),
+            std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read token file {path:?}: {e}"))?,
+        )
+    } else if let Ok(token) = std::env::var(TOKEN_ENV_VAR) {
+        (TOKEN_ENV_VAR.to_string(), token)
+    } else if let Ok(token) = std::fs::read_to_string(TOKEN_SECRET_PATH) {
+        (TOKEN_SECRET_PATH.to_string(), token)
+    } else {
+        (
+            DEFAULT_TOKEN_PATH.to_string(),
+            std::fs::read_to_string(DEFAULT_TOKEN_PATH).map_err(|e| {
+                format!("No token found in --token-file, {TOKEN_ENV_VAR}, {TOKEN_SECRET_PATH} or {DEFAULT_TOKEN_PATH}: {e}")
+            })?,
+        )
+    };
+    let token = token.trim();
+    if token.is_empty() || token.contains(char::is_whitespace) {
+        return Err(format!("The token from {source} is malformed"));
+    }
+    info!("Using token from {source}");
+    Ok(token.to_string())
+}
+
+/// Opens the database, upgrading it to the current schema first.
+fn open_db(path: &Path) -> sled::Db {
+    let db = open_db_as_is(path);
+    if let Err(e) = schema::upgrade(&db) {
+        panic!("{e}");
+    }
+    db
+}
+
+/// Opens the database along with where its names and settings are kept.
+fn open_store(path: &Path, backend: migrate::Backend, store: Option<&Path>) -> Store {
+    let db = open_db(path);
+    match store {
+        Some(store) => Store::new(db, Arc::from(migrate::open_store(backend, store))),
+        None => Store::local(db),
+    }
+}
+
+/// Opens only where names and settings are kept, upgrading a sled database to the current
+/// schema.
+fn open_backend(
+    path: &Path,
+    backend: migrate::Backend,
+    store: Option<&Path>,
+) -> Box<dyn NameStore> {
+    match store {
+        Some(store) => migrate::open_store(backend, store),
+        None => Box::new(SledStore(open_db(path))),
+    }
+}
+
+fn open_db_as_is(path: &Path) -> sled::Db {
+    sled::open(path).unwrap_or_else(|e| panic!("Failed to open database {path:?}: {e}"))
+}
+
+/// Asks a yes or no question on the terminal. Anything but yes is no.
+fn confirm(question: &str) -> bool {
+    print!("{question} [y/N] ");
+    std::io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).unwrap();
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Prints what the running bot answered, exiting with an error if it failed.
+fn print_reply(reply: control::Reply) {
+    match reply {
+        Ok(serde_json::Value::Null) => {}
+        Ok(value) => println!("{}", serde_json::to_string_pretty(&value).unwrap()),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Waits for Ctrl+C or SIGTERM.
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Waits for Ctrl+C.
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c().await.unwrap();
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
-    let token = std::fs::read_to_string("token.txt").unwrap();
-    SimpleLogger::default()
-        .with_level(log::LevelFilter::Warn)
-        .with_module_level("discordnamechanger", log::LevelFilter::Debug)
-        .init()
-        .unwrap();
-    let db = sled::open("names.sled.db").unwrap();
+    let _reporting = reporting::init(cli.sentry_dsn.as_deref());
+    // The terminal UI needs the screen to itself.
+    let quiet = cli.command.is_none() && cli.bot.tui;
+    let targets = if quiet {
+        Targets::new()
+    } else {
+        Targets::new()
+            .with_default(cli.log_level)
+            .with_target("discordnamechanger", cli.log_module_level)
+    };
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_filter(targets))
+        .with(tasks::console_layer(cli.tokio_console))
+        .with(reporting::layer(cli.sentry_dsn.is_some()))
+        .init();
+    if let Err(e) = encryption::configure(cli.name_key.as_deref(), &cli.old_name_keys) {
+        panic!("{e}");
+    }
+    if (cli.backend == migrate::Backend::Sled) != cli.store.is_none() {
+        eprintln!("--store is needed with any --backend but sled, and only then");
+        std::process::exit(2);
+    }
+    let open_store = || open_store(&cli.db, cli.backend, cli.store.as_deref());
+    let open_backend = || open_backend(&cli.db, cli.backend, cli.store.as_deref());
+    let control_socket = cli
+        .control_socket
+        .clone()
+        .unwrap_or_else(|| control::default_path(&cli.db));
 
     match cli.command {
         Some(command) => match command {
-            Commands::Restore { overridden_only } => {
-                if overridden_only {
-                    namerestorer::restore_overridden(token, db).await
+            Commands::Restore {
+                overridden_only,
+                as_of,
+                dry_run,
+            } => {
+                let request = control::Request::Restore {
+                    overridden_only,
+                    as_of,
+                    dry_run,
+                };
+                let restored = if let Some(reply) = control::send(&control_socket, &request).await {
+                    match reply {
+                        Ok(restored) if dry_run => serde_json::from_value(restored).unwrap(),
+                        reply => {
+                            print_reply(reply);
+                            return;
+                        }
+                    }
+                } else if let Some(as_of) = as_of {
+                    namerestorer::restore_as_of(
+                        read_token(cli.token_file.as_deref()),
+                        open_store(),
+                        as_of,
+                        dry_run,
+                    )
+                    .await
+                } else if overridden_only {
+                    namerestorer::restore_overridden(
+                        read_token(cli.token_file.as_deref()),
+                        open_store(),
+                        dry_run,
+                    )
+                    .await
                 } else {
-                    namerestorer::run(token, db).await
+                    namerestorer::run(read_token(cli.token_file.as_deref()), open_store(), dry_run)
+                        .await
+                };
+                if dry_run {
+                    for namerestorer::Restored {
+                        guild_id,
+                        user_id,
+                        name,
+                    } in &restored
+                    {
+                        println!("{guild_id}\t{user_id}\t{name}");
+                    }
+                    println!("Would restore {} names", restored.len());
+                }
+            }
+            Commands::Status => {
+                match control::send(&control_socket, &control::Request::Status).await {
+                    Some(reply) => print_reply(reply),
+                    None => panic!("No bot is listening on {:?}", control_socket),
                 }
             }
             Commands::Set {
@@ -54,12 +506,233 @@ async fn main() {
                 user_id,
                 name,
             } => {
-                db.open_tree(DbKey::from(GuildId::new(guild_id)))
-                    .unwrap()
-                    .insert(DbKey::from(UserId::new(user_id)), name.as_str())
-                    .unwrap();
+                let request = control::Request::Set {
+                    guild_id,
+                    user_id,
+                    name: name.clone(),
+                };
+                if let Some(reply) = control::send(&control_socket, &request).await {
+                    print_reply(reply);
+                    return;
+                }
+                store_name(
+                    &open_store(),
+                    GuildId::new(guild_id),
+                    UserId::new(user_id),
+                    &sanitize_name(&name),
+                    NameSource::Cli,
+                );
+            }
+            Commands::Unset {
+                guild_id,
+                user_id,
+                override_only,
+                yes,
+            } => {
+                let what = if override_only {
+                    "override"
+                } else {
+                    "stored name and override"
+                };
+                if !yes
+                    && !confirm(&format!(
+                        "Delete the {what} of {user_id} in guild {guild_id}?"
+                    ))
+                {
+                    return;
+                }
+                let request = control::Request::Unset {
+                    guild_id,
+                    user_id,
+                    override_only,
+                };
+                if let Some(reply) = control::send(&control_socket, &request).await {
+                    print_reply(reply);
+                } else if !unset_name(
+                    &open_store(),
+                    GuildId::new(guild_id),
+                    UserId::new(user_id),
+                    override_only,
+                ) {
+                    eprintln!("Nothing is stored for {user_id} in guild {guild_id}");
+                    std::process::exit(1);
+                }
+            }
+            Commands::PurgeGuild {
+                guild_id,
+                restore,
+                yes,
+            } => {
+                if !yes && !confirm(&format!("Delete everything stored about guild {guild_id}?")) {
+                    return;
+                }
+                let request = control::Request::PurgeGuild { guild_id, restore };
+                if let Some(reply) = control::send(&control_socket, &request).await {
+                    print_reply(reply);
+                    return;
+                }
+                let db = open_store();
+                let guild_id = GuildId::new(guild_id);
+                if restore {
+                    let http = Http::new(&read_token(cli.token_file.as_deref()));
+                    namerestorer::restore_guild(&db, &http, guild_id).await;
+                }
+                let dropped = drop_guild(&db, guild_id);
+                println!("Dropped {dropped} trees of guild {guild_id}");
+                db.persist();
+            }
+            Commands::ForgetUser {
+                user_id,
+                restore,
+                yes,
+            } => {
+                if !yes && !confirm(&format!("Delete everything stored about user {user_id}?")) {
+                    return;
+                }
+                let request = control::Request::ForgetUser { user_id, restore };
+                if let Some(reply) = control::send(&control_socket, &request).await {
+                    print_reply(reply);
+                    return;
+                }
+                let db = open_store();
+                let user_id = UserId::new(user_id);
+                if restore {
+                    let http = Http::new(&read_token(cli.token_file.as_deref()));
+                    namerestorer::restore_user(&db, &http, user_id).await;
+                }
+                let deleted = forget_user(&db, user_id);
+                println!("Deleted {deleted} entries about user {user_id}");
+                db.persist();
+            }
+            Commands::Backup { file } => {
+                // The bot may have been started from another directory.
+                let file = std::path::absolute(&file)
+                    .unwrap_or_else(|e| panic!("Failed to resolve {file:?}: {e}"));
+                let request = control::Request::Backup { path: file.clone() };
+                if let Some(reply) = control::send(&control_socket, &request).await {
+                    print_reply(reply);
+                    return;
+                }
+                let entries = backup::write(&open_db(&cli.db), &file)
+                    .unwrap_or_else(|e| panic!("Failed to write {file:?}: {e}"));
+                println!("Backed up {entries} entries to {file:?}");
+            }
+            // The backup brings its own schema version.
+            Commands::RestoreBackup { file } => {
+                match backup::restore(&open_db_as_is(&cli.db), &file) {
+                    Ok(entries) => println!("Restored {entries} entries from {file:?}"),
+                    Err(e) => {
+                        eprintln!("{e}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Commands::Doctor => {
+                if doctor::run(find_token(cli.token_file.as_deref()), &cli.db).await {
+                    std::process::exit(1);
+                }
+            }
+            Commands::RotateNameKey => match encryption::rewrite_names(&open_db(&cli.db)) {
+                Ok(rewritten) => println!("Rewrote {rewritten} entries"),
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            },
+            Commands::SetBulk { guild_id, file } => {
+                bulk::set_bulk(&open_store(), GuildId::new(guild_id), &file)
+            }
+            Commands::Stats {
+                command:
+                    StatsCommands::Import {
+                        guild_id,
+                        user_id,
+                        puuid,
+                        file,
+                    },
+            } => stats::import(
+                &open_db(&cli.db),
+                &ddragon::ChampionIndex::load(&cli.bot.ddragon_cache).await,
+                GuildId::new(guild_id),
+                UserId::new(user_id),
+                &puuid,
+                &file,
+            ),
+            Commands::Override { command } => {
+                let db = open_store();
+                match command {
+                    OverrideCommands::Add {
+                        guild_id,
+                        user_id,
+                        name,
+                    } => {
+                        if let Err(e) = commands::set_permanent_nick(
+                            &db,
+                            GuildId::new(guild_id),
+                            UserId::new(user_id),
+                            &name,
+                        ) {
+                            eprintln!("{e}");
+                            std::process::exit(1);
+                        }
+                    }
+                    OverrideCommands::Remove { guild_id, user_id } => {
+                        commands::clear_permanent_nick(
+                            &db,
+                            GuildId::new(guild_id),
+                            UserId::new(user_id),
+                        );
+                    }
+                    OverrideCommands::List { guild_id } => {
+                        for (user_id, nick) in db.names(Names::Permanent, GuildId::new(guild_id)) {
+                            println!("{user_id}\t{}", nick.name);
+                        }
+                    }
+                }
+            }
+            Commands::List {
+                guild,
+                overridden_only,
+            } => {
+                let token = find_token(cli.token_file.as_deref())
+                    .inspect_err(|e| info!("Not looking up names: {e}"))
+                    .ok();
+                list::run(&*open_backend(), token, guild, overridden_only).await
+            }
+            Commands::Get { guild_id, user_id } => {
+                inspect::member(&open_store(), GuildId::new(guild_id), UserId::new(user_id))
+            }
+            Commands::Export { file } => export::run(&*open_backend(), &file),
+            Commands::Import { file, replace, .. } => import::run(
+                &*open_backend(),
+                &file,
+                if replace {
+                    import::ImportMode::Replace
+                } else {
+                    import::ImportMode::Merge
+                },
+            ),
+            Commands::Migrate {
+                from,
+                from_path,
+                to,
+                to_path,
+            } => {
+                if let Err(e) = migrate::run(from, from_path, to, to_path) {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
             }
         },
-        None => namechanger::run(token, db).await,
+        None => {
+            namechanger::run(
+                read_token(cli.token_file.as_deref()),
+                open_store(),
+                cli.bot,
+                control_socket,
+                shutdown_signal(),
+            )
+            .await
+        }
     }
 }
@@ -0,0 +1,27 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A process-wide count of how often something has happened.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// Returns the new count.
+    pub fn increment(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// League activities that didn't say which champion was being played.
+pub static MISSING_ACTIVITY_ASSETS: Counter = Counter::new();
+
+/// Times a guild's circuit breaker paused renames after repeated errors.
+pub static CIRCUIT_BREAKER_TRIPS: Counter = Counter::new();
+
+/// Nicknames the server's moderation refused to apply.
+pub static REJECTED_NICKNAMES: Counter = Counter::new();
+
+/// Times a stored tree lost more of its records at once than expected.
+pub static SHRINK_ALERTS: Counter = Counter::new();
@@ -0,0 +1,169 @@
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use tracing::{info, warn};
+
+use crate::{
+    db::parse_guild_db_tree_name,
+    export::{self, NAME_FIELDS},
+    store::{is_store_tree, NameStore, SledStore},
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Sled,
+    /// A Redis server, given as a `redis://` URL instead of a path. It only holds names and
+    /// settings.
+    #[cfg(feature = "redis")]
+    Redis,
+    /// A single SQLite file. It only holds names and settings.
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+/// Opens where the backend keeps names and settings, as is.
+pub fn open_store(backend: Backend, path: &Path) -> Box<dyn NameStore> {
+    match backend {
+        Backend::Sled => Box::new(SledStore(sled::open(path).unwrap())),
+        #[cfg(feature = "redis")]
+        Backend::Redis => Box::new(
+            crate::redis_store::RedisStore::open(&path.to_string_lossy())
+                .unwrap_or_else(|e| panic!("Failed to connect to {path:?}: {e}")),
+        ),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Box::new(
+            crate::sqlite::SqliteStore::open(path)
+                .unwrap_or_else(|e| panic!("Failed to open database {path:?}: {e}")),
+        ),
+    }
+}
+
+/// Opens the database being migrated from, which has to exist already. SQLite files are opened
+/// read-only. sled can't open a database read-only, so a sled source is only read from.
+fn open_source(backend: Backend, path: &Path) -> Result<Box<dyn NameStore>, String> {
+    #[cfg(feature = "redis")]
+    if backend == Backend::Redis {
+        return Ok(open_store(backend, path));
+    }
+    if !path.exists() {
+        return Err(format!("There is no database at {path:?} to migrate from"));
+    }
+    match backend {
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Ok(Box::new(
+            crate::sqlite::SqliteStore::open_read_only(path)
+                .map_err(|e| format!("Failed to open database {path:?}: {e}"))?,
+        )),
+        _ => Ok(open_store(backend, path)),
+    }
+}
+
+/// Copies every tree (names, overrides, settings and everything else) from one database to
+/// another and verifies the copy. Only names and settings are copied to or from other backends,
+/// and whatever else a sled source holds is listed as left behind. The source is only ever read
+/// from.
+pub fn run(from: Backend, from_path: PathBuf, to: Backend, to_path: PathBuf) -> Result<(), String> {
+    if from != Backend::Sled || to != Backend::Sled {
+        return copy_names(from, &from_path, to, &to_path);
+    }
+    if !from_path.exists() {
+        return Err(format!(
+            "There is no database at {from_path:?} to migrate from"
+        ));
+    }
+    let source = sled::open(&from_path).unwrap();
+    let destination = sled::open(&to_path).unwrap();
+    let destination_is_empty = destination
+        .tree_names()
+        .into_iter()
+        .all(|name| destination.open_tree(name).unwrap().is_empty());
+    if !destination_is_empty {
+        return Err(format!(
+            "Refusing to migrate into {to_path:?} because it already contains data"
+        ));
+    }
+    for name in source.tree_names() {
+        let source_tree = source.open_tree(&name).unwrap();
+        let destination_tree = destination.open_tree(&name).unwrap();
+        for result in source_tree.iter() {
+            let (key, value) = result.unwrap();
+            destination_tree.insert(key, value).unwrap();
+        }
+        let (source_checksum, destination_checksum) = (
+            source_tree.checksum().unwrap(),
+            destination_tree.checksum().unwrap(),
+        );
+        if source_checksum == destination_checksum {
+            info!("Copied {} entries in {name:?}", source_tree.len());
+        } else {
+            return Err(format!(
+                "Verification failed for {name:?}: {source_checksum} != {destination_checksum}"
+            ));
+        }
+    }
+    destination.flush().unwrap();
+    info!("Migrated {from_path:?} ({from:?}) to {to_path:?} ({to:?})");
+    Ok(())
+}
+
+/// Copies names and settings through the stores, checking the destination ends up with the
+/// same ones.
+fn copy_names(from: Backend, from_path: &Path, to: Backend, to_path: &Path) -> Result<(), String> {
+    let left_behind = match from {
+        Backend::Sled if from_path.exists() => left_behind(from_path),
+        _ => Vec::new(),
+    };
+    let source = open_source(from, from_path)?;
+    let destination = open_store(to, to_path);
+    if !destination.guilds().is_empty() {
+        return Err(format!(
+            "Refusing to migrate into {to_path:?} because it already contains data"
+        ));
+    }
+    // Names are copied record and all, which an export would leave out.
+    let guilds = source.guilds();
+    destination.begin();
+    for &guild_id in &guilds {
+        for (names, _) in NAME_FIELDS {
+            for (user_id, name) in source.names(names, guild_id) {
+                destination.set_name(names, guild_id, user_id, &name);
+            }
+        }
+        for (key, value) in source.settings(guild_id) {
+            destination.set_setting(guild_id, &key, &value);
+        }
+    }
+    destination.commit();
+    destination.persist();
+    let (source_json, destination_json) = (
+        serde_json::to_string(&export::collect(&*source)).unwrap(),
+        serde_json::to_string(&export::collect(&*destination)).unwrap(),
+    );
+    if source_json != destination_json {
+        return Err(format!(
+            "Verification failed: {to_path:?} doesn't hold the same names and settings"
+        ));
+    }
+    info!("Migrated names and settings of {} guilds from {from_path:?} ({from:?}) to {to_path:?} ({to:?})", guilds.len());
+    if !left_behind.is_empty() {
+        warn!(
+            "{to:?} only holds names and settings, so {} trees were not migrated and are still only in {from_path:?}: {}",
+            left_behind.len(),
+            left_behind.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// The non-empty trees of the sled database that no store holds, described for the logs.
+fn left_behind(path: &Path) -> Vec<String> {
+    let db = sled::open(path).unwrap();
+    db.tree_names()
+        .into_iter()
+        .filter(|name| !is_store_tree(name) && !db.open_tree(name).unwrap().is_empty())
+        .map(|name| match parse_guild_db_tree_name(&name) {
+            Some((prefix, guild_id)) => format!("'{}' of guild {guild_id}", prefix as char),
+            None => String::from_utf8_lossy(&name).into_owned(),
+        })
+        .collect()
+}
@@ -0,0 +1,462 @@
+use std::{fmt::Display, str::FromStr, sync::Arc};
+
+use clap::ValueEnum;
+use itertools::Itertools;
+use serenity::model::{
+    gateway::Activity,
+    prelude::{ActivityType, GuildId, Presence, UserId},
+};
+use sled::Db;
+use tracing::debug;
+
+use crate::{
+    db::{
+        get_setting, ChampionMode, NameList, SpotifyMode, StreamingMode, BANNED_NAMES_SETTING,
+        CHAMPION_MODE_SETTING, LOCALE_SETTING, SOURCE_PRIORITIES_SETTING, SPOTIFY_MODE_SETTING,
+        STREAMING_MODE_SETTING,
+    },
+    ddragon::{ChampionIndex, CANONICAL_LOCALE},
+    riot::{LiveGames, SpectatorChampion},
+    rules::GuildRules,
+    store::Store,
+};
+
+pub const LEAGUE_OF_LEGENDS_APPLICATION_ID: u64 = 401518684763586560;
+const VALORANT_APPLICATION_ID: u64 = 700136079562375258;
+const TFT_NAME: &str = "Teamfight Tactics";
+
+/// TFT lobbies show up either as their own game or as a League activity describing the mode.
+fn is_tft(activity: &Activity) -> bool {
+    activity.name == TFT_NAME
+        || [&activity.details, &activity.state]
+            .into_iter()
+            .flatten()
+            .any(|text| text.contains(TFT_NAME))
+}
+
+/// Something a member's name can be taken from while they're in a voice channel.
+pub trait NameSource: Send + Sync {
+    fn name_from_presence(&self, _presence: &Presence) -> Option<String> {
+        None
+    }
+
+    /// Sources that don't need the member's presence override this instead.
+    fn name(
+        &self,
+        _guild_id: GuildId,
+        _user_id: UserId,
+        presence: Option<&Presence>,
+    ) -> Option<String> {
+        self.name_from_presence(presence?)
+    }
+}
+
+/// The games the bot pays attention to.
+pub struct TrackedGames {
+    application_ids: Vec<u64>,
+    activity_names: Vec<String>,
+}
+
+impl TrackedGames {
+    pub fn new(application_ids: Vec<u64>, activity_names: Vec<String>) -> Self {
+        Self {
+            application_ids,
+            activity_names: activity_names
+                .into_iter()
+                .map(|name| name.to_lowercase())
+                .collect(),
+        }
+    }
+
+    fn tracks(&self, activity: &Activity) -> bool {
+        activity
+            .application_id
+            .is_some_and(|id| self.application_ids.contains(&id.get()))
+            || self
+                .activity_names
+                .iter()
+                .any(|pattern| activity.name.to_lowercase().contains(pattern))
+    }
+
+    /// Finds the tracked game the member is playing, if any. If they seem to be playing more
+    /// than one, the one they started most recently wins, and after that the first reported.
+    pub fn activity<'a, I: IntoIterator<Item = &'a Activity>>(
+        &self,
+        activities: I,
+    ) -> Option<&'a Activity> {
+        let started = |activity: &Activity| activity.timestamps.as_ref().and_then(|t| t.start);
+        activities
+            .into_iter()
+            .inspect(|activity| debug!("Checking activity {activity:?}"))
+            .filter(|activity| activity.kind == ActivityType::Playing && self.tracks(activity))
+            .reduce(|best, activity| {
+                if started(activity) > started(best) {
+                    activity
+                } else {
+                    best
+                }
+            })
+    }
+}
+
+pub fn current_champion_from_activity<'a>(
+    activity: &'a Activity,
+    champions: &'a ChampionIndex,
+) -> Option<&'a str> {
+    current_champion_and_skin_from_activity(activity, champions).map(|(champion, _)| champion)
+}
+
+/// The champion someone is playing, and the skin if their client reports one.
+fn current_champion_and_skin_from_activity<'a>(
+    activity: &'a Activity,
+    champions: &'a ChampionIndex,
+) -> Option<(&'a str, Option<&'a str>)> {
+    if is_tft(activity) {
+        return None;
+    }
+    activity
+        .assets
+        .as_ref()?
+        .large_text
+        .as_deref()
+        .filter(|large_text| !large_text.trim().is_empty())
+        .and_then(|large_text| {
+            // Clients in other languages report the localized champion name, and sometimes the
+            // skin along with it.
+            match champions.split_skin(large_text) {
+                Some(champion_and_skin) => Some(champion_and_skin),
+                // Without Data Dragon there's nothing to check against.
+                None if champions.is_empty() => Some((large_text, None)),
+                None => {
+                    debug!("Ignoring {large_text} because it isn't a champion");
+                    None
+                }
+            }
+        })
+}
+
+/// The champion someone is playing in a tracked game, or the champion's title or skin if the
+/// guild asked for those.
+pub struct LeagueChampion {
+    games: Arc<TrackedGames>,
+    champions: Arc<ChampionIndex>,
+    db: Store,
+}
+
+impl NameSource for LeagueChampion {
+    fn name_from_presence(&self, presence: &Presence) -> Option<String> {
+        let activity = self.games.activity(&presence.activities)?;
+        let (champion, skin) = current_champion_and_skin_from_activity(activity, &self.champions)?;
+        let guild_id = presence.guild_id;
+        let mode = guild_id
+            .and_then(|guild_id| get_setting(&self.db, guild_id, CHAMPION_MODE_SETTING))
+            .unwrap_or_default();
+        if let (ChampionMode::Skin, Some(skin)) = (mode, skin) {
+            return Some(skin.to_string());
+        }
+        let locale: String = guild_id
+            .and_then(|guild_id| get_setting(&self.db, guild_id, LOCALE_SETTING))
+            .unwrap_or_else(|| CANONICAL_LOCALE.to_string());
+        // Champions the index doesn't know keep their name.
+        let Some(localized) = self.champions.localized(champion, &locale) else {
+            return Some(champion.to_string());
+        };
+        Some(match mode {
+            ChampionMode::Title => localized.title.clone(),
+            // Default skins are named after the champion.
+            ChampionMode::Name | ChampionMode::Skin => localized.name.clone(),
+        })
+    }
+}
+
+/// The name of the tracked game someone is playing.
+pub struct GameName {
+    games: Arc<TrackedGames>,
+}
+
+impl NameSource for GameName {
+    fn name_from_presence(&self, presence: &Presence) -> Option<String> {
+        Some(self.games.activity(&presence.activities)?.name.clone())
+    }
+}
+
+/// The agent someone is playing in Valorant.
+pub struct ValorantAgent;
+
+impl NameSource for ValorantAgent {
+    fn name_from_presence(&self, presence: &Presence) -> Option<String> {
+        presence
+            .activities
+            .iter()
+            .filter(|activity| activity.kind == ActivityType::Playing)
+            .find(|activity| {
+                activity
+                    .application_id
+                    .is_some_and(|id| id.get() == VALORANT_APPLICATION_ID)
+            })?
+            .assets
+            .as_ref()?
+            // The large image is the map; the small one is the agent.
+            .small_text
+            .as_deref()
+            .map(str::trim)
+            .filter(|agent| !agent.is_empty())
+            .map(str::to_string)
+    }
+}
+
+/// The Little Legend someone is playing Teamfight Tactics with, or failing that their trait
+/// composition.
+pub struct TftLittleLegend;
+
+impl NameSource for TftLittleLegend {
+    fn name_from_presence(&self, presence: &Presence) -> Option<String> {
+        let activity = presence
+            .activities
+            .iter()
+            .filter(|activity| activity.kind == ActivityType::Playing)
+            .find(|activity| is_tft(activity))?;
+        let little_legend = activity
+            .assets
+            .as_ref()
+            .and_then(|assets| assets.small_text.as_deref());
+        let composition = activity.state.as_deref();
+        [little_legend, composition]
+            .into_iter()
+            .flatten()
+            .map(str::trim)
+            .find(|name| !name.is_empty() && !name.contains(TFT_NAME))
+            .map(str::to_string)
+    }
+}
+
+/// The song or artist someone is listening to on Spotify, for guilds that turned it on, as long
+/// as they aren't playing anything.
+pub struct SpotifyTrack {
+    db: Store,
+}
+
+impl NameSource for SpotifyTrack {
+    fn name_from_presence(&self, presence: &Presence) -> Option<String> {
+        let mode = get_setting(&self.db, presence.guild_id?, SPOTIFY_MODE_SETTING)
+            .unwrap_or(SpotifyMode::Off);
+        if mode == SpotifyMode::Off
+            || presence
+                .activities
+                .iter()
+                .any(|activity| activity.kind == ActivityType::Playing)
+        {
+            return None;
+        }
+        let activity = presence.activities.iter().find(|activity| {
+            activity.kind == ActivityType::Listening && activity.name == "Spotify"
+        })?;
+        match mode {
+            SpotifyMode::Song => activity.details.clone(),
+            // Multiple artists are separated by semicolons.
+            SpotifyMode::Artist => activity
+                .state
+                .as_deref()
+                .and_then(|artists| artists.split(';').next())
+                .map(|artist| artist.trim().to_string()),
+            SpotifyMode::Off => None,
+        }
+        .filter(|name| !name.is_empty())
+    }
+}
+
+/// The title or game of someone's stream, for guilds that turned it on.
+pub struct StreamTitle {
+    db: Store,
+}
+
+impl NameSource for StreamTitle {
+    fn name_from_presence(&self, presence: &Presence) -> Option<String> {
+        let mode = get_setting(&self.db, presence.guild_id?, STREAMING_MODE_SETTING)
+            .unwrap_or(StreamingMode::Off);
+        let activity = presence
+            .activities
+            .iter()
+            .find(|activity| activity.kind == ActivityType::Streaming)?;
+        match mode {
+            StreamingMode::Off => None,
+            StreamingMode::Title => activity.details.clone(),
+            // Twitch puts the game being streamed in the state.
+            StreamingMode::Game => activity.state.clone(),
+        }
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum NameSourceKind {
+    /// The guild's own activity rules.
+    Rules,
+    /// The champion a linked member is playing according to the Riot spectator API. Needs
+    /// `--riot-api-key`.
+    Spectator,
+    /// The champion someone is playing.
+    LeagueChampion,
+    /// The agent someone is playing in Valorant.
+    ValorantAgent,
+    /// The Little Legend (or trait composition) someone is playing Teamfight Tactics with.
+    TftLittleLegend,
+    /// The name of the game someone is playing.
+    GameName,
+    /// The title or game of someone's stream, if the guild turned it on.
+    Streaming,
+    /// The song or artist someone is listening to on Spotify, if the guild turned it on.
+    Spotify,
+}
+
+impl NameSourceKind {
+    pub fn name(self) -> String {
+        self.to_possible_value().unwrap().get_name().to_string()
+    }
+}
+
+/// The priority of sources a guild hasn't given one.
+pub const DEFAULT_SOURCE_PRIORITY: u32 = 100;
+
+/// A guild's source priorities, stored as a comma separated list of `source=priority`.
+#[derive(Default)]
+pub struct SourcePriorities(pub Vec<(NameSourceKind, u32)>);
+impl SourcePriorities {
+    pub fn get(&self, kind: NameSourceKind) -> u32 {
+        self.0
+            .iter()
+            .find(|(priority_kind, _)| *priority_kind == kind)
+            .map_or(DEFAULT_SOURCE_PRIORITY, |(_, priority)| *priority)
+    }
+
+    pub fn set(&mut self, kind: NameSourceKind, priority: u32) {
+        self.0.retain(|(priority_kind, _)| *priority_kind != kind);
+        self.0.push((kind, priority));
+    }
+}
+impl FromStr for SourcePriorities {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (kind, priority) = entry.split_once('=').ok_or(())?;
+                Ok((
+                    NameSourceKind::from_str(kind, false).map_err(|_| ())?,
+                    priority.parse().map_err(|_| ())?,
+                ))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+impl Display for SourcePriorities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.0
+                .iter()
+                .map(|(kind, priority)| format!("{}={priority}", kind.name()))
+                .join(",")
+        )
+    }
+}
+
+/// The configured sources, tried in each guild's priority order. Lower priorities go first, and
+/// sources with the same priority keep the order they were configured in.
+pub struct NameSources {
+    db: Store,
+    sources: Vec<(NameSourceKind, Box<dyn NameSource>)>,
+}
+
+impl NameSources {
+    /// The guild's sources in the order they're tried, with their priorities.
+    pub fn order(&self, guild_id: GuildId) -> Vec<(NameSourceKind, u32)> {
+        let priorities: SourcePriorities =
+            get_setting(&self.db, guild_id, SOURCE_PRIORITIES_SETTING).unwrap_or_default();
+        self.sources
+            .iter()
+            .map(|(kind, _)| (*kind, priorities.get(*kind)))
+            // Stable, so ties keep the configured order.
+            .sorted_by_key(|(_, priority)| *priority)
+            .collect()
+    }
+
+    /// The title or game of the member's stream, if streams are one of the sources. The rest of
+    /// their channel can be named after it.
+    pub fn stream_name(&self, guild_id: GuildId, presence: &Presence) -> Option<String> {
+        let (_, source) = self
+            .sources
+            .iter()
+            .find(|(kind, _)| *kind == NameSourceKind::Streaming)?;
+        let name = source.name_from_presence(presence)?;
+        let banned: NameList =
+            get_setting(&self.db, guild_id, BANNED_NAMES_SETTING).unwrap_or_default();
+        (!banned.contains(&name)).then_some(name)
+    }
+
+    /// The name from the highest priority source that has one for the member.
+    pub fn name(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        presence: Option<&Presence>,
+    ) -> Option<String> {
+        let banned: NameList =
+            get_setting(&self.db, guild_id, BANNED_NAMES_SETTING).unwrap_or_default();
+        self.order(guild_id).into_iter().find_map(|(kind, _)| {
+            let (_, source) = self
+                .sources
+                .iter()
+                .find(|(source_kind, _)| *source_kind == kind)?;
+            let name = source.name(guild_id, user_id, presence)?;
+            if banned.contains(&name) {
+                debug!("Not taking {name} from {kind:?} because it's banned");
+                return None;
+            }
+            debug!("Took {name} from {kind:?}");
+            Some(name)
+        })
+    }
+}
+
+/// Builds the sources in the order they should be tried when priorities are tied.
+pub fn build(
+    kinds: &[NameSourceKind],
+    games: &Arc<TrackedGames>,
+    champions: &Arc<ChampionIndex>,
+    db: &Store,
+    live_games: Option<&Arc<LiveGames>>,
+) -> NameSources {
+    let sources = kinds
+        .iter()
+        .unique()
+        .filter_map(|kind| -> Option<(NameSourceKind, Box<dyn NameSource>)> {
+            let source: Box<dyn NameSource> = match kind {
+                NameSourceKind::Rules => Box::new(GuildRules { db: Db::clone(db) }),
+                NameSourceKind::Spectator => Box::new(SpectatorChampion {
+                    live_games: live_games?.clone(),
+                }),
+                NameSourceKind::LeagueChampion => Box::new(LeagueChampion {
+                    games: games.clone(),
+                    champions: champions.clone(),
+                    db: db.clone(),
+                }),
+                NameSourceKind::ValorantAgent => Box::new(ValorantAgent),
+                NameSourceKind::TftLittleLegend => Box::new(TftLittleLegend),
+                NameSourceKind::GameName => Box::new(GameName {
+                    games: games.clone(),
+                }),
+                NameSourceKind::Streaming => Box::new(StreamTitle { db: db.clone() }),
+                NameSourceKind::Spotify => Box::new(SpotifyTrack { db: db.clone() }),
+            };
+            Some((*kind, source))
+        })
+        .collect();
+    NameSources {
+        db: db.clone(),
+        sources,
+    }
+}
@@ -1,79 +1,297 @@
-use std::{borrow::Cow, fmt::Display};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use clap::Args;
 
 use futures::{join, stream::iter, StreamExt};
-use log::{debug, info, warn};
+use tracing::{debug, error, info, instrument, warn};
 
 use serenity::{
-    all::{ChannelType, EditMember, GuildMemberUpdateEvent},
+    all::{
+        ChannelType, ChunkGuildFilter, ConnectionStage, GuildMemberUpdateEvent,
+        GuildMembersChunkEvent, Interaction, Reaction, Ready, ResumedEvent, ShardId,
+        ShardStageUpdateEvent,
+    },
     async_trait,
     client::Cache,
+    http::{Http, RatelimitInfo},
     model::{
-        gateway::Activity,
-        prelude::{
-            ActivityType, ApplicationId, ChannelId, Guild, GuildId, Member, Presence, UserId,
-        },
+        prelude::{ChannelId, Guild, GuildId, Member, Presence, UnavailableGuild, UserId},
         user::User,
         voice::VoiceState,
     },
     prelude::*,
 };
-
 use sled::Db;
 
-use crate::db::{
-    get_name, has_overridden_name, make_name_batch, name_overrides_db_tree_name, DbKey,
+use serde_json::json;
+
+use crate::{
+    active_hours, announce, api, audit,
+    breaker::BREAKERS,
+    canary::ShrinkCanary,
+    commands, control, dashboard,
+    db::{
+        bare_names, get_setting, has_overridden_name, rejected_names_db_tree_name,
+        store_member_name, stored_names, unrenamable_db_tree_name, MissingAssetsPolicy, NameCache,
+        NameSource, Participation, DM_NOTIFICATIONS_SETTING, MISSING_ASSETS_SETTING,
+        PLAYERS_ONLY_SETTING, SKIP_MODERATORS_SETTING,
+    },
+    ddragon::ChampionIndex,
+    debounce::SyncDebouncer,
+    departure, event, expiry,
+    grace::PendingRestores,
+    guild_view::GuildView,
+    history,
+    interaction_state::InteractionStore,
+    locks::ChannelLocks,
+    metrics,
+    name_source::{
+        self, current_champion_from_activity, NameSourceKind, NameSources, TrackedGames,
+        LEAGUE_OF_LEGENDS_APPLICATION_ID,
+    },
+    namerestorer,
+    nickname::{
+        apply_nick, fit_nick, is_rejected, is_unrenamable, make_unique, record_outcome,
+        record_rejection, set_dry_run, CountedRename, NickOutcome,
+    },
+    notify::DmNotifier,
+    owner::{self, OWNER},
+    pagination::Paginator,
+    presence::{self, ActivityKind},
+    profanity::ProfanityFilter,
+    retry,
+    riot::LiveGames,
+    rollout::{PlannedRename, RolloutPlanner},
+    sanitize::sanitize_name,
+    scheduler::{EDITS, MAX_CONCURRENT_EDITS},
+    session,
+    settings::{GuildSettings, MAX_OVERRIDE_TTL_HOURS},
+    stats,
+    store::{NameStore, Names, Store},
+    tasks,
+    template::TemplateValues,
+    tui,
+    voice::VoiceChannels,
+    web::{self, DebugState, Readiness},
 };
 
-const LEAGUE_OF_LEGENDS_APPLICATION_ID: Option<ApplicationId> =
-    Some(ApplicationId::new(401518684763586560));
+/// How long a channel has to wait between rerolls from its announcement.
+const REROLL_COOLDOWN: Duration = Duration::from_secs(60);
 
-fn current_champion_from_activities<'a, I: IntoIterator<Item = &'a Activity>>(
-    activities: I,
-) -> Option<&'a str> {
-    activities
-        .into_iter()
-        .inspect(|activity| debug!("Checking activity {activity:?}"))
-        .flat_map(|activity: &Activity| {
-            let is_valid_activity = activity.kind == ActivityType::Playing
-                && activity.application_id == LEAGUE_OF_LEGENDS_APPLICATION_ID;
-            is_valid_activity.then_some(activity.assets.as_ref()?.large_text.as_ref()?)
-        })
-        .next()
-        .map(String::as_str)
+/// Options for the bot itself (as opposed to the offline maintenance subcommands).
+#[derive(Args, Clone)]
+pub struct BotOptions {
+    /// Channels with more members than this have their renames spread out over time.
+    #[arg(long, default_value_t = 15)]
+    large_channel_threshold: usize,
+    /// How long to spread the renames for a large channel over.
+    #[arg(long, default_value_t = 300)]
+    large_channel_rollout_secs: u64,
+    /// Where to cache champion data downloaded from Data Dragon.
+    #[arg(long, default_value = "ddragon.json")]
+    pub ddragon_cache: PathBuf,
+    /// Alert if stored names or overrides lose more than this fraction of their records at once.
+    #[arg(long, default_value_t = 0.5)]
+    shrink_alert_fraction: f64,
+    /// Channel to post shrink alerts to, in addition to logging them.
+    #[arg(long)]
+    alert_channel: Option<u64>,
+    /// Discord application ids of the games to take names from. Defaults to League of Legends.
+    #[arg(long = "game-application-id", default_values_t = [LEAGUE_OF_LEGENDS_APPLICATION_ID])]
+    game_application_ids: Vec<u64>,
+    /// Also take names from games whose activity name contains this (ignoring case), for
+    /// clients that don't report an application id.
+    #[arg(long = "game-activity-name")]
+    game_activity_names: Vec<String>,
+    /// Serve HTTP endpoints (such as /healthz, /readyz and /debug/state) on this address, e.g. 127.0.0.1:8080.
+    #[arg(long)]
+    http_addr: Option<SocketAddr>,
+    /// Bearer token required by /debug/state, which is off unless this is set. It also only
+    /// answers requests from this machine.
+    #[arg(long)]
+    debug_token: Option<String>,
+    /// Show a live summary of every server in the terminal instead of logging.
+    #[arg(long)]
+    pub tui: bool,
+    /// Password for the admin dashboard at /admin and bearer token for the API under /api, which
+    /// are off unless this is set.
+    #[arg(long, env = "NAMECHANGER_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    /// How long to wait before restoring the name of someone who left voice, in case they
+    /// come straight back.
+    #[arg(long, default_value_t = 30)]
+    leave_grace_secs: u64,
+    /// How long to keep a guild's names and settings after the bot is removed from it, in case
+    /// it's added back. 0 drops them straight away, and the most is a year.
+    #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u64).range(..=24 * 365))]
+    left_guild_grace_hours: u64,
+    /// How long to wait for presence and voice updates in a channel to settle before syncing
+    /// it, so a burst of them only causes one sync.
+    #[arg(long, default_value_t = 3000)]
+    sync_debounce_ms: u64,
+    /// Restore names that have been changed for this many hours, in case the session ended
+    /// without a voice event. Guilds can change it with `/namechanger expiry`; 0 turns it off,
+    /// and the most is a year.
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u64).range(..=MAX_OVERRIDE_TTL_HOURS))]
+    override_ttl_hours: u64,
+    /// Where to take names from, in the order they're tried. Guilds can reorder them with
+    /// `/namechanger priority`; this order breaks ties.
+    #[arg(long = "name-source", value_enum, default_values_t = [
+        NameSourceKind::Rules,
+        NameSourceKind::Spectator,
+        NameSourceKind::LeagueChampion,
+        NameSourceKind::TftLittleLegend,
+        NameSourceKind::ValorantAgent,
+        NameSourceKind::Streaming,
+        NameSourceKind::Spotify,
+    ])]
+    name_sources: Vec<NameSourceKind>,
+    /// Riot API key for looking up the champions of members who linked their Riot ID. The
+    /// spectator name source is off without one.
+    #[arg(long, env = "RIOT_API_KEY")]
+    riot_api_key: Option<String>,
+    /// Riot platform the linked accounts play on, e.g. na1, euw1 or kr.
+    #[arg(long, default_value = "na1")]
+    riot_platform: String,
+    /// Riot region for account lookups, e.g. americas, europe or asia.
+    #[arg(long, default_value = "americas")]
+    riot_region: String,
+    /// How often to check linked accounts for a live game.
+    #[arg(long, default_value_t = 120, value_parser = clap::value_parser!(u64).range(1..))]
+    riot_poll_secs: u64,
+    /// The least time between DMs telling a member about their new name, in guilds that turned
+    /// them on.
+    #[arg(long, default_value_t = 60)]
+    dm_cooldown_mins: u64,
+    /// Discord user id of whoever runs the bot, to DM when it hits a problem they need to fix:
+    /// database errors, data loss, or renames paused in a guild after repeated failures.
+    #[arg(long, env = "NAMECHANGER_OWNER_ID")]
+    owner_id: Option<u64>,
+    /// What the bot's activity in the member list says. `{servers}`, `{channels}` and
+    /// `{renamed}` are replaced with how many servers it's in, how many voice channels it's
+    /// renaming people in and how many people it renamed. Empty for no activity.
+    #[arg(long, default_value = "{channels} voice channels")]
+    activity: String,
+    #[arg(long, value_enum, default_value_t = ActivityKind::Watching)]
+    activity_kind: ActivityKind,
+    /// How often to update the bot's activity.
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    activity_interval_secs: u64,
+    /// Run everything as usual but only log nickname edits instead of making them, for trying
+    /// the bot out in a real server.
+    #[arg(long)]
+    dry_run: bool,
+    /// How many gateway shards to run. Uses as many as Discord recommends by default.
+    #[arg(long)]
+    shards: Option<u32>,
 }
+
 struct Handler {
-    db: Db,
+    db: Store,
+    options: BotOptions,
+    rollouts: RolloutPlanner,
+    champions: Arc<ChampionIndex>,
+    games: Arc<TrackedGames>,
+    sources: NameSources,
+    pages: Paginator,
+    interaction_state: Arc<InteractionStore>,
+    canary: ShrinkCanary,
+    name_cache: Arc<NameCache>,
+    pending_restores: PendingRestores,
+    sync_debouncer: SyncDebouncer,
+    channel_locks: ChannelLocks,
+    /// Members who were last seen playing a tracked game, so we notice when they stop.
+    playing: std::sync::Mutex<HashSet<(GuildId, UserId)>>,
+    voice_channels: VoiceChannels,
+    dm_notifier: DmNotifier,
+    /// The connection stage of each shard, for readiness checks.
+    shard_stages: std::sync::Mutex<HashMap<ShardId, ConnectionStage>>,
+    /// When each channel was last rerolled from its announcement.
+    last_rerolls: std::sync::Mutex<HashMap<(GuildId, ChannelId), Instant>>,
 }
 
-fn gen_derangement(size: usize) -> Vec<usize> {
-    if size > 1 {
-        let mut rng = rand::thread_rng();
-        derangement::derange::Derange::new(&mut rng, size)
-            .map()
-            .to_vec()
-    } else {
-        vec![0; size]
-    }
+/// Returns the nickname if the server refused to apply it. Edits that fail for other reasons
+/// are queued to be retried. Only edits that are `counted` go into the stats.
+async fn set_nick(
+    db: &Store,
+    ctx: &Context,
+    guild_id: GuildId,
+    (user_id, nick): (UserId, String),
+    old: Option<&str>,
+    source: Option<&str>,
+    counted: bool,
+) -> Option<(UserId, String)> {
+    let outcome = apply_nick(&ctx.http, guild_id, user_id, &nick).await;
+    let counted = counted.then_some(CountedRename { old, source });
+    record_outcome(db, guild_id, user_id, &nick, &outcome, counted);
+    matches!(outcome, NickOutcome::Rejected).then_some((user_id, nick))
 }
-
-async fn set_nicks<'a, S: Into<String> + Display, I: IntoIterator<Item = (UserId, S)>>(
+/// Sets the nicknames, skipping members whose last known nickname in `current` is already the
+/// one they'd get. `sources` says which source name each nickname came from, for the stats.
+/// `None` means the nicknames are stored names being put back, which isn't counted as renaming
+/// anyone. Returns the nicknames the server refused.
+async fn set_nicks<S: AsRef<str>, I: IntoIterator<Item = (UserId, S)>>(
+    db: &Store,
     ctx: &Context,
     guild_id: GuildId,
     nicks: I,
-) {
-    iter(nicks.into_iter())
-        .for_each_concurrent(10, |(user_id, nick)| async move {
-            info!("Setting nickname to {nick} for {user_id}");
-            if let Err(e) = guild_id
-                .edit_member(&ctx.http, user_id, EditMember::new().nickname(nick))
-                .await
-            {
-                warn!("Failed to set nickname for {user_id}: {e:?}");
-            } else {
-                info!("Successfully set nickname for {user_id}");
-            }
+    current: &HashMap<UserId, String>,
+    sources: Option<&HashMap<UserId, String>>,
+) -> Vec<(UserId, String)> {
+    let (unchanged, nicks): (Vec<_>, Vec<_>) = nicks
+        .into_iter()
+        .map(|(user_id, nick)| (user_id, nick.as_ref().to_string()))
+        .partition(|(user_id, nick)| current.get(user_id) == Some(nick));
+    info!(
+        "Changing {} nicknames in guild {guild_id}, skipping {} that are already set",
+        nicks.len(),
+        unchanged.len()
+    );
+    iter(nicks)
+        .map(|entry| {
+            let old = current.get(&entry.0).map(String::as_str);
+            let source = sources
+                .and_then(|sources| sources.get(&entry.0))
+                .map(String::as_str);
+            set_nick(db, ctx, guild_id, entry, old, source, sources.is_some())
         })
-        .await;
+        // The edit scheduler paces the edits.
+        .buffer_unordered(MAX_CONCURRENT_EDITS)
+        .filter_map(|rejected| async move { rejected })
+        .collect()
+        .await
+}
+/// Whether the channel is where the guild sends idle members. Nobody there is playing together.
+fn is_afk_channel(guild: &Guild, channel_id: ChannelId) -> bool {
+    guild
+        .afk_metadata
+        .as_ref()
+        .is_some_and(|afk| afk.afk_channel_id == channel_id)
+}
+async fn restore_leaver(db: Store, http: Arc<Http>, member: Member, nick_to_restore: String) {
+    info!(
+        "Restoring nickname {nick_to_restore} to {} ({})",
+        member.user.name, member.user.id
+    );
+    if let NickOutcome::Applied =
+        apply_nick(&http, member.guild_id, member.user.id, &nick_to_restore).await
+    {
+        retry::dequeue(&db, member.guild_id, member.user.id);
+    } else {
+        warn!(
+            "Failed to restore user name {nick_to_restore} to {} ({})",
+            member.user.name, member.user.id
+        );
+        retry::enqueue(&db, member.guild_id, member.user.id, &nick_to_restore);
+    }
 }
 async fn channel_members(
     cache: &Cache,
@@ -89,51 +307,208 @@ async fn channel_members(
             warn!("Failed to get members for channel {channel_id:?} in guild {guild_id:?} {e}")
         })
         .ok()
+        // Other bots (music bots and the like) are never renamed.
+        .map(|members| {
+            members
+                .into_iter()
+                .filter(|member| !member.user.bot)
+                .collect()
+        })
 }
 
 #[async_trait]
 impl EventHandler for Handler {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        // After a full reconnect every guild is sent again, and guild_create resyncs them.
+        match ready.shard {
+            Some(shard) => info!(
+                "Connected as {} on shard {} of {}",
+                ready.user.name, shard.id, shard.total
+            ),
+            None => info!("Connected as {}", ready.user.name),
+        }
+        // Commands are global, so one shard registering them is enough.
+        if ctx.shard_id.0 == 0 {
+            commands::register(&ctx).await;
+        }
+    }
+
+    async fn shard_stage_update(&self, _ctx: Context, event: ShardStageUpdateEvent) {
+        info!(
+            "Shard {} went from {} to {}",
+            event.shard_id, event.old, event.new
+        );
+        self.shard_stages
+            .lock()
+            .unwrap()
+            .insert(event.shard_id, event.new);
+    }
+
+    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        commands::handle_consent_reaction(&self.db, &ctx, &reaction, true).await;
+        self.reroll(&ctx, &reaction).await;
+        event::handle_reaction(&self.db, &ctx, &reaction, true).await;
+    }
+
+    async fn reaction_remove(&self, ctx: Context, reaction: Reaction) {
+        commands::handle_consent_reaction(&self.db, &ctx, &reaction, false).await;
+        event::handle_reaction(&self.db, &ctx, &reaction, false).await;
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::Command(command) => {
+                commands::handle(
+                    &self.db,
+                    &self.name_cache,
+                    &self.pages,
+                    &self.champions,
+                    &self.sources,
+                    &ctx,
+                    &command,
+                )
+                .await;
+            }
+            Interaction::Component(component) => {
+                let handled = self.pages.handle(&ctx, &component).await;
+                if !handled {
+                    warn!("Received unknown component {}", component.data.custom_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn guild_delete(
+        &self,
+        _ctx: Context,
+        incomplete: UnavailableGuild,
+        _full: Option<Guild>,
+    ) {
+        // An outage, not the bot being removed.
+        if incomplete.unavailable {
+            return;
+        }
+        departure::left(
+            &self.db,
+            incomplete.id,
+            Duration::from_secs(self.options.left_guild_grace_hours * 60 * 60),
+        );
+        self.name_cache.invalidate_guild(incomplete.id);
+    }
+
+    async fn cache_ready(&self, ctx: Context, guilds: Vec<GuildId>) {
+        // Big guilds only come with some of their members, so ask for the rest.
+        for guild_id in guilds {
+            // Only the shard the guild is on can ask for its members.
+            if guild_id.shard_id(&ctx.cache) != ctx.shard_id.0 {
+                continue;
+            }
+            debug!("Requesting all members of guild {guild_id}");
+            ctx.shard
+                .chunk_guild(guild_id, None, false, ChunkGuildFilter::None, None);
+        }
+    }
+
+    async fn guild_members_chunk(&self, _ctx: Context, chunk: GuildMembersChunkEvent) {
+        let guild_id = chunk.guild_id;
+        info!(
+            "Received member chunk {} of {} for guild {guild_id}",
+            chunk.chunk_index + 1,
+            chunk.chunk_count
+        );
+        let mut changed = Vec::new();
+        for member in chunk.members.values().filter(|member| !member.user.bot) {
+            audit::record(&self.db, guild_id, member.user.id, member.display_name());
+            if has_overridden_name(&self.db, member) {
+                continue;
+            }
+            // Whatever they're showing now isn't ours, so it's their own name.
+            self.db
+                .remove_name(Names::Override, guild_id, member.user.id);
+            changed.push(member);
+            self.name_cache.invalidate(guild_id, member.user.id);
+        }
+        self.db.set_names(
+            Names::Original,
+            guild_id,
+            &stored_names(changed.into_iter(), NameSource::MemberChunk),
+        );
+    }
+
     async fn guild_create(&self, ctx: Context, guild: Guild, _is_new: Option<bool>) {
         info!("Guild create for {} ({})", guild.name, guild.id);
-        let names = self.db.open_tree(DbKey::from(guild.id)).unwrap();
-        let name_overrides = self
-            .db
-            .open_tree(name_overrides_db_tree_name(guild.id))
-            .unwrap();
-        names
-            .apply_batch(make_name_batch(
-                guild
-                    .members
-                    .values()
-                    .filter(|member| !has_overridden_name(member, &name_overrides)),
-            ))
-            .unwrap();
-        iter(
+        let participation = Participation::new(&self.db, guild.id);
+        self.db.set_names(
+            Names::Original,
+            guild.id,
+            &stored_names(
+                guild.members.values().filter(|member| {
+                    !member.user.bot
+                        && (!participation.allows(member.user.id)
+                            || !has_overridden_name(&self.db, member))
+                }),
+                NameSource::GuildCreate,
+            ),
+        );
+        // Drop names stored for bots before we stopped keeping them.
+        for bot in guild.members.values().filter(|member| member.user.bot) {
+            self.db.remove_name(Names::Original, guild.id, bot.user.id);
+        }
+        self.name_cache.invalidate_guild(guild.id);
+        departure::rejoined(&self.db, guild.id);
+        self.voice_channels.seed(&guild);
+        self.playing.lock().unwrap().extend(
             guild
-                .channels
+                .presences
                 .values()
-                .filter(|c| c.kind == ChannelType::Voice),
-        )
-        .for_each_concurrent(10, |channel| {
+                .filter(|presence| self.games.activity(&presence.activities).is_some())
+                .map(|presence| (guild.id, presence.user.id)),
+        );
+        for member in guild.members.values() {
+            audit::record(&self.db, guild.id, member.user.id, member.display_name());
+        }
+        // Start counting from what's stored now.
+        self.canary
+            .check(&ctx.http, &self.db, Names::Original, guild.id)
+            .await;
+        self.canary
+            .check(&ctx.http, &self.db, Names::Override, guild.id)
+            .await;
+        if !is_enabled(&self.db, guild.id) {
             info!(
-                "Examining channel {} ({}) in {} ({})",
-                channel.name, channel.id, guild.name, guild.id
+                "Not syncing {} ({}) because it is disabled",
+                guild.name, guild.id
             );
-            self.sync_nicks(&ctx, guild.id, channel.id)
-        })
-        .await;
+            return;
+        }
+        self.rollouts.resume(&ctx.http, guild.id);
+        self.sync_voice_channels(&ctx, guild.id, "guild create")
+            .await;
+    }
+
+    async fn resume(&self, ctx: Context, _event: ResumedEvent) {
+        // Discord replays what we missed, but anything it dropped would leave names stale.
+        info!("Shard {} resumed, resyncing its guilds", ctx.shard_id);
+        for guild_id in ctx.cache.guilds() {
+            if guild_id.shard_id(&ctx.cache) != ctx.shard_id.0 || !is_enabled(&self.db, guild_id) {
+                continue;
+            }
+            self.sync_voice_channels(&ctx, guild_id, "resume").await;
+        }
     }
 
     async fn presence_update(&self, ctx: Context, presence: Presence) {
         async fn find_channel_containing_user(
-            presence: Presence,
+            presence: &Presence,
             cache: &Cache,
         ) -> Option<ChannelId> {
-            cache
-                .guild(presence.guild_id?)?
+            let guild = cache.guild(presence.guild_id?)?;
+            guild
                 .channels
                 .values()
                 .filter(|channel| channel.kind == ChannelType::Voice)
+                .filter(|channel| !is_afk_channel(&guild, channel.id))
                 .filter_map(|channel| {
                     channel
                         .members(cache)
@@ -150,9 +525,39 @@ impl EventHandler for Handler {
                 })
                 .next()
         }
-        if let Some(guild_id) = presence.guild_id {
-            if let Some(channel_id) = find_channel_containing_user(presence, &ctx.cache).await {
-                self.sync_nicks(&ctx, guild_id, channel_id).await;
+        if let Some(guild_id) = presence.guild_id.filter(|id| is_enabled(&self.db, *id)) {
+            let user_id = presence.user.id;
+            let playing = self.games.activity(&presence.activities).is_some();
+            let was_playing = if playing {
+                !self.playing.lock().unwrap().insert((guild_id, user_id))
+            } else {
+                self.playing.lock().unwrap().remove(&(guild_id, user_id))
+            };
+            let channel_id = find_channel_containing_user(&presence, &ctx.cache)
+                .await
+                .or_else(|| self.voice_channels.channel_of(guild_id, user_id));
+            if let Some(channel_id) = channel_id {
+                // When someone's game ends, only the member named after them needs their name
+                // back, unless another source still has a name for them.
+                if was_playing
+                    && !playing
+                    && self
+                        .sources
+                        .name(guild_id, user_id, Some(&presence))
+                        .is_none()
+                {
+                    let _lock = self.channel_locks.lock(guild_id, channel_id).await;
+                    if let Some(member) =
+                        session::named_after(&self.db, guild_id, channel_id, user_id)
+                    {
+                        info!("{user_id} stopped playing, restoring {member} who was named after them");
+                        self.rollouts.cancel(guild_id, [member]);
+                        namerestorer::restore_member(&self.db, &ctx.http, guild_id, member).await;
+                        return;
+                    }
+                }
+                self.request_sync(&ctx, guild_id, channel_id, "presence")
+                    .await;
             }
         }
     }
@@ -163,38 +568,59 @@ impl EventHandler for Handler {
         old_state: Option<VoiceState>,
         new_state: VoiceState,
     ) {
+        self.voice_channels.update(&new_state);
+        if let Some(guild_id) = new_state.guild_id {
+            // Names during an event have nothing to do with voice.
+            if !is_enabled(&self.db, guild_id) || event::event_role(&self.db, guild_id).is_some() {
+                return;
+            }
+        }
+        if let (Some(guild_id), Some(_)) = (new_state.guild_id, new_state.channel_id) {
+            self.pending_restores.cancel(guild_id, new_state.user_id);
+        }
         let new_state_future = self.process_voice_state_update(&ctx, &new_state);
         let old_state_future = async {
             if let Some(voice_state) = old_state {
-                let restore_leaving_user_name_future = async {
-                    if let Some(ref member) = voice_state.member {
-                        let names = self.db.open_tree(DbKey::from(member.guild_id)).unwrap();
-                        let nick_to_restore = get_name(&names, DbKey::from(member.user.id))
-                            .unwrap_or(member.user.name.clone());
-                        info!(
-                            "Restoring nickname {nick_to_restore} to {} ({})",
-                            member.user.name, member.user.id
-                        );
-                        if let Err(e) = member
-                            .guild_id
-                            .edit_member(
-                                &ctx.http,
-                                member.user.id,
-                                EditMember::new().nickname(nick_to_restore.to_string()),
-                            )
-                            .await
-                        {
-                            warn!(
-                                "Failed to restore user name {nick_to_restore} to {} ({}): {e:?}",
-                                member.user.name, member.user.id
-                            );
-                        }
+                if let Some(member) = voice_state
+                    .member
+                    .as_ref()
+                    .filter(|member| !member.user.bot)
+                {
+                    let nick_to_restore = self
+                        .name_cache
+                        .get_stored(&self.db, member.guild_id, member.user.id)
+                        .map_or(member.user.name.clone(), |stored| {
+                            stored.nickname().to_string()
+                        });
+                    let restore = restore_leaver(
+                        self.db.clone(),
+                        ctx.http.clone(),
+                        member.clone(),
+                        nick_to_restore,
+                    );
+                    if new_state.channel_id.is_none() {
+                        self.pending_restores
+                            .schedule(member.guild_id, member.user.id, restore);
+                    } else {
+                        restore.await;
                     }
-                };
-                join!(
-                    restore_leaving_user_name_future,
-                    self.process_voice_state_update(&ctx, &voice_state),
-                );
+                }
+                if let (Some(guild_id), Some(channel_id)) =
+                    (voice_state.guild_id, voice_state.channel_id)
+                {
+                    let empty = self
+                        .voice_members(&ctx, guild_id, channel_id)
+                        .await
+                        .is_some_and(|members| members.is_empty());
+                    if empty {
+                        session::clear(&self.db, guild_id, channel_id);
+                    } else if new_state.channel_id != Some(channel_id) {
+                        // The channel may have dropped below its minimum size.
+                        self.request_sync(&ctx, guild_id, channel_id, "voice leave")
+                            .await;
+                    }
+                }
+                self.process_voice_state_update(&ctx, &voice_state).await;
             }
         };
         join!(new_state_future, old_state_future);
@@ -202,134 +628,876 @@ impl EventHandler for Handler {
 
     async fn guild_member_update(
         &self,
-        _ctx: Context,
+        ctx: Context,
         _old_if_available: Option<Member>,
         new: Option<Member>,
         _event: GuildMemberUpdateEvent,
     ) {
-        if let Some(new) = new {
-            let name_overrides = self
-                .db
-                .open_tree(name_overrides_db_tree_name(new.guild_id))
-                .unwrap();
-            if !has_overridden_name(&new, &name_overrides) {
-                let user_id_key = DbKey::from(new.user.id);
-                name_overrides.remove(user_id_key).unwrap();
-                let names = self.db.open_tree(DbKey::from(new.guild_id)).unwrap();
-                names
-                    .apply_batch(make_name_batch(std::iter::once((
-                        user_id_key,
-                        new.display_name(),
-                    ))))
-                    .unwrap();
+        if let Some(new) = new.filter(|member| !member.user.bot) {
+            audit::record(&self.db, new.guild_id, new.user.id, new.display_name());
+            if !has_overridden_name(&self.db, &new) {
+                self.db
+                    .remove_name(Names::Override, new.guild_id, new.user.id);
+                store_member_name(&self.db, &new, NameSource::MemberUpdate);
+                self.name_cache.invalidate(new.guild_id, new.user.id);
             }
+            let namer = event::Namer {
+                champions: &self.champions,
+                sources: &self.sources,
+                name_cache: &self.name_cache,
+            };
+            event::member_updated(&self.db, &ctx, &namer, &new).await;
         }
     }
+    async fn ratelimit(&self, data: RatelimitInfo) {
+        EDITS.rate_limited(&data);
+    }
     async fn guild_member_addition(&self, _ctx: Context, new_member: Member) {
-        self.db
-            .open_tree(DbKey::from(new_member.guild_id))
-            .unwrap()
-            .insert(DbKey::from(new_member.user.id), new_member.display_name())
-            .unwrap();
+        if new_member.user.bot {
+            return;
+        }
+        store_member_name(&self.db, &new_member, NameSource::MemberJoin);
+        self.name_cache
+            .invalidate(new_member.guild_id, new_member.user.id);
+        audit::record(
+            &self.db,
+            new_member.guild_id,
+            new_member.user.id,
+            new_member.display_name(),
+        );
     }
     async fn guild_member_removal(
         &self,
-        _ctx: Context,
+        ctx: Context,
         guild_id: GuildId,
         user: User,
         _member_data_if_available: Option<Member>,
     ) {
-        let key = DbKey::from(user.id);
-        self.db
-            .open_tree(name_overrides_db_tree_name(guild_id))
-            .unwrap()
-            .remove(key)
-            .unwrap();
+        self.db.remove_name(Names::Override, guild_id, user.id);
+        self.db.remove_name(Names::Original, guild_id, user.id);
+        self.name_cache.invalidate(guild_id, user.id);
+        self.canary
+            .check(&ctx.http, &self.db, Names::Original, guild_id)
+            .await;
+    }
+}
+impl Readiness for Handler {
+    fn ready(&self) -> Result<(), String> {
+        {
+            let shard_stages = self.shard_stages.lock().unwrap();
+            if shard_stages.is_empty() {
+                return Err("Not connected to the gateway yet".to_string());
+            }
+            if let Some((shard_id, stage)) = shard_stages
+                .iter()
+                .find(|(_, stage)| **stage != ConnectionStage::Connected)
+            {
+                return Err(format!("Shard {shard_id} is {stage}"));
+            }
+        }
         self.db
-            .open_tree(DbKey::from(guild_id))
-            .unwrap()
-            .remove(key)
-            .unwrap();
+            .get([])
+            .map_err(|e| format!("Failed to read the database: {e}"))?;
+        Ok(())
     }
 }
+
+impl DebugState for Handler {
+    fn debug_state(&self) -> serde_json::Value {
+        let guilds: serde_json::Map<_, _> = self
+            .db
+            .guilds()
+            .into_iter()
+            .map(|guild_id| {
+                (
+                    guild_id.to_string(),
+                    json!({
+                        "pending_rollout_edits": self.rollouts.pending(guild_id),
+                        "rollout_active": self.rollouts.is_active(guild_id),
+                        "pending_restores": self.pending_restores.pending(guild_id),
+                        "pending_syncs": self.sync_debouncer.pending(guild_id),
+                        "syncing_channels": self.channel_locks.held(guild_id),
+                        "waiting_edits": EDITS.waiting(guild_id),
+                        "renames_paused": BREAKERS.is_open(guild_id),
+                        "cached_names": self.name_cache.cached(guild_id),
+                        "event_running": event::event_role(&self.db, guild_id).is_some(),
+                        "rename_stats": stats::rename_counts(&self.db, guild_id),
+                    }),
+                )
+            })
+            .collect();
+        json!({
+            "guilds": guilds,
+            "interaction_sessions": self.interaction_state.entries(),
+        })
+    }
+}
+
 impl Handler {
     async fn process_voice_state_update(&self, ctx: &Context, voice_state: &VoiceState) {
         if let Some(guild_id) = voice_state.guild_id {
             if let Some(channel_id) = voice_state.channel_id {
-                self.sync_nicks(ctx, guild_id, channel_id).await;
+                self.request_sync(ctx, guild_id, channel_id, "voice").await;
             }
         }
     }
-    async fn sync_nicks(&self, ctx: &Context, guild_id: GuildId, channel_id: ChannelId) {
+    /// Syncs every voice channel in the guild the bot is allowed to rename people in.
+    async fn sync_voice_channels(&self, ctx: &Context, guild_id: GuildId, trigger: &'static str) {
+        let settings = GuildSettings::load(&self.db, guild_id);
+        let Some(channels) = ctx.cache.guild(guild_id).map(|guild| {
+            guild
+                .channels
+                .values()
+                .filter(|c| c.kind == ChannelType::Voice)
+                .filter(|c| !is_afk_channel(&guild, c.id))
+                .filter(|c| settings.is_channel_allowed(c.id))
+                .map(|c| (c.id, c.name.clone()))
+                .collect::<Vec<_>>()
+        }) else {
+            return;
+        };
+        iter(channels)
+            .for_each_concurrent(10, |(channel_id, name)| {
+                info!("Examining channel {name} ({channel_id}) in guild {guild_id}");
+                self.sync_nicks(ctx, guild_id, channel_id, trigger)
+            })
+            .await;
+    }
+    /// The channel's members from the cache, or fetched one by one if the cache doesn't have
+    /// the guild yet.
+    async fn voice_members(
+        &self,
+        ctx: &Context,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+    ) -> Option<Vec<Member>> {
+        if ctx.cache.guild(guild_id).is_some() {
+            return channel_members(&ctx.cache, guild_id, channel_id).await;
+        }
+        debug!("Guild {guild_id} isn't cached, fetching the members of channel {channel_id}");
+        let members: Vec<Member> = iter(self.voice_channels.members(guild_id, channel_id))
+            .filter_map(|user_id| async move {
+                ctx.http
+                    .get_member(guild_id, user_id)
+                    .await
+                    .inspect_err(|e| warn!("Failed to fetch {user_id} in guild {guild_id}: {e}"))
+                    .ok()
+            })
+            .collect()
+            .await;
+        Some(
+            members
+                .into_iter()
+                .filter(|member| !member.user.bot)
+                .collect(),
+        )
+    }
+    /// Reshuffles a channel when someone in it reacts to its mapping announcement.
+    async fn reroll(&self, ctx: &Context, reaction: &Reaction) {
+        let (Some(guild_id), Some(user_id)) = (reaction.guild_id, reaction.user_id) else {
+            return;
+        };
+        if user_id == ctx.cache.current_user().id
+            || !reaction.emoji.unicode_eq(announce::REROLL_EMOJI)
+            || !is_enabled(&self.db, guild_id)
+        {
+            return;
+        }
+        let Some(channel_id) = announce::reroll_channel(&self.db, guild_id, reaction.message_id)
+        else {
+            return;
+        };
+        if self.voice_channels.channel_of(guild_id, user_id) != Some(channel_id) {
+            debug!("Ignoring reroll from {user_id}, who isn't in {channel_id}");
+            return;
+        }
+        {
+            let now = Instant::now();
+            let mut last_rerolls = self.last_rerolls.lock().unwrap();
+            if last_rerolls
+                .get(&(guild_id, channel_id))
+                .is_some_and(|last| now.duration_since(*last) < REROLL_COOLDOWN)
+            {
+                debug!("Ignoring reroll of {channel_id} in guild {guild_id} during its cooldown");
+                return;
+            }
+            last_rerolls.retain(|_, last| now.duration_since(*last) < REROLL_COOLDOWN);
+            last_rerolls.insert((guild_id, channel_id), now);
+        }
+        info!("{user_id} rerolled the names in {channel_id} in guild {guild_id}");
+        session::clear(&self.db, guild_id, channel_id);
+        self.request_sync(ctx, guild_id, channel_id, "reroll").await;
+    }
+    /// Syncs the channel once the burst of updates it's part of is over. `trigger` says what
+    /// asked for the sync, for the logs.
+    async fn request_sync(
+        &self,
+        ctx: &Context,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        trigger: &'static str,
+    ) {
+        let afk = ctx
+            .cache
+            .guild(guild_id)
+            .is_some_and(|guild| is_afk_channel(&guild, channel_id));
+        if afk {
+            debug!("Not syncing AFK channel {channel_id} in guild {guild_id}");
+            return;
+        }
+        if self.sync_debouncer.wait(guild_id, channel_id).await {
+            self.sync_nicks(ctx, guild_id, channel_id, trigger).await;
+        }
+    }
+    // Syncs run concurrently, so every log line says which one it came from.
+    #[instrument(name = "sync", skip_all, fields(guild = %guild_id, channel = %channel_id, %trigger))]
+    async fn sync_nicks(
+        &self,
+        ctx: &Context,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        trigger: &'static str,
+    ) {
+        // Loaded once, so the whole sync acts on the same settings.
+        let settings = GuildSettings::load(&self.db, guild_id);
+        if !settings.is_channel_allowed(channel_id) {
+            debug!("Not syncing channel {channel_id} in guild {guild_id} because it isn't allowed");
+            return;
+        }
+        if event::event_role(&self.db, guild_id).is_some() {
+            debug!(
+                "Not syncing channel {channel_id} in guild {guild_id} because an event is running"
+            );
+            return;
+        }
+        let _lock = self.channel_locks.lock(guild_id, channel_id).await;
         info!("Syncing nicknames for channel {channel_id} in guild {guild_id}");
-        let members = channel_members(&ctx.cache, guild_id, channel_id)
+        let participation = Participation::new(&self.db, guild_id);
+        let members = self
+            .voice_members(ctx, guild_id, channel_id)
             .await
             .unwrap_or(vec![]);
-        let derangement = gen_derangement(members.len());
-        let (names, new_nicks) = if let Some(guild) = guild_id.to_guild_cached(&ctx.cache) {
-            let names = self.db.open_tree(DbKey::from(guild_id)).unwrap();
-            let new_nicks:Vec<_> = members.iter().enumerate().map(|(user_id_index, member)| {
-                let from_user = &members[derangement[user_id_index]].user;
-                let source_champion_named = guild.presences.get(&from_user.id).and_then(|presence|current_champion_from_activities(&presence.activities));
+        let bot_user_id = ctx.cache.current_user().id;
+        let Some(guild) = GuildView::load(ctx, guild_id, channel_id, &members).await else {
+            warn!(
+                "Failed to sync nicknames for guild {guild_id} because the guild or our member in it couldn't be found"
+            );
+            return;
+        };
+        // Without presences nobody has a name to give, and everyone would be put back to their
+        // stored name. The guild's channels sync once it arrives.
+        if !guild.has_presences {
+            info!("Not syncing channel {channel_id} in guild {guild_id} until its presences are cached");
+            return;
+        }
+        let renamable_members: Vec<_> = {
+            let skip_moderators =
+                get_setting(&self.db, guild_id, SKIP_MODERATORS_SETTING).unwrap_or(false);
+            let unrenamable = self
+                .db
+                .open_tree(unrenamable_db_tree_name(guild_id))
+                .unwrap();
+            members
+                .into_iter()
+                .filter(|member| participation.allows(member.user.id))
+                .filter(|member| {
+                    // Discord won't let us rename the owner, ourselves, or anyone who outranks us.
+                    let outranked = member.user.id == guild.owner_id
+                        || member.user.id == bot_user_id
+                        || guild.role_position(member) >= guild.bot_role_position;
+                    if outranked {
+                        debug!(
+                            "Skipping {} ({}) because we can't rename them",
+                            member.user.name, member.user.id
+                        );
+                    }
+                    !outranked
+                })
+                .filter(|member| {
+                    let forbidden = is_unrenamable(&unrenamable, member.user.id);
+                    if forbidden {
+                        debug!(
+                            "Skipping {} ({}) because we weren't allowed to rename them recently",
+                            member.user.name, member.user.id
+                        );
+                    }
+                    !forbidden
+                })
+                .filter(|member| {
+                    let moderator = skip_moderators && {
+                        let permissions = guild.permissions(member);
+                        permissions.manage_nicknames() || permissions.administrator()
+                    };
+                    if moderator {
+                        debug!(
+                            "Skipping {} ({}) because they're a moderator",
+                            member.user.name, member.user.id
+                        );
+                    }
+                    !moderator
+                })
+                .collect()
+        };
+        let renamable_members =
+            if get_setting(&self.db, guild_id, PLAYERS_ONLY_SETTING).unwrap_or(false) {
+                let (players, spectators): (Vec<_>, Vec<_>) =
+                    renamable_members.into_iter().partition(|member| {
+                        guild.presence(member.user.id).is_some_and(|presence| {
+                            self.games.activity(&presence.activities).is_some()
+                        })
+                    });
+                // Spectators keep their own names, including anyone who just stopped playing.
+                iter(spectators)
+                    .for_each_concurrent(None, |member| {
+                        namerestorer::restore_member(&self.db, &ctx.http, guild_id, member.user.id)
+                    })
+                    .await;
+                players
+            } else {
+                renamable_members
+            };
+        let min_members = settings.min_members;
+        if renamable_members.len() < min_members {
+            info!(
+                "Not shuffling channel {channel_id} in guild {guild_id} because it has fewer than {min_members} members"
+            );
+            iter(renamable_members)
+                .for_each_concurrent(None, |member| {
+                    namerestorer::restore_member(&self.db, &ctx.http, guild_id, member.user.id)
+                })
+                .await;
+            session::clear(&self.db, guild_id, channel_id);
+            return;
+        }
+        let source_names: Vec<_> = renamable_members
+            .iter()
+            .map(|member| {
+                self.sources
+                    .name(guild_id, member.user.id, guild.presence(member.user.id))
+            })
+            .collect();
+        let histories: Vec<_> = renamable_members
+            .iter()
+            .map(|member| history::recent(&self.db, guild_id, member.user.id))
+            .collect();
+        // Small channels otherwise hand people the same champion again all the time.
+        let derangement = session::assignments(
+            &self.db,
+            guild_id,
+            channel_id,
+            &renamable_members
+                .iter()
+                .map(|member| member.user.id)
+                .collect::<Vec<_>>(),
+            |member, from| {
+                source_names[from]
+                    .as_ref()
+                    .is_some_and(|name| histories[member].contains(name))
+            },
+        );
+        // Anyone who drew a member with nothing to be named after is named after a stream going
+        // on in the channel instead, unless it's their own.
+        let streamer = renamable_members
+            .iter()
+            .enumerate()
+            .find_map(|(index, member)| {
+                let presence = guild.presence(member.user.id)?;
+                Some((index, self.sources.stream_name(guild_id, presence)?))
+            });
+        // The name each member gets and which member it came from.
+        let named_after: Vec<Option<(usize, String)>> = derangement
+            .iter()
+            .enumerate()
+            .map(|(member, &from)| match (&source_names[from], &streamer) {
+                (Some(name), _) => Some((from, name.clone())),
+                (None, Some((streamer, name))) if *streamer != member => {
+                    Some((*streamer, name.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        let name_overrides = self.names(Names::Override, guild_id);
+        let rejected_names = self
+            .db
+            .open_tree(rejected_names_db_tree_name(guild_id))
+            .unwrap();
+        let permanent_nicks = self.names(Names::Permanent, guild_id);
+        let profanity = ProfanityFilter::for_guild(&self.db, guild_id);
+        let missing_assets_policy: MissingAssetsPolicy =
+            get_setting(&self.db, guild_id, MISSING_ASSETS_SETTING).unwrap_or_default();
+        let template = settings.name_template;
+        // Members who keep their own or current name instead of getting one from someone.
+        let mut not_named_after = HashSet::new();
+        let new_nicks = {
+            let channel_name = guild.channel_name.as_str();
+            let new_nicks:Vec<_> = renamable_members.iter().enumerate().filter_map(|(user_id_index, member)| {
+                if let Some(nick) = permanent_nicks.get(&member.user.id) {
+                    info!("Selected permanent nick {nick} for {} ({})", member.user.name, member.user.id);
+                    return Some((member.user.id, Cow::Owned(sanitize_name(nick))));
+                }
+                let from_user = &renamable_members[derangement[user_id_index]].user;
+                let source_presence = guild.presence(from_user.id);
+                let source_activity = source_presence.and_then(|presence| self.games.activity(&presence.activities));
+                if let Some((started_at, champion)) = source_activity.and_then(|activity| {
+                    Some((activity.timestamps.as_ref()?.start?, current_champion_from_activity(activity, &self.champions)?))
+                }) {
+                    stats::record_play(&self.db, guild_id, from_user.id, started_at, champion);
+                }
+                let source_name = named_after[user_id_index].as_ref().map(|(_, name)| name.clone());
+                let source_champion_named = match (source_name, source_activity) {
+                    (Some(name), _) => Some(name),
+                    (None, Some(activity)) => {
+                        let missing_count = metrics::MISSING_ACTIVITY_ASSETS.increment();
+                        warn!("{} activity for {} ({}) has no name ({missing_count} so far), falling back to {missing_assets_policy}", activity.name, from_user.name, from_user.id);
+                        match missing_assets_policy {
+                            // Keep whatever they have now so the override survives the rewrite below.
+                            MissingAssetsPolicy::Skip => {
+                                not_named_after.insert(member.user.id);
+                                return name_overrides.get(&member.user.id).map(|nick| (member.user.id, Cow::Owned(nick.clone())));
+                            }
+                            MissingAssetsPolicy::FallbackToStored => None,
+                            MissingAssetsPolicy::FallbackToTheme => self.champions.random_name().map(str::to_string),
+                            MissingAssetsPolicy::UseActivityName => Some(activity.name.clone()),
+                        }
+                    }
+                    (None, None) => None,
+                }.filter(|name| {
+                    // Only the name handed out is checked, not the member's own name in the template.
+                    let profane = profanity.find(name);
+                    if let Some(word) = profane {
+                        info!("Not using {name} for {} ({}) because it contains {word}, falling back to their stored name", member.user.name, member.user.id);
+                    }
+                    profane.is_none()
+                }).map(|name| match &template {
+                    Some(template) => template.render(&TemplateValues {
+                        champion: &name,
+                        original: &self.name_cache.get(&self.db, guild_id, member.user.id).unwrap_or_else(|| member.user.name.clone()),
+                        username: &member.user.name,
+                        channel: channel_name,
+                    }),
+                    None => name,
+                }).map(|name| fit_nick(&sanitize_name(&name)).into_owned()).filter(|name| {
+                    let rejected = is_rejected(&rejected_names, name);
+                    if rejected {
+                        info!("Not using {name} because the server rejected it before");
+                    }
+                    !rejected
+                });
                 let new_nick = if let Some(champion) = source_champion_named {
                     info!(
                         "Selected champion {champion} (from {} ({})) as nick for {} ({})",
                         from_user.name, from_user.id, member.user.name, member.user.id
                     );
-                    // Allows us to drop guild which can't be held across await boundaries.
-                    Cow::Owned(champion.to_string())
-                } else if let Some(nick) = get_name(&names, DbKey::from(member.user.id) ){
+                    Cow::Owned(champion)
+                } else if let Some(nick) = self.name_cache.get_stored(&self.db, guild_id, member.user.id).map(|stored| stored.nickname().to_string()) {
+                    not_named_after.insert(member.user.id);
                     info!("Could not determine champion for {} ({}). Selected historical nick {nick} for {} ({})", from_user.name, from_user.id, member.user.name, member.user.id);
                     Cow::Owned(nick)
                 } else {
+                    not_named_after.insert(member.user.id);
                     info!("Could not determine champion for {} ({}). Selected username for {} ({})", from_user.name, from_user.id, member.user.name, member.user.id);
                     Cow::Borrowed(member.user.name.as_str())
                 };
-                (member.user.id, new_nick)
+                Some((member.user.id, new_nick))
             }).collect();
-            (names, new_nicks)
+            make_unique(new_nicks)
+        };
+        let sources: HashMap<UserId, String> = renamable_members
+            .iter()
+            .zip(&named_after)
+            .filter(|(member, _)| !permanent_nicks.contains_key(&member.user.id))
+            .filter_map(|(member, named_after)| {
+                Some((member.user.id, named_after.as_ref()?.1.clone()))
+            })
+            .collect();
+        for (user_id, name) in &sources {
+            history::record(&self.db, guild_id, *user_id, name);
+        }
+        let shuffled = new_nicks.iter().any(|(user_id, nick)| {
+            name_overrides.get(user_id).map(String::as_str) != Some(nick.as_ref())
+        });
+        let mapping: Vec<_> = if shuffled {
+            let named_after: HashMap<UserId, UserId> = renamable_members
+                .iter()
+                .zip(&named_after)
+                .filter_map(|(member, named_after)| {
+                    let (from, _) = named_after.as_ref()?;
+                    Some((member.user.id, renamable_members[*from].user.id))
+                })
+                .collect();
+            new_nicks
+                .iter()
+                .filter(|(user_id, _)| !not_named_after.contains(user_id))
+                .map(|(user_id, nick)| {
+                    let from = named_after
+                        .get(user_id)
+                        .copied()
+                        .filter(|_| !permanent_nicks.contains_key(user_id));
+                    (*user_id, nick.to_string(), from)
+                })
+                .collect()
         } else {
-            warn!("Failed to sync nicknames for guild {guild_id} because the guild wasn't found in the cache");
-            return;
+            Vec::new()
         };
+        // Members of the channel who ended up without a nickname, e.g. because they drew
+        // someone who isn't playing anything under the skip policy.
+        let unnamed: Vec<_> = renamable_members
+            .iter()
+            .map(|member| member.user.id)
+            .filter(|user_id| !new_nicks.iter().any(|(renamed, _)| renamed == user_id))
+            .collect();
+        if renamable_members.len() > self.options.large_channel_threshold {
+            // Too many renames to do at once. The rollout is persisted so there's no need to set
+            // the old nicks first.
+            let changed = self.replace_overrides(guild_id, &name_overrides, &unnamed, &new_nicks);
+            self.canary
+                .check(&ctx.http, &self.db, Names::Override, guild_id)
+                .await;
+            info!("Planning rollout of {} new nicknames", changed.len());
+            self.rollouts.cancel(guild_id, unnamed);
+            let old_nicks: HashMap<UserId, String> = renamable_members
+                .iter()
+                .map(|member| (member.user.id, member.display_name().to_string()))
+                .collect();
+            self.rollouts.plan(
+                &ctx.http,
+                guild_id,
+                changed.into_iter().map(|(user_id, nick)| {
+                    let rename = PlannedRename {
+                        nick: nick.to_string(),
+                        old: old_nicks.get(user_id).cloned(),
+                        source: sources.get(user_id).cloned(),
+                    };
+                    (*user_id, rename)
+                }),
+            );
+            if shuffled {
+                announce::announce_shuffle(&self.db, &ctx.http, guild_id, channel_id).await;
+                announce::announce_mapping(&self.db, &ctx.http, guild_id, channel_id, &mapping)
+                    .await;
+            }
+            return;
+        }
+        self.rollouts.cancel(
+            guild_id,
+            renamable_members.iter().map(|member| member.user.id),
+        );
         // First set to the old nicks so that if we crash, the old nick will stick.
-        let old_nicks: Vec<_> = members
+        let mut current: HashMap<UserId, String> = renamable_members
+            .iter()
+            .map(|member| (member.user.id, member.display_name().to_string()))
+            .collect();
+        // Members who already have their new nickname are left alone entirely.
+        let old_nicks: Vec<_> = new_nicks
             .iter()
-            .flat_map(|member| {
-                Some((
-                    member.user.id,
-                    get_name(&names, DbKey::from(member.user.id))?,
-                ))
+            .filter(|(user_id, nick)| current.get(user_id).map(String::as_str) != Some(nick))
+            .flat_map(|(user_id, _)| {
+                let stored = self.name_cache.get_stored(&self.db, guild_id, *user_id)?;
+                Some((*user_id, stored.nickname().to_string()))
             })
             .collect();
         info!("Setting old nicknames so they're saved if we encounter an error.");
-        set_nicks(ctx, guild_id, old_nicks).await;
-        let name_overrides = self
-            .db
-            .open_tree(name_overrides_db_tree_name(guild_id))
-            .unwrap();
-        // Clear and set the overrides. We want to record the overrides before we actually make the change just in case we crash in the middle.
-        name_overrides.clear().unwrap();
-        name_overrides
-            .apply_batch(make_name_batch(new_nicks.iter()))
-            .unwrap();
+        set_nicks(
+            &self.db,
+            ctx,
+            guild_id,
+            old_nicks.iter().map(|(user_id, nick)| (*user_id, nick)),
+            &current,
+            None,
+        )
+        .await;
+        current.extend(old_nicks);
+        // We want to record the overrides before we actually make the change just in case we crash in the middle.
+        self.replace_overrides(guild_id, &name_overrides, &unnamed, &new_nicks);
+        self.canary
+            .check(&ctx.http, &self.db, Names::Override, guild_id)
+            .await;
+        let renamed: Vec<_> =
+            if get_setting(&self.db, guild_id, DM_NOTIFICATIONS_SETTING).unwrap_or(false) {
+                new_nicks
+                    .iter()
+                    .filter_map(|(user_id, nick)| {
+                        let original = current.get(user_id)?;
+                        (original != nick).then(|| (*user_id, nick.to_string(), original.clone()))
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
         info!("Setting new nicknames");
-        set_nicks(ctx, guild_id, new_nicks).await;
+        let rejected =
+            set_nicks(&self.db, ctx, guild_id, new_nicks, &current, Some(&sources)).await;
+        if !renamed.is_empty() {
+            let guild_name = guild_id
+                .name(&ctx.cache)
+                .unwrap_or_else(|| "a server".to_string());
+            for (user_id, nick, original) in renamed {
+                if !rejected
+                    .iter()
+                    .any(|(rejected_id, _)| *rejected_id == user_id)
+                {
+                    self.dm_notifier.renamed(
+                        &ctx.http,
+                        guild_id,
+                        &guild_name,
+                        user_id,
+                        &nick,
+                        &original,
+                    );
+                }
+            }
+        }
+        if !rejected.is_empty() {
+            // Don't fight the server's moderation. Fall back to the stored names instead.
+            let fallbacks: Vec<_> = rejected
+                .iter()
+                .flat_map(|(user_id, nick)| {
+                    record_rejection(&self.db, guild_id, nick);
+                    let stored = self.name_cache.get_stored(&self.db, guild_id, *user_id)?;
+                    Some((*user_id, stored.nickname().to_string()))
+                })
+                .collect();
+            self.db
+                .set_names(Names::Override, guild_id, &bare_names(fallbacks.iter()));
+            set_nicks(&self.db, ctx, guild_id, fallbacks, &HashMap::new(), None).await;
+        }
+        if shuffled {
+            announce::announce_shuffle(&self.db, &ctx.http, guild_id, channel_id).await;
+            announce::announce_mapping(&self.db, &ctx.http, guild_id, channel_id, &mapping).await;
+        }
     }
 }
 
-pub async fn run(token: String, db: Db) {
+impl Handler {
+    /// The guild's names, read once so a sync doesn't go back to the store for every member.
+    fn names(&self, names: Names, guild_id: GuildId) -> HashMap<UserId, String> {
+        self.db
+            .names(names, guild_id)
+            .into_iter()
+            .map(|(user_id, stored)| (user_id, stored.name))
+            .collect()
+    }
+
+    /// Records the channel's new overrides and drops those of its members who didn't get one,
+    /// leaving other channels' overrides alone. Only the overrides that changed are written, so
+    /// unchanged ones keep counting towards their TTL. Returns the ones that changed.
+    fn replace_overrides<'a>(
+        &self,
+        guild_id: GuildId,
+        name_overrides: &HashMap<UserId, String>,
+        unnamed: &[UserId],
+        new_nicks: &'a [(UserId, Cow<'a, str>)],
+    ) -> Vec<&'a (UserId, Cow<'a, str>)> {
+        let changed: Vec<_> = new_nicks
+            .iter()
+            .filter(|(user_id, nick)| {
+                name_overrides.get(user_id).map(String::as_str) != Some(nick.as_ref())
+            })
+            .collect();
+        self.db.set_names(
+            Names::Override,
+            guild_id,
+            &bare_names(changed.iter().copied()),
+        );
+        for user_id in unnamed {
+            self.db.remove_name(Names::Override, guild_id, *user_id);
+        }
+        expiry::touch(
+            &self.db,
+            guild_id,
+            changed.iter().map(|(user_id, _)| *user_id),
+        );
+        changed
+    }
+}
+
+/// Whether the bot should rename people in the guild right now: it hasn't been disabled and
+/// it's within the guild's active hours.
+fn is_enabled(db: &Store, guild_id: GuildId) -> bool {
+    GuildSettings::load(db, guild_id).enabled && active_hours::is_active(db, guild_id)
+}
+
+/// Runs the bot until it's disconnected or `shutdown` completes, in which case everyone's
+/// original name is put back first.
+pub async fn run(
+    token: String,
+    db: Store,
+    options: BotOptions,
+    control_socket: PathBuf,
+    shutdown: impl Future<Output = ()>,
+) {
     let intents = GatewayIntents::GUILD_PRESENCES
         | GatewayIntents::GUILD_VOICE_STATES
         | GatewayIntents::GUILDS
-        | GatewayIntents::GUILD_MEMBERS;
+        | GatewayIntents::GUILD_MEMBERS
+        | GatewayIntents::GUILD_MESSAGE_REACTIONS;
 
+    set_dry_run(options.dry_run);
+    // Make sure the token works before doing anything else.
+    match Http::new(&token).get_bot_gateway().await {
+        Ok(gateway) => info!(
+            "Token is valid. Gateway {} recommends {} shards",
+            gateway.url, gateway.shards
+        ),
+        Err(e) => {
+            error!("Failed to reach the gateway with the configured token: {e:?}");
+            return;
+        }
+    }
+    let champions = Arc::new(ChampionIndex::load(&options.ddragon_cache).await);
+    let games = Arc::new(TrackedGames::new(
+        options.game_application_ids.clone(),
+        options.game_activity_names.clone(),
+    ));
+    let live_games = options.riot_api_key.clone().map(|api_key| {
+        let live_games = Arc::new(LiveGames::new(
+            Db::clone(&db),
+            champions.clone(),
+            api_key,
+            options.riot_platform.clone(),
+            options.riot_region.clone(),
+            Duration::from_secs(options.riot_poll_secs),
+        ));
+        live_games.spawn_poller();
+        live_games
+    });
+    let sources = name_source::build(
+        &options.name_sources,
+        &games,
+        &champions,
+        &db,
+        live_games.as_ref(),
+    );
+    let interaction_state = Arc::new(InteractionStore::new(&db));
+    interaction_state.spawn_sweeper();
+    let handler = Arc::new(Handler {
+        champions,
+        games,
+        sources,
+        pages: Paginator::new(interaction_state.clone()),
+        interaction_state,
+        name_cache: Arc::default(),
+        pending_restores: PendingRestores::new(Duration::from_secs(options.leave_grace_secs)),
+        sync_debouncer: SyncDebouncer::new(Duration::from_millis(options.sync_debounce_ms)),
+        channel_locks: ChannelLocks::default(),
+        playing: Default::default(),
+        last_rerolls: Default::default(),
+        shard_stages: Default::default(),
+        voice_channels: VoiceChannels::default(),
+        dm_notifier: DmNotifier::new(Duration::from_secs(options.dm_cooldown_mins * 60)),
+        canary: ShrinkCanary::new(
+            options.shrink_alert_fraction,
+            options.alert_channel.map(ChannelId::new),
+        ),
+        rollouts: RolloutPlanner::new(
+            db.clone(),
+            Duration::from_secs(options.large_channel_rollout_secs),
+        ),
+        db,
+        options: options.clone(),
+    });
+    let db = handler.db.clone();
     let mut client = Client::builder(token, intents)
-        .event_handler(Handler { db })
+        .event_handler_arc(handler.clone())
         .await
         .expect("Error creating client");
+    if let Some(owner_id) = options.owner_id {
+        OWNER.configure(client.http.clone(), UserId::new(owner_id));
+    }
+    owner::spawn_db_check(Db::clone(&db));
+    if options.tui {
+        tasks::spawn("tui", tui::run(db.clone(), client.cache.clone()));
+    }
+    #[cfg(unix)]
+    let listening = match control::bind(&control_socket).await {
+        Some(listener) => {
+            tasks::spawn(
+                "control socket",
+                control::serve(
+                    listener,
+                    db.clone(),
+                    client.http.clone(),
+                    handler.name_cache.clone(),
+                    handler.clone(),
+                ),
+            );
+            true
+        }
+        None => false,
+    };
+    #[cfg(not(unix))]
+    let listening = false;
+    if let Some(addr) = options.http_addr {
+        let admin = options.admin_token.clone().map(|admin_token| {
+            dashboard::router(
+                db.clone(),
+                client.http.clone(),
+                client.cache.clone(),
+                admin_token.clone(),
+            )
+            .merge(api::router(
+                db.clone(),
+                client.http.clone(),
+                client.cache.clone(),
+                admin_token,
+            ))
+        });
+        tasks::spawn(
+            "http",
+            web::serve(
+                addr,
+                handler.clone(),
+                handler,
+                options.debug_token.clone(),
+                admin,
+            ),
+        );
+    }
+    retry::spawn_drainer(db.clone(), client.http.clone());
+    expiry::spawn_sweeper(db.clone(), client.http.clone(), options.override_ttl_hours);
+    active_hours::spawn_sweeper(db.clone(), client.http.clone());
+    departure::spawn_sweeper(
+        db.clone(),
+        client.cache.clone(),
+        Duration::from_secs(options.left_guild_grace_hours * 60 * 60),
+    );
+    if !options.activity.is_empty() {
+        presence::spawn_updater(
+            db.clone(),
+            client.cache.clone(),
+            client.shard_manager.clone(),
+            options.activity_kind,
+            options.activity.clone(),
+            Duration::from_secs(options.activity_interval_secs),
+        );
+    }
 
-    if let Err(why) = client.start().await {
-        println!("Client error: {:?}", why);
+    let shard_manager = client.shard_manager.clone();
+    let http = client.http.clone();
+    tokio::select! {
+        result = async {
+            match options.shards {
+                Some(shards) => client.start_shards(shards).await,
+                None => client.start_autosharded().await,
+            }
+        } => {
+            if let Err(why) = result {
+                println!("Client error: {:?}", why);
+            }
+        }
+        _ = shutdown => {
+            info!("Shutting down, restoring original names");
+            shard_manager.shutdown_all().await;
+            namerestorer::restore_overridden_with(&http, &db, options.dry_run).await;
+        }
+    }
+    if options.tui {
+        tui::restore();
+    }
+    // A socket we didn't bind may belong to another bot.
+    if listening {
+        if let Err(e) = std::fs::remove_file(&control_socket) {
+            warn!("Failed to remove control socket {control_socket:?}: {e}");
+        }
     }
 }
@@ -1,45 +1,75 @@
 use futures::{stream::iter, StreamExt};
-use itertools::{Either, Itertools};
-use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use serenity::{
-    all::EditMember,
     http::Http,
-    model::prelude::{GuildId, UserId},
+    model::prelude::{GuildId, Timestamp, UserId},
 };
-use sled::{Batch, Db};
+use tracing::{debug, info, warn};
 
-use crate::db::{get_name, name_overrides_db_tree_name, DbKey, NameOverridesDbTreeNameType};
+use crate::{
+    audit,
+    db::{
+        nick_to_restore, parse_guild_db_tree_name, rollouts_db_tree_name, unset_name, DbKey,
+        NameSource, StoredName, AUDIT_DB_TREE_PREFIX, ROLLOUTS_DB_TREE_PREFIX,
+    },
+    nickname::{apply_nick, NickOutcome},
+    retry,
+    scheduler::MAX_CONCURRENT_EDITS,
+    store::{NameStore, Names, Store},
+};
+
+/// A name a restore put back, or would put back in a dry run.
+#[derive(Serialize, Deserialize)]
+pub struct Restored {
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    /// The nickname set, empty if it was cleared.
+    pub name: String,
+}
 
-pub async fn restore_overridden(token: String, db: Db) {
+pub async fn restore_overridden(token: String, db: Store, dry_run: bool) -> Vec<Restored> {
+    restore_overridden_with(&Http::new(&token), &db, dry_run).await
+}
+
+/// Puts back the original names of everyone whose nickname the bot changed. A dry run only
+/// works out who that would be, without renaming anyone or changing the database.
+pub async fn restore_overridden_with(http: &Http, db: &Store, dry_run: bool) -> Vec<Restored> {
     struct OverriddenUserName {
         user_id: UserId,
         guild_id: GuildId,
         original_name: String,
         overridden_name: String,
     }
-    let http = Http::new(&token);
-    let overridden_names = db
-        .tree_names()
+    // Don't let a pending rollout put the overrides back once the bot starts again.
+    if !dry_run {
+        for name in db.tree_names() {
+            if let Some((ROLLOUTS_DB_TREE_PREFIX, _)) = parse_guild_db_tree_name(&name) {
+                info!("Dropping {name:?}");
+                db.drop_tree(name).unwrap();
+            }
+        }
+    }
+    let overridden_names: Vec<OverriddenUserName> = db
+        .guilds()
         .into_iter()
-        .filter_map(|name| -> Option<NameOverridesDbTreeNameType> { name.as_ref().try_into().ok() })
-        .flat_map(|name| {
-            let [_, key @ ..] = name;
-            let guild_id_db_key = DbKey(key);
-            let guild_id: GuildId = guild_id_db_key.clone().into();
-            let names = db.open_tree(guild_id_db_key.as_ref()).unwrap();
-            let name_overrides = db.open_tree(name).unwrap();
-            name_overrides.into_iter().map(move |result| {
-                let (key, value) = result.unwrap();
-                let user_id = DbKey(key.as_ref().try_into().unwrap());
-                OverriddenUserName {
-                    guild_id,
-                    user_id: user_id.into(),
-                    original_name: get_name(&names, user_id).unwrap(),
-                    overridden_name: String::from_utf8(value.to_vec()).unwrap(),
-                }
-            })
-        });
-    futures::stream::iter(overridden_names)
+        .flat_map(|guild_id| {
+            db.names(Names::Override, guild_id).into_iter().filter_map(
+                move |(user_id, overridden_name)| {
+                    let Some(original_name) = nick_to_restore(db, guild_id, user_id) else {
+                        warn!("Not restoring {user_id} in guild {guild_id}, their stored name can't be read");
+                        return None;
+                    };
+                    Some(OverriddenUserName {
+                        guild_id,
+                        user_id,
+                        original_name,
+                        overridden_name: overridden_name.name,
+                    })
+                },
+            )
+        })
+        .collect();
+    let forgotten = futures::stream::iter(overridden_names)
         .map(
             |OverriddenUserName {
                  guild_id,
@@ -47,7 +77,6 @@ pub async fn restore_overridden(token: String, db: Db) {
                  original_name,
                  overridden_name,
              }| {
-                let http = &http;
                 async move {
                     if http
                         .get_member(guild_id, user_id)
@@ -55,81 +84,202 @@ pub async fn restore_overridden(token: String, db: Db) {
                         .map(|member| member.display_name() == overridden_name.as_str())
                         .unwrap_or(false)
                     {
+                        if dry_run {
+                            return Some((guild_id, user_id, Some(original_name)));
+                        }
                         info!("Attempting to replace {overridden_name} with {original_name} to {user_id}");
-                        match guild_id
-                            .edit_member(http, user_id, EditMember::new().nickname(original_name))
-                            .await
-                        {
-                            Err(e) => {
-                                warn!("Failed to update {user_id} {e}");
+                        match apply_nick(http, guild_id, user_id, &original_name).await {
+                            NickOutcome::Applied => Some((guild_id, user_id, Some(original_name))),
+                            _ => {
+                                warn!("Failed to update {user_id}");
                                 None
-                            },
-                            Ok(_) => Some((guild_id, user_id))
+                            }
                         }
                     } else {
-                        Some((guild_id, user_id))
+                        // They changed their nickname since, so there's nothing to put back.
+                        Some((guild_id, user_id, None))
                     }
                 }
             },
         )
-        .buffer_unordered(10)
+        .buffer_unordered(MAX_CONCURRENT_EDITS)
         .flat_map(futures::stream::iter)
         .collect::<Vec<_>>()
-        .await
+        .await;
+    if !dry_run {
+        for (guild_id, user_id, _) in &forgotten {
+            db.remove_name(Names::Override, *guild_id, *user_id);
+        }
+    }
+    forgotten
         .into_iter()
-        .into_grouping_map()
-        .fold(Batch::default(), |mut batch, _key, value| {
-            batch.remove(DbKey::from(value).as_ref());
-            batch
+        .filter_map(|(guild_id, user_id, name)| {
+            Some(Restored {
+                guild_id,
+                user_id,
+                name: name?,
+            })
         })
-        .into_iter()
-        .for_each(|(guild_id, batch)| {
-            db.open_tree(name_overrides_db_tree_name(guild_id))
-                .unwrap()
-                .apply_batch(batch)
-                .unwrap()
-        });
+        .collect()
 }
 
-pub async fn run(token: String, db: Db) {
-    let http = Http::new(&token);
-    let (name_trees, name_override_tree_names): (Vec<_>, Vec<_>) = db
-        .tree_names()
-        .into_iter()
-        .filter(|name| name != &db.name())
-        .partition_map(|name| {
-            match name
-                .as_ref()
-                .try_into()
-                .map(|key| -> GuildId { DbKey(key).into() })
-            {
-                Ok(guild_id) => Either::Left((guild_id, db.open_tree(name).unwrap())),
-                Err(_) => Either::Right(name),
-            }
-        });
-    let names = name_trees.into_iter().flat_map(|(guild_id, tree)| {
-        tree.into_iter().map(move |result| {
-            let (key, value) = result.unwrap();
-            (
-                guild_id.clone(),
-                UserId::from(DbKey(key.as_ref().try_into().unwrap())),
-                String::from_utf8(value.to_vec()).unwrap(),
-            )
+pub async fn run(token: String, db: Store, dry_run: bool) -> Vec<Restored> {
+    run_with(&Http::new(&token), &db, dry_run).await
+}
+
+/// Puts back every stored name, whether or not the bot changed it, and forgets the overrides.
+/// A dry run only lists the names, without renaming anyone or changing the database.
+pub async fn run_with(http: &Http, db: &Store, dry_run: bool) -> Vec<Restored> {
+    let guilds = db.guilds();
+    let names: Vec<Restored> = guilds
+        .iter()
+        .flat_map(|&guild_id| {
+            db.names(Names::Original, guild_id)
+                .into_iter()
+                .filter_map(move |(user_id, _)| {
+                    let Some(name) = nick_to_restore(db, guild_id, user_id) else {
+                        warn!("Not restoring {user_id} in guild {guild_id}, their stored name can't be read");
+                        return None;
+                    };
+                    Some(Restored {
+                        guild_id,
+                        user_id,
+                        name,
+                    })
+                })
         })
-    });
-    iter(names).for_each_concurrent(10, |(guild_id, user_id, name)| {
-        let http=&http;
+        .collect();
+    if dry_run {
+        return names;
+    }
+    iter(&names).for_each_concurrent(None, |Restored { guild_id, user_id, name }| {
         async move {
             debug!("Setting user with id {user_id} to name {name} in guild {guild_id}.");
-            if let Err(e) = guild_id
-                .edit_member(http, user_id, EditMember::new().nickname(&name))
-                .await {
-                    warn!("Failed to restore user with id {user_id} to name {name} in guild {guild_id}. {e}");
-                }
+            if !matches!(apply_nick(http, *guild_id, *user_id, name).await, NickOutcome::Applied) {
+                warn!("Failed to restore user with id {user_id} to name {name} in guild {guild_id}.");
+            }
         }
     }).await;
-    for tree_name in name_override_tree_names {
-        info!("Dropping {tree_name:?}");
-        db.drop_tree(tree_name).unwrap();
+    for guild_id in guilds {
+        info!("Forgetting overrides and any rollout in guild {guild_id}");
+        db.clear_names(Names::Override, guild_id);
+        db.drop_tree(rollouts_db_tree_name(guild_id)).unwrap();
     }
+    names
+}
+
+pub async fn restore_as_of(
+    token: String,
+    db: Store,
+    as_of: Timestamp,
+    dry_run: bool,
+) -> Vec<Restored> {
+    restore_as_of_with(&Http::new(&token), &db, as_of, dry_run).await
+}
+
+/// Puts back the names members had chosen themselves at the given time according to the audit
+/// history, and stores them as their names from now on. Their overrides and pending rollout
+/// renames are dropped. A dry run only lists the names, without renaming anyone or changing the
+/// database.
+pub async fn restore_as_of_with(
+    http: &Http,
+    db: &Store,
+    as_of: Timestamp,
+    dry_run: bool,
+) -> Vec<Restored> {
+    let as_of_ms = (as_of.unix_timestamp() * 1000).try_into().unwrap_or(0);
+    let mut restored = vec![];
+    for name in db.tree_names() {
+        if let Some((AUDIT_DB_TREE_PREFIX, guild_id)) = parse_guild_db_tree_name(&name) {
+            let history = db.open_tree(&name).unwrap();
+            restored.extend(audit::members(&history).into_iter().filter_map(|user_id| {
+                Some(Restored {
+                    guild_id,
+                    user_id,
+                    name: audit::own_name_as_of(&history, user_id, as_of_ms)?,
+                })
+            }));
+        }
+    }
+    if dry_run {
+        return restored;
+    }
+    info!("Restoring {} names as of {as_of}", restored.len());
+    iter(&restored)
+        .for_each_concurrent(None, |Restored { guild_id, user_id, name }| {
+            async move {
+                debug!("Setting {user_id} to {name} in guild {guild_id}");
+                match apply_nick(http, *guild_id, *user_id, name).await {
+                    NickOutcome::Applied => {
+                        db.set_name(
+                            Names::Original,
+                            *guild_id,
+                            *user_id,
+                            &StoredName::new(name, NameSource::Restore),
+                        );
+                        // Their override and any pending rollout rename are what we replaced.
+                        unset_name(db, *guild_id, *user_id, true);
+                        db.open_tree(rollouts_db_tree_name(*guild_id))
+                            .unwrap()
+                            .remove(DbKey::from(*user_id))
+                            .unwrap();
+                    }
+                    _ => warn!(
+                        "Failed to restore user with id {user_id} to name {name} in guild {guild_id}."
+                    ),
+                }
+            }
+        })
+        .await;
+    restored
+}
+
+/// Drops a member's override and, if they had one, puts their stored name back.
+pub async fn restore_member(db: &Store, http: &Http, guild_id: GuildId, user_id: UserId) {
+    if db.remove_name(Names::Override, guild_id, user_id).is_none() {
+        return;
+    }
+    if let Some(name) = nick_to_restore(db, guild_id, user_id) {
+        info!("Restoring {user_id} to {name} in guild {guild_id}");
+        if let NickOutcome::Applied = apply_nick(http, guild_id, user_id, &name).await {
+            retry::dequeue(db, guild_id, user_id);
+        } else {
+            warn!("Failed to restore user with id {user_id} to name {name} in guild {guild_id}.");
+            retry::enqueue(db, guild_id, user_id, &name);
+        }
+    }
+}
+
+/// Puts back the user's stored name in every guild the bot renamed them in.
+pub async fn restore_user(db: &Store, http: &Http, user_id: UserId) {
+    iter(db.guilds())
+        .for_each_concurrent(None, |guild_id| restore_member(db, http, guild_id, user_id))
+        .await;
+}
+
+/// Cancels the guild's rollout and restores everyone the bot renamed in it.
+pub async fn restore_guild(db: &Store, http: &Http, guild_id: GuildId) {
+    db.open_tree(rollouts_db_tree_name(guild_id))
+        .unwrap()
+        .clear()
+        .unwrap();
+    restore_guild_members(db, http, guild_id, |_| true).await;
+}
+
+/// Restores every overridden member of the guild picked out by `should_restore`.
+pub async fn restore_guild_members<F: Fn(UserId) -> bool>(
+    db: &Store,
+    http: &Http,
+    guild_id: GuildId,
+    should_restore: F,
+) {
+    let overridden: Vec<UserId> = db
+        .names(Names::Override, guild_id)
+        .into_iter()
+        .map(|(user_id, _)| user_id)
+        .filter(|user_id| should_restore(*user_id))
+        .collect();
+    iter(overridden)
+        .for_each_concurrent(None, |user_id| restore_member(db, http, guild_id, user_id))
+        .await;
 }
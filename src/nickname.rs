@@ -0,0 +1,319 @@
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serenity::{
+    all::EditMember,
+    http::{Http, HttpError},
+    model::prelude::{GuildId, UserId},
+    Error,
+};
+use sled::{Db, Tree};
+use tracing::{debug, info, warn};
+
+use crate::{
+    breaker::BREAKERS,
+    db::{rejected_names_db_tree_name, unrenamable_db_tree_name, DbKey},
+    metrics, retry,
+    scheduler::EDITS,
+    stats,
+};
+
+/// Discord's limit on nickname length.
+pub const MAX_NAME_CHARS: usize = 32;
+
+/// Shortens a nickname to Discord's limit, cutting at a word boundary and adding an ellipsis
+/// when part of it had to go. Discord would otherwise refuse the whole edit.
+pub fn fit_nick(nick: &str) -> Cow<'_, str> {
+    let nick = nick.trim();
+    if nick.chars().count() <= MAX_NAME_CHARS {
+        return Cow::Borrowed(nick);
+    }
+    // Leave room for the ellipsis.
+    let cut: String = nick.chars().take(MAX_NAME_CHARS - 1).collect();
+    let at_boundary = cut
+        .rfind(|c: char| c.is_whitespace() || c == '-')
+        // Don't throw away most of the name just to end on a whole word.
+        .filter(|&end| cut[..end].chars().count() >= MAX_NAME_CHARS / 2)
+        .map_or(cut.as_str(), |end| &cut[..end]);
+    Cow::Owned(format!(
+        "{}…",
+        at_boundary.trim_end_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation())
+    ))
+}
+
+/// Numbers repeated nicknames ("Jinx", "Jinx 2") so nobody in the channel shares a name with
+/// anyone else. Comparisons ignore case. Empty nicknames clear the nickname, so they're left as
+/// they are.
+pub fn make_unique<'a>(nicks: Vec<(UserId, Cow<'a, str>)>) -> Vec<(UserId, Cow<'a, str>)> {
+    let mut taken: HashSet<String> = HashSet::new();
+    let mut unique = Vec::with_capacity(nicks.len());
+    // Plain names are claimed first so a numbered name can't take one someone else needs.
+    let plain: Vec<bool> = nicks
+        .iter()
+        .map(|(_, nick)| nick.is_empty() || taken.insert(nick.to_lowercase()))
+        .collect();
+    for ((user_id, nick), plain) in nicks.into_iter().zip(plain) {
+        if plain {
+            unique.push((user_id, nick));
+            continue;
+        }
+        let numbered = (2..)
+            .map(|n| {
+                let suffix = format!(" {n}");
+                let room = MAX_NAME_CHARS.saturating_sub(suffix.chars().count());
+                let base: String = nick.chars().take(room).collect();
+                format!("{}{suffix}", base.trim_end())
+            })
+            .find(|numbered| !taken.contains(&numbered.to_lowercase()))
+            .unwrap();
+        info!("Renaming duplicate {nick} for {user_id} to {numbered}");
+        taken.insert(numbered.to_lowercase());
+        unique.push((user_id, Cow::Owned(numbered)));
+    }
+    unique
+}
+
+/// Discord's error codes for content blocked by AutoMod.
+const AUTOMOD_BLOCKED_CODES: &[isize] = &[200000, 200001];
+/// Discord's error code for an edit we don't have permission to make.
+const MISSING_PERMISSIONS_CODE: isize = 50013;
+/// How long to leave a member we weren't allowed to rename alone before trying again.
+const UNRENAMABLE_RECHECK: Duration = Duration::from_secs(6 * 60 * 60);
+/// How long a nickname the server rejected stays off the table. Moderation rules change, and
+/// some rejections are only inferred from what the server applied instead.
+const REJECTION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Set by `--dry-run`, in which nickname edits are only logged.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// In a dry run, logs the edit that would have been made and returns true so the caller skips
+/// it.
+pub fn skip_edit(guild_id: GuildId, user_id: UserId, nick: &str) -> bool {
+    let dry_run = is_dry_run();
+    if dry_run {
+        info!("Dry run: would set the nickname of {user_id} in guild {guild_id} to {nick}");
+    }
+    dry_run
+}
+
+pub enum NickOutcome {
+    Applied,
+    /// The server's moderation refused the nickname, either outright or by applying something
+    /// else instead.
+    Rejected,
+    /// We aren't allowed to change the member's nickname.
+    Forbidden,
+    Failed,
+}
+
+/// What the stats need to know about an edit that hands out a shuffled name, as for
+/// `stats::record_rename`. Names being put back aren't counted.
+#[derive(Clone, Copy)]
+pub struct CountedRename<'a> {
+    pub old: Option<&'a str>,
+    pub source: Option<&'a str>,
+}
+
+/// Records what came of setting the member's nickname: in the stats if the edit is `counted`,
+/// and in the retry queue and the members we can't rename. Rejections are left to the caller,
+/// which knows what to fall back to.
+pub fn record_outcome(
+    db: &Db,
+    guild_id: GuildId,
+    user_id: UserId,
+    nick: &str,
+    outcome: &NickOutcome,
+    counted: Option<CountedRename>,
+) {
+    match (outcome, counted) {
+        (_, None) => {}
+        (NickOutcome::Applied, Some(CountedRename { old, source })) => {
+            stats::record_rename(db, guild_id, user_id, old, nick, source)
+        }
+        _ => stats::record_rename_failure(db, guild_id, user_id, nick),
+    }
+    match outcome {
+        NickOutcome::Rejected => {}
+        NickOutcome::Applied => {
+            retry::dequeue(db, guild_id, user_id);
+            clear_unrenamable(db, guild_id, user_id);
+        }
+        NickOutcome::Forbidden => {
+            record_unrenamable(db, guild_id, user_id);
+            retry::dequeue(db, guild_id, user_id);
+        }
+        NickOutcome::Failed => match counted {
+            Some(CountedRename { old, source }) => {
+                retry::enqueue_rename(db, guild_id, user_id, nick, old, source)
+            }
+            None => retry::enqueue(db, guild_id, user_id, nick),
+        },
+    }
+}
+
+/// Sets a member's nickname and checks that it actually stuck.
+pub async fn apply_nick(
+    http: &Http,
+    guild_id: GuildId,
+    user_id: UserId,
+    nick: &str,
+) -> NickOutcome {
+    if BREAKERS.is_open(guild_id) {
+        debug!("Not setting nickname for {user_id} while guild {guild_id} is paused");
+        return NickOutcome::Failed;
+    }
+    if skip_edit(guild_id, user_id, nick) {
+        return NickOutcome::Applied;
+    }
+    EDITS.acquire(guild_id).await;
+    info!("Setting nickname to {nick} for {user_id}");
+    match guild_id
+        .edit_member(http, user_id, EditMember::new().nickname(nick))
+        .await
+    {
+        // An empty nickname clears it, which leaves the member without one.
+        Ok(member)
+            if member.nick.as_deref().unwrap_or(if nick.is_empty() {
+                ""
+            } else {
+                member.user.name.as_str()
+            }) == nick =>
+        {
+            info!("Successfully set nickname for {user_id}");
+            BREAKERS.record_success(guild_id);
+            NickOutcome::Applied
+        }
+        Ok(member) => {
+            warn!(
+                "Set nickname {nick} for {user_id} but the server applied {:?} instead",
+                member.nick
+            );
+            NickOutcome::Rejected
+        }
+        Err(Error::Http(HttpError::UnsuccessfulRequest(response)))
+            if AUTOMOD_BLOCKED_CODES.contains(&response.error.code) =>
+        {
+            warn!(
+                "Nickname {nick} for {user_id} was blocked: {}",
+                response.error.message
+            );
+            NickOutcome::Rejected
+        }
+        Err(Error::Http(HttpError::UnsuccessfulRequest(response)))
+            if response.error.code == MISSING_PERMISSIONS_CODE =>
+        {
+            // Could be just this member outranking the bot, who's left alone for a while. Each
+            // refused member still counts toward the breaker, so a guild that took away
+            // Manage Nicknames is paused once several in a row are refused.
+            warn!("Not allowed to set the nickname of {user_id}");
+            BREAKERS.record_failure(guild_id, true);
+            NickOutcome::Forbidden
+        }
+        Err(e) => {
+            warn!("Failed to set nickname for {user_id}: {e:?}");
+            if let Error::Http(HttpError::UnsuccessfulRequest(response)) = &e {
+                match response.status_code.as_u16() {
+                    403 => BREAKERS.record_failure(guild_id, true),
+                    429 => BREAKERS.record_failure(guild_id, false),
+                    _ => {}
+                }
+            }
+            NickOutcome::Failed
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Remembers that the guild won't accept a nickname so we stop trying to hand it out for a
+/// while.
+pub fn record_rejection(db: &Db, guild_id: GuildId, nick: &str) {
+    let count = metrics::REJECTED_NICKNAMES.increment();
+    warn!("Recording rejected nickname {nick} in guild {guild_id} ({count} so far)");
+    db.open_tree(rejected_names_db_tree_name(guild_id))
+        .unwrap()
+        .insert(nick, &now_secs().to_be_bytes())
+        .unwrap();
+}
+
+/// Whether a rejection recorded with `value` still stands. Entries from before rejections
+/// expired have no timestamp and count as expired.
+fn rejection_live(value: &[u8]) -> bool {
+    value
+        .try_into()
+        .map(u64::from_be_bytes)
+        .is_ok_and(|since| now_secs().saturating_sub(since) < REJECTION_TTL.as_secs())
+}
+
+/// Remembers that we aren't allowed to rename the member, so we leave them alone for a while.
+pub fn record_unrenamable(db: &Db, guild_id: GuildId, user_id: UserId) {
+    info!("Leaving {user_id} in guild {guild_id} alone for {UNRENAMABLE_RECHECK:?}");
+    db.open_tree(unrenamable_db_tree_name(guild_id))
+        .unwrap()
+        .insert(DbKey::from(user_id), &now_secs().to_be_bytes())
+        .unwrap();
+}
+
+pub fn clear_unrenamable(db: &Db, guild_id: GuildId, user_id: UserId) {
+    db.open_tree(unrenamable_db_tree_name(guild_id))
+        .unwrap()
+        .remove(DbKey::from(user_id))
+        .unwrap();
+}
+
+/// Whether we recently failed to rename the member for lack of permission.
+pub fn is_unrenamable(unrenamable: &Tree, user_id: UserId) -> bool {
+    let Some(since) = unrenamable
+        .get(DbKey::from(user_id))
+        .unwrap()
+        .and_then(|since| Some(u64::from_be_bytes(since.as_ref().try_into().ok()?)))
+    else {
+        return false;
+    };
+    now_secs().saturating_sub(since) < UNRENAMABLE_RECHECK.as_secs()
+}
+
+/// Whether the server rejected the nickname recently. Expired rejections are removed.
+pub fn is_rejected(rejected_names: &Tree, nick: &str) -> bool {
+    let Some(since) = rejected_names
+        .get(nick)
+        .inspect_err(|e| warn!("Failed to check whether {nick} was rejected: {e}"))
+        .unwrap_or(None)
+    else {
+        return false;
+    };
+    let live = rejection_live(&since);
+    if !live {
+        info!("Rejection of {nick} expired, it can be handed out again");
+        let _ = rejected_names.compare_and_swap(nick, Some(since), None::<&[u8]>);
+    }
+    live
+}
+
+/// The nicknames the guild rejected recently.
+pub fn rejected_names(db: &Db, guild_id: GuildId) -> Vec<String> {
+    db.open_tree(rejected_names_db_tree_name(guild_id))
+        .unwrap()
+        .iter()
+        .filter_map(Result::ok)
+        .filter(|(_, since)| rejection_live(since))
+        .filter_map(|(nick, _)| String::from_utf8(nick.to_vec()).ok())
+        .collect()
+}
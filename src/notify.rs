@@ -0,0 +1,82 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serenity::{
+    all::CreateMessage,
+    http::Http,
+    model::prelude::{GuildId, UserId},
+    utils::MessageBuilder,
+};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::tasks;
+
+/// DMs members the bot renamed, at most once per `cooldown` per member and guild so a busy
+/// evening doesn't flood their inbox.
+pub struct DmNotifier {
+    cooldown: Duration,
+    last_sent: Mutex<HashMap<(GuildId, UserId), Instant>>,
+}
+
+impl DmNotifier {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            last_sent: Mutex::default(),
+        }
+    }
+
+    /// Tells the member about their new name in the background, unless they were told recently.
+    pub fn renamed(
+        &self,
+        http: &Arc<Http>,
+        guild_id: GuildId,
+        guild_name: &str,
+        user_id: UserId,
+        nick: &str,
+        original: &str,
+    ) {
+        let now = Instant::now();
+        {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            if last_sent
+                .get(&(guild_id, user_id))
+                .is_some_and(|sent| now.duration_since(*sent) < self.cooldown)
+            {
+                return;
+            }
+            last_sent.insert((guild_id, user_id), now);
+            last_sent.retain(|_, sent| now.duration_since(*sent) < self.cooldown);
+        }
+        let content = MessageBuilder::new()
+            .push("Your nickname in ")
+            .push_bold_safe(guild_name)
+            .push(" is now ")
+            .push_bold_safe(nick)
+            .push(" while you're in voice. You'll get ")
+            .push_bold_safe(original)
+            .push(" back when you leave. Use ")
+            .push_mono("/namechanger optout")
+            .push(" in the server if you'd rather keep your own name.")
+            .build();
+        let http = http.clone();
+        tasks::spawn(&format!("dm {user_id}"), async move {
+            info!("Telling {user_id} about their new name in guild {guild_id}");
+            let sent = match user_id.create_dm_channel(&http).await {
+                Ok(channel) => channel
+                    .send_message(&http, CreateMessage::new().content(content))
+                    .await
+                    .map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                // Plenty of people don't accept DMs from server members.
+                warn!("Failed to DM {user_id}: {e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex, OnceLock},
+    time::Duration,
+};
+
+use serenity::{
+    all::CreateMessage,
+    http::Http,
+    model::prelude::{GuildId, UserId},
+};
+use sled::Db;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::tasks;
+
+/// The least time between two DMs about the same kind of problem in the same guild.
+const ALERT_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+/// How often to check that the database can still be read and written.
+const DB_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// What a problem is about, and which guild it's in if any.
+type AlertKey = (&'static str, Option<GuildId>);
+
+/// DMs whoever hosts the bot when it runs into something they have to fix themselves, since
+/// otherwise the bot just quietly stops renaming people.
+pub struct OwnerAlerts {
+    owner: OnceLock<(Arc<Http>, UserId)>,
+    last_sent: LazyLock<Mutex<HashMap<AlertKey, Instant>>>,
+}
+
+pub static OWNER: OwnerAlerts = OwnerAlerts {
+    owner: OnceLock::new(),
+    last_sent: LazyLock::new(Mutex::default),
+};
+
+impl OwnerAlerts {
+    /// Sends alerts to `owner` from now on.
+    pub fn configure(&self, http: Arc<Http>, owner: UserId) {
+        if self.owner.set((http, owner)).is_err() {
+            warn!("Owner alerts were already configured, ignoring {owner}");
+        }
+    }
+
+    /// DMs the owner about the problem in the background, unless they were told about the same
+    /// `kind` of problem in the guild recently. Does nothing if there's no owner to tell.
+    pub fn alert(&self, kind: &'static str, guild_id: Option<GuildId>, problem: String) {
+        let Some((http, owner)) = self.owner.get() else {
+            return;
+        };
+        let now = Instant::now();
+        {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            if last_sent
+                .get(&(kind, guild_id))
+                .is_some_and(|sent| now.duration_since(*sent) < ALERT_COOLDOWN)
+            {
+                return;
+            }
+            last_sent.insert((kind, guild_id), now);
+        }
+        let (http, owner) = (http.clone(), *owner);
+        tasks::spawn("owner alert", async move {
+            info!("Alerting owner {owner}: {problem}");
+            let sent = match owner.create_dm_channel(&http).await {
+                Ok(channel) => channel
+                    .send_message(&http, CreateMessage::new().content(problem))
+                    .await
+                    .map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                warn!("Failed to DM owner {owner}: {e}");
+            }
+        });
+    }
+}
+
+/// Periodically reads every tree and flushes the database, alerting the owner if sled reports
+/// corruption or can't write to disk.
+pub fn spawn_db_check(db: Db) {
+    tasks::spawn("db check", async move {
+        let mut interval = tokio::time::interval(DB_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let checked = tokio::task::spawn_blocking({
+                let db = db.clone();
+                move || db.checksum()
+            })
+            .await
+            .unwrap();
+            let flushed = match checked {
+                Ok(_) => db.flush_async().await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = flushed {
+                error!("Database check failed: {e}");
+                OWNER.alert(
+                    "database",
+                    None,
+                    format!("The bot's database failed a check and may be corrupted: {e}. Check the disk it lives on and the logs."),
+                );
+            }
+        }
+    });
+}
@@ -0,0 +1,110 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serenity::{
+    all::{
+        ButtonStyle, ComponentInteraction, CreateActionRow, CreateButton, CreateEmbed,
+        CreateEmbedFooter, CreateInteractionResponse, CreateInteractionResponseMessage,
+    },
+    prelude::*,
+};
+use tracing::warn;
+
+use crate::interaction_state::InteractionStore;
+
+const LINES_PER_PAGE: usize = 15;
+const CUSTOM_ID_PREFIX: &str = "page:";
+const STATE_KIND: &str = "listing";
+const LISTING_TTL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Serialize, Deserialize)]
+struct Listing {
+    title: String,
+    pages: Vec<String>,
+}
+
+/// Keeps long listings server side so they can be paged through with buttons.
+pub struct Paginator {
+    state: Arc<InteractionStore>,
+}
+
+impl Paginator {
+    pub fn new(state: Arc<InteractionStore>) -> Self {
+        Self { state }
+    }
+
+    /// Builds the first page of a new listing.
+    pub fn start<I: IntoIterator<Item = String>>(
+        &self,
+        title: impl Into<String>,
+        lines: I,
+    ) -> CreateInteractionResponseMessage {
+        let lines: Vec<String> = lines.into_iter().collect();
+        let mut pages: Vec<String> = lines
+            .chunks(LINES_PER_PAGE)
+            .map(|chunk| chunk.join("\n"))
+            .collect();
+        if pages.is_empty() {
+            pages.push("Nothing to show.".to_string());
+        }
+        let listing = Listing {
+            title: title.into(),
+            pages,
+        };
+        let id = self.state.insert(STATE_KIND, &listing, LISTING_TTL);
+        render(id, &listing, 0)
+    }
+
+    /// Handles a page button. Returns false if the interaction wasn't for a listing.
+    pub async fn handle(&self, ctx: &Context, component: &ComponentInteraction) -> bool {
+        let Some((id, page)) = component
+            .data
+            .custom_id
+            .strip_prefix(CUSTOM_ID_PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+            .and_then(|(id, page)| Some((id.parse().ok()?, page.parse().ok()?)))
+        else {
+            return false;
+        };
+        let message = match self.state.get::<Listing>(STATE_KIND, id) {
+            Some(listing) => render(id, &listing, page),
+            None => CreateInteractionResponseMessage::new()
+                .content("This listing has expired. Run the command again.")
+                .embeds(vec![])
+                .components(vec![]),
+        };
+        if let Err(e) = component
+            .create_response(&ctx.http, CreateInteractionResponse::UpdateMessage(message))
+            .await
+        {
+            warn!("Failed to turn the page: {e:?}");
+        }
+        true
+    }
+}
+
+fn render(id: u64, listing: &Listing, page: usize) -> CreateInteractionResponseMessage {
+    let page = page.min(listing.pages.len() - 1);
+    let button = |label: &str, target: usize, disabled: bool| {
+        CreateButton::new(format!("{CUSTOM_ID_PREFIX}{id}:{target}"))
+            .label(label)
+            .style(ButtonStyle::Secondary)
+            .disabled(disabled)
+    };
+    CreateInteractionResponseMessage::new()
+        .ephemeral(true)
+        .embed(
+            CreateEmbed::new()
+                .title(&listing.title)
+                .description(&listing.pages[page])
+                .footer(CreateEmbedFooter::new(format!(
+                    "Page {} of {}",
+                    page + 1,
+                    listing.pages.len()
+                ))),
+        )
+        .components(vec![CreateActionRow::Buttons(vec![
+            button("Previous", page.saturating_sub(1), page == 0),
+            button("Next", page + 1, page + 1 >= listing.pages.len()),
+        ])])
+}
@@ -0,0 +1,83 @@
+use std::{sync::Arc, time::Duration};
+
+use clap::ValueEnum;
+use serenity::{cache::Cache, gateway::ActivityData, gateway::ShardManager};
+use tracing::debug;
+
+use crate::{
+    session,
+    store::{NameStore, Names, Store},
+    tasks,
+};
+
+/// How the bot's activity reads in the member list.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ActivityKind {
+    Playing,
+    Listening,
+    Watching,
+    Competing,
+    /// Just the text, without a verb in front.
+    Custom,
+}
+
+impl ActivityKind {
+    fn activity(self, text: String) -> ActivityData {
+        match self {
+            Self::Playing => ActivityData::playing(text),
+            Self::Listening => ActivityData::listening(text),
+            Self::Watching => ActivityData::watching(text),
+            Self::Competing => ActivityData::competing(text),
+            Self::Custom => ActivityData::custom(text),
+        }
+    }
+}
+
+/// Fills in the template's `{servers}`, `{channels}` and `{renamed}` with how many servers the
+/// bot is in, how many voice channels it's renaming people in and how many people it renamed.
+fn render(template: &str, db: &Store, cache: &Cache) -> String {
+    let guilds = cache.guilds();
+    let channels: usize = guilds
+        .iter()
+        .map(|guild_id| session::guild_assignments(db, *guild_id).len())
+        .sum();
+    let renamed: usize = guilds
+        .iter()
+        .map(|guild_id| db.count_names(Names::Override, *guild_id))
+        .sum();
+    template
+        .replace("{servers}", &guilds.len().to_string())
+        .replace("{channels}", &channels.to_string())
+        .replace("{renamed}", &renamed.to_string())
+}
+
+/// Keeps the bot's activity on every shard up to date. Only sends it again when it changed.
+pub fn spawn_updater(
+    db: Store,
+    cache: Arc<Cache>,
+    shard_manager: Arc<ShardManager>,
+    kind: ActivityKind,
+    template: String,
+    interval: Duration,
+) {
+    tasks::spawn("presence", async move {
+        let mut interval = tokio::time::interval(interval);
+        let mut last = None;
+        loop {
+            interval.tick().await;
+            let text = render(&template, &db, &cache);
+            let runners = shard_manager.runners.lock().await;
+            // Shards that connected since the last update haven't got it yet.
+            if last.as_ref() == Some(&(text.clone(), runners.len())) {
+                continue;
+            }
+            debug!("Setting activity to {text}");
+            for runner in runners.values() {
+                runner
+                    .runner_tx
+                    .set_activity(Some(kind.activity(text.clone())));
+            }
+            last = Some((text, runners.len()));
+        }
+    });
+}
@@ -0,0 +1,92 @@
+use serenity::model::prelude::GuildId;
+
+use crate::{
+    db::{get_setting, NameList, PROFANITY_BUILTIN_SETTING, PROFANITY_WORDS_SETTING},
+    store::Store,
+};
+
+/// Common profanity and the most common slurs, used by guilds that turn the built-in list on.
+/// Guilds add anything else to their own list.
+const BUILTIN_WORDS: &[&str] = &[
+    "asshole",
+    "bastard",
+    "bitch",
+    "bollocks",
+    "bullshit",
+    "cock",
+    "cunt",
+    "dick",
+    "dickhead",
+    "fag",
+    "faggot",
+    "fuck",
+    "fucker",
+    "fucking",
+    "motherfucker",
+    "nigga",
+    "nigger",
+    "piss",
+    "prick",
+    "pussy",
+    "retard",
+    "shit",
+    "slut",
+    "twat",
+    "wanker",
+    "whore",
+];
+
+/// Lowercases the word and undoes common letter substitutions like `5h1t`.
+fn normalize(word: &str) -> String {
+    word.chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | '!' => 'i',
+            '3' => 'e',
+            '4' | '@' => 'a',
+            '5' | '$' => 's',
+            '7' => 't',
+            c => c,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Keeps offensive names from being handed out. Matches whole words so champions like
+/// Cassiopeia aren't caught by a word they happen to contain.
+pub struct ProfanityFilter {
+    words: Vec<String>,
+}
+
+impl ProfanityFilter {
+    pub fn for_guild(db: &Store, guild_id: GuildId) -> Self {
+        let NameList(mut words) =
+            get_setting(db, guild_id, PROFANITY_WORDS_SETTING).unwrap_or_default();
+        if get_setting(db, guild_id, PROFANITY_BUILTIN_SETTING).unwrap_or(false) {
+            words.extend(BUILTIN_WORDS.iter().map(|word| word.to_string()));
+        }
+        Self {
+            words: words.iter().map(|word| normalize(word)).collect(),
+        }
+    }
+
+    /// The listed word the name contains, if any.
+    pub fn find(&self, name: &str) -> Option<&str> {
+        if self.words.is_empty() {
+            return None;
+        }
+        let name = normalize(name);
+        let name_words: Vec<&str> = name
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        self.words
+            .iter()
+            .find(|word| {
+                // Listed phrases match runs of words.
+                let phrase: Vec<&str> = word.split_whitespace().collect();
+                !phrase.is_empty() && name_words.windows(phrase.len()).any(|run| run == phrase)
+            })
+            .map(String::as_str)
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use redis::{Client, Commands, Connection, ConnectionLike};
+use serenity::model::prelude::{GuildId, UserId};
+use tracing::warn;
+
+use crate::{
+    db::StoredName,
+    store::{decode_name, NameStore, Names},
+};
+
+/// Every key the bot uses starts with this.
+const PREFIX: &str = "namechanger";
+
+/// Names and settings kept on a Redis server, so several processes can share them. Each guild
+/// has a hash per kind of name (user id → name, the way `Names::encode` writes it) and one for
+/// settings, and a set lists the guilds.
+pub struct RedisStore {
+    client: Client,
+    connection: Mutex<Connection>,
+}
+
+impl RedisStore {
+    pub fn open(url: &str) -> redis::RedisResult<Self> {
+        let client = Client::open(url)?;
+        Ok(RedisStore {
+            connection: Mutex::new(client.get_connection()?),
+            client,
+        })
+    }
+
+    /// The connection, reconnecting first if the server dropped it. A running bot outlives
+    /// restarts of the server, and a command that failed on the old connection mustn't take
+    /// every later one down with it.
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        let mut connection = self
+            .connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !connection.is_open() {
+            match self.client.get_connection() {
+                Ok(reconnected) => *connection = reconnected,
+                Err(e) => warn!("Failed to reconnect to Redis: {e}"),
+            }
+        }
+        connection
+    }
+}
+
+fn guilds_key() -> String {
+    format!("{PREFIX}:guilds")
+}
+
+fn names_key(names: Names, guild_id: GuildId) -> String {
+    let kind = match names {
+        Names::Original => "original",
+        Names::Override => "override",
+        Names::Permanent => "permanent",
+    };
+    format!("{PREFIX}:{guild_id}:names:{kind}")
+}
+
+fn settings_key(guild_id: GuildId) -> String {
+    format!("{PREFIX}:{guild_id}:settings")
+}
+
+impl NameStore for RedisStore {
+    fn guilds(&self) -> Vec<GuildId> {
+        let guild_ids: Vec<u64> = self.connection().smembers(guilds_key()).unwrap();
+        let mut guilds: Vec<GuildId> = guild_ids.into_iter().map(GuildId::new).collect();
+        guilds.sort();
+        guilds
+    }
+
+    fn get_name(&self, names: Names, guild_id: GuildId, user_id: UserId) -> Option<StoredName> {
+        let value: Option<Vec<u8>> = self
+            .connection()
+            .hget(names_key(names, guild_id), user_id.get())
+            .unwrap();
+        decode_name(guild_id, &value?)
+    }
+
+    fn set_name(&self, names: Names, guild_id: GuildId, user_id: UserId, name: &StoredName) {
+        redis::pipe()
+            .atomic()
+            .sadd(guilds_key(), guild_id.get())
+            .hset(
+                names_key(names, guild_id),
+                user_id.get(),
+                names.encode(name),
+            )
+            .exec(&mut self.connection())
+            .unwrap();
+    }
+
+    fn remove_name(&self, names: Names, guild_id: GuildId, user_id: UserId) -> Option<StoredName> {
+        let key = names_key(names, guild_id);
+        let (removed,): (Option<Vec<u8>>,) = redis::pipe()
+            .atomic()
+            .hget(&key, user_id.get())
+            .hdel(&key, user_id.get())
+            .ignore()
+            .query(&mut self.connection())
+            .unwrap();
+        decode_name(guild_id, &removed?)
+    }
+
+    fn names(&self, names: Names, guild_id: GuildId) -> Vec<(UserId, StoredName)> {
+        let mut values: Vec<(u64, Vec<u8>)> = self
+            .connection()
+            .hgetall(names_key(names, guild_id))
+            .unwrap();
+        values.sort();
+        values
+            .into_iter()
+            .filter_map(|(user_id, value)| {
+                Some((UserId::new(user_id), decode_name(guild_id, &value)?))
+            })
+            .collect()
+    }
+
+    fn clear_names(&self, names: Names, guild_id: GuildId) {
+        let _: () = self.connection().del(names_key(names, guild_id)).unwrap();
+    }
+
+    fn get_setting(&self, guild_id: GuildId, key: &str) -> Option<String> {
+        self.connection().hget(settings_key(guild_id), key).unwrap()
+    }
+
+    fn set_setting(&self, guild_id: GuildId, key: &str, value: &str) {
+        redis::pipe()
+            .atomic()
+            .sadd(guilds_key(), guild_id.get())
+            .hset(settings_key(guild_id), key, value)
+            .exec(&mut self.connection())
+            .unwrap();
+    }
+
+    fn remove_setting(&self, guild_id: GuildId, key: &str) {
+        let _: () = self.connection().hdel(settings_key(guild_id), key).unwrap();
+    }
+
+    fn settings(&self, guild_id: GuildId) -> Vec<(String, String)> {
+        let mut settings: Vec<(String, String)> =
+            self.connection().hgetall(settings_key(guild_id)).unwrap();
+        settings.sort();
+        settings
+    }
+
+    fn clear_settings(&self, guild_id: GuildId) {
+        let _: () = self.connection().del(settings_key(guild_id)).unwrap();
+    }
+
+    fn persist(&self) {
+        // How durable writes are is up to the server's persistence settings.
+    }
+}
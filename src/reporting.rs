@@ -0,0 +1,108 @@
+#[cfg(feature = "sentry")]
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "sentry")]
+use sentry::integrations::tracing::EventFilter;
+use tracing::Subscriber;
+#[cfg(feature = "sentry")]
+use tracing::{callsite::Identifier, Level, Metadata};
+#[cfg(not(feature = "sentry"))]
+use tracing_subscriber::layer::Identity;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+/// How many times the same warning has to come up within [`REPEAT_WINDOW`] to be reported.
+#[cfg(feature = "sentry")]
+const REPEAT_THRESHOLD: u32 = 5;
+#[cfg(feature = "sentry")]
+const REPEAT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Keeps error reporting running. Dropping it flushes anything not yet sent.
+#[cfg(feature = "sentry")]
+pub type Guard = Option<sentry::ClientInitGuard>;
+#[cfg(not(feature = "sentry"))]
+pub struct Guard;
+
+/// Starts reporting panics and errors to Sentry if a DSN was given.
+pub fn init(dsn: Option<&str>) -> Guard {
+    #[cfg(feature = "sentry")]
+    return dsn.map(|dsn| {
+        sentry::init((
+            dsn,
+            sentry::ClientOptions {
+                release: sentry::release_name!(),
+                ..Default::default()
+            },
+        ))
+    });
+    #[cfg(not(feature = "sentry"))]
+    if dsn.is_some() {
+        // Logging isn't set up yet.
+        eprintln!("--sentry-dsn requires building with --features sentry");
+    }
+    #[cfg(not(feature = "sentry"))]
+    Guard
+}
+
+/// Sends errors, and warnings that keep coming up, to Sentry. Other warnings and info logs are
+/// attached to them as breadcrumbs, along with the fields of the spans they were logged in,
+/// like the guild and channel of a sync.
+#[cfg(feature = "sentry")]
+pub fn layer<S>(enabled: bool) -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    enabled.then(|| {
+        sentry::integrations::tracing::layer().event_filter(|metadata| match *metadata.level() {
+            Level::ERROR => EventFilter::Event,
+            Level::WARN if WARNINGS.repeated(metadata) => EventFilter::Event,
+            Level::WARN | Level::INFO => EventFilter::Breadcrumb,
+            _ => EventFilter::Ignore,
+        })
+    })
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn layer<S>(_enabled: bool) -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    None::<Identity>
+}
+
+/// How often each warning has come up lately, by where it's logged.
+#[cfg(feature = "sentry")]
+struct RepeatedWarnings {
+    seen: LazyLock<Mutex<HashMap<Identifier, (Instant, u32)>>>,
+}
+
+#[cfg(feature = "sentry")]
+static WARNINGS: RepeatedWarnings = RepeatedWarnings {
+    seen: LazyLock::new(Mutex::default),
+};
+
+#[cfg(feature = "sentry")]
+impl RepeatedWarnings {
+    /// Whether this warning just reached the threshold. It starts counting again afterwards so
+    /// a warning that never stops is reported once per threshold, not on every line.
+    fn repeated(&self, metadata: &Metadata) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        let (since, count) = seen.entry(metadata.callsite()).or_insert((now, 0));
+        if now.duration_since(*since) > REPEAT_WINDOW {
+            *since = now;
+            *count = 0;
+        }
+        *count += 1;
+        if *count >= REPEAT_THRESHOLD {
+            *count = 0;
+            *since = now;
+            true
+        } else {
+            false
+        }
+    }
+}
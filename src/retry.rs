@@ -0,0 +1,198 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serenity::{
+    http::Http,
+    model::prelude::{GuildId, UserId},
+};
+use sled::Db;
+use tracing::{info, warn};
+
+use crate::{
+    db::{
+        parse_guild_db_tree_name, pending_renames_db_tree_name, DbKey,
+        PENDING_RENAMES_DB_TREE_PREFIX,
+    },
+    encryption,
+    nickname::{apply_nick, record_rejection, record_unrenamable, NickOutcome},
+    stats,
+    store::Store,
+    tasks,
+};
+
+const DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+const FIRST_BACKOFF_MS: u64 = 5_000;
+const MAX_BACKOFF_MS: u64 = 60 * 60 * 1000;
+const MAX_ATTEMPTS: u32 = 10;
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingRename {
+    nick: String,
+    attempts: u32,
+    next_attempt_ms: u64,
+    /// Set for shuffled names, which go into the stats. Names being put back don't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    counted: Option<CountedRename>,
+}
+
+/// What the stats need to know about a shuffled name.
+#[derive(Serialize, Deserialize)]
+struct CountedRename {
+    old: Option<String>,
+    source: Option<String>,
+}
+impl PendingRename {
+    fn encode(&self) -> Vec<u8> {
+        encryption::seal(&serde_json::to_string(self).unwrap())
+    }
+    fn decode(value: &[u8]) -> Option<PendingRename> {
+        serde_json::from_str(&encryption::open(value).ok()?).ok()
+    }
+}
+
+/// Remembers a nickname edit that failed so it's tried again later, even after a restart.
+/// Replaces any edit already waiting for the member.
+pub fn enqueue(db: &Db, guild_id: GuildId, user_id: UserId, nick: &str) {
+    queue(db, guild_id, user_id, nick, None);
+}
+
+/// Like `enqueue`, for a shuffled name, which is counted in the stats once it's applied.
+/// `old` and `source` are as for `stats::record_rename`.
+pub fn enqueue_rename(
+    db: &Db,
+    guild_id: GuildId,
+    user_id: UserId,
+    nick: &str,
+    old: Option<&str>,
+    source: Option<&str>,
+) {
+    let counted = CountedRename {
+        old: old.map(str::to_string),
+        source: source.map(str::to_string),
+    };
+    queue(db, guild_id, user_id, nick, Some(counted));
+}
+
+fn queue(db: &Db, guild_id: GuildId, user_id: UserId, nick: &str, counted: Option<CountedRename>) {
+    info!("Queueing a retry of {nick} for {user_id} in guild {guild_id}");
+    let pending = PendingRename {
+        nick: nick.to_string(),
+        attempts: 0,
+        next_attempt_ms: now_ms() + FIRST_BACKOFF_MS,
+        counted,
+    };
+    db.open_tree(pending_renames_db_tree_name(guild_id))
+        .unwrap()
+        .insert(DbKey::from(user_id), pending.encode())
+        .unwrap();
+}
+
+/// The nickname waiting to be retried for the member, and how many times it's failed.
+pub fn pending(db: &Db, guild_id: GuildId, user_id: UserId) -> Option<(String, u32)> {
+    let pending = db
+        .open_tree(pending_renames_db_tree_name(guild_id))
+        .unwrap()
+        .get(DbKey::from(user_id))
+        .unwrap()?;
+    let PendingRename { nick, attempts, .. } = PendingRename::decode(&pending)?;
+    Some((nick, attempts))
+}
+
+/// Drops the member's waiting edit, since a newer one went through.
+pub fn dequeue(db: &Db, guild_id: GuildId, user_id: UserId) {
+    db.open_tree(pending_renames_db_tree_name(guild_id))
+        .unwrap()
+        .remove(DbKey::from(user_id))
+        .unwrap();
+}
+
+/// Retries the queued edits as they come due, backing off after each failure.
+pub fn spawn_drainer(db: Store, http: Arc<Http>) {
+    tasks::spawn("rename retry queue", async move {
+        let mut interval = tokio::time::interval(DRAIN_INTERVAL);
+        loop {
+            interval.tick().await;
+            drain(&db, &http).await;
+        }
+    });
+}
+
+async fn drain(db: &Store, http: &Http) {
+    for name in db.tree_names() {
+        let Some((PENDING_RENAMES_DB_TREE_PREFIX, guild_id)) = parse_guild_db_tree_name(&name)
+        else {
+            continue;
+        };
+        let pending_renames = db.open_tree(name).unwrap();
+        for entry in pending_renames.iter() {
+            let (key, value) = entry.unwrap();
+            let Ok(user_key) = key.as_ref().try_into() else {
+                continue;
+            };
+            let user_id = UserId::from(DbKey(user_key));
+            let Some(mut pending) = PendingRename::decode(&value) else {
+                warn!("Dropping corrupt pending rename for {user_id} in guild {guild_id}");
+                pending_renames.remove(&key).unwrap();
+                continue;
+            };
+            if pending.next_attempt_ms > now_ms() {
+                continue;
+            }
+            let outcome = apply_nick(http, guild_id, user_id, &pending.nick).await;
+            match (&outcome, &pending.counted) {
+                (_, None) => {}
+                (NickOutcome::Applied, Some(CountedRename { old, source })) => {
+                    stats::record_rename(
+                        db,
+                        guild_id,
+                        user_id,
+                        old.as_deref(),
+                        &pending.nick,
+                        source.as_deref(),
+                    )
+                }
+                _ => stats::record_rename_failure(db, guild_id, user_id, &pending.nick),
+            }
+            let next = match outcome {
+                NickOutcome::Applied => None,
+                NickOutcome::Rejected => {
+                    record_rejection(db, guild_id, &pending.nick);
+                    None
+                }
+                NickOutcome::Forbidden => {
+                    record_unrenamable(db, guild_id, user_id);
+                    None
+                }
+                NickOutcome::Failed if pending.attempts + 1 >= MAX_ATTEMPTS => {
+                    warn!(
+                        "Giving up on {} for {user_id} in guild {guild_id} after {MAX_ATTEMPTS} attempts",
+                        pending.nick
+                    );
+                    None
+                }
+                NickOutcome::Failed => {
+                    pending.attempts += 1;
+                    pending.next_attempt_ms = now_ms()
+                        + FIRST_BACKOFF_MS
+                            .saturating_mul(1 << pending.attempts)
+                            .min(MAX_BACKOFF_MS);
+                    Some(pending.encode())
+                }
+            };
+            // Leave it alone if a newer edit was queued while this one was being tried.
+            let _ = pending_renames
+                .compare_and_swap(&key, Some(&value), next)
+                .unwrap();
+        }
+    }
+}
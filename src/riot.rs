@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serenity::model::prelude::{GuildId, Presence, UserId};
+use sled::Db;
+use tracing::{debug, info, warn};
+
+use crate::{
+    db::{
+        get_name, parse_guild_db_tree_name, riot_accounts_db_tree_name, DbKey,
+        RIOT_ACCOUNTS_DB_TREE_PREFIX,
+    },
+    ddragon::ChampionIndex,
+    name_source::NameSource,
+    tasks,
+};
+
+/// Riot's limit for development keys is 100 requests every two minutes.
+const REQUEST_SPACING: Duration = Duration::from_millis(1200);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Account {
+    puuid: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Participant {
+    puuid: Option<String>,
+    champion_id: i64,
+}
+
+#[derive(Deserialize)]
+struct ActiveGame {
+    participants: Vec<Participant>,
+}
+
+/// Splits a Riot ID like `Faker#KR1` into its name and tag.
+pub fn parse_riot_id(riot_id: &str) -> Option<(&str, &str)> {
+    let (name, tag) = riot_id.trim().rsplit_once('#')?;
+    (!name.is_empty() && !tag.is_empty()).then_some((name, tag))
+}
+
+/// Looks up linked members' live League games with the Riot API, for players who hide their
+/// rich presence.
+pub struct LiveGames {
+    db: Db,
+    champions: Arc<ChampionIndex>,
+    client: reqwest::Client,
+    api_key: String,
+    /// Platform host for the spectator API, like `na1`.
+    platform: String,
+    /// Regional host for the account API, like `americas`.
+    region: String,
+    poll_interval: Duration,
+    /// Riot ID → PUUID.
+    puuids: Mutex<HashMap<String, String>>,
+    /// PUUID → the champion they're playing right now.
+    playing: Mutex<HashMap<String, String>>,
+}
+
+impl LiveGames {
+    pub fn new(
+        db: Db,
+        champions: Arc<ChampionIndex>,
+        api_key: String,
+        platform: String,
+        region: String,
+        poll_interval: Duration,
+    ) -> Self {
+        Self {
+            db,
+            champions,
+            client: reqwest::Client::new(),
+            api_key,
+            platform,
+            region,
+            poll_interval,
+            puuids: Mutex::default(),
+            playing: Mutex::default(),
+        }
+    }
+
+    /// Keeps checking every linked account for a live game.
+    pub fn spawn_poller(self: &Arc<Self>) {
+        let live_games = self.clone();
+        tasks::spawn("riot live game poller", async move {
+            let mut interval = tokio::time::interval(live_games.poll_interval);
+            loop {
+                interval.tick().await;
+                live_games.poll().await;
+            }
+        });
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>>(&self, url: String) -> reqwest::Result<Option<T>> {
+        tokio::time::sleep(REQUEST_SPACING).await;
+        let response = self
+            .client
+            .get(url)
+            .header("X-Riot-Token", &self.api_key)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        response.error_for_status()?.json().await.map(Some)
+    }
+
+    async fn puuid(&self, riot_id: &str) -> Option<String> {
+        if let Some(puuid) = self.puuids.lock().unwrap().get(riot_id) {
+            return Some(puuid.clone());
+        }
+        let (name, tag) = parse_riot_id(riot_id)?;
+        let url = format!(
+            "https://{}.api.riotgames.com/riot/account/v1/accounts/by-riot-id/{name}/{tag}",
+            self.region
+        );
+        match self.get::<Account>(url).await {
+            Ok(Some(account)) => {
+                self.puuids
+                    .lock()
+                    .unwrap()
+                    .insert(riot_id.to_string(), account.puuid.clone());
+                Some(account.puuid)
+            }
+            Ok(None) => {
+                warn!("There's no Riot account {riot_id}");
+                None
+            }
+            Err(e) => {
+                warn!("Failed to look up Riot account {riot_id}: {e}");
+                None
+            }
+        }
+    }
+
+    async fn poll(&self) {
+        let mut riot_ids: Vec<String> = self
+            .db
+            .tree_names()
+            .into_iter()
+            .filter(|name| {
+                parse_guild_db_tree_name(name)
+                    .is_some_and(|(prefix, _)| prefix == RIOT_ACCOUNTS_DB_TREE_PREFIX)
+            })
+            .flat_map(|name| self.db.open_tree(name).unwrap().iter().values())
+            .filter_map(|riot_id| String::from_utf8(riot_id.ok()?.to_vec()).ok())
+            .collect();
+        riot_ids.sort_unstable();
+        riot_ids.dedup();
+        let mut playing = HashMap::new();
+        for riot_id in riot_ids {
+            let Some(puuid) = self.puuid(&riot_id).await else {
+                continue;
+            };
+            // Someone else's lookup already found them in a game.
+            if playing.contains_key(&puuid) {
+                continue;
+            }
+            let url = format!(
+                "https://{}.api.riotgames.com/lol/spectator/v5/active-games/by-summoner/{puuid}",
+                self.platform
+            );
+            match self.get::<ActiveGame>(url).await {
+                Ok(Some(game)) => {
+                    for participant in game.participants {
+                        let (Some(puuid), Some(champion)) = (
+                            participant.puuid,
+                            self.champions.name_by_key(participant.champion_id),
+                        ) else {
+                            continue;
+                        };
+                        playing.insert(puuid, champion.to_string());
+                    }
+                }
+                Ok(None) => debug!("{riot_id} isn't in a game"),
+                Err(e) => warn!("Failed to look up the live game of {riot_id}: {e}"),
+            }
+        }
+        info!("{} linked players are in a live game", playing.len());
+        *self.playing.lock().unwrap() = playing;
+    }
+
+    /// The champion the member is playing according to the Riot API, if they linked an account.
+    pub fn champion(&self, guild_id: GuildId, user_id: UserId) -> Option<String> {
+        let riot_id = get_name(
+            &self
+                .db
+                .open_tree(riot_accounts_db_tree_name(guild_id))
+                .unwrap(),
+            DbKey::from(user_id),
+        )?;
+        let puuid = self.puuids.lock().unwrap().get(&riot_id)?.clone();
+        self.playing.lock().unwrap().get(&puuid).cloned()
+    }
+}
+
+/// The champion a linked member is playing according to the Riot spectator API. Works even if
+/// they hide their rich presence.
+pub struct SpectatorChampion {
+    pub live_games: Arc<LiveGames>,
+}
+
+impl NameSource for SpectatorChampion {
+    fn name(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        _presence: Option<&Presence>,
+    ) -> Option<String> {
+        self.live_games.champion(guild_id, user_id)
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serenity::{
+    http::Http,
+    model::prelude::{GuildId, UserId},
+};
+use sled::{Batch, IVec};
+use tracing::{info, warn};
+
+use crate::{
+    db::{nick_to_restore, rollouts_db_tree_name, DbKey, StoredName},
+    encryption,
+    nickname::{apply_nick, record_outcome, record_rejection, CountedRename, NickOutcome},
+    store::{NameStore, Names, Store},
+    tasks,
+};
+
+/// A rename waiting to be rolled out, with what the stats need to know about it.
+#[derive(Serialize, Deserialize)]
+pub struct PlannedRename {
+    pub nick: String,
+    /// As for `stats::record_rename`.
+    pub old: Option<String>,
+    pub source: Option<String>,
+}
+impl PlannedRename {
+    fn encode(&self) -> Vec<u8> {
+        encryption::seal(&serde_json::to_string(self).unwrap())
+    }
+    /// Renames planned before the rest was stored are just the nickname.
+    fn decode(value: &[u8]) -> Option<PlannedRename> {
+        let text = encryption::open(value).ok()?;
+        Some(serde_json::from_str(&text).unwrap_or(PlannedRename {
+            nick: text,
+            old: None,
+            source: None,
+        }))
+    }
+}
+
+/// Spreads the renames for large channels over a window of time instead of issuing them all at
+/// once. Pending renames live in a per-guild tree so a restart picks up where it left off.
+#[derive(Clone)]
+pub struct RolloutPlanner {
+    db: Store,
+    duration: Duration,
+    active: Arc<Mutex<HashSet<GuildId>>>,
+}
+
+impl RolloutPlanner {
+    pub fn new(db: Store, duration: Duration) -> Self {
+        Self {
+            db,
+            duration,
+            active: Arc::default(),
+        }
+    }
+
+    /// Records the renames and makes sure a rollout is running for the guild.
+    pub fn plan<I: IntoIterator<Item = (UserId, PlannedRename)>>(
+        &self,
+        http: &Arc<Http>,
+        guild_id: GuildId,
+        renames: I,
+    ) {
+        let mut batch = Batch::default();
+        for (user_id, rename) in renames {
+            batch.insert(&DbKey::from(user_id).0, rename.encode());
+        }
+        self.db
+            .open_tree(rollouts_db_tree_name(guild_id))
+            .unwrap()
+            .apply_batch(batch)
+            .unwrap();
+        self.resume(http, guild_id);
+    }
+
+    /// Drops any pending renames for the given users, e.g. because they're being renamed
+    /// immediately instead.
+    pub fn cancel<I: IntoIterator<Item = UserId>>(&self, guild_id: GuildId, user_ids: I) {
+        let mut batch = Batch::default();
+        for user_id in user_ids {
+            batch.remove(&DbKey::from(user_id).0);
+        }
+        self.db
+            .open_tree(rollouts_db_tree_name(guild_id))
+            .unwrap()
+            .apply_batch(batch)
+            .unwrap();
+    }
+
+    /// How many renames are waiting to be rolled out in the guild.
+    pub fn pending(&self, guild_id: GuildId) -> usize {
+        self.db
+            .open_tree(rollouts_db_tree_name(guild_id))
+            .unwrap()
+            .len()
+    }
+
+    pub fn is_active(&self, guild_id: GuildId) -> bool {
+        self.active.lock().unwrap().contains(&guild_id)
+    }
+
+    /// Starts working through the guild's pending renames unless that's already happening.
+    pub fn resume(&self, http: &Arc<Http>, guild_id: GuildId) {
+        let pending = self.db.open_tree(rollouts_db_tree_name(guild_id)).unwrap();
+        if pending.is_empty() || !self.active.lock().unwrap().insert(guild_id) {
+            return;
+        }
+        let planner = self.clone();
+        let http = http.clone();
+        tasks::spawn(&format!("rollout {guild_id}"), async move {
+            planner.roll_out(&http, guild_id).await;
+            planner.active.lock().unwrap().remove(&guild_id);
+            // Anything planned after the last check but before we deregistered would otherwise
+            // sit there until the next restart.
+            planner.resume(&http, guild_id);
+        });
+    }
+
+    async fn roll_out(&self, http: &Http, guild_id: GuildId) {
+        let pending = self.db.open_tree(rollouts_db_tree_name(guild_id)).unwrap();
+        loop {
+            let mut keys: Vec<IVec> = pending.iter().keys().filter_map(Result::ok).collect();
+            if keys.is_empty() {
+                break;
+            }
+            keys.shuffle(&mut rand::thread_rng());
+            let interval = self.duration / keys.len() as u32;
+            info!(
+                "Rolling out {} renames in guild {guild_id} over {:?}",
+                keys.len(),
+                self.duration
+            );
+            for key in keys {
+                tokio::time::sleep(interval).await;
+                // The entry may have been replaced or cancelled while we were waiting.
+                let Ok(Some(value)) = pending.get(&key) else {
+                    continue;
+                };
+                let (Ok(key_bytes), Some(rename)) =
+                    (key.as_ref().try_into(), PlannedRename::decode(&value))
+                else {
+                    warn!("Dropping corrupt rollout entry {key:?} in guild {guild_id}");
+                    pending.remove(&key).unwrap();
+                    continue;
+                };
+                let user_id = UserId::from(DbKey(key_bytes));
+                let nick_str = rename.nick;
+                info!("Rolling out nickname {nick_str} for {user_id}");
+                let outcome = apply_nick(http, guild_id, user_id, &nick_str).await;
+                let counted = CountedRename {
+                    old: rename.old.as_deref(),
+                    source: rename.source.as_deref(),
+                };
+                record_outcome(
+                    &self.db,
+                    guild_id,
+                    user_id,
+                    &nick_str,
+                    &outcome,
+                    Some(counted),
+                );
+                if let NickOutcome::Rejected = outcome {
+                    record_rejection(&self.db, guild_id, &nick_str);
+                    if let Some(fallback) = nick_to_restore(&self.db, guild_id, user_id) {
+                        self.db.set_name(
+                            Names::Override,
+                            guild_id,
+                            user_id,
+                            &StoredName::bare(&fallback),
+                        );
+                        apply_nick(http, guild_id, user_id, &fallback).await;
+                    }
+                }
+                // Only remove the entry if it hasn't been replaced by a newer plan in the meantime.
+                let _ = pending
+                    .compare_and_swap(&key, Some(value), None::<IVec>)
+                    .unwrap();
+            }
+        }
+        info!("Finished rollout in guild {guild_id}");
+    }
+}
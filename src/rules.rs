@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serenity::model::{gateway::Activity, prelude::GuildId, prelude::Presence};
+use sled::Db;
+use tracing::warn;
+
+use crate::{db::rules_db_tree_name, name_source::NameSource};
+
+/// The part of an activity a rule takes the name from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityField {
+    Name,
+    Details,
+    State,
+    LargeText,
+    SmallText,
+}
+
+impl ActivityField {
+    pub const ALL: [(&'static str, Self); 5] = [
+        ("name", Self::Name),
+        ("details", Self::Details),
+        ("state", Self::State),
+        ("large_text", Self::LargeText),
+        ("small_text", Self::SmallText),
+    ];
+
+    fn get(self, activity: &Activity) -> Option<&str> {
+        match self {
+            Self::Name => Some(&activity.name),
+            Self::Details => activity.details.as_deref(),
+            Self::State => activity.state.as_deref(),
+            Self::LargeText => activity.assets.as_ref()?.large_text.as_deref(),
+            Self::SmallText => activity.assets.as_ref()?.small_text.as_deref(),
+        }
+    }
+}
+
+/// Turns an activity into a name: activities from the application (or with a name matching
+/// the regex) have `pattern` matched against `field`, and the captures are substituted into
+/// `template` (`$1`, `$name` and so on).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub application_id: Option<u64>,
+    pub activity_name: Option<String>,
+    pub field: ActivityField,
+    pub pattern: String,
+    pub template: String,
+}
+
+struct CompiledRule {
+    application_id: Option<u64>,
+    activity_name: Option<Regex>,
+    field: ActivityField,
+    pattern: Regex,
+    template: String,
+}
+
+impl Rule {
+    fn compile(&self) -> Result<CompiledRule, regex::Error> {
+        Ok(CompiledRule {
+            application_id: self.application_id,
+            activity_name: self.activity_name.as_deref().map(Regex::new).transpose()?,
+            field: self.field,
+            pattern: Regex::new(&self.pattern)?,
+            template: self.template.clone(),
+        })
+    }
+}
+
+impl CompiledRule {
+    fn name_from_activity(&self, activity: &Activity) -> Option<String> {
+        let application_matches = self
+            .application_id
+            .is_none_or(|id| activity.application_id.is_some_and(|app| app.get() == id));
+        let name_matches = self
+            .activity_name
+            .as_ref()
+            .is_none_or(|name| name.is_match(&activity.name));
+        if !application_matches || !name_matches {
+            return None;
+        }
+        let captures = self.pattern.captures(self.field.get(activity)?)?;
+        let mut name = String::new();
+        captures.expand(&self.template, &mut name);
+        let name = name.trim();
+        (!name.is_empty()).then(|| name.to_string())
+    }
+}
+
+/// Each guild's rules, compiled the first time they're needed so presence updates don't
+/// recompile the regexes. Anything that changes a guild's rules has to `invalidate` it.
+static COMPILED: LazyLock<Mutex<HashMap<GuildId, Arc<Vec<CompiledRule>>>>> =
+    LazyLock::new(Mutex::default);
+
+/// The guild's valid rules in priority order.
+fn compiled(db: &Db, guild_id: GuildId) -> Arc<Vec<CompiledRule>> {
+    // Held while compiling so an invalidation can't be overwritten by rules read before it.
+    let mut compiled = COMPILED.lock().unwrap();
+    compiled
+        .entry(guild_id)
+        .or_insert_with(|| {
+            Arc::new(
+                list(db, guild_id)
+                    .into_iter()
+                    .filter_map(|(_, id, rule)| {
+                        rule.compile()
+                            .inspect_err(|e| warn!("Skipping rule {id} with an invalid regex: {e}"))
+                            .ok()
+                    })
+                    .collect(),
+            )
+        })
+        .clone()
+}
+
+/// Forgets the guild's compiled rules, so they're read again the next time they're needed.
+pub fn invalidate(guild_id: GuildId) {
+    COMPILED.lock().unwrap().remove(&guild_id);
+}
+
+/// Rules are keyed by priority and then id, so iterating the tree gives them in priority order.
+fn rule_key(priority: u32, id: u64) -> [u8; 12] {
+    let mut key = [0; 12];
+    key[..4].copy_from_slice(&priority.to_be_bytes());
+    key[4..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+/// Checks the rule's regexes and stores it. Returns the rule's id.
+pub fn add(db: &Db, guild_id: GuildId, priority: u32, rule: &Rule) -> Result<u64, String> {
+    if rule.application_id.is_none() && rule.activity_name.is_none() {
+        return Err("A rule needs an application id or an activity name regex.".to_string());
+    }
+    rule.compile().map_err(|e| format!("Invalid regex: {e}"))?;
+    let id = db.generate_id().unwrap();
+    db.open_tree(rules_db_tree_name(guild_id))
+        .unwrap()
+        .insert(rule_key(priority, id), serde_json::to_vec(rule).unwrap())
+        .unwrap();
+    invalidate(guild_id);
+    Ok(id)
+}
+
+/// Returns whether there was a rule with the id.
+pub fn remove(db: &Db, guild_id: GuildId, id: u64) -> bool {
+    let rules = db.open_tree(rules_db_tree_name(guild_id)).unwrap();
+    let key = rules
+        .iter()
+        .keys()
+        .filter_map(Result::ok)
+        .find(|key| key.len() == 12 && key[4..] == id.to_be_bytes());
+    let removed = key.is_some_and(|key| rules.remove(key).unwrap().is_some());
+    invalidate(guild_id);
+    removed
+}
+
+/// The guild's rules in priority order, with their priorities and ids.
+pub fn list(db: &Db, guild_id: GuildId) -> Vec<(u32, u64, Rule)> {
+    db.open_tree(rules_db_tree_name(guild_id))
+        .unwrap()
+        .iter()
+        .filter_map(|entry| {
+            let (key, value) = entry.ok()?;
+            let priority = u32::from_be_bytes(key.get(..4)?.try_into().ok()?);
+            let id = u64::from_be_bytes(key.get(4..)?.try_into().ok()?);
+            match serde_json::from_slice(&value) {
+                Ok(rule) => Some((priority, id, rule)),
+                Err(e) => {
+                    warn!("Corrupt rule {id} in guild {guild_id}: {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// The guild's activity rules, tried in priority order.
+pub struct GuildRules {
+    pub db: Db,
+}
+
+impl NameSource for GuildRules {
+    fn name_from_presence(&self, presence: &Presence) -> Option<String> {
+        compiled(&self.db, presence.guild_id?)
+            .iter()
+            .find_map(|rule| {
+                presence
+                    .activities
+                    .iter()
+                    .find_map(|activity| rule.name_from_activity(activity))
+            })
+    }
+}
@@ -0,0 +1,43 @@
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Combining marks allowed on one character. Real scripts rarely need more; "zalgo" text
+/// stacks dozens.
+const MAX_COMBINING_MARKS: usize = 2;
+
+/// Characters that render as nothing or reorder the text around them. Zero-width joiners and
+/// non-joiners are kept because emoji sequences and scripts like Persian and Devanagari need them.
+fn is_invisible(c: char) -> bool {
+    matches!(c,
+        '\u{00AD}' // soft hyphen
+        | '\u{034F}' // combining grapheme joiner
+        | '\u{061C}' // Arabic letter mark
+        | '\u{115F}' | '\u{1160}' | '\u{3164}' | '\u{FFA0}' // Hangul fillers
+        | '\u{180E}' // Mongolian vowel separator
+        | '\u{200B}' // zero-width space
+        | '\u{200E}' | '\u{200F}' // direction marks
+        | '\u{202A}'..='\u{202E}' // direction embeddings and overrides
+        | '\u{2060}'..='\u{2064}' // word joiner and invisible operators
+        | '\u{2066}'..='\u{2069}' // direction isolates
+        | '\u{FEFF}' // byte order mark
+    )
+}
+
+/// Normalizes a name to NFC and strips control characters, invisible characters and piles of
+/// combining marks, so names look the same everywhere and can't hide or reorder text.
+pub fn sanitize_name(name: &str) -> String {
+    let mut marks = 0;
+    name.nfc()
+        .filter(|&c| !c.is_control() && !is_invisible(c))
+        .filter(|&c| {
+            if is_combining_mark(c) {
+                marks += 1;
+                marks <= MAX_COMBINING_MARKS
+            } else {
+                marks = 0;
+                true
+            }
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
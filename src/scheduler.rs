@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use serenity::{http::RatelimitInfo, model::prelude::GuildId};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// Discord lets a bot edit about this many members of a guild in a burst...
+const BURST: f64 = 10.0;
+/// ...and then about one every this long.
+const EDIT_INTERVAL: Duration = Duration::from_secs(1);
+/// The most edits a sync or restore has in flight at once. The rest wait their turn without
+/// queueing up on the scheduler.
+pub const MAX_CONCURRENT_EDITS: usize = BURST as usize;
+
+struct Bucket {
+    /// Edits that can go straight away. Negative when edits are queued.
+    tokens: f64,
+    refilled_at: Instant,
+    waiting: usize,
+}
+
+/// Spaces nickname edits out per guild so a big reshuffle doesn't run into Discord's rate
+/// limits, however many edits are started at once.
+pub struct EditScheduler {
+    buckets: LazyLock<Mutex<HashMap<GuildId, Bucket>>>,
+}
+
+pub static EDITS: EditScheduler = EditScheduler {
+    buckets: LazyLock::new(Mutex::default),
+};
+
+impl Bucket {
+    fn new(now: Instant) -> Bucket {
+        Bucket {
+            tokens: BURST,
+            refilled_at: now,
+            waiting: 0,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let refill =
+            now.duration_since(self.refilled_at).as_secs_f64() / EDIT_INTERVAL.as_secs_f64();
+        self.tokens = (self.tokens + refill).min(BURST);
+        self.refilled_at = now;
+    }
+
+    /// Makes the next edit wait at least `timeout`.
+    fn hold_back(&mut self, now: Instant, timeout: Duration) {
+        self.refill(now);
+        self.tokens = self
+            .tokens
+            .min(-timeout.as_secs_f64() / EDIT_INTERVAL.as_secs_f64());
+    }
+}
+
+/// The guild whose members the request edits, going by its path.
+fn member_route_guild(path: &str) -> Option<GuildId> {
+    let (_, rest) = path.split_once("/guilds/")?;
+    let (guild_id, rest) = rest.split_once('/')?;
+    if !rest.starts_with("members/") {
+        return None;
+    }
+    guild_id.parse().ok().map(GuildId::new)
+}
+
+impl EditScheduler {
+    /// Waits until the guild has room for another nickname edit.
+    pub async fn acquire(&self, guild_id: GuildId) {
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let now = Instant::now();
+            let bucket = buckets.entry(guild_id).or_insert_with(|| Bucket::new(now));
+            bucket.refill(now);
+            // Take a token even if there isn't one, which reserves the next one to come in.
+            bucket.tokens -= 1.0;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            bucket.waiting += 1;
+            EDIT_INTERVAL.mul_f64(-bucket.tokens)
+        };
+        debug!("Waiting {wait:?} to edit a member of guild {guild_id}");
+        tokio::time::sleep(wait).await;
+        if let Some(bucket) = self.buckets.lock().unwrap().get_mut(&guild_id) {
+            bucket.waiting -= 1;
+        }
+    }
+
+    /// Takes in a rate limit Discord reported, from its headers or a 429's `retry_after`, so
+    /// later edits wait it out instead of running into it again. Global limits hold back every
+    /// guild, and limits on other routes are none of the scheduler's business.
+    pub fn rate_limited(&self, info: &RatelimitInfo) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        if info.global {
+            warn!(
+                "Hit Discord's global rate limit, holding back edits for {:?}",
+                info.timeout
+            );
+            for bucket in buckets.values_mut() {
+                bucket.hold_back(now, info.timeout);
+            }
+        } else if let Some(guild_id) = member_route_guild(&info.path) {
+            warn!(
+                "Hit the rate limit for editing members of guild {guild_id}, holding back edits for {:?}",
+                info.timeout
+            );
+            buckets
+                .entry(guild_id)
+                .or_insert_with(|| Bucket::new(now))
+                .hold_back(now, info.timeout);
+        }
+    }
+
+    /// How many edits in the guild are waiting their turn.
+    pub fn waiting(&self, guild_id: GuildId) -> usize {
+        self.buckets
+            .lock()
+            .unwrap()
+            .get(&guild_id)
+            .map_or(0, |bucket| bucket.waiting)
+    }
+}
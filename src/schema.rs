@@ -0,0 +1,125 @@
+use sled::{Batch, Db};
+use tracing::{info, warn};
+
+use crate::{
+    db::{
+        names_tree_guild, parse_guild_db_tree_name, StoredName, NAME_OVERRIDES_DB_TREE_PREFIX,
+        PERMANENT_NICKS_DB_TREE_PREFIX,
+    },
+    encryption, stats,
+};
+
+/// Holds facts about the database itself rather than any guild. Its name mustn't be 8 or 9
+/// bytes long, or it would be mistaken for a guild's tree.
+const METADATA_TREE: &[u8] = b"schema_metadata";
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+/// One step up from the previous version. Databases from before versioning are version 0.
+struct Migration {
+    description: &'static str,
+    run: fn(&Db),
+}
+
+/// The migrations in order: the first one upgrades to version 1, and so on.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        // Version 1 is the layout from before versioning, so there's nothing to change.
+        description: "record the schema version",
+        run: |_| {},
+    },
+    Migration {
+        description: "store names as records of when and how they were captured",
+        run: store_names_as_records,
+    },
+    Migration {
+        description: "count each member's renames",
+        run: stats::count_member_renames,
+    },
+    Migration {
+        description: "encrypt overrides and permanent nicknames",
+        run: seal_overrides,
+    },
+];
+
+/// Rewrites bare stored names as records without a capture time or source. Names encrypted with
+/// a key that isn't configured are left bare, which is still readable once the key is given.
+fn store_names_as_records(db: &Db) {
+    for name in db.tree_names() {
+        let Some(guild_id) = names_tree_guild(&name) else {
+            continue;
+        };
+        let tree = db.open_tree(&name).unwrap();
+        let mut batch = Batch::default();
+        for entry in tree.iter() {
+            let (key, value) = entry.unwrap();
+            match StoredName::decode(&value) {
+                Ok(stored) => batch.insert(key, stored.encode()),
+                Err(e) => {
+                    warn!("Leaving the stored name {key:?} in guild {guild_id} as it is: {e}")
+                }
+            }
+        }
+        tree.apply_batch(batch).unwrap();
+    }
+}
+
+/// Encrypts the overrides and permanent nicknames stored in plain text before they were sealed
+/// like stored names. Without a key they stay as they are, the same as stored names.
+fn seal_overrides(db: &Db) {
+    for name in db.tree_names() {
+        let Some((NAME_OVERRIDES_DB_TREE_PREFIX | PERMANENT_NICKS_DB_TREE_PREFIX, guild_id)) =
+            parse_guild_db_tree_name(&name)
+        else {
+            continue;
+        };
+        let tree = db.open_tree(&name).unwrap();
+        let mut batch = Batch::default();
+        for entry in tree.iter() {
+            let (key, value) = entry.unwrap();
+            match encryption::open(&value) {
+                Ok(nick) => batch.insert(key, encryption::seal(&nick)),
+                Err(e) => warn!("Leaving the name {key:?} in guild {guild_id} as it is: {e}"),
+            }
+        }
+        tree.apply_batch(batch).unwrap();
+    }
+}
+
+/// The version this build writes.
+pub const CURRENT_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub fn version(db: &Db) -> u32 {
+    db.open_tree(METADATA_TREE)
+        .unwrap()
+        .get(SCHEMA_VERSION_KEY)
+        .unwrap()
+        .map_or(0, |version| {
+            u32::from_be_bytes(version.as_ref().try_into().expect("corrupt schema version"))
+        })
+}
+
+/// Brings the database up to the current version, one migration at a time so an interrupted
+/// upgrade picks up where it left off. Refuses databases written by a newer build, whose layout
+/// this one doesn't know.
+pub fn upgrade(db: &Db) -> Result<(), String> {
+    let version = version(db);
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "The database is at schema version {version}, but this build only understands up to {CURRENT_VERSION}. Run a newer build."
+        ));
+    }
+    let metadata = db.open_tree(METADATA_TREE).unwrap();
+    for (to, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let to = to as u32 + 1;
+        info!(
+            "Migrating the database to schema version {to}: {}",
+            migration.description
+        );
+        (migration.run)(db);
+        metadata
+            .insert(SCHEMA_VERSION_KEY, &to.to_be_bytes())
+            .unwrap();
+        db.flush().unwrap();
+    }
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use rand::seq::SliceRandom;
+use serenity::model::prelude::{ChannelId, GuildId, UserId};
+use sled::Db;
+use tracing::info;
+
+use crate::db::{sessions_db_tree_name, DbKey};
+
+/// How many shuffles to try when looking for one that doesn't repeat anyone's recent names.
+const SHUFFLE_ATTEMPTS: usize = 20;
+
+fn gen_derangement(size: usize) -> Vec<usize> {
+    if size > 1 {
+        let mut rng = rand::thread_rng();
+        derangement::derange::Derange::new(&mut rng, size)
+            .map()
+            .to_vec()
+    } else {
+        vec![0; size]
+    }
+}
+
+fn session_key(channel_id: ChannelId, user_id: UserId) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&channel_id.get().to_be_bytes());
+    key[8..].copy_from_slice(&DbKey::from(user_id).0);
+    key
+}
+
+/// Of a few generated assignments, the one where the fewest members are named after a source
+/// `repeats` says they had recently.
+fn fewest_repeats(
+    generate: impl Fn() -> Vec<usize>,
+    repeats: impl Fn(usize, usize) -> bool,
+) -> Vec<usize> {
+    let count = |assignments: &[usize]| {
+        assignments
+            .iter()
+            .enumerate()
+            .filter(|(member, from)| repeats(*member, **from))
+            .count()
+    };
+    let mut best = generate();
+    let mut best_count = count(&best);
+    for _ in 1..SHUFFLE_ATTEMPTS {
+        if best_count == 0 {
+            break;
+        }
+        let candidate = generate();
+        let candidate_count = count(&candidate);
+        if candidate_count < best_count {
+            best = candidate;
+            best_count = candidate_count;
+        }
+    }
+    best
+}
+
+/// Whose name each member in the channel gets, as indices into `members`: member `i` is named
+/// after `members[result[i]]`. Assignments are kept for the whole session so names don't
+/// churn on every update; only members without one get a new one, preferring sources for which
+/// `repeats(i, source)` is false.
+pub fn assignments(
+    db: &Db,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    members: &[UserId],
+    repeats: impl Fn(usize, usize) -> bool,
+) -> Vec<usize> {
+    let sessions = db.open_tree(sessions_db_tree_name(guild_id)).unwrap();
+    let index = |user_id: UserId| members.iter().position(|member| *member == user_id);
+    let mut assigned: Vec<Option<usize>> = vec![None; members.len()];
+    let mut used = vec![false; members.len()];
+    for (i, member) in members.iter().enumerate() {
+        let Some(from) = sessions
+            .get(session_key(channel_id, *member))
+            .unwrap()
+            .and_then(|from| Some(UserId::from(DbKey(from.as_ref().try_into().ok()?))))
+            .and_then(index)
+        else {
+            continue;
+        };
+        if from != i && !used[from] {
+            assigned[i] = Some(from);
+            used[from] = true;
+        }
+    }
+    let assignments = if assigned.iter().all(Option::is_none) {
+        fewest_repeats(|| gen_derangement(members.len()), repeats)
+    } else {
+        fewest_repeats(|| assign_remaining(assigned.clone(), &used), repeats)
+    };
+    sessions
+        .apply_batch(members.iter().zip(&assignments).fold(
+            sled::Batch::default(),
+            |mut batch, (member, from)| {
+                batch.insert(
+                    &session_key(channel_id, *member),
+                    &DbKey::from(members[*from]).0,
+                );
+                batch
+            },
+        ))
+        .unwrap();
+    assignments
+}
+
+/// Gives the members without an assignment the sources nobody is using, never their own.
+fn assign_remaining(mut assigned: Vec<Option<usize>>, used: &[bool]) -> Vec<usize> {
+    let members: Vec<usize> = (0..assigned.len())
+        .filter(|i| assigned[*i].is_none())
+        .collect();
+    let mut sources: Vec<usize> = (0..used.len()).filter(|i| !used[*i]).collect();
+    sources.shuffle(&mut rand::thread_rng());
+    for k in 0..members.len() {
+        if members[k] == sources[k] && members.len() > 1 {
+            // Sources are distinct, so swapping with the next one can't land anyone on
+            // themselves.
+            let next = (k + 1) % members.len();
+            sources.swap(k, next);
+        }
+    }
+    if let ([member], [source]) = (members.as_slice(), sources.as_slice()) {
+        if member == source {
+            // The only one left is the newcomer's own name. Trade with someone who already
+            // has one.
+            if let Some(other) = assigned.iter().position(Option::is_some) {
+                assigned[*member] = assigned[other];
+                assigned[other] = Some(*member);
+                return assigned.into_iter().map(Option::unwrap).collect();
+            }
+        }
+    }
+    for (member, source) in members.into_iter().zip(sources) {
+        assigned[member] = Some(source);
+    }
+    assigned.into_iter().map(Option::unwrap).collect()
+}
+
+/// The member in the channel who is named after `from`, if anyone.
+pub fn named_after(
+    db: &Db,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    from: UserId,
+) -> Option<UserId> {
+    let sessions = db.open_tree(sessions_db_tree_name(guild_id)).unwrap();
+    sessions
+        .scan_prefix(channel_id.get().to_be_bytes())
+        .find_map(|entry| {
+            let (key, value) = entry.unwrap();
+            (value.as_ref() == DbKey::from(from).0)
+                .then(|| UserId::from(DbKey(key[8..].try_into().unwrap())))
+        })
+}
+
+/// Who is named after whom in each of the guild's channels.
+pub fn guild_assignments(db: &Db, guild_id: GuildId) -> Vec<(ChannelId, Vec<(UserId, UserId)>)> {
+    let sessions = db.open_tree(sessions_db_tree_name(guild_id)).unwrap();
+    let mut channels: Vec<(ChannelId, Vec<(UserId, UserId)>)> = Vec::new();
+    for entry in sessions.iter() {
+        let (key, value) = entry.unwrap();
+        let (Ok(channel), Ok(user), Ok(from)) = (
+            key[..8].try_into(),
+            key[8..].try_into(),
+            value.as_ref().try_into(),
+        ) else {
+            continue;
+        };
+        let channel_id = ChannelId::new(u64::from_be_bytes(channel));
+        let assignment = (UserId::from(DbKey(user)), UserId::from(DbKey(from)));
+        // Keys sort by channel, so a channel's assignments are next to each other.
+        match channels.last_mut() {
+            Some((last, assignments)) if *last == channel_id => assignments.push(assignment),
+            _ => channels.push((channel_id, vec![assignment])),
+        }
+    }
+    channels
+}
+
+/// Forgets who is named after whom in every channel of the guild.
+pub fn clear_guild(db: &Db, guild_id: GuildId) {
+    info!("Ending every naming session in guild {guild_id}");
+    db.open_tree(sessions_db_tree_name(guild_id))
+        .unwrap()
+        .clear()
+        .unwrap();
+}
+
+/// Forgets who is named after whom in the channel, so the next sync reshuffles everyone.
+pub fn clear(db: &Db, guild_id: GuildId, channel_id: ChannelId) {
+    info!("Ending the naming session in channel {channel_id} of guild {guild_id}");
+    let sessions = db.open_tree(sessions_db_tree_name(guild_id)).unwrap();
+    for key in sessions.scan_prefix(channel_id.get().to_be_bytes()).keys() {
+        sessions.remove(key.unwrap()).unwrap();
+    }
+}
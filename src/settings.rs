@@ -0,0 +1,195 @@
+use serenity::model::prelude::{ChannelId, GuildId};
+use tracing::info;
+
+use crate::{
+    db::{
+        get_setting, remove_setting, set_setting, ChannelList, ALLOWED_CHANNELS_SETTING,
+        DENIED_CHANNELS_SETTING, ENABLED_SETTING, MIN_MEMBERS_SETTING, NAME_TEMPLATE_SETTING,
+        OVERRIDE_TTL_HOURS_SETTING,
+    },
+    store::NameStore,
+    template::NameTemplate,
+};
+
+/// Longer TTLs are almost certainly mistakes, and would overflow once turned into seconds.
+pub const MAX_OVERRIDE_TTL_HOURS: u64 = 24 * 365;
+
+/// The settings that decide whether and how a guild's channels are shuffled, read from the
+/// guild's settings tree. Anything unset or unreadable gets its default.
+#[derive(Clone, Debug)]
+pub struct GuildSettings {
+    pub enabled: bool,
+    /// An empty list allows every channel that isn't denied.
+    pub allowed_channels: Vec<ChannelId>,
+    pub denied_channels: Vec<ChannelId>,
+    pub name_template: Option<NameTemplate>,
+    /// The fewest members a channel needs before it's shuffled.
+    pub min_members: usize,
+    /// `None` uses `--override-ttl-hours`. 0 means overrides never expire.
+    pub override_ttl_hours: Option<u64>,
+}
+impl Default for GuildSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allowed_channels: Vec::new(),
+            denied_channels: Vec::new(),
+            name_template: None,
+            min_members: 1,
+            override_ttl_hours: None,
+        }
+    }
+}
+impl GuildSettings {
+    pub fn load(store: &(impl NameStore + ?Sized), guild_id: GuildId) -> GuildSettings {
+        let defaults = GuildSettings::default();
+        let ChannelList(allowed_channels) =
+            get_setting(store, guild_id, ALLOWED_CHANNELS_SETTING).unwrap_or_default();
+        let ChannelList(denied_channels) =
+            get_setting(store, guild_id, DENIED_CHANNELS_SETTING).unwrap_or_default();
+        GuildSettings {
+            enabled: get_setting(store, guild_id, ENABLED_SETTING).unwrap_or(defaults.enabled),
+            allowed_channels,
+            denied_channels,
+            name_template: get_setting(store, guild_id, NAME_TEMPLATE_SETTING),
+            min_members: get_setting(store, guild_id, MIN_MEMBERS_SETTING)
+                .filter(|members| *members >= 1)
+                .unwrap_or(defaults.min_members),
+            // Out of range is treated as unset, but stays stored until it's changed.
+            override_ttl_hours: get_setting(store, guild_id, OVERRIDE_TTL_HOURS_SETTING)
+                .filter(|hours| *hours <= MAX_OVERRIDE_TTL_HOURS),
+        }
+    }
+
+    /// Explains what's wrong with the settings, if anything.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_members < 1 {
+            return Err("Channels need at least 1 member.".to_string());
+        }
+        if let Some(hours) = self
+            .override_ttl_hours
+            .filter(|hours| *hours > MAX_OVERRIDE_TTL_HOURS)
+        {
+            return Err(format!(
+                "{hours} hours is too long, the most is {MAX_OVERRIDE_TTL_HOURS}."
+            ));
+        }
+        if let Some(channel_id) = self
+            .allowed_channels
+            .iter()
+            .find(|channel_id| self.denied_channels.contains(channel_id))
+        {
+            return Err(format!("Channel {channel_id} is both allowed and denied."));
+        }
+        Ok(())
+    }
+
+    /// Validates the settings and writes the ones that differ from `loaded`, what `load`
+    /// returned before they were changed, so a stored value `load` couldn't use is left alone
+    /// unless it's actually changed. Settings left at their default are removed, so the guild
+    /// picks up any later change to the default.
+    fn save(
+        &self,
+        store: &(impl NameStore + ?Sized),
+        guild_id: GuildId,
+        loaded: &GuildSettings,
+    ) -> Result<(), String> {
+        self.validate()?;
+        info!("Saving settings for guild {guild_id}: {self:?}");
+        let defaults = GuildSettings::default();
+        if loaded.enabled != self.enabled {
+            save_unless(
+                store,
+                guild_id,
+                ENABLED_SETTING,
+                self.enabled,
+                defaults.enabled,
+            );
+        }
+        if loaded.allowed_channels != self.allowed_channels {
+            save_unless(
+                store,
+                guild_id,
+                ALLOWED_CHANNELS_SETTING,
+                ChannelList(self.allowed_channels.clone()),
+                ChannelList(Vec::new()),
+            );
+        }
+        if loaded.denied_channels != self.denied_channels {
+            save_unless(
+                store,
+                guild_id,
+                DENIED_CHANNELS_SETTING,
+                ChannelList(self.denied_channels.clone()),
+                ChannelList(Vec::new()),
+            );
+        }
+        if loaded.name_template != self.name_template {
+            save_optional(store, guild_id, NAME_TEMPLATE_SETTING, &self.name_template);
+        }
+        if loaded.min_members != self.min_members {
+            save_unless(
+                store,
+                guild_id,
+                MIN_MEMBERS_SETTING,
+                self.min_members,
+                defaults.min_members,
+            );
+        }
+        if loaded.override_ttl_hours != self.override_ttl_hours {
+            save_optional(
+                store,
+                guild_id,
+                OVERRIDE_TTL_HOURS_SETTING,
+                &self.override_ttl_hours,
+            );
+        }
+        Ok(())
+    }
+
+    /// Loads the guild's settings, changes them and saves the ones that changed, unless they no
+    /// longer validate.
+    pub fn update(
+        store: &(impl NameStore + ?Sized),
+        guild_id: GuildId,
+        change: impl FnOnce(&mut GuildSettings),
+    ) -> Result<GuildSettings, String> {
+        let loaded = GuildSettings::load(store, guild_id);
+        let mut settings = loaded.clone();
+        change(&mut settings);
+        settings.save(store, guild_id, &loaded)?;
+        Ok(settings)
+    }
+
+    /// Whether renaming happens in the channel according to the allow and deny lists.
+    pub fn is_channel_allowed(&self, channel_id: ChannelId) -> bool {
+        (self.allowed_channels.is_empty() || self.allowed_channels.contains(&channel_id))
+            && !self.denied_channels.contains(&channel_id)
+    }
+}
+
+fn save_unless<T: std::fmt::Display>(
+    store: &(impl NameStore + ?Sized),
+    guild_id: GuildId,
+    key: &str,
+    value: T,
+    default: T,
+) {
+    if value.to_string() == default.to_string() {
+        remove_setting(store, guild_id, key);
+    } else {
+        set_setting(store, guild_id, key, value);
+    }
+}
+
+fn save_optional<T: std::fmt::Display>(
+    store: &(impl NameStore + ?Sized),
+    guild_id: GuildId,
+    key: &str,
+    value: &Option<T>,
+) {
+    match value {
+        Some(value) => set_setting(store, guild_id, key, value),
+        None => remove_setting(store, guild_id, key),
+    }
+}
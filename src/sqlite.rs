@@ -0,0 +1,236 @@
+use std::{path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row};
+use serenity::model::prelude::{GuildId, UserId};
+
+use crate::{
+    db::StoredName,
+    store::{decode_name, NameStore, Names},
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS names (
+    kind TEXT NOT NULL,
+    guild_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    name BLOB NOT NULL,
+    PRIMARY KEY (kind, guild_id, user_id)
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS settings (
+    guild_id INTEGER NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (guild_id, key)
+) WITHOUT ROWID;
+";
+
+/// Names and settings kept in a single SQLite file, one row per name or setting. Names are
+/// stored the way `Names::encode` writes them.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Opens an existing file without creating it or its tables, for only reading from.
+    pub fn open_read_only(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+/// What goes in the `kind` column.
+fn kind(names: Names) -> &'static str {
+    match names {
+        Names::Original => "original",
+        Names::Override => "override",
+        Names::Permanent => "permanent",
+    }
+}
+
+// Discord ids fit in 63 bits, so they round trip through SQLite's signed integers.
+fn id(id: u64) -> i64 {
+    id as i64
+}
+
+/// The stored name in the column. Older files have names as text rather than bytes.
+fn name_bytes(row: &Row, index: usize) -> rusqlite::Result<Vec<u8>> {
+    let value = row.get_ref(index)?;
+    value.as_bytes().map(<[u8]>::to_vec).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, value.data_type(), Box::new(e))
+    })
+}
+
+impl NameStore for SqliteStore {
+    fn guilds(&self) -> Vec<GuildId> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT guild_id FROM names UNION SELECT guild_id FROM settings ORDER BY 1")
+            .unwrap();
+        statement
+            .query_map([], |row| Ok(GuildId::new(row.get::<_, i64>(0)? as u64)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn get_name(&self, names: Names, guild_id: GuildId, user_id: UserId) -> Option<StoredName> {
+        let value = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT name FROM names WHERE kind = ?1 AND guild_id = ?2 AND user_id = ?3",
+                params![kind(names), id(guild_id.get()), id(user_id.get())],
+                |row| name_bytes(row, 0),
+            )
+            .optional()
+            .unwrap()?;
+        decode_name(guild_id, &value)
+    }
+
+    fn set_name(&self, names: Names, guild_id: GuildId, user_id: UserId, name: &StoredName) {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO names (kind, guild_id, user_id, name) VALUES (?1, ?2, ?3, ?4)",
+                params![kind(names), id(guild_id.get()), id(user_id.get()), names.encode(name)],
+            )
+            .unwrap();
+    }
+
+    fn remove_name(&self, names: Names, guild_id: GuildId, user_id: UserId) -> Option<StoredName> {
+        let removed = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "DELETE FROM names WHERE kind = ?1 AND guild_id = ?2 AND user_id = ?3 RETURNING name",
+                params![kind(names), id(guild_id.get()), id(user_id.get())],
+                |row| name_bytes(row, 0),
+            )
+            .optional()
+            .unwrap()?;
+        decode_name(guild_id, &removed)
+    }
+
+    fn names(&self, names: Names, guild_id: GuildId) -> Vec<(UserId, StoredName)> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT user_id, name FROM names WHERE kind = ?1 AND guild_id = ?2 ORDER BY user_id")
+            .unwrap();
+        statement
+            .query_map(params![kind(names), id(guild_id.get())], |row| {
+                Ok((
+                    UserId::new(row.get::<_, i64>(0)? as u64),
+                    name_bytes(row, 1)?,
+                ))
+            })
+            .unwrap()
+            .map(Result::unwrap)
+            .filter_map(|(user_id, value)| Some((user_id, decode_name(guild_id, &value)?)))
+            .collect()
+    }
+
+    fn clear_names(&self, names: Names, guild_id: GuildId) {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM names WHERE kind = ?1 AND guild_id = ?2",
+                params![kind(names), id(guild_id.get())],
+            )
+            .unwrap();
+    }
+
+    fn get_setting(&self, guild_id: GuildId, key: &str) -> Option<String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT value FROM settings WHERE guild_id = ?1 AND key = ?2",
+                params![id(guild_id.get()), key],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap()
+    }
+
+    fn set_setting(&self, guild_id: GuildId, key: &str, value: &str) {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO settings (guild_id, key, value) VALUES (?1, ?2, ?3)",
+                params![id(guild_id.get()), key, value],
+            )
+            .unwrap();
+    }
+
+    fn remove_setting(&self, guild_id: GuildId, key: &str) {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM settings WHERE guild_id = ?1 AND key = ?2",
+                params![id(guild_id.get()), key],
+            )
+            .unwrap();
+    }
+
+    fn settings(&self, guild_id: GuildId) -> Vec<(String, String)> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT key, value FROM settings WHERE guild_id = ?1 ORDER BY key")
+            .unwrap();
+        statement
+            .query_map(params![id(guild_id.get())], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn clear_settings(&self, guild_id: GuildId) {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM settings WHERE guild_id = ?1",
+                params![id(guild_id.get())],
+            )
+            .unwrap();
+    }
+
+    fn persist(&self) {
+        // Every statement outside `begin` and `commit` is its own transaction, so it's on disk
+        // once it returns.
+    }
+
+    fn begin(&self) {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch("BEGIN")
+            .unwrap();
+    }
+
+    fn commit(&self) {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch("COMMIT")
+            .unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serenity::model::prelude::{GuildId, UserId};
+use sled::{Batch, Db, Tree};
+use tracing::{info, warn};
+
+use crate::{
+    db::{
+        member_rename_counts_db_tree_name, parse_guild_db_tree_name, plays_db_tree_name,
+        rename_counts_db_tree_name, renames_db_tree_name, DbKey, RENAMES_DB_TREE_PREFIX,
+    },
+    ddragon::ChampionIndex,
+    encryption,
+};
+
+/// Key for a play: the user followed by when the game started, so seeing the same game again
+/// doesn't count it twice.
+fn play_key(user_id: UserId, started_at_ms: u64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&DbKey::from(user_id).0);
+    key[8..].copy_from_slice(&started_at_ms.to_be_bytes());
+    key
+}
+
+/// Records that the user played the champion in a game that started at the given time.
+pub fn record_play(
+    db: &Db,
+    guild_id: GuildId,
+    user_id: UserId,
+    started_at_ms: u64,
+    champion: &str,
+) {
+    db.open_tree(plays_db_tree_name(guild_id))
+        .unwrap()
+        .insert(play_key(user_id, started_at_ms), champion)
+        .unwrap();
+}
+
+/// Counter of every nickname the bot applied in the guild.
+const RENAMES_COUNTER: &str = "renames";
+/// Counter of nickname edits that didn't go through.
+const FAILURES_COUNTER: &str = "failures";
+/// Prefix of the counters of how often each source name was handed out.
+const SOURCE_COUNTER_PREFIX: &str = "source:";
+/// How long renames are kept for the recent renames list. The counters keep counting.
+const RENAME_RETENTION_MS: u64 = 90 * 24 * 60 * 60 * 1000;
+
+/// Key of the member's counter of how often they got the source name. Their counter of how
+/// often they were renamed at all is keyed by just the member.
+fn member_source_key(user_id: UserId, source: &str) -> Vec<u8> {
+    [&DbKey::from(user_id).0, source.as_bytes()].concat()
+}
+
+/// A nickname the bot applied, or tried to.
+#[derive(Serialize, Deserialize)]
+pub struct Rename {
+    pub at_ms: u64,
+    pub user_id: UserId,
+    /// What they were showing before, when we knew.
+    pub old: Option<String>,
+    pub new: String,
+    /// The champion (or other source name) the nickname came from, if it came from one.
+    pub source: Option<String>,
+    /// Whether the edit didn't go through.
+    #[serde(default)]
+    pub failed: bool,
+}
+
+/// Totals of the renames recorded for a guild.
+#[derive(Serialize, Default)]
+pub struct RenameCounts {
+    pub renames: u64,
+    pub failures: u64,
+    /// The source name handed out most often, and how often.
+    pub most_assigned: Option<(String, u64)>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Key for a rename: when it happened followed by the user, so renames sort in order.
+fn rename_key(at_ms: u64, user_id: UserId) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&at_ms.to_be_bytes());
+    key[8..].copy_from_slice(&DbKey::from(user_id).0);
+    key
+}
+
+fn increment(counts: &Tree, counter: impl AsRef<[u8]>) {
+    counts
+        .update_and_fetch(counter, |count| {
+            let count = count
+                .and_then(|count| count.try_into().ok())
+                .map_or(0, u64::from_be_bytes);
+            Some((count + 1).to_be_bytes().to_vec())
+        })
+        .unwrap();
+}
+
+fn count(value: &[u8]) -> u64 {
+    value.try_into().map_or(0, u64::from_be_bytes)
+}
+
+/// Records a nickname the bot applied, and counts it towards the guild's totals.
+pub fn record_rename(
+    db: &Db,
+    guild_id: GuildId,
+    user_id: UserId,
+    old: Option<&str>,
+    new: &str,
+    source: Option<&str>,
+) {
+    store(
+        db,
+        guild_id,
+        &Rename {
+            at_ms: now_ms(),
+            user_id,
+            old: old.map(str::to_string),
+            new: new.to_string(),
+            source: source.map(str::to_string),
+            failed: false,
+        },
+    );
+    let counts = db.open_tree(rename_counts_db_tree_name(guild_id)).unwrap();
+    increment(&counts, RENAMES_COUNTER);
+    if let Some(source) = source {
+        increment(&counts, format!("{SOURCE_COUNTER_PREFIX}{source}"));
+    }
+    count_member_rename(db, guild_id, user_id, source);
+}
+
+fn count_member_rename(db: &Db, guild_id: GuildId, user_id: UserId, source: Option<&str>) {
+    let counts = db
+        .open_tree(member_rename_counts_db_tree_name(guild_id))
+        .unwrap();
+    increment(&counts, DbKey::from(user_id));
+    if let Some(source) = source {
+        increment(&counts, member_source_key(user_id, source));
+    }
+}
+
+/// Stores the rename and drops those older than the retention period.
+fn store(db: &Db, guild_id: GuildId, rename: &Rename) {
+    let renames = db.open_tree(renames_db_tree_name(guild_id)).unwrap();
+    renames
+        .insert(
+            rename_key(rename.at_ms, rename.user_id),
+            encryption::seal(&serde_json::to_string(rename).unwrap()),
+        )
+        .unwrap();
+    let cutoff = rename.at_ms.saturating_sub(RENAME_RETENTION_MS);
+    for key in renames.range(..cutoff.to_be_bytes()).keys() {
+        renames.remove(key.unwrap()).unwrap();
+    }
+}
+
+/// Reads a rename as `store` wrote it.
+fn decode(value: &[u8]) -> Option<Rename> {
+    serde_json::from_str(&encryption::open(value).ok()?).ok()
+}
+
+/// Records a nickname edit that didn't go through.
+pub fn record_rename_failure(db: &Db, guild_id: GuildId, user_id: UserId, nick: &str) {
+    store(
+        db,
+        guild_id,
+        &Rename {
+            at_ms: now_ms(),
+            user_id,
+            old: None,
+            new: nick.to_string(),
+            source: None,
+            failed: true,
+        },
+    );
+    increment(
+        &db.open_tree(rename_counts_db_tree_name(guild_id)).unwrap(),
+        FAILURES_COUNTER,
+    );
+}
+
+/// The guild's rename totals.
+pub fn rename_counts(db: &Db, guild_id: GuildId) -> RenameCounts {
+    let counts = db.open_tree(rename_counts_db_tree_name(guild_id)).unwrap();
+    let get = |counter: &str| {
+        counts
+            .get(counter)
+            .unwrap()
+            .map_or(0, |value| count(&value))
+    };
+    RenameCounts {
+        renames: get(RENAMES_COUNTER),
+        failures: get(FAILURES_COUNTER),
+        most_assigned: top_sources(db, guild_id).into_iter().next(),
+    }
+}
+
+/// The guild's most recent renames and failed renames, newest first.
+pub fn recent_renames(db: &Db, guild_id: GuildId, limit: usize) -> Vec<Rename> {
+    db.open_tree(renames_db_tree_name(guild_id))
+        .unwrap()
+        .iter()
+        .values()
+        .rev()
+        .filter_map(|rename| decode(&rename.ok()?))
+        .take(limit)
+        .collect()
+}
+
+/// How often each source name was handed out in the guild, most often first.
+pub fn top_sources(db: &Db, guild_id: GuildId) -> Vec<(String, u64)> {
+    let counts = db.open_tree(rename_counts_db_tree_name(guild_id)).unwrap();
+    let mut sources: Vec<_> = counts
+        .scan_prefix(SOURCE_COUNTER_PREFIX)
+        .filter_map(|entry| {
+            let (key, value) = entry.ok()?;
+            let source = std::str::from_utf8(&key[SOURCE_COUNTER_PREFIX.len()..]).ok()?;
+            Some((source.to_string(), count(&value)))
+        })
+        .collect();
+    sources.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    sources
+}
+
+/// How many times the member was renamed, and how often they were given each source name,
+/// most often first.
+pub fn member_summary(db: &Db, guild_id: GuildId, user_id: UserId) -> (u64, Vec<(String, u64)>) {
+    let counts = db
+        .open_tree(member_rename_counts_db_tree_name(guild_id))
+        .unwrap();
+    let user = DbKey::from(user_id);
+    let renames = counts.get(user).unwrap().map_or(0, |value| count(&value));
+    let sources = counts
+        .scan_prefix(user)
+        .filter_map(|entry| {
+            let (key, value) = entry.ok()?;
+            let source = std::str::from_utf8(&key[user.0.len()..]).ok()?;
+            Some((source.to_string(), count(&value)))
+        })
+        .filter(|(source, _)| !source.is_empty())
+        .sorted_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)))
+        .collect();
+    (renames, sources)
+}
+
+/// Counts the renames recorded before members had counters of their own.
+pub fn count_member_renames(db: &Db) {
+    for name in db.tree_names() {
+        let Some((RENAMES_DB_TREE_PREFIX, guild_id)) = parse_guild_db_tree_name(&name) else {
+            continue;
+        };
+        for rename in db.open_tree(&name).unwrap().iter().values() {
+            let Some(rename) = decode(&rename.unwrap()).filter(|rename| !rename.failed) else {
+                continue;
+            };
+            count_member_rename(db, guild_id, rename.user_id, rename.source.as_deref());
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MatchHistory {
+    Many(Vec<Match>),
+    One(Match),
+}
+
+#[derive(Deserialize)]
+struct Match {
+    info: MatchInfo,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MatchInfo {
+    game_start_timestamp: u64,
+    participants: Vec<Participant>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Participant {
+    puuid: String,
+    champion_name: String,
+}
+
+/// Seeds a member's play history from a match history export (match-v5 JSON, either a single
+/// match or a list of them) for the Riot account with the given PUUID.
+pub fn import(
+    db: &Db,
+    champions: &ChampionIndex,
+    guild_id: GuildId,
+    user_id: UserId,
+    puuid: &str,
+    file: &Path,
+) {
+    let history: MatchHistory = serde_json::from_slice(
+        &std::fs::read(file).unwrap_or_else(|e| panic!("Failed to read {file:?}: {e}")),
+    )
+    .unwrap_or_else(|e| panic!("Failed to parse match history {file:?}: {e}"));
+    let matches = match history {
+        MatchHistory::Many(matches) => matches,
+        MatchHistory::One(one) => vec![one],
+    };
+    let mut batch = Batch::default();
+    let mut imported = 0;
+    for game in &matches {
+        let Some(participant) = game.info.participants.iter().find(|p| p.puuid == puuid) else {
+            warn!(
+                "Skipping game started at {} because {puuid} didn't play in it",
+                game.info.game_start_timestamp
+            );
+            continue;
+        };
+        // Match history uses champion ids (e.g. MonkeyKing) rather than display names.
+        let champion = champions
+            .canonical_name(&participant.champion_name)
+            .unwrap_or(&participant.champion_name);
+        batch.insert(&play_key(user_id, game.info.game_start_timestamp), champion);
+        imported += 1;
+    }
+    db.open_tree(plays_db_tree_name(guild_id))
+        .unwrap()
+        .apply_batch(batch)
+        .unwrap();
+    info!(
+        "Imported {imported} of {} games for {user_id} in guild {guild_id}",
+        matches.len()
+    );
+}
@@ -0,0 +1,332 @@
+use std::{ops::Deref, sync::Arc};
+
+use serenity::model::prelude::{GuildId, UserId};
+use sled::{Batch, Db, IVec, Tree};
+use tracing::warn;
+
+use crate::{
+    db::{
+        get_stored_name, name_overrides_db_tree_name, names_tree_guild, parse_guild_db_tree_name,
+        permanent_nicks_db_tree_name, settings_db_tree_name, DbKey, StoredName,
+        NAME_OVERRIDES_DB_TREE_PREFIX, PERMANENT_NICKS_DB_TREE_PREFIX, SETTINGS_DB_TREE_PREFIX,
+    },
+    encryption,
+};
+
+/// Which of a guild's names to look at.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Names {
+    /// Members' own names, put back when the bot is done with them.
+    Original,
+    /// The names the bot gave members who are renamed right now.
+    Override,
+    /// Nicknames particular members always get instead of a shuffled one.
+    Permanent,
+}
+impl Names {
+    pub const ALL: [Names; 3] = [Names::Original, Names::Override, Names::Permanent];
+}
+
+/// Keeps the names, overrides and settings of every guild. Code that only needs those should
+/// take a `NameStore` rather than a sled database, so it works with any backend.
+pub trait NameStore: Send + Sync {
+    /// Every guild with names or settings stored.
+    fn guilds(&self) -> Vec<GuildId>;
+    /// The member's name. Only stored names have the rest of the record kept.
+    fn get_name(&self, names: Names, guild_id: GuildId, user_id: UserId) -> Option<StoredName>;
+    fn set_name(&self, names: Names, guild_id: GuildId, user_id: UserId, name: &StoredName);
+    /// Sets several names at once, all or none of them where the backend can.
+    fn set_names(&self, names: Names, guild_id: GuildId, entries: &[(UserId, StoredName)]) {
+        for (user_id, name) in entries {
+            self.set_name(names, guild_id, *user_id, name);
+        }
+    }
+    /// Returns the name that was removed, if there was one.
+    fn remove_name(&self, names: Names, guild_id: GuildId, user_id: UserId) -> Option<StoredName>;
+    fn names(&self, names: Names, guild_id: GuildId) -> Vec<(UserId, StoredName)>;
+    fn count_names(&self, names: Names, guild_id: GuildId) -> usize {
+        self.names(names, guild_id).len()
+    }
+    fn clear_names(&self, names: Names, guild_id: GuildId);
+    fn get_setting(&self, guild_id: GuildId, key: &str) -> Option<String>;
+    fn set_setting(&self, guild_id: GuildId, key: &str, value: &str);
+    fn remove_setting(&self, guild_id: GuildId, key: &str);
+    fn settings(&self, guild_id: GuildId) -> Vec<(String, String)>;
+    fn clear_settings(&self, guild_id: GuildId);
+    /// Makes sure everything written so far is on disk.
+    fn persist(&self);
+    /// Starts grouping writes, so none of them are kept unless `commit` is reached. Backends
+    /// that can't group writes make each one as it comes.
+    fn begin(&self) {}
+    /// Keeps every write since `begin`.
+    fn commit(&self) {}
+}
+
+impl Names {
+    /// What a backend stores for the name, encrypted if there's a key. Stored names keep the
+    /// whole record. Overrides and permanent nicknames are just the name.
+    pub fn encode(self, name: &StoredName) -> Vec<u8> {
+        match self {
+            Names::Original => name.encode(),
+            Names::Override | Names::Permanent => encryption::seal(&name.name),
+        }
+    }
+}
+
+/// Reads a name a backend stored, warning about it if it can't be read.
+pub fn decode_name(guild_id: GuildId, value: &[u8]) -> Option<StoredName> {
+    let stored = StoredName::decode(value);
+    if let Err(e) = &stored {
+        warn!("Skipping unreadable name {value:?} for guild {guild_id}: {e}");
+    }
+    stored.ok()
+}
+
+fn names_tree(db: &Db, names: Names, guild_id: GuildId) -> Tree {
+    match names {
+        Names::Original => db.open_tree(DbKey::from(guild_id)),
+        Names::Override => db.open_tree(name_overrides_db_tree_name(guild_id)),
+        Names::Permanent => db.open_tree(permanent_nicks_db_tree_name(guild_id)),
+    }
+    .unwrap()
+}
+
+fn utf8(guild_id: GuildId, bytes: &IVec) -> Option<String> {
+    let string = String::from_utf8(bytes.to_vec());
+    if string.is_err() {
+        warn!("Skipping corrupt entry {bytes:?} for guild {guild_id}");
+    }
+    string.ok()
+}
+
+/// Whether the sled tree holds names or settings, which every store keeps.
+pub fn is_store_tree(name: &[u8]) -> bool {
+    store_tree_guild(name).is_some()
+}
+
+/// The guild whose names or settings the sled tree holds.
+fn store_tree_guild(name: &[u8]) -> Option<GuildId> {
+    match parse_guild_db_tree_name(name) {
+        Some((
+            NAME_OVERRIDES_DB_TREE_PREFIX
+            | PERMANENT_NICKS_DB_TREE_PREFIX
+            | SETTINGS_DB_TREE_PREFIX,
+            guild_id,
+        )) => Some(guild_id),
+        Some(_) => None,
+        None => names_tree_guild(name),
+    }
+}
+
+/// Names and settings kept in trees of the sled database, next to everything else.
+pub struct SledStore(pub Db);
+
+impl NameStore for SledStore {
+    fn guilds(&self) -> Vec<GuildId> {
+        let mut guilds: Vec<GuildId> = self
+            .0
+            .tree_names()
+            .iter()
+            .filter_map(|name| store_tree_guild(name))
+            .collect();
+        guilds.sort();
+        guilds.dedup();
+        guilds
+    }
+
+    fn get_name(&self, names: Names, guild_id: GuildId, user_id: UserId) -> Option<StoredName> {
+        get_stored_name(&names_tree(&self.0, names, guild_id), DbKey::from(user_id))
+    }
+
+    fn set_name(&self, names: Names, guild_id: GuildId, user_id: UserId, name: &StoredName) {
+        names_tree(&self.0, names, guild_id)
+            .insert(DbKey::from(user_id), names.encode(name))
+            .unwrap();
+    }
+
+    fn set_names(&self, names: Names, guild_id: GuildId, entries: &[(UserId, StoredName)]) {
+        let mut batch = Batch::default();
+        for (user_id, name) in entries {
+            batch.insert(&DbKey::from(*user_id).0, names.encode(name));
+        }
+        names_tree(&self.0, names, guild_id)
+            .apply_batch(batch)
+            .unwrap();
+    }
+
+    fn remove_name(&self, names: Names, guild_id: GuildId, user_id: UserId) -> Option<StoredName> {
+        let removed = names_tree(&self.0, names, guild_id)
+            .remove(DbKey::from(user_id))
+            .unwrap()?;
+        decode_name(guild_id, &removed)
+    }
+
+    fn names(&self, names: Names, guild_id: GuildId) -> Vec<(UserId, StoredName)> {
+        names_tree(&self.0, names, guild_id)
+            .iter()
+            .filter_map(|entry| {
+                let (key, value) = entry.unwrap();
+                let user_id = DbKey(key.as_ref().try_into().ok()?).into();
+                Some((user_id, decode_name(guild_id, &value)?))
+            })
+            .collect()
+    }
+
+    fn count_names(&self, names: Names, guild_id: GuildId) -> usize {
+        names_tree(&self.0, names, guild_id).len()
+    }
+
+    fn clear_names(&self, names: Names, guild_id: GuildId) {
+        names_tree(&self.0, names, guild_id).clear().unwrap();
+    }
+
+    fn get_setting(&self, guild_id: GuildId, key: &str) -> Option<String> {
+        let value = self
+            .0
+            .open_tree(settings_db_tree_name(guild_id))
+            .unwrap()
+            .get(key)
+            .inspect_err(|e| warn!("Failed to get setting {key} for {guild_id}: {e}"))
+            .ok()??;
+        utf8(guild_id, &value)
+    }
+
+    fn set_setting(&self, guild_id: GuildId, key: &str, value: &str) {
+        self.0
+            .open_tree(settings_db_tree_name(guild_id))
+            .unwrap()
+            .insert(key, value)
+            .unwrap();
+    }
+
+    fn remove_setting(&self, guild_id: GuildId, key: &str) {
+        self.0
+            .open_tree(settings_db_tree_name(guild_id))
+            .unwrap()
+            .remove(key)
+            .unwrap();
+    }
+
+    fn settings(&self, guild_id: GuildId) -> Vec<(String, String)> {
+        self.0
+            .open_tree(settings_db_tree_name(guild_id))
+            .unwrap()
+            .iter()
+            .filter_map(|entry| {
+                let (key, value) = entry.unwrap();
+                Some((utf8(guild_id, &key)?, utf8(guild_id, &value)?))
+            })
+            .collect()
+    }
+
+    fn clear_settings(&self, guild_id: GuildId) {
+        self.0
+            .open_tree(settings_db_tree_name(guild_id))
+            .unwrap()
+            .clear()
+            .unwrap();
+    }
+
+    fn persist(&self) {
+        self.0.flush().unwrap();
+    }
+}
+
+/// The bot's database: the sled database for everything the bot keeps for itself, and a backend
+/// for the names and settings, which other processes may share. It derefs to the sled database,
+/// so only names and settings go through the backend.
+#[derive(Clone)]
+pub struct Store {
+    db: Db,
+    backend: Arc<dyn NameStore>,
+}
+
+impl Store {
+    pub fn new(db: Db, backend: Arc<dyn NameStore>) -> Store {
+        Store { db, backend }
+    }
+
+    /// Keeps the names and settings in the sled database as well.
+    pub fn local(db: Db) -> Store {
+        Store {
+            backend: Arc::new(SledStore(db.clone())),
+            db,
+        }
+    }
+}
+
+impl Deref for Store {
+    type Target = Db;
+
+    fn deref(&self) -> &Db {
+        &self.db
+    }
+}
+
+impl NameStore for Store {
+    fn guilds(&self) -> Vec<GuildId> {
+        self.backend.guilds()
+    }
+
+    fn get_name(&self, names: Names, guild_id: GuildId, user_id: UserId) -> Option<StoredName> {
+        self.backend.get_name(names, guild_id, user_id)
+    }
+
+    fn set_name(&self, names: Names, guild_id: GuildId, user_id: UserId, name: &StoredName) {
+        self.backend.set_name(names, guild_id, user_id, name)
+    }
+
+    fn set_names(&self, names: Names, guild_id: GuildId, entries: &[(UserId, StoredName)]) {
+        self.backend.set_names(names, guild_id, entries)
+    }
+
+    fn remove_name(&self, names: Names, guild_id: GuildId, user_id: UserId) -> Option<StoredName> {
+        self.backend.remove_name(names, guild_id, user_id)
+    }
+
+    fn names(&self, names: Names, guild_id: GuildId) -> Vec<(UserId, StoredName)> {
+        self.backend.names(names, guild_id)
+    }
+
+    fn count_names(&self, names: Names, guild_id: GuildId) -> usize {
+        self.backend.count_names(names, guild_id)
+    }
+
+    fn clear_names(&self, names: Names, guild_id: GuildId) {
+        self.backend.clear_names(names, guild_id)
+    }
+
+    fn get_setting(&self, guild_id: GuildId, key: &str) -> Option<String> {
+        self.backend.get_setting(guild_id, key)
+    }
+
+    fn set_setting(&self, guild_id: GuildId, key: &str, value: &str) {
+        self.backend.set_setting(guild_id, key, value)
+    }
+
+    fn remove_setting(&self, guild_id: GuildId, key: &str) {
+        self.backend.remove_setting(guild_id, key)
+    }
+
+    fn settings(&self, guild_id: GuildId) -> Vec<(String, String)> {
+        self.backend.settings(guild_id)
+    }
+
+    fn clear_settings(&self, guild_id: GuildId) {
+        self.backend.clear_settings(guild_id)
+    }
+
+    /// Flushes the sled database as well as the backend.
+    fn persist(&self) {
+        self.db.flush().unwrap();
+        self.backend.persist();
+    }
+
+    fn begin(&self) {
+        self.backend.begin()
+    }
+
+    fn commit(&self) {
+        self.backend.commit()
+    }
+}
@@ -0,0 +1,46 @@
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+use tracing::Subscriber;
+#[cfg(not(feature = "tokio-console"))]
+use tracing_subscriber::layer::Identity;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+/// Spawns a background task, naming it so it can be told apart in tokio-console.
+pub fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "tokio-console", tokio_unstable))]
+    return tokio::task::Builder::new()
+        .name(name)
+        .spawn(future)
+        .unwrap();
+    #[cfg(not(all(feature = "tokio-console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::spawn(future)
+    }
+}
+
+/// A layer serving runtime diagnostics to tokio-console, if they were asked for.
+#[cfg(feature = "tokio-console")]
+pub fn console_layer<S>(enabled: bool) -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    enabled.then(console_subscriber::spawn)
+}
+
+#[cfg(not(feature = "tokio-console"))]
+pub fn console_layer<S>(enabled: bool) -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if enabled {
+        // Logging isn't set up yet.
+        eprintln!("--tokio-console requires building with --features tokio-console");
+    }
+    None::<Identity>
+}
@@ -0,0 +1,171 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::nickname::{fit_nick, MAX_NAME_CHARS};
+
+/// How much of the champion survives a long template.
+const MIN_CHAMPION_CHARS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Placeholder {
+    Champion,
+    Original,
+    Username,
+    Channel,
+}
+
+impl Placeholder {
+    const ALL: [(&'static str, Self); 4] = [
+        ("champion", Self::Champion),
+        ("original", Self::Original),
+        ("username", Self::Username),
+        ("channel", Self::Channel),
+    ];
+
+    fn name(self) -> &'static str {
+        Self::ALL
+            .iter()
+            .find(|(_, placeholder)| *placeholder == self)
+            .unwrap()
+            .0
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// What fills in a template's placeholders.
+pub struct TemplateValues<'a> {
+    /// The name the bot picked.
+    pub champion: &'a str,
+    /// The member's own name, from before the bot renamed them.
+    pub original: &'a str,
+    pub username: &'a str,
+    pub channel: &'a str,
+}
+
+impl TemplateValues<'_> {
+    fn get(&self, placeholder: Placeholder) -> &str {
+        match placeholder {
+            Placeholder::Champion => self.champion,
+            Placeholder::Original => self.original,
+            Placeholder::Username => self.username,
+            Placeholder::Channel => self.channel,
+        }
+    }
+}
+
+/// A guild's nickname template, like `{champion} ({original})`. `{{` and `}}` are literal
+/// braces.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NameTemplate(Vec<Segment>);
+
+impl NameTemplate {
+    /// Fills in the placeholders, shortening `{champion}` and then the whole nickname so it fits
+    /// in Discord's limit.
+    pub fn render(&self, values: &TemplateValues) -> String {
+        let champions = self
+            .0
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Placeholder(Placeholder::Champion)))
+            .count();
+        let fixed_chars: usize = self
+            .0
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.chars().count(),
+                Segment::Placeholder(Placeholder::Champion) => 0,
+                Segment::Placeholder(placeholder) => values.get(*placeholder).chars().count(),
+            })
+            .sum();
+        let champion_chars = MAX_NAME_CHARS
+            .saturating_sub(fixed_chars)
+            .checked_div(champions)
+            .unwrap_or(0)
+            // Better to cut the rest of the template than lose the champion entirely.
+            .max(MIN_CHAMPION_CHARS);
+        let champion: String = values.champion.chars().take(champion_chars).collect();
+        let rendered: String = self
+            .0
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.as_str(),
+                Segment::Placeholder(Placeholder::Champion) => champion.trim_end(),
+                Segment::Placeholder(placeholder) => values.get(*placeholder),
+            })
+            .collect();
+        fit_nick(&rendered).into_owned()
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let (name, rest) = chars
+                        .as_str()
+                        .split_once('}')
+                        .ok_or_else(|| "A placeholder is missing its closing }.".to_string())?;
+                    let placeholder = Placeholder::ALL
+                        .iter()
+                        .find(|(placeholder_name, _)| *placeholder_name == name)
+                        .map(|(_, placeholder)| *placeholder)
+                        .ok_or_else(|| {
+                            format!(
+                                "Unknown placeholder {{{name}}}. Use {}.",
+                                Placeholder::ALL
+                                    .iter()
+                                    .map(|(name, _)| format!("{{{name}}}"))
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            )
+                        })?;
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Placeholder(placeholder));
+                    chars = rest.chars();
+                }
+                '}' => return Err("Unmatched }. Use }} for a literal brace.".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        if !segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Placeholder(Placeholder::Champion)))
+        {
+            return Err("The template needs a {champion} placeholder.".to_string());
+        }
+        Ok(Self(segments))
+    }
+}
+
+impl Display for NameTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for segment in &self.0 {
+            match segment {
+                Segment::Text(text) => f.write_str(&text.replace('{', "{{").replace('}', "}}"))?,
+                Segment::Placeholder(placeholder) => write!(f, "{{{}}}", placeholder.name())?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    io::{self, stdout, Stdout},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local};
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        cursor::{Hide, Show},
+        execute,
+        terminal::{EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, Row, Table},
+    Frame, Terminal,
+};
+use serenity::{
+    cache::Cache,
+    model::prelude::{GuildId, UserId},
+};
+
+use crate::{
+    breaker::BREAKERS,
+    db::StoredName,
+    guild_names::GuildNames,
+    scheduler::EDITS,
+    session, stats,
+    store::{NameStore, Names, Store},
+};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// How many of each guild's latest renames are shown.
+const RECENT_RENAMES: usize = 5;
+
+/// Redraws a summary of every guild on the terminal's alternate screen until the process exits.
+/// Logging is off while it runs so the two don't fight over the screen, and `restore` gives the
+/// screen back. The terminal is left out of raw mode so Ctrl+C still shuts the bot down.
+pub async fn run(db: Store, cache: Arc<Cache>) {
+    // There's nowhere left to report a broken terminal to.
+    let Ok(mut terminal) = enter() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let _ = terminal.draw(|frame| draw(frame, &db, &cache));
+    }
+}
+
+fn enter() -> io::Result<Terminal<CrosstermBackend<Stdout>>> {
+    execute!(stdout(), EnterAlternateScreen, Hide)?;
+    Terminal::new(CrosstermBackend::new(stdout()))
+}
+
+/// Leaves the alternate screen `run` draws on.
+pub fn restore() {
+    let _ = execute!(stdout(), LeaveAlternateScreen, Show);
+}
+
+fn time(at_ms: u64) -> String {
+    DateTime::from_timestamp_millis(at_ms as i64).map_or_else(String::new, |at| {
+        at.with_timezone(&Local).format("%H:%M:%S").to_string()
+    })
+}
+
+fn draw(frame: &mut Frame, db: &Store, cache: &Cache) {
+    let guilds = cache.guilds();
+    let [header, body] =
+        Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(frame.area());
+    frame.render_widget(
+        Line::from(vec![
+            "Name changer".bold(),
+            Span::raw(format!(
+                ": {} servers, {}",
+                guilds.len(),
+                Local::now().format("%H:%M:%S")
+            )),
+        ]),
+        header,
+    );
+    let panels = Layout::vertical(guilds.iter().map(|_| Constraint::Fill(1))).split(body);
+    for (guild_id, area) in guilds.into_iter().zip(panels.iter()) {
+        draw_guild(frame, *area, db, cache, guild_id);
+    }
+}
+
+fn draw_guild(frame: &mut Frame, area: Rect, db: &Store, cache: &Cache, guild_id: GuildId) {
+    let names = GuildNames::new(cache, guild_id);
+    let counts = stats::rename_counts(db, guild_id);
+    let status = if BREAKERS.is_open(guild_id) {
+        "paused".red()
+    } else {
+        "running".green()
+    };
+    let block = Block::bordered()
+        .title(Line::from(vec![
+            Span::raw(format!(" {} ({guild_id}): ", names.guild())).bold(),
+            status,
+            Span::raw(" "),
+        ]))
+        .title_bottom(format!(
+            " {} edits waiting, {} renames, {} failed ",
+            EDITS.waiting(guild_id),
+            counts.renames,
+            counts.failures
+        ));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+    let [assignments_area, renames_area] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(inner);
+
+    let name_overrides: HashMap<UserId, StoredName> =
+        db.names(Names::Override, guild_id).into_iter().collect();
+    let mut rows = Vec::new();
+    for (channel_id, assignments) in session::guild_assignments(db, guild_id) {
+        let channel = names.channel(channel_id);
+        for (user_id, from) in assignments {
+            rows.push(Row::new([
+                channel.clone(),
+                names.member(user_id),
+                name_overrides
+                    .get(&user_id)
+                    .map(|nick| nick.name.clone())
+                    .unwrap_or_default(),
+                names.member(from),
+            ]));
+        }
+    }
+    let assignments = Table::new(rows, [Constraint::Fill(1); 4])
+        .header(
+            Row::new(["Channel", "Member", "Nickname", "Named after"]).style(Style::new().bold()),
+        )
+        .block(Block::new().borders(Borders::RIGHT));
+    frame.render_widget(assignments, assignments_area);
+
+    let renames: Vec<_> = stats::recent_renames(db, guild_id, RECENT_RENAMES)
+        .into_iter()
+        .map(|rename| {
+            let outcome = if rename.failed {
+                "failed".red()
+            } else {
+                "ok".green()
+            };
+            ListItem::new(Line::from(vec![
+                Span::raw(format!("{} ", time(rename.at_ms))),
+                outcome,
+                Span::raw(format!(
+                    " {} → {}",
+                    names.member(rename.user_id),
+                    rename.new
+                )),
+            ]))
+        })
+        .collect();
+    frame.render_widget(
+        List::new(renames).block(Block::new().title("Latest renames")),
+        renames_area,
+    );
+}
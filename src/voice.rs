@@ -0,0 +1,55 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use serenity::model::{
+    prelude::{ChannelId, Guild, GuildId, UserId},
+    voice::VoiceState,
+};
+
+/// Who is in which voice channel, as far as voice state updates have told us. Fills in for the
+/// cache while it doesn't have a guild, e.g. right after reconnecting.
+#[derive(Default)]
+pub struct VoiceChannels {
+    channels: Mutex<HashMap<(GuildId, UserId), ChannelId>>,
+}
+
+impl VoiceChannels {
+    pub fn update(&self, voice_state: &VoiceState) {
+        let Some(guild_id) = voice_state.guild_id else {
+            return;
+        };
+        let mut channels = self.channels.lock().unwrap();
+        match voice_state.channel_id {
+            Some(channel_id) => channels.insert((guild_id, voice_state.user_id), channel_id),
+            None => channels.remove(&(guild_id, voice_state.user_id)),
+        };
+    }
+
+    /// Starts over from the voice states the guild came with.
+    pub fn seed(&self, guild: &Guild) {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|(guild_id, _), _| *guild_id != guild.id);
+        channels.extend(guild.voice_states.values().filter_map(|voice_state| {
+            Some(((guild.id, voice_state.user_id), voice_state.channel_id?))
+        }));
+    }
+
+    pub fn channel_of(&self, guild_id: GuildId, user_id: UserId) -> Option<ChannelId> {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(&(guild_id, user_id))
+            .copied()
+    }
+
+    pub fn members(&self, guild_id: GuildId, channel_id: ChannelId) -> Vec<UserId> {
+        self.channels
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((member_guild_id, _), member_channel_id)| {
+                *member_guild_id == guild_id && **member_channel_id == channel_id
+            })
+            .map(|((_, user_id), _)| *user_id)
+            .collect()
+    }
+}
@@ -0,0 +1,101 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+/// Live internal state an operator might want to look at.
+pub trait DebugState: Send + Sync + 'static {
+    fn debug_state(&self) -> serde_json::Value;
+}
+
+/// Whether the bot can do its job right now.
+pub trait Readiness: Send + Sync + 'static {
+    /// Says what's wrong if it can't.
+    fn ready(&self) -> Result<(), String>;
+}
+
+struct Web {
+    debug: Arc<dyn DebugState>,
+    readiness: Arc<dyn Readiness>,
+    debug_token: Option<String>,
+}
+
+/// Serves the bot's HTTP endpoints until the process exits.
+pub async fn serve(
+    addr: SocketAddr,
+    debug: Arc<dyn DebugState>,
+    readiness: Arc<dyn Readiness>,
+    debug_token: Option<String>,
+    admin: Option<Router>,
+) {
+    let web = Arc::new(Web {
+        debug,
+        readiness,
+        debug_token,
+    });
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/debug/state", get(debug_state))
+        .with_state(web)
+        .merge(admin.unwrap_or_default());
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on {addr}: {e}");
+            return;
+        }
+    };
+    info!("Serving HTTP on {addr}");
+    if let Err(e) = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    {
+        error!("HTTP server failed: {e}");
+    }
+}
+
+/// Answers as long as the process is up.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Fails while the gateway is disconnected or the database can't be read, so orchestrators can
+/// restart a bot that's silently stopped working.
+async fn readyz(State(web): State<Arc<Web>>) -> Response {
+    match web.readiness.ready() {
+        Ok(()) => "ready".into_response(),
+        Err(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason).into_response(),
+    }
+}
+
+/// Only answers local requests that present the debug token, and only if one is configured.
+async fn debug_state(
+    State(web): State<Arc<Web>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(token) = &web.debug_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !peer.ip().is_loopback() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let presented = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if presented != Some(token.as_str()) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(web.debug.debug_state()).into_response()
+}